@@ -7,6 +7,7 @@ const FileUpload = forwardRef(({
   onUploadError,
   disabled,
   attachedFiles = [],
+  sessionId,
 }, ref) => {
   const [uploading, setUploading] = useState(false);
   const fileInputRef = useRef(null);
@@ -14,6 +15,8 @@ const FileUpload = forwardRef(({
   const allowedExtensions = [
     // 文档
     ".txt", ".pdf", ".docx", ".pptx", ".xlsx",
+    // 压缩包（解压后逐个解析）
+    ".zip",
     // Markdown
     ".md", ".markdown",
     // 代码文件
@@ -63,6 +66,7 @@ const FileUpload = forwardRef(({
       pptx: "PowerPoint",
       xlsx: "Excel Spreadsheet",
       txt: "Text File",
+      zip: "ZIP Archive",
       // Markdown
       md: "Markdown",
       markdown: "Markdown",
@@ -162,16 +166,37 @@ const FileUpload = forwardRef(({
     return filename?.split(".").pop().toLowerCase() || "";
   };
 
+  // 文档在后台解析，等待解析结束（ready 或 failed）
+  const waitUntilParsed = (fileId) =>
+    new Promise((resolve) => {
+      const source = new EventSource(
+        `http://localhost:8080/files/${fileId}/status?stream=true`
+      );
+      source.addEventListener("status", (event) => {
+        const status = JSON.parse(event.data);
+        if (status.status !== "processing") {
+          source.close();
+          resolve(status);
+        }
+      });
+      source.onerror = () => {
+        source.close();
+        resolve({ status: "failed", error: "Lost connection while parsing file" });
+      };
+    });
+
   const handleFileSelect = async (e) => {
-    const file = e.target.files?.[0];
-    if (!file) return;
+    const files = Array.from(e.target.files || []);
+    if (files.length === 0) return;
 
     // 前端文件类型校验
-    const ext = "." + file.name.split(".").pop().toLowerCase();
-    if (!allowedExtensions.includes(ext)) {
+    const unsupported = files.find(
+      (file) => !allowedExtensions.includes("." + file.name.split(".").pop().toLowerCase())
+    );
+    if (unsupported) {
       onUploadError?.({
         error: "Unsupported file type",
-        file_type: ext.slice(1) // 移除前面的点
+        file_type: unsupported.name.split(".").pop().toLowerCase()
       });
       if (fileInputRef.current) {
         fileInputRef.current.value = "";
@@ -182,10 +207,16 @@ const FileUpload = forwardRef(({
     setUploading(true);
 
     try {
+      // 所有文件在同一个请求中上传
       const formData = new FormData();
-      formData.append("file", file);
+      files.forEach((file) => formData.append("file", file));
+
+      // 文件归属于当前会话，没有会话时由后端分配
+      const uploadUrl = sessionId
+        ? `http://localhost:8080/upload?session_id=${encodeURIComponent(sessionId)}`
+        : "http://localhost:8080/upload";
 
-      const response = await fetch("http://localhost:8080/upload", {
+      const response = await fetch(uploadUrl, {
         method: "POST",
         body: formData,
       });
@@ -197,14 +228,30 @@ const FileUpload = forwardRef(({
         return;
       }
 
-      const data = await response.json();
-      data.filesize = file.size;
-      onFileUploaded?.(data);
+      const uploaded = await response.json();
+      const statuses = await Promise.all(
+        uploaded.map((data) =>
+          data.status === "processing" ? waitUntilParsed(data.file_id) : data
+        )
+      );
+
+      // 压缩包会展开成多个文件，所以使用后端返回的大小
+      uploaded.forEach((data, i) => {
+        if (statuses[i].status === "failed") {
+          onUploadError?.({
+            error: statuses[i].error || "Failed to parse file",
+            file_type: getFileExt(data.filename),
+          });
+          return;
+        }
+        data.filesize = data.file_size;
+        onFileUploaded?.(data);
+      });
     } catch (err) {
       // 网络错误等
       onUploadError?.({
         error: "Upload failed",
-        file_type: file.name.split('.').pop() || "unknown"
+        file_type: files[0].name.split('.').pop() || "unknown"
       });
     } finally {
       setUploading(false);
@@ -235,6 +282,7 @@ const FileUpload = forwardRef(({
       <input
         ref={fileInputRef}
         type="file"
+        multiple
         accept={allowedExtensions.join(",")}
         onChange={handleFileSelect}
         disabled={disabled || uploading}
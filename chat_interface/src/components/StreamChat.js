@@ -26,11 +26,23 @@ const StreamingChat = () => {
   const abortControllerRef = useRef(null);
   const shouldAutoScrollRef = useRef(true);
 
-  const models = [
+  const [models, setModels] = useState([
     { id: "qwen", name: "QWEN" },
     { id: "smollm2", name: "SmolLM2 1.7B" },
     { id: "llama8b", name: "LLaMA 8B" },
-  ];
+  ]);
+
+  // 从后端获取模型列表，失败时使用默认列表
+  useEffect(() => {
+    fetch("http://localhost:8080/models")
+      .then((res) => (res.ok ? res.json() : Promise.reject(res.status)))
+      .then((data) => {
+        if (data.models?.length > 0) {
+          setModels(data.models.map((m) => ({ id: m.name, name: m.name.toUpperCase() })));
+        }
+      })
+      .catch((e) => console.error("Failed to load model list:", e));
+  }, []);
 
   // 从 localStorage 加载会话列表
   useEffect(() => {
@@ -84,6 +96,10 @@ const StreamingChat = () => {
   // 添加文件到列表
   const handleFileUploaded = (fileData) => {
     setAttachedFiles((prev) => [...prev, fileData]);
+    // 新对话的第一个文件会由后端分配会话 ID
+    if (!currentSessionId && fileData.session_id) {
+      setCurrentSessionId(fileData.session_id);
+    }
   };
 
   // 处理上传错误
@@ -279,6 +295,7 @@ const StreamingChat = () => {
       let buffer = "";
       let fullResponse = "";
       let receivedSessionId = currentSessionId;
+      let eventName = "message";
 
       while (true) {
         const { done, value } = await reader.read();
@@ -289,41 +306,43 @@ const StreamingChat = () => {
         buffer = lines.pop() || "";
 
         for (const line of lines) {
-          // 处理会话信息事件
-          if (line.startsWith("event: session")) {
+          // 每个 SSE 事件以 "event: <name>" 开头，空行结束
+          if (line.startsWith("event: ")) {
+            eventName = line.slice(7).trim();
+            continue;
+          }
+          if (line.trim() === "") {
+            eventName = "message";
+            continue;
+          }
+          if (!line.startsWith("data: ")) continue;
+
+          let parsed;
+          try {
+            parsed = JSON.parse(line.slice(6));
+          } catch {
             continue;
           }
-          
-          if (line.startsWith("data: ")) {
-            const data = line.slice(6).trim();
-            if (data === "[DONE]") continue;
-
-            try {
-              const parsed = JSON.parse(data);
-              
-              // 检查是否是会话信息
-              if (parsed.session_id && parsed.type === "session_info") {
-                receivedSessionId = parsed.session_id;
-                if (!currentSessionId) {
-                  setCurrentSessionId(receivedSessionId);
-                }
-                continue;
-              }
-              
-              const content = parsed.content || "";
-              if (content) {
-                fullResponse += content;
-                setMessages((prev) => {
-                  const updated = [...prev];
-                  updated[updated.length - 1] = {
-                    ...updated[updated.length - 1],
-                    content: updated[updated.length - 1].content + content,
-                  };
-                  return updated;
-                });
-              }
-            } catch {
-              // ignore parse errors
+
+          if (eventName === "session") {
+            receivedSessionId = parsed.session_id;
+            if (!currentSessionId) {
+              setCurrentSessionId(receivedSessionId);
+            }
+          } else if (eventName === "error") {
+            throw new Error(parsed.error);
+          } else if (eventName === "token") {
+            const content = parsed.content || "";
+            if (content) {
+              fullResponse += content;
+              setMessages((prev) => {
+                const updated = [...prev];
+                updated[updated.length - 1] = {
+                  ...updated[updated.length - 1],
+                  content: updated[updated.length - 1].content + content,
+                };
+                return updated;
+              });
             }
           }
         }
@@ -569,6 +588,7 @@ const StreamingChat = () => {
               onUploadError={handleUploadError}
               disabled={isStreaming}
               attachedFiles={attachedFiles}
+              sessionId={currentSessionId}
             />
 
             <div className={styles.inputRow}>
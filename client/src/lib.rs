@@ -0,0 +1,321 @@
+//! LLM Inference Service 的异步客户端，请求和响应都是带类型的结构体，
+//! 流式接口返回解析好的 SSE 事件。
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use llm_inference_client::{ChatEvent, ChatRequest, Client};
+//!
+//! # async fn run() -> Result<(), llm_inference_client::ClientError> {
+//! let client = Client::new("http://localhost:3000").with_api_key("sk-alice");
+//! let mut events = client.chat_stream(&ChatRequest::new("qwen2.5-7b", "Hello")).await?;
+//! while let Some(event) = events.next().await {
+//!     if let ChatEvent::Token { content, .. } = event? {
+//!         print!("{}", content);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod types;
+
+use std::fmt;
+use std::path::Path;
+use std::pin::Pin;
+use eventsource_stream::Eventsource;
+use futures::{Stream, StreamExt};
+use reqwest::{multipart, RequestBuilder, Response, StatusCode};
+use serde_json::Value;
+
+pub use types::*;
+
+
+/// `/generate/stream` 的事件流，在 `done` 事件之后结束
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<ChatEvent, ClientError>> + Send>>;
+
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// 连接失败、超时等
+    Http(reqwest::Error),
+    /// 服务端返回的错误状态码。error 为响应中的 `error` 字段，body 为完整的响应
+    /// （例如 400 的 `field`、413 的 `limit`）
+    Api { status: StatusCode, error: String, body: Value },
+    /// 响应或 SSE 事件不是预期的 JSON
+    Json(serde_json::Error),
+    /// SSE 流在传输中出错
+    Stream(String),
+    /// 读取要上传的文件失败
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "request failed: {}", e),
+            ClientError::Api { status, error, .. } => write!(f, "server returned {}: {}", status, error),
+            ClientError::Json(e) => write!(f, "invalid response: {}", e),
+            ClientError::Stream(e) => write!(f, "event stream failed: {}", e),
+            ClientError::Io(e) => write!(f, "failed to read file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            ClientError::Json(e) => Some(e),
+            ClientError::Io(e) => Some(e),
+            ClientError::Api { .. } | ClientError::Stream(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        ClientError::Json(e)
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+
+/// 服务的客户端。内部的 reqwest::Client 共享连接池，clone 的开销很小
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl Client {
+    /// base_url 为服务的地址，例如 `http://localhost:3000`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    /// 服务配置了 api_keys 时，每个请求带上 `Authorization: Bearer <key>`
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// 使用自定义的 reqwest::Client（超时、代理、TLS 等）
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    // 发送请求，非 2xx 的响应转换为 ClientError::Api
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let request = match &self.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        };
+
+        let response = request.send().await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let body = serde_json::from_str(&text).unwrap_or_else(|_| Value::String(text.clone()));
+        let error = body.get("error")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .unwrap_or_else(|| text.trim().to_string());
+        Err(ClientError::Api { status, error, body })
+    }
+
+    pub async fn health(&self) -> Result<HealthResponse, ClientError> {
+        let response = self.send(self.http.get(self.url("/health"))).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, ClientError> {
+        let response = self.send(self.http.get(self.url("/models"))).await?;
+        Ok(response.json::<ListModelsResponse>().await?.models)
+    }
+
+    /// `POST /generate`，等待完整的回答
+    pub async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, ClientError> {
+        let response = self.send(self.http.post(self.url("/generate")).json(request)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// `POST /generate/stream`，逐个返回 SSE 事件。请求被拒绝（400、413、429 等）时返回 Err，
+    /// 生成过程中的错误是流中的 [`ChatEvent::Error`]
+    pub async fn chat_stream(&self, request: &ChatRequest) -> Result<ChatStream, ClientError> {
+        let response = self.send(self.http.post(self.url("/generate/stream")).json(request)).await?;
+
+        let events = response.bytes_stream()
+            .eventsource()
+            .filter_map(|event| async move {
+                match event {
+                    Ok(event) => ChatEvent::from_sse(&event.event, &event.data)
+                        .map_err(ClientError::from)
+                        .transpose(),
+                    Err(e) => Some(Err(ClientError::Stream(e.to_string()))),
+                }
+            });
+        Ok(Box::pin(events))
+    }
+
+    /// 取消正在进行的生成，request_id 来自 [`ChatEvent::Request`]
+    pub async fn cancel(&self, request_id: &str) -> Result<CancelResponse, ClientError> {
+        let url = self.url(&format!("/generate/cancel/{}", request_id));
+        let response = self.send(self.http.post(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// 上传一个文件。session_id 为 None 时服务端创建新的 session，见返回的 session_id
+    pub async fn upload(
+        &self,
+        filename: impl Into<String>,
+        data: impl Into<Vec<u8>>,
+        session_id: Option<&str>,
+    ) -> Result<Vec<UploadResponse>, ClientError> {
+        let part = multipart::Part::bytes(data.into()).file_name(filename.into());
+        let form = multipart::Form::new().part("file", part);
+
+        let mut request = self.http.post(self.url("/upload")).multipart(form);
+        if let Some(session_id) = session_id {
+            request = request.query(&[("session_id", session_id)]);
+        }
+        let response = self.send(request).await?;
+        Ok(response.json().await?)
+    }
+
+    /// 读取本地文件并上传，文件名取路径的最后一部分
+    pub async fn upload_path(
+        &self,
+        path: impl AsRef<Path>,
+        session_id: Option<&str>,
+    ) -> Result<Vec<UploadResponse>, ClientError> {
+        let path = path.as_ref();
+        let data = tokio::fs::read(path).await?;
+        let filename = path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.upload(filename, data, session_id).await
+    }
+
+    /// 文档在后台解析，status 变为 ready 后才会出现在对话的上下文中
+    pub async fn file_status(&self, file_id: &str) -> Result<FileStatusResponse, ClientError> {
+        let url = self.url(&format!("/files/{}/status", file_id));
+        let response = self.send(self.http.get(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn remove_session(&self, session_id: &str) -> Result<RemoveSessionResponse, ClientError> {
+        let url = self.url(&format!("/sessions/{}", session_id));
+        let response = self.send(self.http.delete(url)).await?;
+        Ok(response.json().await?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::Multipart,
+        http::{header, HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::post,
+        Json, Router,
+    };
+
+    // 模拟服务端的几个接口，监听随机端口
+    async fn mock_server() -> String {
+        async fn stream(headers: HeaderMap) -> impl IntoResponse {
+            assert_eq!(headers.get(header::AUTHORIZATION).unwrap(), "Bearer sk-test");
+            let body = concat!(
+                "event: request\ndata: {\"request_id\":\"r1\"}\n\n",
+                "event: token\ndata: {\"content\":\"Hi\"}\n\n",
+                ": keep-alive\n\n",
+                "event: usage\ndata: {\"prompt_tokens\":1,\"completion_tokens\":1,\"total_tokens\":2,\"finish_reason\":\"stop\"}\n\n",
+                "event: done\ndata: {}\n\n",
+            );
+            ([(header::CONTENT_TYPE, "text/event-stream")], body)
+        }
+
+        async fn upload(mut multipart: Multipart) -> impl IntoResponse {
+            let field = multipart.next_field().await.unwrap().unwrap();
+            let filename = field.file_name().unwrap().to_string();
+            let file_size = field.bytes().await.unwrap().len();
+            Json(serde_json::json!([{
+                "file_id": "f1",
+                "filename": filename,
+                "file_size": file_size,
+                "session_id": "s1",
+                "status": "processing",
+            }]))
+        }
+
+        async fn generate() -> impl IntoResponse {
+            (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "prompt must not be empty", "field": "prompt" })))
+        }
+
+        let app = Router::new()
+            .route("/generate/stream", post(stream))
+            .route("/generate", post(generate))
+            .route("/upload", post(upload));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_events() {
+        let client = Client::new(mock_server().await).with_api_key("sk-test");
+        let events: Vec<ChatEvent> = client.chat_stream(&ChatRequest::new("", "Hi")).await.unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], ChatEvent::Request { request_id: "r1".to_string() });
+        assert_eq!(events[1], ChatEvent::Token { content: "Hi".to_string(), logprobs: None });
+        assert_eq!(events[3], ChatEvent::Done { timings: None });
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_api_error() {
+        let client = Client::new(mock_server().await);
+        let files = client.upload("notes.txt", b"hello".to_vec(), Some("s1")).await.unwrap();
+        assert_eq!(files[0].filename, "notes.txt");
+        assert_eq!(files[0].file_size, 5);
+        assert_eq!(files[0].status, FileStatus::Processing);
+
+        match client.chat(&ChatRequest::new("", "")).await {
+            Err(ClientError::Api { status, error, body }) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(error, "prompt must not be empty");
+                assert_eq!(body["field"], "prompt");
+            }
+            other => panic!("expected an API error, got {:?}", other),
+        }
+    }
+}
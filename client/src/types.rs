@@ -0,0 +1,272 @@
+use serde::{Deserialize, Serialize};
+
+
+/// `POST /generate` 和 `/generate/stream` 的请求体，未设置的字段不发送，由服务端使用模型的默认值
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChatRequest {
+    #[serde(rename = "model_name", skip_serializing_if = "String::is_empty")]
+    pub model: String,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub file_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub image_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+    // 服务端注册的 few-shot 示例组名称
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub examples: Option<String>,
+}
+
+impl ChatRequest {
+    pub fn new(model: impl Into<String>, prompt: impl Into<String>) -> Self {
+        Self {
+            model: model.into(),
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn session(mut self, session_id: impl Into<String>) -> Self {
+        self.session_id = Some(session_id.into());
+        self
+    }
+}
+
+
+/// `POST /generate` 的响应
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ChatResponse {
+    pub text: String,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(flatten)]
+    pub usage: Usage,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolInvocation>,
+    #[serde(default)]
+    pub timings: Option<Timings>,
+    // 回答来自服务器的 response cache
+    #[serde(default)]
+    pub cached: bool,
+}
+
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    // "stop"、"length" 或 "canceled"
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Timings {
+    pub queue_ms: u64,
+    #[serde(default)]
+    pub ttft_ms: Option<u64>,
+    pub total_ms: u64,
+    pub tokens_per_second: f64,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ToolInvocation {
+    pub name: String,
+    pub arguments: serde_json::Value,
+    pub output: String,
+    pub success: bool,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TokenLogprobs {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+}
+
+
+/// `/generate/stream` 的一个 SSE 事件，`event:` 字段决定变体，`data:` 为变体的字段
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ChatEvent {
+    Request { request_id: String },
+    Token {
+        content: String,
+        #[serde(default)]
+        logprobs: Option<TokenLogprobs>,
+    },
+    ToolCall { name: String, arguments: serde_json::Value },
+    ToolResult { name: String, output: String, success: bool },
+    Usage(Usage),
+    Error {
+        error: String,
+        #[serde(default)]
+        request_id: Option<String>,
+        #[serde(default)]
+        finish_reason: Option<String>,
+    },
+    Session { session_id: String },
+    Done {
+        #[serde(default)]
+        timings: Option<Timings>,
+    },
+}
+
+impl ChatEvent {
+    const NAMES: [&'static str; 8] = ["request", "token", "tool_call", "tool_result", "usage", "error", "session", "done"];
+
+    /// 解析一个 SSE 事件。服务端以后新增的事件返回 None，旧的客户端可以跳过它们
+    pub fn from_sse(event: &str, data: &str) -> Result<Option<ChatEvent>, serde_json::Error> {
+        if !Self::NAMES.contains(&event) {
+            return Ok(None);
+        }
+        let data: serde_json::Value = serde_json::from_str(data)?;
+        serde_json::from_value(serde_json::json!({ "event": event, "data": data })).map(Some)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Processing,
+    Ready,
+    Failed,
+}
+
+
+/// `POST /upload` 返回的一个文件（zip 解压后的每个成员各一项）
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct UploadResponse {
+    pub file_id: String,
+    pub filename: String,
+    pub file_size: usize,
+    pub session_id: String,
+    pub status: FileStatus,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct FileStatusResponse {
+    pub file_id: String,
+    pub status: FileStatus,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct HealthResponse {
+    pub is_healthy: bool,
+    pub status: String,
+    pub cpu_fallback: bool,
+    // 默认模型已加载
+    #[serde(default)]
+    pub ready: bool,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    pub repo: String,
+    pub file: String,
+    pub quantization: String,
+    pub context_length: usize,
+    pub downloaded: bool,
+    pub loaded: bool,
+    pub vision: bool,
+    pub device: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ListModelsResponse {
+    pub models: Vec<ModelInfo>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CancelResponse {
+    pub request_id: String,
+    pub cancelled: bool,
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RemoveSessionResponse {
+    pub session_id: String,
+    pub cleared: bool,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_request_sends_only_set_fields() {
+        let request = ChatRequest {
+            temperature: Some(0.2),
+            ..ChatRequest::new("qwen2.5-7b", "Hi").session("s1")
+        };
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body, serde_json::json!({
+            "model_name": "qwen2.5-7b",
+            "prompt": "Hi",
+            "session_id": "s1",
+            "temperature": 0.2,
+        }));
+    }
+
+    #[test]
+    fn test_parse_chat_events() {
+        assert_eq!(
+            ChatEvent::from_sse("token", r#"{"content":"Hel"}"#).unwrap(),
+            Some(ChatEvent::Token { content: "Hel".to_string(), logprobs: None }),
+        );
+        assert_eq!(
+            ChatEvent::from_sse("usage", r#"{"prompt_tokens":3,"completion_tokens":2,"total_tokens":5,"finish_reason":"stop"}"#).unwrap(),
+            Some(ChatEvent::Usage(Usage { prompt_tokens: 3, completion_tokens: 2, total_tokens: 5, finish_reason: Some("stop".to_string()) })),
+        );
+        // done 在排队时取消的请求中没有 timings
+        assert_eq!(ChatEvent::from_sse("done", "{}").unwrap(), Some(ChatEvent::Done { timings: None }));
+        assert_eq!(ChatEvent::from_sse("heartbeat", "{}").unwrap(), None);
+        assert!(ChatEvent::from_sse("token", "not json").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+use crate::types::{ChatMessage, MessageRole};
+use crate::tools::{parse_tool_call, with_tool_prompt, Tool, ToolCall};
+
+
+// POST /agent/run 默认和最多的迭代次数（每次迭代为一次生成，最多调用一次工具）
+pub const DEFAULT_AGENT_ITERATIONS: usize = 5;
+pub const MAX_AGENT_ITERATIONS: usize = 20;
+
+const AGENT_PROMPT: &str = "You are an agent that solves the user's task step by step. \
+    Before each tool call, briefly explain what you are going to do and why. \
+    Look at every tool result before deciding the next step. \
+    When you know the answer, reply with the final answer and no tool call.";
+
+
+/// agent 的初始对话：系统提示词（请求未指定时使用默认的 agent 提示词）、工具说明和任务
+pub fn agent_conversation(task: &str, system_prompt: Option<&str>, tools: &[Arc<dyn Tool>]) -> Vec<ChatMessage> {
+    let messages = vec![
+        ChatMessage::new(MessageRole::System, system_prompt.unwrap_or(AGENT_PROMPT).to_string()),
+        ChatMessage::new(MessageRole::User, task.to_string()),
+    ];
+    with_tool_prompt(&messages, tools)
+}
+
+
+/// 模型一次回复的含义
+#[derive(Debug, PartialEq)]
+pub enum AgentStep {
+    /// 先思考再调用工具，thought 为调用之前的文本
+    Act { thought: String, call: ToolCall },
+    /// 没有工具调用，回复即最终答案
+    Finish { answer: String },
+}
+
+pub fn next_step(text: &str) -> AgentStep {
+    match parse_tool_call(text) {
+        Some((thought, call)) => AgentStep::Act { thought, call },
+        None => AgentStep::Finish { answer: text.trim().to_string() },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::Calculator;
+    use serde_json::json;
+
+    #[test]
+    fn test_conversation_puts_tools_after_system_prompt() {
+        let tools: Vec<Arc<dyn Tool>> = vec![Arc::new(Calculator)];
+        let messages = agent_conversation("What is 2+2?", None, &tools);
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].content, AGENT_PROMPT);
+        assert_eq!(messages[1].role, MessageRole::System);
+        assert!(messages[1].content.contains("calculator"));
+        assert_eq!(messages[2].content, "What is 2+2?");
+
+        let messages = agent_conversation("task", Some("Be terse."), &[]);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "Be terse.");
+    }
+
+    #[test]
+    fn test_next_step() {
+        let text = "I need to add.\n<tool_call>{\"name\": \"calculator\", \"arguments\": {\"expression\": \"2+2\"}}</tool_call>";
+        assert_eq!(next_step(text), AgentStep::Act {
+            thought: "I need to add.".to_string(),
+            call: ToolCall {
+                name: "calculator".to_string(),
+                arguments: json!({"expression": "2+2"}),
+            },
+        });
+        assert_eq!(next_step(" The answer is 4. "), AgentStep::Finish { answer: "The answer is 4.".to_string() });
+    }
+}
@@ -0,0 +1,285 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use crate::file_store::unix_now;
+use crate::types::{AuditQuery, ChatMessage, MessageRole, Timings, Usage};
+
+
+// 每小时按 audit_retention_days 清理一次过期的记录
+const PRUNE_INTERVAL_SECS: u64 = 3600;
+
+
+/// prompt 和回答只以 blake3 哈希记录，审计日志中不保存原文
+pub fn content_hash(text: &str) -> String {
+    blake3::hash(text.as_bytes()).to_hex().to_string()
+}
+
+/// 对话中最后一条用户消息，即本次请求的 prompt
+pub fn last_user_prompt(messages: &[ChatMessage]) -> &str {
+    messages.iter()
+        .rev()
+        .find(|m| m.role == MessageRole::User)
+        .map_or("", |m| m.content.as_str())
+}
+
+
+/// 审计日志的一行：一次生成请求
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub request_id: String,
+    // API key 对应的用户名（不保存 key 本身），未配置 api_keys 时为 null
+    pub owner: Option<String>,
+    pub session_id: Option<String>,
+    // 请求的路径，例如 "/generate"
+    pub endpoint: String,
+    pub model: String,
+    pub prompt_hash: String,
+    pub response_hash: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    pub finish_reason: Option<String>,
+    pub queue_ms: u64,
+    pub ttft_ms: Option<u64>,
+    pub duration_ms: u64,
+}
+
+impl AuditRecord {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        endpoint: &str,
+        request_id: &str,
+        owner: Option<&str>,
+        session_id: Option<&str>,
+        model: &str,
+        prompt: &str,
+        response: &str,
+        usage: &Usage,
+        timings: &Timings,
+    ) -> Self {
+        Self {
+            timestamp: unix_now(),
+            request_id: request_id.to_string(),
+            owner: owner.map(str::to_string),
+            session_id: session_id.map(str::to_string),
+            endpoint: endpoint.to_string(),
+            model: model.to_string(),
+            prompt_hash: content_hash(prompt),
+            response_hash: content_hash(response),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            finish_reason: usage.finish_reason.clone(),
+            queue_ms: timings.queue_ms,
+            ttft_ms: timings.ttft_ms,
+            duration_ms: timings.total_ms,
+        }
+    }
+
+    fn matches(&self, query: &AuditQuery) -> bool {
+        let same = |filter: &Option<String>, value: Option<&str>| {
+            filter.as_deref().is_none_or(|filter| Some(filter) == value)
+        };
+        same(&query.owner, self.owner.as_deref())
+            && same(&query.session_id, self.session_id.as_deref())
+            && same(&query.model, Some(&self.model))
+            && same(&query.request_id, Some(&self.request_id))
+            && query.since.is_none_or(|since| self.timestamp >= since)
+            && query.until.is_none_or(|until| self.timestamp < until)
+    }
+}
+
+
+/// 只追加的审计日志（JSON Lines），按时间顺序每次生成一行。path 为空时不记录。
+/// 超过 retention_days 的记录由后台任务删除，0 表示永久保留
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    retention_days: u64,
+    // 追加、查询和清理串行执行，清理重写文件时不会丢失新的记录
+    lock: Mutex<()>,
+}
+
+pub type SharedAuditLog = Arc<AuditLog>;
+
+pub fn new_audit_log(path: &str, retention_days: u64) -> SharedAuditLog {
+    Arc::new(AuditLog {
+        path: (!path.is_empty()).then(|| PathBuf::from(path)),
+        retention_days,
+        lock: Mutex::new(()),
+    })
+}
+
+impl AuditLog {
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub async fn record(&self, record: &AuditRecord) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _guard = self.lock.lock().await;
+        if let Err(e) = append_line(path, record).await {
+            tracing::error!(path = %path.display(), request_id = %record.request_id, error = %e, "Failed to write audit log");
+        }
+    }
+
+    /// 符合条件的记录，最新的在前，最多 limit 条。无法解析的行（例如写到一半的行）跳过
+    pub async fn query(&self, query: &AuditQuery, limit: usize) -> Result<Vec<AuditRecord>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let _guard = self.lock.lock().await;
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(content.lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<AuditRecord>(line).ok())
+            .filter(|record| record.matches(query))
+            .take(limit)
+            .collect())
+    }
+
+    /// 删除早于 now - retention_days 的记录，返回删除的行数
+    pub async fn prune(&self, now: u64) -> Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        if self.retention_days == 0 {
+            return Ok(0);
+        }
+        let cutoff = now.saturating_sub(self.retention_days * 24 * 3600);
+
+        let _guard = self.lock.lock().await;
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        // 记录按时间顺序追加，保留第一条未过期的记录及之后的全部内容
+        let lines: Vec<&str> = content.lines().collect();
+        let expired = lines.iter()
+            .position(|line| {
+                !matches!(serde_json::from_str::<AuditRecord>(line), Ok(record) if record.timestamp < cutoff)
+            })
+            .unwrap_or(lines.len());
+        if expired == 0 {
+            return Ok(0);
+        }
+
+        let mut kept = lines[expired..].join("\n");
+        if !kept.is_empty() {
+            kept.push('\n');
+        }
+        let tmp_path = path.with_extension("jsonl.tmp");
+        fs::write(&tmp_path, kept).await?;
+        fs::rename(&tmp_path, path).await?;
+        Ok(expired)
+    }
+}
+
+async fn append_line(path: &Path, record: &AuditRecord) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+
+/// 后台定期删除超过保留期的审计记录。未启用审计日志或 retention_days 为 0 时不清理
+pub fn spawn_audit_pruner(log: SharedAuditLog) {
+    if !log.enabled() || log.retention_days == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(PRUNE_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            match log.prune(unix_now()).await {
+                Ok(0) => {}
+                Ok(removed) => tracing::info!(records = removed, retention_days = log.retention_days, "Pruned audit log"),
+                Err(e) => tracing::error!(error = %e, "Failed to prune audit log"),
+            }
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(request_id: &str, owner: &str, timestamp: u64) -> AuditRecord {
+        let usage = Usage { prompt_tokens: 3, completion_tokens: 5, total_tokens: 8, finish_reason: None };
+        let timings = Timings { queue_ms: 1, ttft_ms: Some(2), total_ms: 30, tokens_per_second: 0.0 };
+        let mut record = AuditRecord::new("/generate", request_id, Some(owner), None, "qwen", "hi", "hello", &usage, &timings);
+        record.timestamp = timestamp;
+        record
+    }
+
+    fn test_log(retention_days: u64) -> (SharedAuditLog, PathBuf) {
+        let path = std::env::temp_dir().join(format!("audit-test-{}.jsonl", uuid::Uuid::new_v4()));
+        (new_audit_log(path.to_str().unwrap(), retention_days), path)
+    }
+
+    #[test]
+    fn test_record_hashes_content() {
+        let record = record("r1", "alice", 0);
+        assert_eq!(record.prompt_hash, content_hash("hi"));
+        assert_ne!(record.prompt_hash, record.response_hash);
+        assert_eq!(record.prompt_hash.len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_query_filters_newest_first() {
+        let (log, path) = test_log(0);
+        log.record(&record("r1", "alice", 100)).await;
+        log.record(&record("r2", "bob", 200)).await;
+        log.record(&record("r3", "alice", 300)).await;
+
+        let all = log.query(&AuditQuery::default(), 10).await.unwrap();
+        let ids: Vec<_> = all.iter().map(|r| r.request_id.as_str()).collect();
+        assert_eq!(ids, ["r3", "r2", "r1"]);
+
+        let query = AuditQuery { owner: Some("alice".to_string()), since: Some(150), ..Default::default() };
+        let alice = log.query(&query, 10).await.unwrap();
+        assert_eq!(alice, vec![record("r3", "alice", 300)]);
+        assert_eq!(log.query(&AuditQuery::default(), 1).await.unwrap().len(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prune_drops_expired_records() {
+        let (log, path) = test_log(1);
+        let now = 10 * 24 * 3600;
+        log.record(&record("old", "alice", now - 2 * 24 * 3600)).await;
+        log.record(&record("new", "alice", now - 3600)).await;
+
+        assert_eq!(log.prune(now).await.unwrap(), 1);
+        assert_eq!(log.prune(now).await.unwrap(), 0);
+        let remaining = log.query(&AuditQuery::default(), 10).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].request_id, "new");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
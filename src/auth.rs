@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use crate::AppState;
+use crate::error::UnauthorizedError;
+use crate::rate_limit::{caller_key, too_many_requests};
+
+
+/// 发起请求的用户及其租户，由认证中间件放入请求的 extensions。未配置 api_keys 时都为 None
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Caller {
+    pub owner: Option<String>,
+    pub tenant: Option<String>,
+}
+
+impl Caller {
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+}
+
+
+/// 根据 Authorization 头确定调用方，key 无效时返回 None。api_keys 为空时不需要认证。
+/// 租户由 tenants（用户名 -> 租户）决定，不在表中的用户自成一个租户
+pub fn authenticate(
+    api_keys: &HashMap<String, String>,
+    tenants: &HashMap<String, String>,
+    authorization: Option<&str>,
+) -> Option<Caller> {
+    if api_keys.is_empty() {
+        return Some(Caller::default());
+    }
+
+    let key = authorization?.strip_prefix("Bearer ")?.trim();
+    api_keys.get(key).map(|user| Caller {
+        owner: Some(user.clone()),
+        tenant: Some(tenants.get(user).unwrap_or(user).clone()),
+    })
+}
+
+
+/// 能否使用 /admin 下的接口：未配置 api_keys 时所有调用方都可以，否则只有 admin_users 中的用户
+pub fn is_admin(api_keys: &HashMap<String, String>, admin_users: &[String], caller: &Caller) -> bool {
+    if api_keys.is_empty() {
+        return true;
+    }
+    caller.owner().is_some_and(|owner| admin_users.iter().any(|admin| admin == owner))
+}
+
+
+// 不需要认证的路径：健康检查、API 文档和内置聊天页面的静态文件
+pub fn is_public(path: &str) -> bool {
+    matches!(path, "/" | "/health" | "/health/ready" | "/openapi.json" | "/docs")
+        || path.starts_with("/docs/")
+        || path.starts_with("/ui/")
+}
+
+/// 认证中间件：/health、API 文档和聊天页面不需要认证，其余请求的 key 无效时返回 401。
+/// 同一 IP 每分钟认证失败超过 auth_failures_per_minute 次后，窗口结束前它的请求都返回 429
+pub async fn require_api_key(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    if is_public(request.uri().path()) {
+        request.extensions_mut().insert(Caller::default());
+        return next.run(request).await;
+    }
+
+    let addr = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    let client = caller_key(None, addr);
+    if let Some(retry_after) = state.rate_limiter.auth_blocked(&client, Instant::now()) {
+        return too_many_requests("Too many failed authentication attempts, try again later", retry_after, None);
+    }
+
+    let authorization = request.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    match authenticate(&state.config.api_keys, &state.config.tenants, authorization) {
+        Some(caller) => {
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+        None => {
+            tracing::warn!(client = %client, "Authentication failed");
+            state.rate_limiter.auth_failed(&client, Instant::now());
+            (StatusCode::UNAUTHORIZED, Json(UnauthorizedError {
+                error: "Missing or invalid API key".to_string(),
+            })).into_response()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> HashMap<String, String> {
+        HashMap::from([("sk-alice".to_string(), "alice".to_string())])
+    }
+
+    #[test]
+    fn test_no_keys_disables_auth() {
+        assert_eq!(authenticate(&HashMap::new(), &HashMap::new(), None), Some(Caller::default()));
+        assert_eq!(authenticate(&HashMap::new(), &HashMap::new(), Some("Bearer anything")), Some(Caller::default()));
+    }
+
+    #[test]
+    fn test_bearer_key_maps_to_owner() {
+        let caller = authenticate(&keys(), &HashMap::new(), Some("Bearer sk-alice")).unwrap();
+        assert_eq!(caller.owner(), Some("alice"));
+        assert_eq!(caller.tenant(), Some("alice"));
+    }
+
+    #[test]
+    fn test_tenant_from_user() {
+        let tenants = HashMap::from([("alice".to_string(), "acme".to_string())]);
+        let caller = authenticate(&keys(), &tenants, Some("Bearer sk-alice")).unwrap();
+        assert_eq!(caller.tenant(), Some("acme"));
+    }
+
+    #[test]
+    fn test_missing_or_unknown_key_is_rejected() {
+        assert_eq!(authenticate(&keys(), &HashMap::new(), None), None);
+        assert_eq!(authenticate(&keys(), &HashMap::new(), Some("Bearer sk-bob")), None);
+        assert_eq!(authenticate(&keys(), &HashMap::new(), Some("sk-alice")), None);
+    }
+
+    #[test]
+    fn test_admin_users() {
+        let admins = vec!["alice".to_string()];
+        let alice = authenticate(&keys(), &HashMap::new(), Some("Bearer sk-alice")).unwrap();
+        assert!(is_admin(&keys(), &admins, &alice));
+        assert!(!is_admin(&keys(), &[], &alice));
+        assert!(is_admin(&HashMap::new(), &[], &Caller::default()));
+    }
+
+    #[test]
+    fn test_public_paths() {
+        assert!(is_public("/health"));
+        assert!(is_public("/health/ready"));
+        assert!(is_public("/openapi.json"));
+        assert!(is_public("/docs/index.html"));
+        assert!(is_public("/"));
+        assert!(is_public("/ui/app.js"));
+        assert!(!is_public("/documents"));
+        assert!(!is_public("/generate"));
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
+use crate::engine::{get_or_load_engine, ModelCache, StreamChunk};
+use crate::file_store::unix_now;
+use crate::health::gpu_memory;
+use crate::metrics::GenerationTimer;
+use crate::registry::{Device, SharedRegistry};
+use crate::types::{ChatMessage, GenerationConfig, MessageRole, Usage};
+
+
+pub const DEFAULT_MAX_TOKENS: usize = 128;
+pub const MAX_RUNS: usize = 10;
+
+// 每隔这么久记录一次进程的内存和 GPU 显存，取最大值
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+// 固定的测试 prompt：短问答、较长的回答和较长的上下文（主要测 prefill），
+// 结果只和模型、量化方式和硬件有关，不同时间的结果可以比较
+const PROMPTS: [(&str, &str); 3] = [
+    ("short", "What is the capital of France? Answer in one sentence."),
+    ("long_answer", "Explain how a hash map works, including hashing, collisions and resizing."),
+    ("long_context", concat!(
+        "Read the following notes and list the three most important points.\n\n",
+        "The service accepts prompts over HTTP and queues them before generation. Each model is loaded ",
+        "once and kept in memory until the memory budget is exceeded, then the least recently used model ",
+        "is unloaded. Uploaded files are parsed in the background and split into chunks; the chunks most ",
+        "similar to the prompt are added to the conversation. Sessions keep the message history, which is ",
+        "trimmed from the oldest message when it no longer fits in the context window. Requests can be ",
+        "canceled while they are generating, and a watchdog stops generations that take too long. Usage ",
+        "is counted per API key and can be limited by daily and monthly token quotas. Administrators can ",
+        "read the audit log, which records hashes of prompts and answers instead of the text itself.",
+    )),
+];
+
+
+/// 一个测试 prompt 一次生成的结果
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct PromptResult {
+    pub name: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub ttft_ms: Option<u64>,
+    pub tokens_per_second: f64,
+    pub total_ms: u64,
+}
+
+
+/// 一次 benchmark，保存在 benchmark_path 中用于比较不同的量化方式和硬件
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct BenchmarkRun {
+    pub id: String,
+    pub timestamp: u64,
+    pub model: String,
+    pub quantization: String,
+    // 例如 "cpu"、"cuda:0"（见 GET /models）
+    pub device: String,
+    // 调用方给这次运行的说明，例如机器或驱动版本
+    pub label: Option<String>,
+    pub runs: usize,
+    pub max_tokens: usize,
+    // 加载模型的时间，模型已加载时接近 0
+    pub load_ms: u64,
+    pub results: Vec<PromptResult>,
+    pub avg_ttft_ms: Option<u64>,
+    pub avg_tokens_per_second: f64,
+    // 运行期间整个进程的最大内存（RSS）和整块 GPU 的已用显存（包括其他已加载的模型和其他进程），
+    // 无法读取时为 null
+    pub peak_rss_mb: Option<u64>,
+    pub peak_gpu_mb: Option<u64>,
+    // 加载模型前的值，以及峰值比它多出的部分（模型在运行前已加载时只有生成时的增长）
+    #[serde(default)]
+    pub baseline_rss_mb: Option<u64>,
+    #[serde(default)]
+    pub baseline_gpu_mb: Option<u64>,
+    #[serde(default)]
+    pub rss_increase_mb: Option<u64>,
+    #[serde(default)]
+    pub gpu_increase_mb: Option<u64>,
+}
+
+
+pub struct BenchmarkOptions {
+    pub label: Option<String>,
+    // 每个 prompt 重复的次数
+    pub runs: usize,
+    pub max_tokens: usize,
+}
+
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        Self { label: None, runs: 1, max_tokens: DEFAULT_MAX_TOKENS }
+    }
+}
+
+
+/// 用固定的 prompt 测试 model：每个 prompt 运行 options.runs 次，temperature 为 0，
+/// 记录首 token 时间、生成速度和峰值内存。调用方负责不让其他生成同时运行
+pub async fn run_benchmark(
+    cache: &ModelCache,
+    registry: &SharedRegistry,
+    model_dir: &str,
+    model: &str,
+    options: BenchmarkOptions,
+) -> Result<BenchmarkRun> {
+    let (quantization, placement) = {
+        let registry = registry.read().await;
+        let spec = registry.get(model).ok_or_else(|| anyhow!("Unknown model {}", model))?;
+        (spec.quantization.clone(), registry.placement(spec))
+    };
+    let gpu_index = (placement.device != Device::Cpu).then_some(placement.gpu_index);
+    let baseline = sample_memory(gpu_index).await;
+    let sampler = MemorySampler::start(gpu_index);
+
+    let load_started = Instant::now();
+    let engine = get_or_load_engine(cache, registry, model_dir, model).await;
+    let load_ms = load_started.elapsed().as_millis() as u64;
+    let engine = match engine {
+        Ok(engine) => engine,
+        Err(e) => {
+            sampler.stop().await;
+            return Err(e);
+        }
+    };
+
+    let config = GenerationConfig {
+        temperature: Some(0.0),
+        seed: Some(0),
+        max_tokens: Some(options.max_tokens),
+        ..Default::default()
+    };
+    let mut results = Vec::new();
+    for _ in 0..options.runs {
+        for (name, prompt) in PROMPTS {
+            let messages = vec![ChatMessage::new(MessageRole::User, prompt.to_string())];
+            let mut timer = GenerationTimer::new();
+            timer.start();
+            let mut stream = match engine.stream(&messages, &config, CancellationToken::new()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    sampler.stop().await;
+                    return Err(e);
+                }
+            };
+            let mut usage = Usage::default();
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    StreamChunk::Token(_) => timer.first_token(),
+                    StreamChunk::Usage(final_usage) => usage = final_usage,
+                    StreamChunk::Logprobs(_) => {}
+                    StreamChunk::Error(error) => {
+                        sampler.stop().await;
+                        anyhow::bail!(error);
+                    }
+                }
+            }
+            let timings = timer.finish(usage.completion_tokens);
+            tracing::info!(model = %model, prompt = name, ttft_ms = ?timings.ttft_ms,
+                           tokens_per_second = timings.tokens_per_second, "Benchmark prompt finished");
+            results.push(PromptResult {
+                name: name.to_string(),
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                ttft_ms: timings.ttft_ms,
+                tokens_per_second: timings.tokens_per_second,
+                total_ms: timings.total_ms,
+            });
+        }
+    }
+    let peak = sampler.stop().await;
+
+    let (avg_ttft_ms, avg_tokens_per_second) = averages(&results);
+    Ok(BenchmarkRun {
+        id: uuid::Uuid::new_v4().to_string(),
+        timestamp: unix_now(),
+        model: model.to_string(),
+        quantization,
+        device: placement.describe(),
+        label: options.label,
+        runs: options.runs,
+        max_tokens: options.max_tokens,
+        load_ms,
+        results,
+        avg_ttft_ms,
+        avg_tokens_per_second,
+        peak_rss_mb: peak.rss_mb,
+        peak_gpu_mb: peak.gpu_mb,
+        baseline_rss_mb: baseline.rss_mb,
+        baseline_gpu_mb: baseline.gpu_mb,
+        rss_increase_mb: increase(peak.rss_mb, baseline.rss_mb),
+        gpu_increase_mb: increase(peak.gpu_mb, baseline.gpu_mb),
+    })
+}
+
+// 峰值比加载前多出的部分，两者都有时才计算
+fn increase(peak: Option<u64>, baseline: Option<u64>) -> Option<u64> {
+    Some(peak?.saturating_sub(baseline?))
+}
+
+// 平均的首 token 时间（只算有 token 的生成）和生成速度
+fn averages(results: &[PromptResult]) -> (Option<u64>, f64) {
+    let ttfts: Vec<u64> = results.iter().filter_map(|result| result.ttft_ms).collect();
+    let avg_ttft_ms = (!ttfts.is_empty()).then(|| ttfts.iter().sum::<u64>() / ttfts.len() as u64);
+    let avg_tokens_per_second = if results.is_empty() {
+        0.0
+    } else {
+        let sum: f64 = results.iter().map(|result| result.tokens_per_second).sum();
+        (sum / results.len() as f64 * 10.0).round() / 10.0
+    };
+    (avg_ttft_ms, avg_tokens_per_second)
+}
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct PeakMemory {
+    rss_mb: Option<u64>,
+    gpu_mb: Option<u64>,
+}
+
+impl PeakMemory {
+    fn record(&mut self, rss_mb: Option<u64>, gpu_mb: Option<u64>) {
+        self.rss_mb = self.rss_mb.max(rss_mb);
+        self.gpu_mb = self.gpu_mb.max(gpu_mb);
+    }
+}
+
+// 后台定期采样内存，stop 时返回期间的最大值
+struct MemorySampler {
+    stop: CancellationToken,
+    handle: JoinHandle<PeakMemory>,
+}
+
+impl MemorySampler {
+    fn start(gpu_index: Option<usize>) -> Self {
+        let stop = CancellationToken::new();
+        let token = stop.clone();
+        let handle = tokio::spawn(async move {
+            let mut peak = PeakMemory::default();
+            let mut interval = tokio::time::interval(MEMORY_SAMPLE_INTERVAL);
+            loop {
+                let sample = sample_memory(gpu_index).await;
+                peak.record(sample.rss_mb, sample.gpu_mb);
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = token.cancelled() => return peak,
+                }
+            }
+        });
+        Self { stop, handle }
+    }
+
+    async fn stop(self) -> PeakMemory {
+        self.stop.cancel();
+        self.handle.await.unwrap_or_default()
+    }
+}
+
+// 进程的常驻内存和 GPU 的已用显存
+async fn sample_memory(gpu_index: Option<usize>) -> PeakMemory {
+    let gpu_mb = match gpu_index {
+        Some(index) => gpu_memory(index).await.map(|memory| memory.total_mb.saturating_sub(memory.free_mb)),
+        None => None,
+    };
+    PeakMemory { rss_mb: process_rss_mb().await, gpu_mb }
+}
+
+// 当前进程的常驻内存（MB），只支持 Linux（/proc/self/status）
+async fn process_rss_mb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").await.ok()?;
+    parse_rss_kb(&status).map(|kb| kb / 1024)
+}
+
+// /proc/self/status 中的一行，例如 "VmRSS:\t  204800 kB"
+fn parse_rss_kb(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|kb| kb.parse().ok())
+}
+
+
+/// 历史结果，每行一个 BenchmarkRun（JSON Lines）。path 为空时不保存
+pub struct BenchmarkHistory {
+    path: Option<PathBuf>,
+    lock: Mutex<()>,
+}
+
+pub type SharedBenchmarkHistory = Arc<BenchmarkHistory>;
+
+pub fn new_benchmark_history(path: &str) -> SharedBenchmarkHistory {
+    Arc::new(BenchmarkHistory {
+        path: (!path.is_empty()).then(|| PathBuf::from(path)),
+        lock: Mutex::new(()),
+    })
+}
+
+impl BenchmarkHistory {
+    pub async fn record(&self, run: &BenchmarkRun) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _guard = self.lock.lock().await;
+        append_line(path, run).await
+    }
+
+    /// 保存的结果，最新的在前，model 不为空时只返回该模型的结果
+    pub async fn list(&self, model: Option<&str>, limit: usize) -> Result<Vec<BenchmarkRun>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let _guard = self.lock.lock().await;
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(content.lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<BenchmarkRun>(line).ok())
+            .filter(|run| model.is_none_or(|model| run.model == model))
+            .take(limit)
+            .collect())
+    }
+}
+
+async fn append_line(path: &Path, run: &BenchmarkRun) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut line = serde_json::to_vec(run)?;
+    line.push(b'\n');
+
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+
+/// `--benchmark <model> [--label <text>] [--runs <n>]`：运行 benchmark 后退出，不启动服务器
+pub fn parse_cli(args: &[String]) -> Result<Option<(String, BenchmarkOptions)>> {
+    let Some(position) = args.iter().position(|arg| arg == "--benchmark") else {
+        return Ok(None);
+    };
+    let value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1));
+
+    let model = args.get(position + 1)
+        .filter(|model| !model.starts_with("--"))
+        .ok_or_else(|| anyhow!("--benchmark needs a model name"))?;
+    let runs = match value("--runs") {
+        Some(runs) => runs.parse::<usize>()?.clamp(1, MAX_RUNS),
+        None => 1,
+    };
+    Ok(Some((model.clone(), BenchmarkOptions {
+        label: value("--label").cloned(),
+        runs,
+        max_tokens: DEFAULT_MAX_TOKENS,
+    })))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(model: &str, ttft_ms: Option<u64>) -> BenchmarkRun {
+        let result = PromptResult {
+            name: "short".to_string(),
+            prompt_tokens: 12,
+            completion_tokens: 8,
+            ttft_ms,
+            tokens_per_second: 20.0,
+            total_ms: 500,
+        };
+        BenchmarkRun {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: 0,
+            model: model.to_string(),
+            quantization: "Q4_K_M".to_string(),
+            device: "cpu".to_string(),
+            label: None,
+            runs: 1,
+            max_tokens: DEFAULT_MAX_TOKENS,
+            load_ms: 0,
+            results: vec![result],
+            avg_ttft_ms: ttft_ms,
+            avg_tokens_per_second: 20.0,
+            peak_rss_mb: None,
+            peak_gpu_mb: None,
+            baseline_rss_mb: None,
+            baseline_gpu_mb: None,
+            rss_increase_mb: None,
+            gpu_increase_mb: None,
+        }
+    }
+
+    #[test]
+    fn test_memory_increase() {
+        assert_eq!(increase(Some(900), Some(600)), Some(300));
+        assert_eq!(increase(Some(500), Some(600)), Some(0));
+        assert_eq!(increase(Some(900), None), None);
+        assert_eq!(increase(None, Some(600)), None);
+    }
+
+    #[test]
+    fn test_parse_rss() {
+        let status = "Name:\tLLMInferenceService\nVmHWM:\t  409600 kB\nVmRSS:\t  204800 kB\n";
+        assert_eq!(parse_rss_kb(status), Some(204800));
+        assert_eq!(parse_rss_kb("Name:\tx\n"), None);
+    }
+
+    #[test]
+    fn test_averages_skip_missing_ttft() {
+        let mut results = run("qwen", Some(100)).results;
+        results.extend(run("qwen", Some(300)).results);
+        results.extend(run("qwen", None).results);
+        results[2].tokens_per_second = 5.0;
+        assert_eq!(averages(&results), (Some(200), 15.0));
+        assert_eq!(averages(&[]), (None, 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_history_newest_first_by_model() {
+        let path = std::env::temp_dir().join(format!("benchmarks-{}.jsonl", uuid::Uuid::new_v4()));
+        let history = new_benchmark_history(path.to_str().unwrap());
+        let first = run("qwen", Some(100));
+        let second = run("smollm2", Some(50));
+        let third = run("qwen", Some(90));
+        for entry in [&first, &second, &third] {
+            history.record(entry).await.unwrap();
+        }
+
+        let all = history.list(None, 10).await.unwrap();
+        assert_eq!(all, vec![third.clone(), second, first.clone()]);
+        assert_eq!(history.list(Some("qwen"), 10).await.unwrap(), vec![third, first]);
+        assert_eq!(history.list(None, 1).await.unwrap().len(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_cli() {
+        let args = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+        assert!(parse_cli(&args("server")).unwrap().is_none());
+
+        let (model, options) = parse_cli(&args("server --benchmark qwen --label a10-q4 --runs 3")).unwrap().unwrap();
+        assert_eq!(model, "qwen");
+        assert_eq!(options.label.as_deref(), Some("a10-q4"));
+        assert_eq!(options.runs, 3);
+
+        assert!(parse_cli(&args("server --benchmark")).is_err());
+        assert!(parse_cli(&args("server --benchmark --runs 2")).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+use crate::registry::ModelSpec;
+
+
+/// 模型使用的对话模板。未设置时 mistralrs 使用 GGUF 文件中自带的模板；
+/// 有些 GGUF 没有模板或模板不对，模型就会看到错误的控制 token
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatTemplate {
+    /// `<|start_header_id|>role<|end_header_id|>`，Llama 3 / 3.1
+    Llama3,
+    /// `<|im_start|>role`，Qwen、SmolLM2 等
+    Chatml,
+    /// `[INST] ... [/INST]`，Mistral / Mixtral，系统提示词放在第一条用户消息之前
+    Mistral,
+    /// 自定义 Jinja 模板，变量与 Hugging Face 的 chat_template 相同
+    /// （messages、add_generation_prompt、bos_token、eos_token）
+    Custom(String),
+}
+
+const LLAMA3_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}\
+    {{ '<|start_header_id|>' + message['role'] + '<|end_header_id|>\\n\\n' + message['content'] | trim + '<|eot_id|>' }}\
+    {% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\\n\\n' }}{% endif %}";
+
+const CHATML_TEMPLATE: &str = "{% for message in messages %}\
+    {{ '<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>\\n' }}\
+    {% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+const MISTRAL_TEMPLATE: &str = "{{ bos_token }}{% set system = namespace(text='') %}{% for message in messages %}\
+    {% if message['role'] == 'system' %}{% set system.text = system.text + message['content'] + '\\n\\n' %}\
+    {% elif message['role'] == 'user' %}{{ '[INST] ' + system.text + message['content'] + ' [/INST]' }}{% set system.text = '' %}\
+    {% else %}{{ message['content'] + eos_token }}{% endif %}{% endfor %}";
+
+impl ChatTemplate {
+    pub fn jinja(&self) -> &str {
+        match self {
+            ChatTemplate::Llama3 => LLAMA3_TEMPLATE,
+            ChatTemplate::Chatml => CHATML_TEMPLATE,
+            ChatTemplate::Mistral => MISTRAL_TEMPLATE,
+            ChatTemplate::Custom(template) => template,
+        }
+    }
+}
+
+
+/// 传给 mistralrs 的对话模板文件：chat_template 文件优先，其次把 template 写入
+/// `{model_dir}/templates/{name}.json`，两者都没有时返回 None（使用 GGUF 自带的模板）
+pub async fn template_file(model_dir: &str, spec: &ModelSpec) -> Result<Option<String>> {
+    if let Some(file) = &spec.chat_template {
+        return Ok(Some(file.clone()));
+    }
+    let Some(template) = &spec.template else {
+        return Ok(None);
+    };
+
+    let dir = Path::new(model_dir).join("templates");
+    fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("{}.json", spec.name));
+    // same layout as tokenizer_config.json, which mistralrs reads the template from
+    let content = serde_json::json!({ "chat_template": template.jinja() });
+    fs::write(&path, serde_json::to_vec_pretty(&content)?).await?;
+
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ModelRegistry;
+
+    #[test]
+    fn test_template_parsed_from_registry() {
+        let registry = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "qwen"
+            repo = "someone/qwen-GGUF"
+            file = "qwen.gguf"
+            template = "chatml"
+
+            [[models]]
+            name = "odd"
+            repo = "someone/odd-GGUF"
+            file = "odd.gguf"
+            template = { custom = "{% for message in messages %}{{ message['content'] }}{% endfor %}" }
+        "#).unwrap();
+
+        assert_eq!(registry.get("qwen").unwrap().template, Some(ChatTemplate::Chatml));
+        let odd = registry.get("odd").unwrap().template.clone().unwrap();
+        assert!(odd.jinja().starts_with("{% for message in messages %}"));
+    }
+
+    #[test]
+    fn test_builtin_templates_use_their_control_tokens() {
+        assert!(ChatTemplate::Llama3.jinja().contains("<|eot_id|>"));
+        assert!(ChatTemplate::Chatml.jinja().contains("<|im_start|>assistant\\n"));
+        assert!(ChatTemplate::Mistral.jinja().contains("[/INST]"));
+        assert!(!ChatTemplate::Chatml.jinja().contains("<|start_header_id|>"));
+    }
+
+    #[tokio::test]
+    async fn test_template_file_prefers_chat_template() {
+        let model_dir = std::env::temp_dir().join(format!("template-test-{}", uuid::Uuid::new_v4()));
+        let model_dir = model_dir.to_str().unwrap();
+
+        let mut spec = ModelSpec::new("qwen", "someone/qwen-GGUF", "qwen.gguf");
+        assert_eq!(template_file(model_dir, &spec).await.unwrap(), None);
+
+        spec.template = Some(ChatTemplate::Chatml);
+        let path = template_file(model_dir, &spec).await.unwrap().unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["chat_template"], CHATML_TEMPLATE);
+
+        spec.chat_template = Some("templates/qwen.json".to_string());
+        assert_eq!(template_file(model_dir, &spec).await.unwrap().as_deref(), Some("templates/qwen.json"));
+
+        let _ = std::fs::remove_dir_all(model_dir);
+    }
+}
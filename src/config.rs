@@ -0,0 +1,765 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use crate::logging::LogFormat;
+use crate::moderation::ModerationMode;
+use crate::usage::TokenQuota;
+use crate::registry::{Device, Placement};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub model_dir: String,
+    // 解析后的上传文件保存目录
+    pub file_dir: String,
+    // 模型注册表文件（models.toml）
+    pub registry_path: String,
+    // 上传大小限制（字节）：整个请求 / 单个文件
+    pub max_upload_size: usize,
+    pub max_file_size: usize,
+    // 上传中的文件先写入的临时目录，为空时使用系统临时目录下的 llm-uploads
+    pub upload_spool_dir: String,
+    // JSON 请求体的大小限制（字节），以及 prompt 的字符数 / token 数上限（0 表示不限制）
+    pub max_json_body_size: usize,
+    pub max_prompt_chars: usize,
+    pub max_prompt_tokens: usize,
+    pub default_model: String,
+    // 启动后在后台依次加载（需要时先下载）的模型，名称或别名
+    pub preload_models: Vec<String>,
+    // 为空或包含 "*" 时允许任意来源
+    pub cors_origins: Vec<String>,
+    // API key -> 用户名。为空时不需要认证；设置后请求需带 `Authorization: Bearer <key>`，
+    // session 只对创建它的用户可见
+    pub api_keys: HashMap<String, String>,
+    // 可以使用 /admin 下的接口（如审计日志）的用户名（api_keys 中的值）。未配置 api_keys 时不限制
+    pub admin_users: Vec<String>,
+    // 用户名 -> 租户。同一租户的用户共用上传的文件和 few-shot 示例组，用量按租户汇总；
+    // 不在表中的用户自成一个租户（租户名即用户名）
+    pub tenants: HashMap<String, String>,
+    // 文件检索：切块大小、相邻块的重叠（字符数）和每次注入的块数
+    pub rag_chunk_size: usize,
+    pub rag_chunk_overlap: usize,
+    pub rag_top_k: usize,
+    // 每个 session 最多的文件数和文件总大小（字节）
+    pub max_files_per_session: usize,
+    pub max_session_file_size: usize,
+    // 上传的文件保留多久（秒，0 表示永久保留），以及清理过期文件的间隔
+    pub file_ttl_secs: u64,
+    pub file_sweep_interval_secs: u64,
+    // session 空闲多久后清理（秒，0 表示永不清理），以及清理的间隔
+    pub session_ttl_secs: u64,
+    pub session_sweep_interval_secs: u64,
+    // session 和上传文件的存储："local"（内存 + file_dir）或 "redis"（需要 redis feature），
+    // 多个实例共享同一个 Redis 时可以放在负载均衡后面
+    pub state_store: String,
+    pub redis_url: String,
+    pub redis_prefix: String,
+    // 用户长期记忆的保存文件（为空时只保存在内存中），以及是否在每轮对话后由模型提取记忆
+    pub memory_path: String,
+    pub memory_extraction: bool,
+    // 命名的 few-shot 示例组的保存文件，为空时只保存在内存中
+    pub few_shot_path: String,
+    // 上传的 eval 集合的保存文件，为空时只保存在内存中
+    pub evals_path: String,
+    // 内容审核："off"、"flag"（只记录）或 "block"（拒绝 prompt、拦截回答），检查 prompt 和 / 或回答。
+    // 命中关键词或分类模型（moderation_model，为空时不用）判为 unsafe 的内容写入 moderation_log_path
+    pub moderation: ModerationMode,
+    pub moderate_prompts: bool,
+    pub moderate_outputs: bool,
+    pub moderation_keywords: Vec<String>,
+    pub moderation_model: String,
+    pub moderation_log_path: String,
+    // 审计日志：每次生成一行 JSON（请求 id、用户、session、模型、prompt 和回答的哈希、token 数和耗时），
+    // 为空时不记录；超过 audit_retention_days 天的记录被删除，0 表示永久保留
+    pub audit_log_path: String,
+    pub audit_retention_days: u64,
+    // 每个 key 的 token 用量保存到 usage_path（为空时只在内存中）。daily_token_quota / monthly_token_quota
+    // 为每个 key 每天 / 每月的 token 配额（0 表示不限制），token_quotas 按用户名覆盖
+    pub usage_path: String,
+    pub daily_token_quota: u64,
+    pub monthly_token_quota: u64,
+    pub token_quotas: HashMap<String, TokenQuota>,
+    // 请求带 callback_url 时在后台生成，结束后把结果 POST 到该地址，用 webhook_secret 做 HMAC-SHA256 签名。
+    // webhook_secret 为空时不接受 callback_url；webhook_allowed_hosts 为空时允许任意公网 host
+    pub webhook_secret: String,
+    pub webhook_allowed_hosts: Vec<String>,
+    pub webhook_timeout_secs: u64,
+    // 事件总线："" 不发布，"nats" 或 "kafka"（需要对应的 feature）。event_bus_url 为 NATS 地址或逗号分隔的
+    // Kafka broker；event_topic 为 NATS subject 的前缀或 Kafka topic
+    pub event_bus: String,
+    pub event_bus_url: String,
+    pub event_topic: String,
+    // POST /admin/benchmark 和 --benchmark 的结果，每行一次运行，为空时不保存
+    pub benchmark_path: String,
+    // 工具调用：每次调用的超时（秒），是否允许 python / javascript 代码执行工具，以及解释器命令
+    pub tool_timeout_secs: u64,
+    pub code_execution: bool,
+    pub python_command: String,
+    pub node_command: String,
+    // 代码执行工具外层的隔离命令（bwrap、firejail、nsjail 等），解释器命令追加在后面，{dir} 替换为代码所在的临时目录。
+    // 开启 code_execution 时必须设置
+    pub code_sandbox_command: Vec<String>,
+    // 保留的解析结果数和它们的总字节数，相同内容重复上传时不再解析。parse_cache_size 为 0 时不缓存
+    pub parse_cache_size: usize,
+    pub parse_cache_max_bytes: usize,
+    // 同时在后台解析的文档数，其余的排队等待（压缩包的每个成员单独解析）
+    pub max_parse_tasks: usize,
+    // 解析上传的文档后遮盖邮箱、电话号码、SSN 和信用卡号，再缓存或发给模型。
+    // 关闭时也可以在上传时用表单字段 redact_pii=true 单独开启
+    pub redact_pii: bool,
+    // temperature 为 0 的 /generate 和 /generate/batch 请求的回答缓存：保留的回答数（0 表示不缓存）和有效期（秒，0 表示不过期）
+    pub response_cache_size: usize,
+    pub response_cache_ttl_secs: u64,
+    // 推理队列：同时生成的请求数、排队请求数，以及队列满时 Retry-After 的秒数
+    pub max_concurrent_inferences: usize,
+    pub max_queue_depth: usize,
+    pub queue_retry_after_secs: u64,
+    // 限流（0 表示不限制）：每个调用方（API key 对应的用户，未开启认证时为客户端 IP）每分钟的请求数，
+    // 以及同时打开的流式请求数
+    pub rate_limit_per_minute: u32,
+    pub max_concurrent_streams: usize,
+    // 每个客户端 IP 每分钟允许的认证失败次数，超过后到窗口结束前返回 429（0 表示不限制）
+    pub auth_failures_per_minute: u32,
+    // 模型加载到的设备（auto / cpu / cuda）、放到 GPU 的层数（未设置时全部）和 GPU 编号，
+    // 可以在 models.toml 中按模型覆盖
+    pub device: Device,
+    pub gpu_layers: Option<usize>,
+    pub gpu_index: usize,
+    // 同时加载的模型的显存 / 内存上限（MB，0 表示不限制），超出时卸载最久未使用的模型
+    pub model_memory_budget_mb: u64,
+    // 每个请求最多生成的 token 数，请求的 max_tokens 超过时按上限生成（0 表示不限制）
+    pub max_tokens_limit: usize,
+    // 一次生成的硬超时（秒，0 表示不限制）：模型卡住时中止生成并释放队列的位置
+    pub generation_timeout_secs: u64,
+    // 语音转文字使用的 whisper.cpp 模型（GGML）及识别语言（"auto" 自动检测）
+    pub whisper_repo: String,
+    pub whisper_model: String,
+    pub whisper_language: String,
+    // 是否在 / 提供内置的聊天页面
+    pub web_ui: bool,
+    // SSE：keep-alive 注释的间隔（秒），生成任务和响应之间的事件缓冲，
+    // 以及把 token 合并成一个事件（攒够 N 个或等待 M 毫秒后发出，N 为 1 时每个 token 立即发出，M 为 0 时只按个数）
+    pub sse_keep_alive_secs: u64,
+    pub sse_buffer_size: usize,
+    pub sse_coalesce_tokens: usize,
+    pub sse_coalesce_ms: u64,
+    // 日志格式："text" 或 "json"（每行一个 JSON 对象），级别由 RUST_LOG 控制
+    pub log_format: LogFormat,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            model_dir: "models".to_string(),
+            file_dir: "files".to_string(),
+            registry_path: "models.toml".to_string(),
+            max_upload_size: 50 * 1024 * 1024,
+            max_file_size: 20 * 1024 * 1024,
+            upload_spool_dir: String::new(),
+            max_json_body_size: 4 * 1024 * 1024,
+            max_prompt_chars: 100_000,
+            max_prompt_tokens: 0,
+            default_model: "qwen".to_string(),
+            preload_models: vec![],
+            cors_origins: vec![],
+            api_keys: HashMap::new(),
+            admin_users: vec![],
+            tenants: HashMap::new(),
+            rag_chunk_size: 1000,
+            rag_chunk_overlap: 200,
+            rag_top_k: 4,
+            parse_cache_size: 64,
+            parse_cache_max_bytes: 64 * 1024 * 1024,
+            max_parse_tasks: 4,
+            response_cache_size: 0,
+            response_cache_ttl_secs: 3600,
+            redact_pii: false,
+            session_ttl_secs: 24 * 3600,
+            session_sweep_interval_secs: 300,
+            state_store: "local".to_string(),
+            redis_url: "redis://127.0.0.1:6379/".to_string(),
+            redis_prefix: "llm:".to_string(),
+            memory_path: "memory.json".to_string(),
+            memory_extraction: false,
+            few_shot_path: "few_shot.json".to_string(),
+            evals_path: "evals.json".to_string(),
+            moderation: ModerationMode::Off,
+            moderate_prompts: true,
+            moderate_outputs: false,
+            moderation_keywords: Vec::new(),
+            moderation_model: String::new(),
+            moderation_log_path: "moderation.jsonl".to_string(),
+            audit_log_path: String::new(),
+            audit_retention_days: 90,
+            usage_path: "usage.json".to_string(),
+            daily_token_quota: 0,
+            monthly_token_quota: 0,
+            token_quotas: HashMap::new(),
+            webhook_secret: String::new(),
+            webhook_allowed_hosts: vec![],
+            webhook_timeout_secs: 10,
+            event_bus: String::new(),
+            event_bus_url: "nats://127.0.0.1:4222".to_string(),
+            event_topic: "llm.events".to_string(),
+            benchmark_path: "benchmarks.jsonl".to_string(),
+            tool_timeout_secs: 10,
+            code_execution: false,
+            web_ui: true,
+            sse_keep_alive_secs: 10,
+            sse_buffer_size: 32,
+            sse_coalesce_tokens: 1,
+            sse_coalesce_ms: 0,
+            python_command: "python3".to_string(),
+            node_command: "node".to_string(),
+            code_sandbox_command: vec![],
+            max_files_per_session: 50,
+            max_session_file_size: 200 * 1024 * 1024,
+            file_ttl_secs: 7 * 24 * 3600,
+            file_sweep_interval_secs: 600,
+            max_concurrent_inferences: 1,
+            max_queue_depth: 8,
+            queue_retry_after_secs: 5,
+            rate_limit_per_minute: 0,
+            max_concurrent_streams: 0,
+            auth_failures_per_minute: 10,
+            device: Device::Auto,
+            gpu_layers: None,
+            gpu_index: 0,
+            model_memory_budget_mb: 0,
+            max_tokens_limit: 4096,
+            generation_timeout_secs: 300,
+            whisper_repo: "ggerganov/whisper.cpp".to_string(),
+            whisper_model: "ggml-base.bin".to_string(),
+            whisper_language: "auto".to_string(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// 配置文件的路径：`LLM_CONFIG`，默认 `config.toml`
+    pub fn path() -> String {
+        std::env::var("LLM_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string())
+    }
+
+    /// 读取配置文件（不存在时使用默认值），再应用环境变量覆盖。
+    /// 此时日志还没有初始化（日志格式来自配置），由调用方记录读取了哪个文件
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+
+        let mut config = if Path::new(&path).exists() {
+            Self::from_toml_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            Self::default()
+        };
+
+        config.apply_overrides(|key| std::env::var(key).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // 不能单独检查的字段组合
+    fn validate(&self) -> Result<()> {
+        if self.code_execution && self.code_sandbox_command.is_empty() {
+            anyhow::bail!("code_execution requires code_sandbox_command, the code tools must not run unisolated");
+        }
+        if let Some(origin) = self.cors_origins.iter().find(|origin| *origin != "*" && !valid_origin(origin)) {
+            anyhow::bail!("Invalid CORS origin {:?}, expected scheme://host[:port] such as https://example.com", origin);
+        }
+        Ok(())
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    fn apply_overrides<F>(&mut self, lookup: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(host) = lookup("LLM_HOST") {
+            self.host = host;
+        }
+        if let Some(port) = lookup("LLM_PORT") {
+            self.port = port.parse()?;
+        }
+        if let Some(model_dir) = lookup("LLM_MODEL_DIR") {
+            self.model_dir = model_dir;
+        }
+        if let Some(file_dir) = lookup("LLM_FILE_DIR") {
+            self.file_dir = file_dir;
+        }
+        if let Some(path) = lookup("LLM_MODEL_REGISTRY") {
+            self.registry_path = path;
+        }
+        if let Some(size) = lookup("LLM_MAX_UPLOAD_SIZE") {
+            self.max_upload_size = size.parse()?;
+        }
+        if let Some(size) = lookup("LLM_MAX_FILE_SIZE") {
+            self.max_file_size = size.parse()?;
+        }
+        if let Some(size) = lookup("LLM_PARSE_CACHE_MAX_BYTES") {
+            self.parse_cache_max_bytes = size.parse()?;
+        }
+        if let Some(n) = lookup("LLM_MAX_PARSE_TASKS") {
+            self.max_parse_tasks = n.parse()?;
+        }
+        if let Some(dir) = lookup("LLM_UPLOAD_SPOOL_DIR") {
+            self.upload_spool_dir = dir;
+        }
+        if let Some(size) = lookup("LLM_MAX_JSON_BODY_SIZE") {
+            self.max_json_body_size = size.parse()?;
+        }
+        if let Some(n) = lookup("LLM_MAX_PROMPT_CHARS") {
+            self.max_prompt_chars = n.parse()?;
+        }
+        if let Some(n) = lookup("LLM_MAX_PROMPT_TOKENS") {
+            self.max_prompt_tokens = n.parse()?;
+        }
+        if let Some(model) = lookup("LLM_DEFAULT_MODEL") {
+            self.default_model = model;
+        }
+        if let Some(models) = lookup("LLM_PRELOAD_MODELS") {
+            self.preload_models = models
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Some(origins) = lookup("LLM_CORS_ORIGINS") {
+            self.cors_origins = origins
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        // "key1=alice,key2=bob"
+        if let Some(keys) = lookup("LLM_API_KEYS") {
+            self.api_keys = keys
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|entry| match entry.split_once('=') {
+                    Some((key, user)) if !key.trim().is_empty() && !user.trim().is_empty() => {
+                        Ok((key.trim().to_string(), user.trim().to_string()))
+                    }
+                    _ => Err(anyhow::anyhow!("LLM_API_KEYS entries must be key=user")),
+                })
+                .collect::<Result<_>>()?;
+        }
+        // "alice=acme,bob=acme"
+        if let Some(tenants) = lookup("LLM_TENANTS") {
+            self.tenants = tenants
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|entry| match entry.split_once('=') {
+                    Some((user, tenant)) if !user.trim().is_empty() && !tenant.trim().is_empty() => {
+                        Ok((user.trim().to_string(), tenant.trim().to_string()))
+                    }
+                    _ => Err(anyhow::anyhow!("LLM_TENANTS entries must be user=tenant")),
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(users) = lookup("LLM_ADMIN_USERS") {
+            self.admin_users = users
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+
+        if let Some(n) = lookup("LLM_MAX_FILES_PER_SESSION") {
+            self.max_files_per_session = n.parse()?;
+        }
+        if let Some(secs) = lookup("LLM_FILE_TTL_SECS") {
+            self.file_ttl_secs = secs.parse()?;
+        }
+        if let Some(enabled) = lookup("LLM_REDACT_PII") {
+            self.redact_pii = enabled.parse()?;
+        }
+        if let Some(secs) = lookup("LLM_SESSION_TTL_SECS") {
+            self.session_ttl_secs = secs.parse()?;
+        }
+        if let Some(store) = lookup("LLM_STATE_STORE") {
+            self.state_store = store;
+        }
+        if let Some(url) = lookup("LLM_REDIS_URL") {
+            self.redis_url = url;
+        }
+        if let Some(path) = lookup("LLM_MEMORY_PATH") {
+            self.memory_path = path;
+        }
+        if let Some(enabled) = lookup("LLM_MEMORY_EXTRACTION") {
+            self.memory_extraction = enabled.parse()?;
+        }
+        if let Some(path) = lookup("LLM_FEW_SHOT_PATH") {
+            self.few_shot_path = path;
+        }
+        if let Some(path) = lookup("LLM_EVALS_PATH") {
+            self.evals_path = path;
+        }
+        if let Some(mode) = lookup("LLM_MODERATION") {
+            self.moderation = ModerationMode::parse(&mode)
+                .ok_or_else(|| anyhow::anyhow!("LLM_MODERATION must be off, flag or block, got {}", mode))?;
+        }
+        if let Some(enabled) = lookup("LLM_MODERATE_PROMPTS") {
+            self.moderate_prompts = enabled.parse()?;
+        }
+        if let Some(enabled) = lookup("LLM_MODERATE_OUTPUTS") {
+            self.moderate_outputs = enabled.parse()?;
+        }
+        if let Some(keywords) = lookup("LLM_MODERATION_KEYWORDS") {
+            self.moderation_keywords = keywords
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Some(model) = lookup("LLM_MODERATION_MODEL") {
+            self.moderation_model = model;
+        }
+        if let Some(path) = lookup("LLM_MODERATION_LOG_PATH") {
+            self.moderation_log_path = path;
+        }
+        if let Some(path) = lookup("LLM_AUDIT_LOG_PATH") {
+            self.audit_log_path = path;
+        }
+        if let Some(days) = lookup("LLM_AUDIT_RETENTION_DAYS") {
+            self.audit_retention_days = days.parse()?;
+        }
+        if let Some(path) = lookup("LLM_USAGE_PATH") {
+            self.usage_path = path;
+        }
+        if let Some(tokens) = lookup("LLM_DAILY_TOKEN_QUOTA") {
+            self.daily_token_quota = tokens.parse()?;
+        }
+        if let Some(tokens) = lookup("LLM_MONTHLY_TOKEN_QUOTA") {
+            self.monthly_token_quota = tokens.parse()?;
+        }
+        if let Some(secret) = lookup("LLM_WEBHOOK_SECRET") {
+            self.webhook_secret = secret;
+        }
+        if let Some(hosts) = lookup("LLM_WEBHOOK_ALLOWED_HOSTS") {
+            self.webhook_allowed_hosts = hosts
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Some(secs) = lookup("LLM_WEBHOOK_TIMEOUT_SECS") {
+            self.webhook_timeout_secs = secs.parse()?;
+        }
+        if let Some(bus) = lookup("LLM_EVENT_BUS") {
+            self.event_bus = bus;
+        }
+        if let Some(url) = lookup("LLM_EVENT_BUS_URL") {
+            self.event_bus_url = url;
+        }
+        if let Some(topic) = lookup("LLM_EVENT_TOPIC") {
+            self.event_topic = topic;
+        }
+        if let Some(path) = lookup("LLM_BENCHMARK_PATH") {
+            self.benchmark_path = path;
+        }
+        if let Some(enabled) = lookup("LLM_CODE_EXECUTION") {
+            self.code_execution = enabled.parse()?;
+        }
+        // "bwrap --unshare-all --die-with-parent ..."
+        if let Some(command) = lookup("LLM_CODE_SANDBOX_COMMAND") {
+            self.code_sandbox_command = command.split_whitespace().map(str::to_string).collect();
+        }
+        if let Some(enabled) = lookup("LLM_WEB_UI") {
+            self.web_ui = enabled.parse()?;
+        }
+
+        if let Some(n) = lookup("LLM_RESPONSE_CACHE_SIZE") {
+            self.response_cache_size = n.parse()?;
+        }
+        if let Some(secs) = lookup("LLM_RESPONSE_CACHE_TTL_SECS") {
+            self.response_cache_ttl_secs = secs.parse()?;
+        }
+        if let Some(n) = lookup("LLM_MAX_CONCURRENT_INFERENCES") {
+            self.max_concurrent_inferences = n.parse()?;
+        }
+        if let Some(n) = lookup("LLM_MAX_QUEUE_DEPTH") {
+            self.max_queue_depth = n.parse()?;
+        }
+        if let Some(n) = lookup("LLM_RATE_LIMIT_PER_MINUTE") {
+            self.rate_limit_per_minute = n.parse()?;
+        }
+        if let Some(n) = lookup("LLM_MAX_CONCURRENT_STREAMS") {
+            self.max_concurrent_streams = n.parse()?;
+        }
+        if let Some(n) = lookup("LLM_AUTH_FAILURES_PER_MINUTE") {
+            self.auth_failures_per_minute = n.parse()?;
+        }
+        if let Some(device) = lookup("LLM_DEVICE") {
+            self.device = Device::parse(&device)
+                .ok_or_else(|| anyhow::anyhow!("LLM_DEVICE must be auto, cpu or cuda, got {}", device))?;
+        }
+        if let Some(layers) = lookup("LLM_GPU_LAYERS") {
+            self.gpu_layers = Some(layers.parse()?);
+        }
+        if let Some(index) = lookup("LLM_GPU_INDEX") {
+            self.gpu_index = index.parse()?;
+        }
+        if let Some(mb) = lookup("LLM_MODEL_MEMORY_BUDGET_MB") {
+            self.model_memory_budget_mb = mb.parse()?;
+        }
+        if let Some(n) = lookup("LLM_MAX_TOKENS_LIMIT") {
+            self.max_tokens_limit = n.parse()?;
+        }
+        if let Some(secs) = lookup("LLM_GENERATION_TIMEOUT_SECS") {
+            self.generation_timeout_secs = secs.parse()?;
+        }
+        if let Some(secs) = lookup("LLM_SSE_KEEP_ALIVE_SECS") {
+            self.sse_keep_alive_secs = secs.parse()?;
+        }
+        if let Some(n) = lookup("LLM_SSE_BUFFER_SIZE") {
+            self.sse_buffer_size = n.parse()?;
+        }
+        if let Some(n) = lookup("LLM_SSE_COALESCE_TOKENS") {
+            self.sse_coalesce_tokens = n.parse()?;
+        }
+        if let Some(ms) = lookup("LLM_SSE_COALESCE_MS") {
+            self.sse_coalesce_ms = ms.parse()?;
+        }
+        if let Some(format) = lookup("LLM_LOG_FORMAT") {
+            self.log_format = LogFormat::parse(&format)
+                .ok_or_else(|| anyhow::anyhow!("LLM_LOG_FORMAT must be text or json, got {}", format))?;
+        }
+
+        Ok(())
+    }
+
+    /// 模型默认的设备设置
+    pub fn placement(&self) -> Placement {
+        Placement {
+            device: self.device,
+            gpu_layers: self.gpu_layers,
+            gpu_index: self.gpu_index,
+        }
+    }
+
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.cors_origins.is_empty() || self.cors_origins.iter().any(|o| o == "*")
+    }
+
+    /// 用户所属的租户
+    pub fn tenant_of(&self, owner: &str) -> String {
+        self.tenants.get(owner).cloned().unwrap_or_else(|| owner.to_string())
+    }
+
+    /// owner 的 token 配额：token_quotas 中的设置，否则为默认配额
+    pub fn quota_for(&self, owner: Option<&str>) -> TokenQuota {
+        owner
+            .and_then(|owner| self.token_quotas.get(owner))
+            .copied()
+            .unwrap_or(TokenQuota { daily: self.daily_token_quota, monthly: self.monthly_token_quota })
+    }
+}
+
+
+// 浏览器发送的 Origin 头是 scheme://host[:port]，没有路径和结尾的 /，否则永远不会匹配
+fn valid_origin(origin: &str) -> bool {
+    let Some((scheme, host)) = origin.split_once("://") else {
+        return false;
+    };
+    !scheme.is_empty() && !host.is_empty() && !host.contains('/')
+        && origin.parse::<axum::http::HeaderValue>().is_ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config() {
+        let config = ServerConfig::default();
+        assert_eq!(config.bind_address(), "127.0.0.1:8080");
+        assert_eq!(config.model_dir, "models");
+        assert_eq!(config.default_model, "qwen");
+        assert!(config.allows_any_origin());
+    }
+
+    #[test]
+    fn test_partial_toml_uses_defaults() {
+        let config = ServerConfig::from_toml_str("port = 9000\nmodel_dir = \"/data/models\"").unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.model_dir, "/data/models");
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.max_upload_size, 50 * 1024 * 1024);
+        assert_eq!(config.max_file_size, 20 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_invalid_toml() {
+        assert!(ServerConfig::from_toml_str("port = \"not a number\"").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env: HashMap<&str, &str> = HashMap::from([
+            ("LLM_HOST", "0.0.0.0"),
+            ("LLM_PORT", "3000"),
+            ("LLM_DEFAULT_MODEL", "smollm2"),
+            ("LLM_MAX_QUEUE_DEPTH", "2"),
+            ("LLM_RESPONSE_CACHE_SIZE", "500"),
+            ("LLM_MAX_TOKENS_LIMIT", "1024"),
+            ("LLM_MAX_PROMPT_TOKENS", "2048"),
+            ("LLM_RATE_LIMIT_PER_MINUTE", "60"),
+            ("LLM_AUTH_FAILURES_PER_MINUTE", "5"),
+            ("LLM_GENERATION_TIMEOUT_SECS", "0"),
+            ("LLM_DEVICE", "cpu"),
+            ("LLM_GPU_LAYERS", "12"),
+            ("LLM_LOG_FORMAT", "json"),
+            ("LLM_FILE_TTL_SECS", "0"),
+            ("LLM_REDACT_PII", "true"),
+            ("LLM_SESSION_TTL_SECS", "600"),
+            ("LLM_STATE_STORE", "redis"),
+            ("LLM_MEMORY_EXTRACTION", "true"),
+            ("LLM_WEB_UI", "false"),
+            ("LLM_API_KEYS", "sk-alice=alice, sk-bob=bob"),
+            ("LLM_CORS_ORIGINS", "http://localhost:3000, https://example.com"),
+            ("LLM_PRELOAD_MODELS", "qwen, ,smollm2"),
+            ("LLM_SSE_COALESCE_TOKENS", "4"),
+            ("LLM_MODERATION", "Block"),
+            ("LLM_MODERATION_KEYWORDS", "bomb, ,scam"),
+            ("LLM_ADMIN_USERS", "alice"),
+            ("LLM_TENANTS", "alice=acme, bob=acme"),
+            ("LLM_AUDIT_RETENTION_DAYS", "0"),
+            ("LLM_DAILY_TOKEN_QUOTA", "50000"),
+            ("LLM_WEBHOOK_ALLOWED_HOSTS", "hooks.example.com, "),
+            ("LLM_EVENT_BUS", "kafka"),
+            ("LLM_EVENT_BUS_URL", "kafka-1:9092,kafka-2:9092"),
+            ("LLM_BENCHMARK_PATH", "data/benchmarks.jsonl"),
+            ("LLM_EVALS_PATH", ""),
+            ("LLM_UPLOAD_SPOOL_DIR", "/var/tmp/llm"),
+            ("LLM_MAX_PARSE_TASKS", "2"),
+            ("LLM_PARSE_CACHE_MAX_BYTES", "1048576"),
+            ("LLM_CODE_SANDBOX_COMMAND", "firejail --quiet --net=none --private={dir}"),
+        ]);
+
+        let mut config = ServerConfig::default();
+        config.apply_overrides(|key| env.get(key).map(|v| v.to_string())).unwrap();
+
+        assert_eq!(config.bind_address(), "0.0.0.0:3000");
+        assert_eq!(config.default_model, "smollm2");
+        assert_eq!(config.max_queue_depth, 2);
+        assert_eq!((config.response_cache_size, config.response_cache_ttl_secs), (500, 3600));
+        assert_eq!(config.max_concurrent_inferences, 1);
+        assert_eq!(config.max_tokens_limit, 1024);
+        assert_eq!(config.max_prompt_tokens, 2048);
+        assert_eq!(config.max_prompt_chars, 100_000);
+        assert_eq!(config.rate_limit_per_minute, 60);
+        assert_eq!(config.max_concurrent_streams, 0);
+        assert_eq!(config.auth_failures_per_minute, 5);
+        assert_eq!(config.generation_timeout_secs, 0);
+        assert_eq!(config.placement(), Placement { device: Device::Cpu, gpu_layers: Some(12), gpu_index: 0 });
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.file_ttl_secs, 0);
+        assert!(config.redact_pii);
+        assert_eq!(config.session_ttl_secs, 600);
+        assert_eq!(config.state_store, "redis");
+        assert!(config.memory_extraction);
+        assert!(!config.web_ui);
+        assert_eq!(config.api_keys.get("sk-bob").map(String::as_str), Some("bob"));
+        assert_eq!(config.api_keys.len(), 2);
+        assert_eq!(config.cors_origins, vec!["http://localhost:3000", "https://example.com"]);
+        assert_eq!(config.preload_models, vec!["qwen", "smollm2"]);
+        assert_eq!((config.sse_coalesce_tokens, config.sse_coalesce_ms, config.sse_buffer_size), (4, 0, 32));
+        assert_eq!(config.moderation, ModerationMode::Block);
+        assert_eq!(config.moderation_keywords, vec!["bomb", "scam"]);
+        assert!(config.moderate_prompts && !config.moderate_outputs);
+        assert_eq!(config.admin_users, vec!["alice"]);
+        assert_eq!(config.tenant_of("bob"), "acme");
+        assert_eq!(config.tenant_of("carol"), "carol");
+        assert_eq!(config.audit_retention_days, 0);
+        assert_eq!(config.quota_for(Some("alice")), TokenQuota { daily: 50000, monthly: 0 });
+        assert_eq!(config.webhook_allowed_hosts, vec!["hooks.example.com"]);
+        assert_eq!(config.event_bus, "kafka");
+        assert_eq!(config.event_bus_url, "kafka-1:9092,kafka-2:9092");
+        assert_eq!(config.event_topic, "llm.events");
+        assert_eq!(config.benchmark_path, "data/benchmarks.jsonl");
+        assert_eq!(config.evals_path, "");
+        assert_eq!(config.upload_spool_dir, "/var/tmp/llm");
+        assert_eq!(config.max_parse_tasks, 2);
+        assert_eq!((config.parse_cache_size, config.parse_cache_max_bytes), (64, 1048576));
+        assert_eq!(config.code_sandbox_command, vec!["firejail", "--quiet", "--net=none", "--private={dir}"]);
+        assert!(!config.allows_any_origin());
+    }
+
+    #[test]
+    fn test_code_execution_requires_sandbox() {
+        let mut config = ServerConfig::from_toml_str("code_execution = true").unwrap();
+        assert!(config.validate().is_err());
+
+        config.code_sandbox_command = vec!["bwrap".to_string(), "--unshare-all".to_string()];
+        assert!(config.validate().is_ok());
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_cors_origins() {
+        let mut config = ServerConfig::default();
+        config.cors_origins = vec!["*".to_string(), "http://localhost:3000".to_string()];
+        assert!(config.validate().is_ok());
+
+        for origin in ["example.com", "https://example.com/", "https://example.com\n"] {
+            config.cors_origins = vec!["https://example.com".to_string(), origin.to_string()];
+            assert!(config.validate().is_err(), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn test_invalid_env_override() {
+        let mut config = ServerConfig::default();
+        let result = config.apply_overrides(|key| {
+            if key == "LLM_PORT" { Some("abc".to_string()) } else { None }
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_device_from_toml() {
+        let config = ServerConfig::from_toml_str("device = \"cuda\"\ngpu_index = 1").unwrap();
+        assert_eq!(config.device, Device::Cuda);
+        assert_eq!(config.gpu_index, 1);
+        assert!(config.gpu_layers.is_none());
+
+        assert!(ServerConfig::from_toml_str("device = \"vulkan\"").is_err());
+    }
+
+    #[test]
+    fn test_token_quotas_from_toml() {
+        let toml = "daily_token_quota = 1000\n[token_quotas.alice]\nmonthly = 20000\n";
+        let config = ServerConfig::from_toml_str(toml).unwrap();
+        assert_eq!(config.quota_for(Some("alice")), TokenQuota { daily: 0, monthly: 20000 });
+        assert_eq!(config.quota_for(Some("bob")), TokenQuota { daily: 1000, monthly: 0 });
+        assert_eq!(config.quota_for(None), TokenQuota { daily: 1000, monthly: 0 });
+    }
+
+    #[test]
+    fn test_invalid_api_keys_override() {
+        let mut config = ServerConfig::default();
+        let result = config.apply_overrides(|key| {
+            if key == "LLM_API_KEYS" { Some("sk-alice".to_string()) } else { None }
+        });
+        assert!(result.is_err());
+    }
+}
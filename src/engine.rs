@@ -0,0 +1,548 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, RwLock};
+use tokio_util::sync::CancellationToken;
+use crate::file_parser::estimate_tokens;
+use crate::mistral_runner::{fetch_gguf, load_gguf_engine, load_vision_engine};
+use crate::registry::{ModelSpec, SharedRegistry};
+use crate::remote::RemoteEngine;
+use crate::service_state::{LoadPhase, SharedServiceStatus};
+use crate::types::{ChatMessage, GenerationConfig, TokenLogprobs, Usage};
+
+// items produced by InferenceEngine::stream
+pub enum StreamChunk {
+    Token(String),
+    // log probability of the next token, sent just before it when the request asks for logprobs
+    Logprobs(TokenLogprobs),
+    // sent once, after the last token
+    Usage(Usage),
+    // the backend failed mid-generation; nothing follows
+    Error(String),
+}
+
+pub type ChunkStream = Pin<Box<dyn Stream<Item = StreamChunk> + Send>>;
+
+
+/// 推理后端的统一接口，handler 只依赖这个 trait，新增后端（远程 API、candle 等）
+/// 只需实现它并在 [`get_or_load_engine`] 中注册
+#[async_trait]
+pub trait InferenceEngine: Send + Sync {
+    /// 流式生成，cancel 被触发后流应尽快结束
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> Result<ChunkStream>;
+
+    /// 带图片的流式生成，图片附加在最后一条 user 消息上。只有视觉模型需要实现
+    async fn stream_with_images(
+        &self,
+        _messages: &[ChatMessage],
+        _images: Vec<Vec<u8>>,
+        _config: &GenerationConfig,
+        _cancel: CancellationToken,
+    ) -> Result<ChunkStream> {
+        anyhow::bail!("This model does not support image input")
+    }
+
+    /// 非流式生成，默认实现收集 stream 的全部输出
+    async fn generate(
+        &self,
+        messages: &[ChatMessage],
+        config: &GenerationConfig,
+    ) -> Result<(String, Usage)> {
+        let stream = self.stream(messages, config, CancellationToken::new()).await?;
+        collect_stream(stream).await
+    }
+
+    /// 用模型的 tokenizer 统计 token 数，默认按字符数估算
+    async fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(estimate_tokens(text))
+    }
+
+    /// 文本切分成 token id，不加特殊 token 和对话模板
+    async fn tokenize(&self, _text: &str) -> Result<Vec<u32>> {
+        anyhow::bail!("This model does not expose its tokenizer")
+    }
+
+    /// token id 还原成文本
+    async fn detokenize(&self, _tokens: &[u32]) -> Result<String> {
+        anyhow::bail!("This model does not expose its tokenizer")
+    }
+}
+
+
+// 收集流中的全部 token 和最终的 usage，后端中途出错时返回错误
+pub async fn collect_stream(mut stream: ChunkStream) -> Result<(String, Usage)> {
+    let mut output = String::new();
+    let mut usage = Usage::default();
+
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            StreamChunk::Token(token) => output.push_str(&token),
+            StreamChunk::Usage(chunk_usage) => usage = chunk_usage,
+            StreamChunk::Logprobs(_) => {}
+            StreamChunk::Error(error) => anyhow::bail!(error),
+        }
+    }
+
+    Ok((output, usage))
+}
+
+
+struct LoadedModel {
+    engine: Arc<dyn InferenceEngine>,
+    // estimated VRAM / RAM footprint in bytes
+    size: u64,
+    // value of LoadedModels::clock at the last use
+    last_used: AtomicU64,
+}
+
+/// 已加载的模型。budget 不为 0 时按估算的占用限制同时加载的模型，
+/// 加载新模型前卸载最久未使用的模型（正在生成的请求仍持有引擎，结束后才释放）
+pub struct LoadedModels {
+    engines: HashMap<String, LoadedModel>,
+    budget: u64,
+    clock: AtomicU64,
+    // 正在加载的模型各有一个锁，同一模型的并发请求等待同一次加载；下载和加载期间不持有本结构的写锁
+    loading: Arc<DashMap<String, Arc<Mutex<()>>>>,
+    // 正在加载的模型预留的占用（字节），同时加载的几个模型加上已加载的模型不超过 budget
+    reserved: Arc<AtomicU64>,
+    // 预留被释放时通知等待预留的加载
+    released: Arc<Notify>,
+    // 每个模型的加载进度，handler 对正在加载的模型的请求返回 503
+    status: SharedServiceStatus,
+}
+
+impl LoadedModels {
+    pub fn new(budget: u64, status: SharedServiceStatus) -> Self {
+        Self {
+            engines: HashMap::new(),
+            budget,
+            clock: AtomicU64::new(0),
+            loading: Arc::new(DashMap::new()),
+            reserved: Arc::new(AtomicU64::new(0)),
+            released: Arc::new(Notify::new()),
+            status,
+        }
+    }
+
+    /// 取出已加载的引擎并记为最近使用
+    pub fn get(&self, name: &str) -> Option<Arc<dyn InferenceEngine>> {
+        let model = self.engines.get(name)?;
+        model.last_used.store(self.tick(), Ordering::Relaxed);
+        Some(model.engine.clone())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.engines.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.engines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.engines.is_empty()
+    }
+
+    /// 已加载的模型名，按名称排序
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.engines.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// 已加载模型的估算占用（字节）
+    pub fn used(&self) -> u64 {
+        self.engines.values().map(|model| model.size).sum()
+    }
+
+    /// 为 size 字节的新模型腾出空间（其他加载预留的部分不能使用），返回被卸载的模型
+    pub fn make_room(&mut self, size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        if self.budget == 0 {
+            return evicted;
+        }
+
+        let reserved = self.reserved.load(Ordering::Relaxed);
+        while !self.engines.is_empty() && self.used() + reserved + size > self.budget {
+            let oldest = self.engines.iter()
+                .min_by_key(|(_, model)| model.last_used.load(Ordering::Relaxed))
+                .map(|(name, _)| name.clone())
+                .expect("engines is not empty");
+            self.engines.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    /// 加载前为模型预留 size 字节，需要时卸载最久未使用的模型。其他正在加载的模型占着预算、
+    /// 卸载所有模型也放不下时返回 None，等那些加载结束后再试。单独一个超过 budget 的模型仍可以加载
+    fn reserve(&mut self, size: u64) -> Option<(MemoryReservation, Vec<String>)> {
+        // nothing is unloaded for a load that has to wait anyway
+        let reserved = self.reserved.load(Ordering::Relaxed);
+        if self.budget > 0 && reserved > 0 && reserved + size > self.budget {
+            return None;
+        }
+        let evicted = self.make_room(size);
+
+        self.reserved.fetch_add(size, Ordering::Relaxed);
+        let reservation = MemoryReservation {
+            reserved: self.reserved.clone(),
+            released: self.released.clone(),
+            size,
+        };
+        Some((reservation, evicted))
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.engines.remove(name).is_some()
+    }
+
+    pub fn insert(&mut self, name: &str, engine: Arc<dyn InferenceEngine>, size: u64) {
+        self.engines.insert(name.to_string(), LoadedModel {
+            engine,
+            size,
+            last_used: AtomicU64::new(self.tick()),
+        });
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+// 持有期间其他请求不会加载同一个模型。drop 时没有其他请求在等待就删除这个模型的锁
+struct ModelLoadLock {
+    locks: Arc<DashMap<String, Arc<Mutex<()>>>>,
+    model: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl ModelLoadLock {
+    async fn acquire(locks: Arc<DashMap<String, Arc<Mutex<()>>>>, model: &str) -> Self {
+        let lock = locks.entry(model.to_string()).or_default().clone();
+        let guard = lock.lock_owned().await;
+        Self { locks, model: model.to_string(), guard: Some(guard) }
+    }
+}
+
+impl Drop for ModelLoadLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.locks.remove_if(&self.model, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+// 加载期间预留的占用，模型放入缓存（成功）或加载失败、请求被取消时 drop 归还
+struct MemoryReservation {
+    reserved: Arc<AtomicU64>,
+    released: Arc<Notify>,
+    size: u64,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(self.size, Ordering::Relaxed);
+        self.released.notify_waiters();
+    }
+}
+
+// loaded engines, keyed by model name
+pub type ModelCache = Arc<RwLock<LoadedModels>>;
+
+/// budget 为同时加载的模型的总占用上限（字节），0 表示不限制。加载模型时更新 status
+pub fn new_model_cache(budget: u64, status: SharedServiceStatus) -> ModelCache {
+    Arc::new(RwLock::new(LoadedModels::new(budget, status)))
+}
+
+
+/// 估算模型加载后的占用：registry 中的 memory_mb，否则为 GGUF 文件（所有分片）大小（权重基本原样加载）。
+/// 视觉模型没有设置 memory_mb 时无法估算，记为 0
+pub async fn estimate_model_size(model_dir: &str, spec: &ModelSpec) -> u64 {
+    if let Some(mb) = spec.memory_mb {
+        return mb * 1024 * 1024;
+    }
+    if spec.vision {
+        return 0;
+    }
+    let mut size = 0;
+    for path in spec.gguf_paths(model_dir) {
+        size += tokio::fs::metadata(path).await.map(|meta| meta.len()).unwrap_or(0);
+    }
+    size
+}
+
+/// 启动后在后台依次加载 models（需要时先下载），第一个请求不用等待冷启动。
+/// 加载期间该模型处于 Loading 状态；加载失败只记录日志，使用该模型的请求会再次尝试加载
+pub fn spawn_preload(cache: ModelCache, registry: SharedRegistry, model_dir: String, models: Vec<String>) {
+    if models.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        for requested in models {
+            let model = {
+                let registry = registry.read().await;
+                let model = registry.resolve(&requested).to_string();
+                registry.get(&model).map(|_| model)
+            };
+            let Some(model) = model else {
+                tracing::warn!(model = %requested, "Unknown model in preload_models, skipping");
+                continue;
+            };
+
+            let started = std::time::Instant::now();
+            match get_or_load_engine(&cache, &registry, &model_dir, &model).await {
+                Ok(_) => tracing::info!(model = %model, duration_ms = started.elapsed().as_millis() as u64, "Preloaded model"),
+                Err(e) => tracing::error!(model = %model, error = %e, "Failed to preload model"),
+            }
+        }
+    });
+}
+
+
+// in-flight generations, keyed by request id, so they can be cancelled
+pub type ActiveGenerations = Arc<RwLock<HashMap<String, CancellationToken>>>;
+
+pub fn new_active_generations() -> ActiveGenerations {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+
+/// 获取已加载的引擎，未加载时加载后放入缓存
+pub async fn get_or_load_engine(
+    cache: &ModelCache,
+    registry: &SharedRegistry,
+    model_dir: &str,
+    model_name: &str,
+) -> Result<Arc<dyn InferenceEngine>> {
+    let (locks, status, released) = {
+        let engines = cache.read().await;
+        if let Some(engine) = engines.get(model_name) {
+            return Ok(engine);
+        }
+        (engines.loading.clone(), engines.status.clone(), engines.released.clone())
+    };
+
+    // concurrent requests for the same model wait for one load instead of building it twice; the
+    // cache is only write-locked to evict and insert, so other models stay usable while this one loads
+    let _loading = ModelLoadLock::acquire(locks, model_name).await;
+    if let Some(engine) = cache.read().await.get(model_name) {
+        return Ok(engine);
+    }
+
+    let (spec, placement) = {
+        let registry = registry.read().await;
+        let spec = registry.get(model_name).cloned().ok_or_else(|| anyhow::anyhow!("Unknown model"))?;
+        let placement = registry.placement(&spec);
+        (spec, placement)
+    };
+
+    // remote models take no local memory and have nothing to download
+    if let Some(remote) = &spec.remote {
+        let engine: Arc<dyn InferenceEngine> = Arc::new(RemoteEngine::new(remote, &spec)?);
+        cache.write().await.insert(model_name, engine.clone(), 0);
+        tracing::info!(model = %model_name, base_url = %remote.base_url, "Remote model registered");
+        return Ok(engine);
+    }
+
+    let phase = if spec.vision { LoadPhase::Loading } else { LoadPhase::Downloading };
+    let progress = status.start_loading(model_name, phase);
+    let loaded = async {
+        // download first so the size of the GGUF file is known, then reserve it in the budget before
+        // loading: models loading at the same time and the loaded ones never exceed the budget together
+        if !spec.vision {
+            fetch_gguf(model_dir, &spec).await?;
+            progress.set_phase(LoadPhase::Loading);
+        }
+        let size = estimate_model_size(model_dir, &spec).await;
+        let reservation = loop {
+            let mut waiting = std::pin::pin!(released.notified());
+            waiting.as_mut().enable();
+
+            if let Some((reservation, evicted)) = cache.write().await.reserve(size) {
+                for evicted in evicted {
+                    tracing::info!(model = %evicted, "Model unloaded to stay within the memory budget");
+                }
+                break reservation;
+            }
+            tracing::info!(model = %model_name, "Waiting for other models to load before loading");
+            waiting.await;
+        };
+
+        let engine = if spec.vision {
+            load_vision_engine(model_dir, &spec, placement).await?
+        } else {
+            load_gguf_engine(model_dir, &spec, placement).await?
+        };
+        Ok::<_, anyhow::Error>((engine, size, reservation))
+    }.await;
+    progress.finish(loaded.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+    let (engine, size, reservation) = loaded?;
+
+    // the reservation becomes the loaded model under the same lock, so the budget never counts it twice
+    let mut engines = cache.write().await;
+    engines.insert(model_name, engine.clone(), size);
+    drop(reservation);
+    tracing::info!(
+        model = %model_name,
+        device = %placement.describe(),
+        size_mb = size / (1024 * 1024),
+        cached_models = engines.len(),
+        used_mb = engines.used() / (1024 * 1024),
+        "Model loaded",
+    );
+
+    Ok(engine)
+}
+
+
+// non-streaming inference
+pub async fn run_inference_collect(
+    cache: &ModelCache,
+    registry: &SharedRegistry,
+    model_dir: &str,
+    model_name: &str,
+    messages: &[ChatMessage],
+    images: Vec<Vec<u8>>,
+    config: &GenerationConfig,
+) -> Result<(String, Usage)> {
+    let engine = get_or_load_engine(cache, registry, model_dir, model_name).await?;
+    if images.is_empty() {
+        engine.generate(messages, config).await
+    } else {
+        let stream = engine.stream_with_images(messages, images, config, CancellationToken::new()).await?;
+        collect_stream(stream).await
+    }
+}
+
+
+// streaming inference
+pub async fn run_inference_stream(
+    cache: &ModelCache,
+    registry: &SharedRegistry,
+    model_dir: &str,
+    model_name: &str,
+    messages: &[ChatMessage],
+    images: Vec<Vec<u8>>,
+    config: &GenerationConfig,
+    cancel: CancellationToken,
+) -> Result<ChunkStream> {
+    let engine = get_or_load_engine(cache, registry, model_dir, model_name).await?;
+    if images.is_empty() {
+        engine.stream(messages, config, cancel).await
+    } else {
+        engine.stream_with_images(messages, images, config, cancel).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service_state::new_service_status;
+
+    struct DummyEngine;
+
+    #[async_trait]
+    impl InferenceEngine for DummyEngine {
+        async fn stream(
+            &self,
+            _messages: &[ChatMessage],
+            _config: &GenerationConfig,
+            _cancel: CancellationToken,
+        ) -> Result<ChunkStream> {
+            anyhow::bail!("not a real model")
+        }
+    }
+
+    fn loaded(budget: u64, names: &[&str]) -> LoadedModels {
+        let mut models = LoadedModels::new(budget, new_service_status());
+        for name in names {
+            models.insert(name, Arc::new(DummyEngine), 4);
+        }
+        models
+    }
+
+    #[test]
+    fn test_make_room_evicts_least_recently_used() {
+        let mut models = loaded(10, &["a", "b"]);
+        assert!(models.get("a").is_some());
+
+        // b was used longest ago
+        assert_eq!(models.make_room(4), vec!["b".to_string()]);
+        assert!(models.contains_key("a"));
+        assert_eq!(models.used(), 4);
+    }
+
+    #[test]
+    fn test_make_room_keeps_models_that_fit() {
+        let mut models = loaded(10, &["a"]);
+        assert!(models.make_room(6).is_empty());
+
+        let mut unlimited = loaded(0, &["a", "b", "c"]);
+        assert!(unlimited.make_room(1 << 40).is_empty());
+        assert_eq!(unlimited.len(), 3);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut models = loaded(0, &["a"]);
+        assert!(models.remove("a"));
+        assert!(!models.remove("a"));
+        assert!(models.get("a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_model_load_lock_is_per_model() {
+        let models = loaded(0, &[]);
+        let first = ModelLoadLock::acquire(models.loading.clone(), "a").await;
+
+        // another model loads at the same time, the same model waits
+        let other = ModelLoadLock::acquire(models.loading.clone(), "b").await;
+        drop(other);
+        let locks = models.loading.clone();
+        let waiter = tokio::spawn(async move { drop(ModelLoadLock::acquire(locks, "a").await) });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        waiter.await.unwrap();
+        assert!(models.loading.is_empty());
+    }
+
+    #[test]
+    fn test_reservations_count_against_the_budget() {
+        let mut models = loaded(10, &["a"]);
+
+        // a second load has to wait for the first one instead of going over the budget
+        let (first, evicted) = models.reserve(6).unwrap();
+        assert!(evicted.is_empty());
+        assert!(models.reserve(6).is_none());
+        assert!(models.contains_key("a"));
+
+        // the loaded model takes over the reservation
+        models.insert("b", Arc::new(DummyEngine), 6);
+        drop(first);
+        assert_eq!(models.reserved.load(Ordering::Relaxed), 0);
+        assert!(models.get("a").is_some());
+        let (_second, evicted) = models.reserve(6).unwrap();
+        assert_eq!(evicted, vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn test_model_larger_than_budget_unloads_everything() {
+        let mut models = loaded(10, &["a", "b"]);
+        assert_eq!(models.make_room(20).len(), 2);
+        assert_eq!(models.len(), 0);
+    }
+}
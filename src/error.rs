@@ -1,21 +1,289 @@
 use serde::{Serialize};
+use utoipa::ToSchema;
+use crate::service_state::ServiceState;
+use crate::file_parser::FileStatus;
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct UnsupportedFileError {
     pub error: String,
     pub file_type: String,
+    // 文件内容是二进制时，嗅探出的 MIME 类型
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detected_type: Option<String>,
 }
 
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct RemoveFileError {
     pub error: String,
     pub file_id: String,
 }
 
 
-#[derive(Serialize)]
+// 文件还在解析或解析失败（409）
+#[derive(Serialize, ToSchema)]
+pub struct FileNotReadyError {
+    pub error: String,
+    pub file_id: String,
+    pub status: FileStatus,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct SummarizeError {
+    pub error: String,
+    pub file_id: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ExtractError {
+    pub error: String,
+    pub file_id: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
 pub struct RemoveSessionError {
     pub error: String,
     pub session_id: String,
-}
\ No newline at end of file
+}
+
+
+// session 不存在或属于其他用户（404）
+#[derive(Serialize, ToSchema)]
+pub struct SessionNotFoundError {
+    pub error: String,
+    pub session_id: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct MemoryError {
+    pub error: String,
+}
+
+
+// 示例组无效（400）或不存在（404）
+#[derive(Serialize, ToSchema)]
+pub struct ExampleSetError {
+    pub error: String,
+    pub name: String,
+}
+
+
+// eval 集合的 YAML 无效（400）或集合不存在（404）
+#[derive(Serialize, ToSchema)]
+pub struct EvalError {
+    pub error: String,
+    pub name: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ToolError {
+    pub error: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct AgentError {
+    pub error: String,
+}
+
+
+// Ollama 兼容接口的错误，流式响应中也作为单独的一行发送
+#[derive(Serialize, ToSchema)]
+pub struct OllamaError {
+    pub error: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct UnauthorizedError {
+    pub error: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct MessageError {
+    pub error: String,
+    pub session_id: String,
+    pub message_id: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct CancelRequestError {
+    pub error: String,
+    pub request_id: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ModelError {
+    pub error: String,
+    pub model: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct PullModelError {
+    pub error: String,
+    pub alias: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct GenerationTimeoutError {
+    pub error: String,
+    pub finish_reason: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct QueueFullError {
+    pub error: String,
+    pub retry_after: u64,
+}
+
+
+// 正在加载模型（503），status 为加载的模型、阶段和已用时间
+#[derive(Serialize, ToSchema)]
+pub struct ServiceLoadingError {
+    pub error: String,
+    pub retry_after: u64,
+    pub status: ServiceState,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct RateLimitError {
+    pub error: String,
+    pub retry_after: u64,
+}
+
+
+// 请求参数无效（400），field 为出错的字段
+#[derive(Serialize, ToSchema)]
+pub struct ValidationError {
+    pub error: String,
+    pub field: String,
+}
+
+
+// prompt 超过 max_prompt_chars / max_prompt_tokens（413），unit 为 "chars" 或 "tokens"
+#[derive(Serialize, ToSchema)]
+pub struct PromptTooLongError {
+    pub error: String,
+    pub field: String,
+    pub length: usize,
+    pub limit: usize,
+    pub unit: String,
+}
+
+
+// JSON 请求体超过 max_json_body_size（413），limit 为字节数
+#[derive(Serialize, ToSchema)]
+pub struct BodyTooLargeError {
+    pub error: String,
+    pub limit: usize,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadError {
+    pub error: String,
+    pub filename: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadTooLargeError {
+    pub error: String,
+    pub filename: String,
+    // bytes
+    pub limit: usize,
+}
+
+
+// session 的文件数或文件总大小超出限制（413）
+#[derive(Serialize, ToSchema)]
+pub struct SessionQuotaError {
+    pub error: String,
+    pub session_id: String,
+    pub max_files: usize,
+    // bytes
+    pub max_size: usize,
+}
+
+
+// 加密 PDF 的密码缺失或错误（422）
+#[derive(Serialize, ToSchema)]
+pub struct InvalidPasswordError {
+    pub error: String,
+    pub filename: String,
+    pub password_provided: bool,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadNotFoundError {
+    pub error: String,
+    pub upload_id: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct FileNotFoundError {
+    pub error: String,
+    pub file_id: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ImageError {
+    pub error: String,
+    pub image_id: String,
+}
+
+
+// prompt 被内容审核拦截（400）
+#[derive(Serialize, ToSchema)]
+pub struct ModerationError {
+    pub error: String,
+    // 命中的原因："keyword:<关键词>" 或 "classifier"
+    pub reasons: Vec<String>,
+}
+
+
+// 审计日志未启用（404）、调用方不是管理员（403）或读取失败（500）
+#[derive(Serialize, ToSchema)]
+pub struct AuditError {
+    pub error: String,
+}
+
+
+// 调用方当天或当月的 token 配额已用完（429），reset_at 为配额恢复的 unix 时间戳
+#[derive(Serialize, ToSchema)]
+pub struct QuotaExceededError {
+    pub error: String,
+    // "day" 或 "month"
+    pub period: String,
+    pub limit: u64,
+    pub used: u64,
+    pub reset_at: i64,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageError {
+    pub error: String,
+}
+
+
+// 调用方不是管理员（403）、model_name 未注册（400）或运行失败（500）
+#[derive(Serialize, ToSchema)]
+pub struct BenchmarkError {
+    pub error: String,
+}
@@ -0,0 +1,317 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use utoipa::ToSchema;
+use crate::json_file::JsonFile;
+use crate::few_shot::{set_key, split_key, valid_name, MAX_SET_NAME_CHARS};
+use crate::types::{ChatMessage, GenerationConfig, MessageRole};
+
+
+// 每个集合最多的用例数
+pub const MAX_EVAL_CASES: usize = 200;
+
+
+/// 一个用例：prompt 和对回答的要求。pattern 为在回答中搜索的正则表达式（`(?i)` 忽略大小写），
+/// expected 为回答（去掉首尾空白后）应完全相同的文本，至少设置一个，都设置时都要满足
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct EvalCase {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+}
+
+
+/// 上传的 YAML 文件，例如
+///
+/// ```yaml
+/// model: qwen
+/// system_prompt: Answer with one word.
+/// generation:
+///   max_tokens: 16
+/// cases:
+///   - name: capital
+///     prompt: What is the capital of France?
+///     pattern: "(?i)paris"
+///   - prompt: "2 + 2 ="
+///     expected: "4"
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct EvalSuite {
+    // 运行时未指定 model_name 时使用，都未设置时为默认模型
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    // 未设置 temperature 时按 0 生成，同一个模型的结果可以复现
+    #[serde(default)]
+    pub generation: GenerationConfig,
+    pub cases: Vec<EvalCase>,
+}
+
+impl EvalSuite {
+    /// 发给模型的对话：系统消息（如果有）和用例的 prompt
+    pub fn messages(&self, case: &EvalCase) -> Vec<ChatMessage> {
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = &self.system_prompt {
+            messages.push(ChatMessage::new(MessageRole::System, system_prompt.clone()));
+        }
+        messages.push(ChatMessage::new(MessageRole::User, case.prompt.clone()));
+        messages
+    }
+}
+
+
+/// 解析并校验上传的 YAML
+pub fn parse_suite(yaml: &str) -> Result<EvalSuite, String> {
+    let suite: EvalSuite = serde_yaml::from_str(yaml).map_err(|e| format!("Invalid eval YAML: {}", e))?;
+    if suite.cases.is_empty() || suite.cases.len() > MAX_EVAL_CASES {
+        return Err(format!("An eval suite must have 1 to {} cases", MAX_EVAL_CASES));
+    }
+    for (idx, case) in suite.cases.iter().enumerate() {
+        if case.prompt.trim().is_empty() {
+            return Err(format!("Case {} has an empty prompt", idx));
+        }
+        if case.pattern.is_none() && case.expected.is_none() {
+            return Err(format!("Case {} needs a pattern or an expected answer", idx));
+        }
+        if let Some(pattern) = &case.pattern {
+            Regex::new(pattern).map_err(|e| format!("Case {} has an invalid pattern: {}", idx, e))?;
+        }
+    }
+    Ok(suite)
+}
+
+
+/// 用例的检查结果。失败时 diff 为期望（expected 或 `/pattern/`）和实际回答的逐行对比
+#[derive(Debug, PartialEq)]
+pub struct CaseOutcome {
+    pub passed: bool,
+    pub diff: Option<String>,
+}
+
+pub fn check_case(case: &EvalCase, output: &str) -> CaseOutcome {
+    let output = output.trim();
+    if let Some(expected) = &case.expected {
+        if expected.trim() != output {
+            return CaseOutcome { passed: false, diff: Some(line_diff(expected.trim(), output)) };
+        }
+    }
+    if let Some(pattern) = &case.pattern {
+        // parse_suite 已经校验过，这里编译失败的 pattern 视为不匹配
+        if !Regex::new(pattern).is_ok_and(|regex| regex.is_match(output)) {
+            return CaseOutcome { passed: false, diff: Some(line_diff(&format!("/{}/", pattern), output)) };
+        }
+    }
+    CaseOutcome { passed: true, diff: None }
+}
+
+
+/// 逐行对比：相同的行以两个空格开头，只在期望中的以 "- " 开头，只在回答中的以 "+ " 开头
+pub fn line_diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+
+    // lcs[i][j]：a[i..] 和 b[j..] 的最长公共子序列长度
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(format!("  {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("- {}", a[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", b[j]));
+            j += 1;
+        }
+    }
+    lines.join("\n")
+}
+
+
+/// 按名称保存的 eval 集合，和 few-shot 示例组一样按租户区分。
+/// 设置 path 时每次修改后写入该 JSON 文件，重启后仍然保留
+pub struct EvalSuites {
+    suites: DashMap<String, EvalSuite>,
+    file: Option<JsonFile>,
+}
+
+pub type SharedEvalSuites = Arc<EvalSuites>;
+
+/// 读取保存的 eval 集合，path 为空时只保存在内存中，文件不存在时返回空表
+pub async fn load_eval_suites(path: &str) -> Result<SharedEvalSuites> {
+    let suites = DashMap::new();
+    let path = (!path.is_empty()).then(|| PathBuf::from(path));
+
+    if let Some(path) = &path {
+        match fs::read(path).await {
+            Ok(data) => {
+                let saved: BTreeMap<String, EvalSuite> = serde_json::from_slice(&data)?;
+                tracing::info!(suites = saved.len(), path = %path.display(), "Loaded eval suites");
+                for (name, suite) in saved {
+                    suites.insert(name, suite);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(Arc::new(EvalSuites {
+        suites,
+        file: path.map(JsonFile::new),
+    }))
+}
+
+impl EvalSuites {
+    pub fn get(&self, tenant: Option<&str>, name: &str) -> Option<EvalSuite> {
+        self.suites.get(&set_key(tenant, name)).map(|suite| suite.clone())
+    }
+
+    /// 租户的全部集合的名称和用例数，按名称排序
+    pub fn list(&self, tenant: Option<&str>) -> Vec<(String, usize)> {
+        let mut suites: Vec<_> = self.suites.iter()
+            .filter_map(|entry| match split_key(entry.key()) {
+                (owner, name) if owner == tenant => Some((name.to_string(), entry.value().cases.len())),
+                _ => None,
+            })
+            .collect();
+        suites.sort();
+        suites
+    }
+
+    /// 解析 YAML 并创建或替换集合。名称或内容无效时不做任何修改
+    pub async fn put(&self, tenant: Option<&str>, name: &str, yaml: &str) -> Result<EvalSuite, String> {
+        if !valid_name(name) {
+            return Err(format!(
+                "Eval suite names must be 1 to {} letters, digits, '-', '_' or '.'", MAX_SET_NAME_CHARS));
+        }
+        let suite = parse_suite(yaml)?;
+        self.suites.insert(set_key(tenant, name), suite.clone());
+        self.save().await;
+        Ok(suite)
+    }
+
+    pub async fn remove(&self, tenant: Option<&str>, name: &str) -> bool {
+        let existed = self.suites.remove(&set_key(tenant, name)).is_some();
+        if existed {
+            self.save().await;
+        }
+        existed
+    }
+
+    async fn save(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let snapshot = || -> BTreeMap<String, EvalSuite> {
+            self.suites.iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect()
+        };
+        if let Err(e) = file.save(snapshot).await {
+            tracing::error!(path = %file.path().display(), error = %e, "Failed to save eval suites");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUITE: &str = r#"
+model: qwen
+system_prompt: Answer with one word.
+generation:
+  max_tokens: 16
+cases:
+  - name: capital
+    prompt: What is the capital of France?
+    pattern: "(?i)\\bparis\\b"
+  - prompt: "2 + 2 ="
+    expected: "4"
+"#;
+
+    fn case(pattern: Option<&str>, expected: Option<&str>) -> EvalCase {
+        EvalCase {
+            name: None,
+            prompt: "p".to_string(),
+            pattern: pattern.map(str::to_string),
+            expected: expected.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_parse_suite() {
+        let suite = parse_suite(SUITE).unwrap();
+        assert_eq!(suite.model.as_deref(), Some("qwen"));
+        assert_eq!(suite.generation.max_tokens, Some(16));
+        assert_eq!(suite.cases.len(), 2);
+        assert_eq!(suite.cases[1].expected.as_deref(), Some("4"));
+        assert_eq!(suite.messages(&suite.cases[0]).len(), 2);
+
+        assert!(parse_suite("cases: []").is_err());
+        assert!(parse_suite("cases:\n  - prompt: hi\n").is_err());
+        assert!(parse_suite("cases:\n  - prompt: hi\n    pattern: \"(\"\n").is_err());
+        assert!(parse_suite("not: [valid").is_err());
+    }
+
+    #[test]
+    fn test_check_case() {
+        assert!(check_case(&case(Some("(?i)paris"), None), "It is Paris.").passed);
+        assert!(check_case(&case(None, Some("4")), " 4\n").passed);
+
+        let failed = check_case(&case(Some("(?i)paris"), None), "Lyon");
+        assert!(!failed.passed);
+        assert_eq!(failed.diff.as_deref(), Some("- /(?i)paris/\n+ Lyon"));
+
+        // expected 和 pattern 都设置时都要满足
+        assert!(!check_case(&case(Some("^4$"), Some("four")), "4").passed);
+    }
+
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(line_diff("a\nb\nc", "a\nc\nd"), "  a\n- b\n  c\n+ d");
+        assert_eq!(line_diff("same", "same"), "  same");
+        assert_eq!(line_diff("", "new"), "+ new");
+    }
+
+    #[tokio::test]
+    async fn test_put_validates_and_persists_per_tenant() {
+        let dir = std::env::temp_dir().join(format!("evals-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("evals.json");
+        let suites = load_eval_suites(path.to_str().unwrap()).await.unwrap();
+
+        assert!(suites.put(None, "bad name", SUITE).await.is_err());
+        assert!(suites.put(None, "geo", "cases: []").await.is_err());
+        suites.put(Some("acme"), "geo", SUITE).await.unwrap();
+
+        let reloaded = load_eval_suites(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(reloaded.list(Some("acme")), vec![("geo".to_string(), 2)]);
+        assert!(reloaded.list(None).is_empty());
+        assert!(reloaded.get(Some("globex"), "geo").is_none());
+        assert!(reloaded.remove(Some("acme"), "geo").await);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
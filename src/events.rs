@@ -0,0 +1,227 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use crate::config::ServerConfig;
+use crate::file_store::unix_now;
+
+
+// 事件类型，NATS 的 subject 为 `{event_topic}.{类型}`，Kafka 中作为消息的 key
+pub const SESSION_CREATED: &str = "session.created";
+pub const GENERATION_COMPLETED: &str = "generation.completed";
+pub const FILE_INGESTED: &str = "file.ingested";
+
+// 等待发送的事件数，消息队列不可用时超出的事件被丢弃，不影响请求
+const EVENT_BUFFER: usize = 1024;
+
+
+/// 发布到事件总线的一条事件（JSON）。data 的内容取决于事件类型，不包含 prompt、回答或文件内容
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    pub id: String,
+    pub event: &'static str,
+    pub timestamp: u64,
+    // API key 对应的用户名和租户，未配置 api_keys 时为 null
+    pub owner: Option<String>,
+    pub tenant: Option<String>,
+    pub data: serde_json::Value,
+}
+
+impl Event {
+    pub fn new(event: &'static str, owner: Option<&str>, tenant: Option<&str>, data: impl Serialize) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            event,
+            timestamp: unix_now(),
+            owner: owner.map(str::to_string),
+            tenant: tenant.map(str::to_string),
+            data: serde_json::to_value(data).unwrap_or_default(),
+        }
+    }
+}
+
+
+/// 事件的目的地（NATS 或 Kafka）
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn send(&self, event: &str, payload: Vec<u8>) -> Result<()>;
+}
+
+
+/// 事件总线。publish 不等待发送，事件由后台任务按顺序交给 EventSink；未配置 event_bus 时什么也不做
+pub struct EventBus {
+    tx: Option<mpsc::Sender<Event>>,
+}
+
+pub type SharedEventBus = Arc<EventBus>;
+
+impl EventBus {
+    pub fn disabled() -> SharedEventBus {
+        Arc::new(EventBus { tx: None })
+    }
+
+    pub fn new(sink: Arc<dyn EventSink>) -> SharedEventBus {
+        let (tx, mut rx) = mpsc::channel::<Event>(EVENT_BUFFER);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                let payload = match serde_json::to_vec(&event) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        tracing::error!(event = event.event, error = %e, "Failed to serialize event");
+                        continue;
+                    }
+                };
+                if let Err(e) = sink.send(event.event, payload).await {
+                    tracing::warn!(event = event.event, id = %event.id, error = %e, "Failed to publish event");
+                }
+            }
+        });
+        Arc::new(EventBus { tx: Some(tx) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn publish(&self, event: Event) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(event)) = tx.try_send(event) {
+            tracing::warn!(event = event.event, id = %event.id, "Event buffer full, dropping event");
+        }
+    }
+}
+
+
+/// 按 `event_bus` 配置连接消息队列："" 不发布事件，"nats" 或 "kafka" 需要对应的 feature
+pub async fn connect_event_bus(config: &ServerConfig) -> Result<SharedEventBus> {
+    match config.event_bus.as_str() {
+        "" => Ok(EventBus::disabled()),
+        "nats" => Ok(EventBus::new(open_nats(config).await?)),
+        "kafka" => Ok(EventBus::new(open_kafka(config).await?)),
+        other => anyhow::bail!("Unknown event_bus \"{}\", expected \"nats\" or \"kafka\"", other),
+    }
+}
+
+
+#[cfg(feature = "nats")]
+struct NatsSink {
+    client: async_nats::Client,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl EventSink for NatsSink {
+    async fn send(&self, event: &str, payload: Vec<u8>) -> Result<()> {
+        self.client.publish(format!("{}.{}", self.subject, event), payload.into()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "nats")]
+async fn open_nats(config: &ServerConfig) -> Result<Arc<dyn EventSink>> {
+    let client = async_nats::connect(config.event_bus_url.as_str()).await?;
+    tracing::info!(url = %config.event_bus_url, subject = %config.event_topic, "Connected to NATS");
+    Ok(Arc::new(NatsSink {
+        client,
+        subject: config.event_topic.clone(),
+    }))
+}
+
+#[cfg(not(feature = "nats"))]
+async fn open_nats(_config: &ServerConfig) -> Result<Arc<dyn EventSink>> {
+    anyhow::bail!("event_bus = \"nats\" requires building the server with --features nats")
+}
+
+
+// 所有事件写入 event_topic 的 0 号分区，保持发布的顺序
+#[cfg(feature = "kafka")]
+struct KafkaSink {
+    partition: rskafka::client::partition::PartitionClient,
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl EventSink for KafkaSink {
+    async fn send(&self, event: &str, payload: Vec<u8>) -> Result<()> {
+        let record = rskafka::record::Record {
+            key: Some(event.as_bytes().to_vec()),
+            value: Some(payload),
+            headers: Default::default(),
+            timestamp: chrono::Utc::now(),
+        };
+        self.partition.produce(vec![record], rskafka::client::partition::Compression::NoCompression).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+async fn open_kafka(config: &ServerConfig) -> Result<Arc<dyn EventSink>> {
+    use rskafka::client::{partition::UnknownTopicHandling, ClientBuilder};
+
+    // event_bus_url 为逗号分隔的 broker 地址，例如 "kafka-1:9092,kafka-2:9092"
+    let brokers: Vec<String> = config.event_bus_url
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let client = ClientBuilder::new(brokers).build().await?;
+    let partition = client
+        .partition_client(config.event_topic.clone(), 0, UnknownTopicHandling::Retry)
+        .await?;
+    tracing::info!(brokers = %config.event_bus_url, topic = %config.event_topic, "Connected to Kafka");
+    Ok(Arc::new(KafkaSink { partition }))
+}
+
+#[cfg(not(feature = "kafka"))]
+async fn open_kafka(_config: &ServerConfig) -> Result<Arc<dyn EventSink>> {
+    anyhow::bail!("event_bus = \"kafka\" requires building the server with --features kafka")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct ChannelSink(mpsc::UnboundedSender<(String, Vec<u8>)>);
+
+    #[async_trait]
+    impl EventSink for ChannelSink {
+        async fn send(&self, event: &str, payload: Vec<u8>) -> Result<()> {
+            self.0.send((event.to_string(), payload))?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_reach_the_sink_in_order() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let bus = EventBus::new(Arc::new(ChannelSink(tx)));
+        bus.publish(Event::new(SESSION_CREATED, Some("alice"), Some("acme"), serde_json::json!({"session_id": "s1"})));
+        bus.publish(Event::new(FILE_INGESTED, None, None, serde_json::json!({"file_id": "f1"})));
+
+        let (event, payload) = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(event, SESSION_CREATED);
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["event"], "session.created");
+        assert_eq!(json["tenant"], "acme");
+        assert_eq!(json["data"]["session_id"], "s1");
+
+        let (event, _) = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(event, FILE_INGESTED);
+    }
+
+    #[tokio::test]
+    async fn test_unconfigured_bus_is_disabled() {
+        let bus = connect_event_bus(&ServerConfig::default()).await.unwrap();
+        assert!(!bus.enabled());
+        bus.publish(Event::new(SESSION_CREATED, None, None, ()));
+
+        let config = ServerConfig { event_bus: "rabbitmq".to_string(), ..Default::default() };
+        assert!(connect_event_bus(&config).await.is_err());
+    }
+}
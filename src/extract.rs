@@ -0,0 +1,263 @@
+use serde_json::{Map, Value};
+use crate::types::{ChatMessage, MessageRole};
+
+
+// schema 最多的字段数
+pub const MAX_EXTRACT_FIELDS: usize = 32;
+// 抽取一个字段的回复只需要一个 JSON 值
+pub const FIELD_MAX_TOKENS: usize = 256;
+
+const SYSTEM_PROMPT: &str = "You extract data from documents. Use only information stated in the document excerpts. \
+    Reply with a single JSON object of the form {\"value\": ...} and nothing else. \
+    Use {\"value\": null} when the excerpts do not contain the answer.";
+
+
+/// schema 中字段的类型（JSON Schema 的子集）
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Array(Box<FieldType>),
+}
+
+impl FieldType {
+    fn parse(schema: &Map<String, Value>) -> Result<FieldType, String> {
+        match schema.get("type").and_then(Value::as_str).unwrap_or("string") {
+            "string" => Ok(FieldType::String),
+            "number" => Ok(FieldType::Number),
+            "integer" => Ok(FieldType::Integer),
+            "boolean" => Ok(FieldType::Boolean),
+            "array" => {
+                let items = schema.get("items").and_then(Value::as_object);
+                let item_type = match items {
+                    Some(items) => FieldType::parse(items)?,
+                    None => FieldType::String,
+                };
+                if matches!(item_type, FieldType::Array(_)) {
+                    return Err("Nested arrays are not supported".to_string());
+                }
+                Ok(FieldType::Array(Box::new(item_type)))
+            }
+            other => Err(format!("Unsupported type \"{}\"", other)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            FieldType::String => "a string".to_string(),
+            FieldType::Number => "a number".to_string(),
+            FieldType::Integer => "an integer".to_string(),
+            FieldType::Boolean => "true or false".to_string(),
+            FieldType::Array(item) => format!("a JSON array where every item is {}", item.describe()),
+        }
+    }
+}
+
+
+/// 要抽取的一个字段
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldSpec {
+    pub name: String,
+    pub field_type: FieldType,
+    pub description: Option<String>,
+    // schema 的 enum：值只能是其中之一
+    pub allowed: Vec<Value>,
+    pub required: bool,
+}
+
+
+/// 解析请求中的 schema：`{"type": "object", "properties": {...}, "required": [...]}`。
+/// 支持 string、number、integer、boolean 和这些类型的 array，以及 description 和 enum
+pub fn parse_schema(schema: &Value) -> Result<Vec<FieldSpec>, String> {
+    let properties = schema.get("properties")
+        .and_then(Value::as_object)
+        .ok_or("schema must be an object with \"properties\"")?;
+    if properties.is_empty() || properties.len() > MAX_EXTRACT_FIELDS {
+        return Err(format!("schema must have 1 to {} properties", MAX_EXTRACT_FIELDS));
+    }
+
+    let required: Vec<&str> = schema.get("required")
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    if let Some(unknown) = required.iter().find(|name| !properties.contains_key(**name)) {
+        return Err(format!("Required field \"{}\" is not in properties", unknown));
+    }
+
+    properties.iter()
+        .map(|(name, property)| {
+            let property = property.as_object()
+                .ok_or_else(|| format!("Property \"{}\" must be an object", name))?;
+            let field_type = FieldType::parse(property)
+                .map_err(|e| format!("Property \"{}\": {}", name, e))?;
+            Ok(FieldSpec {
+                name: name.clone(),
+                field_type,
+                description: property.get("description").and_then(Value::as_str).map(str::to_string),
+                allowed: property.get("enum").and_then(Value::as_array).cloned().unwrap_or_default(),
+                required: required.contains(&name.as_str()),
+            })
+        })
+        .collect()
+}
+
+
+/// 检索和这个字段相关的块用的查询
+pub fn field_query(field: &FieldSpec) -> String {
+    let name = field.name.replace(['_', '-'], " ");
+    match &field.description {
+        Some(description) => format!("{} {}", name, description),
+        None => name,
+    }
+}
+
+
+/// 抽取一个字段的对话。previous_error 为上一次回复校验失败的原因，让模型改正
+pub fn field_prompt(field: &FieldSpec, filename: &str, excerpts: &[&str], previous_error: Option<&str>) -> Vec<ChatMessage> {
+    let mut request = format!(
+        "Document \"{}\" excerpts:\n\n{}\n\nExtract the field \"{}\"",
+        filename,
+        excerpts.join("\n\n---\n\n"),
+        field.name,
+    );
+    if let Some(description) = &field.description {
+        request.push_str(&format!(" ({})", description));
+    }
+    request.push_str(&format!(". The value must be {}", field.field_type.describe()));
+    if !field.allowed.is_empty() {
+        let allowed: Vec<String> = field.allowed.iter().map(Value::to_string).collect();
+        request.push_str(&format!(", one of {}", allowed.join(", ")));
+    }
+    request.push('.');
+    if let Some(error) = previous_error {
+        request.push_str(&format!("\n\nYour previous answer was invalid: {}. Answer again.", error));
+    }
+
+    vec![
+        ChatMessage::new(MessageRole::System, SYSTEM_PROMPT.to_string()),
+        ChatMessage::new(MessageRole::User, request),
+    ]
+}
+
+
+/// 解析模型的回复并按字段类型校验。数字和布尔值写成字符串时转换为对应的类型，
+/// 没有找到时为 null
+pub fn parse_value(field: &FieldSpec, reply: &str) -> Result<Value, String> {
+    let value = match json_object(reply) {
+        Some(Value::Object(mut object)) if object.contains_key("value") => object.remove("value").unwrap_or(Value::Null),
+        _ => serde_json::from_str(reply.trim()).map_err(|_| "the reply is not JSON".to_string())?,
+    };
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+
+    let value = coerce(&field.field_type, value)?;
+    if !field.allowed.is_empty() && !field.allowed.contains(&value) {
+        return Err(format!("{} is not one of the allowed values", value));
+    }
+    Ok(value)
+}
+
+// 回复中第一个 `{` 到最后一个 `}` 之间的 JSON 对象（模型常把 JSON 放在 ``` 代码块或说明文字中）
+fn json_object(reply: &str) -> Option<Value> {
+    let start = reply.find('{')?;
+    let end = reply.rfind('}')?;
+    serde_json::from_str(reply.get(start..=end)?).ok()
+}
+
+fn coerce(field_type: &FieldType, value: Value) -> Result<Value, String> {
+    match (field_type, value) {
+        (FieldType::String, Value::String(s)) => Ok(Value::String(s)),
+        (FieldType::String, value @ (Value::Number(_) | Value::Bool(_))) => Ok(Value::String(value.to_string())),
+
+        (FieldType::Number, Value::Number(n)) => Ok(Value::Number(n)),
+        (FieldType::Number, Value::String(s)) => parse_number(&s)
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(|| format!("\"{}\" is not a number", s)),
+
+        (FieldType::Integer, Value::Number(n)) => integer(n.as_f64()).ok_or_else(|| format!("{} is not an integer", n)),
+        (FieldType::Integer, Value::String(s)) => integer(parse_number(&s)).ok_or_else(|| format!("\"{}\" is not an integer", s)),
+
+        (FieldType::Boolean, Value::Bool(b)) => Ok(Value::Bool(b)),
+        (FieldType::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+            "true" | "yes" => Ok(Value::Bool(true)),
+            "false" | "no" => Ok(Value::Bool(false)),
+            _ => Err(format!("\"{}\" is not true or false", s)),
+        },
+
+        (FieldType::Array(item), Value::Array(items)) => items.into_iter()
+            .filter(|value| !value.is_null())
+            .map(|value| coerce(item, value))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array),
+        // 只有一个值时模型常常不写成数组
+        (FieldType::Array(item), value) => Ok(Value::Array(vec![coerce(item, value)?])),
+
+        (field_type, value) => Err(format!("{} is not {}", value, field_type.describe())),
+    }
+}
+
+// 允许千位分隔符，例如 "1,250.50"
+fn parse_number(s: &str) -> Option<f64> {
+    s.trim().replace(',', "").parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+fn integer(n: Option<f64>) -> Option<Value> {
+    let n = n?;
+    (n.fract() == 0.0 && n.abs() < i64::MAX as f64).then(|| Value::from(n as i64))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(schema: Value) -> FieldSpec {
+        parse_schema(&json!({ "properties": { "f": schema } })).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_parse_schema() {
+        let fields = parse_schema(&json!({
+            "type": "object",
+            "properties": {
+                "invoice_number": { "type": "string", "description": "The invoice id" },
+                "total": { "type": "number" },
+                "items": { "type": "array", "items": { "type": "string" } },
+                "currency": { "type": "string", "enum": ["EUR", "USD"] },
+            },
+            "required": ["invoice_number"],
+        })).unwrap();
+
+        assert_eq!(fields.len(), 4);
+        let invoice = fields.iter().find(|f| f.name == "invoice_number").unwrap();
+        assert!(invoice.required);
+        assert_eq!(field_query(invoice), "invoice number The invoice id");
+        let items = fields.iter().find(|f| f.name == "items").unwrap();
+        assert_eq!(items.field_type, FieldType::Array(Box::new(FieldType::String)));
+
+        assert!(parse_schema(&json!({ "properties": {} })).is_err());
+        assert!(parse_schema(&json!({ "properties": { "a": { "type": "object" } } })).is_err());
+        assert!(parse_schema(&json!({ "properties": { "a": {} }, "required": ["b"] })).is_err());
+    }
+
+    #[test]
+    fn test_parse_value_coerces_types() {
+        assert_eq!(parse_value(&field(json!({ "type": "number" })), r#"{"value": "1,250.50"}"#), Ok(json!(1250.5)));
+        assert_eq!(parse_value(&field(json!({ "type": "integer" })), "```json\n{\"value\": 3.0}\n```"), Ok(json!(3)));
+        assert_eq!(parse_value(&field(json!({ "type": "boolean" })), r#"{"value": "yes"}"#), Ok(json!(true)));
+        assert_eq!(parse_value(&field(json!({ "type": "array" })), r#"{"value": "one"}"#), Ok(json!(["one"])));
+        assert_eq!(parse_value(&field(json!({ "type": "string" })), r#"{"value": null}"#), Ok(Value::Null));
+    }
+
+    #[test]
+    fn test_parse_value_rejects_invalid_output() {
+        assert!(parse_value(&field(json!({ "type": "integer" })), r#"{"value": 2.5}"#).is_err());
+        assert!(parse_value(&field(json!({ "type": "number" })), "The total is unknown").is_err());
+        assert!(parse_value(&field(json!({ "enum": ["EUR", "USD"] })), r#"{"value": "GBP"}"#).is_err());
+    }
+}
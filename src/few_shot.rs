@@ -0,0 +1,244 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use utoipa::ToSchema;
+use crate::json_file::JsonFile;
+use crate::types::{ChatMessage, MessageRole};
+
+
+// 每组最多的示例数，以及名称的最大字符数
+pub const MAX_EXAMPLES_PER_SET: usize = 32;
+pub const MAX_SET_NAME_CHARS: usize = 64;
+
+
+/// 一个示例：用户的输入和期望的回答
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Example {
+    pub input: String,
+    pub output: String,
+}
+
+impl Example {
+    /// 作为一问一答两条消息放进对话
+    pub fn messages(&self) -> [ChatMessage; 2] {
+        [
+            ChatMessage::new(MessageRole::User, self.input.clone()),
+            ChatMessage::new(MessageRole::Assistant, self.output.clone()),
+        ]
+    }
+}
+
+
+/// 按名称保存的 few-shot 示例组，请求通过 `examples` 引用，示例放在系统消息之后、对话历史之前。
+/// 每个租户有自己的一组名称，同一租户的用户共用（未配置 api_keys 时所有用户共用）。
+/// 设置 path 时每次修改后写入该 JSON 文件，重启后仍然保留
+pub struct ExampleSets {
+    sets: DashMap<String, Vec<Example>>,
+    file: Option<JsonFile>,
+}
+
+pub type SharedExampleSets = Arc<ExampleSets>;
+
+/// 读取保存的示例组，path 为空时只保存在内存中，文件不存在时返回空表
+pub async fn load_example_sets(path: &str) -> Result<SharedExampleSets> {
+    let sets = DashMap::new();
+    let path = (!path.is_empty()).then(|| PathBuf::from(path));
+
+    if let Some(path) = &path {
+        match fs::read(path).await {
+            Ok(data) => {
+                let saved: BTreeMap<String, Vec<Example>> = serde_json::from_slice(&data)?;
+                tracing::info!(sets = saved.len(), path = %path.display(), "Loaded few-shot examples");
+                for (name, examples) in saved {
+                    sets.insert(name, examples);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(Arc::new(ExampleSets {
+        sets,
+        file: path.map(JsonFile::new),
+    }))
+}
+
+// 保存时的键：有租户时为 "租户/名称"。名称中不能有 '/'，所以按最后一个 '/' 拆分（eval 集合也使用）
+pub fn set_key(tenant: Option<&str>, name: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{}/{}", tenant, name),
+        None => name.to_string(),
+    }
+}
+
+pub fn split_key(key: &str) -> (Option<&str>, &str) {
+    match key.rsplit_once('/') {
+        Some((tenant, name)) => (Some(tenant), name),
+        None => (None, key),
+    }
+}
+
+impl ExampleSets {
+    pub fn get(&self, tenant: Option<&str>, name: &str) -> Option<Vec<Example>> {
+        self.sets.get(&set_key(tenant, name)).map(|examples| examples.clone())
+    }
+
+    /// 租户的全部示例组的名称和示例数，按名称排序
+    pub fn list(&self, tenant: Option<&str>) -> Vec<(String, usize)> {
+        let mut sets: Vec<_> = self.sets.iter()
+            .filter_map(|entry| match split_key(entry.key()) {
+                (owner, name) if owner == tenant => Some((name.to_string(), entry.value().len())),
+                _ => None,
+            })
+            .collect();
+        sets.sort();
+        sets
+    }
+
+    /// 创建或替换一组示例。校验失败时不做任何修改
+    pub async fn put(&self, tenant: Option<&str>, name: &str, examples: Vec<Example>) -> Result<(), String> {
+        validate_set(name, &examples)?;
+        self.sets.insert(set_key(tenant, name), examples);
+        self.save().await;
+        Ok(())
+    }
+
+    pub async fn remove(&self, tenant: Option<&str>, name: &str) -> bool {
+        let existed = self.sets.remove(&set_key(tenant, name)).is_some();
+        if existed {
+            self.save().await;
+        }
+        existed
+    }
+
+    async fn save(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let snapshot = || -> BTreeMap<String, Vec<Example>> {
+            self.sets.iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect()
+        };
+        if let Err(e) = file.save(snapshot).await {
+            tracing::error!(path = %file.path().display(), error = %e, "Failed to save few-shot examples");
+        }
+    }
+}
+
+
+// 名称由字母、数字、`-`、`_`、`.` 组成，出现在 URL 中
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_SET_NAME_CHARS
+        && name.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn validate_set(name: &str, examples: &[Example]) -> Result<(), String> {
+    if !valid_name(name) {
+        return Err(format!(
+            "Example set names must be 1 to {} letters, digits, '-', '_' or '.'", MAX_SET_NAME_CHARS));
+    }
+
+    if examples.is_empty() || examples.len() > MAX_EXAMPLES_PER_SET {
+        return Err(format!("An example set must have 1 to {} examples", MAX_EXAMPLES_PER_SET));
+    }
+    if let Some(idx) = examples.iter().position(|e| e.input.trim().is_empty() || e.output.trim().is_empty()) {
+        return Err(format!("Example {} has an empty input or output", idx));
+    }
+    Ok(())
+}
+
+
+/// 按顺序保留放得进 budget 的示例数。示例要么整个保留要么丢弃，
+/// 一个放不下时之后的也不再尝试，模型看到的示例顺序和注册时一致
+pub fn examples_within(example_tokens: &[usize], budget: usize) -> usize {
+    let mut used = 0;
+    example_tokens.iter()
+        .take_while(|&&tokens| {
+            used += tokens;
+            used <= budget
+        })
+        .count()
+}
+
+
+/// 把示例插在开头的系统消息（系统提示词、用户记忆、摘要）之后
+pub fn insert_examples(messages: &mut Vec<ChatMessage>, examples: &[Example]) {
+    let idx = messages.iter()
+        .position(|m| m.role != MessageRole::System)
+        .unwrap_or(messages.len());
+    messages.splice(idx..idx, examples.iter().flat_map(Example::messages));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(input: &str, output: &str) -> Example {
+        Example { input: input.to_string(), output: output.to_string() }
+    }
+
+    #[test]
+    fn test_examples_within_budget() {
+        assert_eq!(examples_within(&[10, 20, 30], 100), 3);
+        assert_eq!(examples_within(&[10, 20, 30], 35), 2);
+        // 第二个放不下时，不跳过它去放更短的第三个
+        assert_eq!(examples_within(&[10, 50, 5], 40), 1);
+        assert_eq!(examples_within(&[10], 0), 0);
+    }
+
+    #[test]
+    fn test_insert_after_system_messages() {
+        let mut messages = vec![
+            ChatMessage::new(MessageRole::System, "Answer in SQL".to_string()),
+            ChatMessage::new(MessageRole::User, "All users".to_string()),
+        ];
+        insert_examples(&mut messages, &[example("All orders", "SELECT * FROM orders;")]);
+
+        let roles: Vec<_> = messages.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(roles, [MessageRole::System, MessageRole::User, MessageRole::Assistant, MessageRole::User]);
+        assert_eq!(messages[1].content, "All orders");
+        assert_eq!(messages[3].content, "All users");
+    }
+
+    #[tokio::test]
+    async fn test_put_validates_and_persists() {
+        let dir = std::env::temp_dir().join(format!("few-shot-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("few_shot.json");
+        let sets = load_example_sets(path.to_str().unwrap()).await.unwrap();
+
+        assert!(sets.put(None, "bad name", vec![example("a", "b")]).await.is_err());
+        assert!(sets.put(None, "a/b", vec![example("a", "b")]).await.is_err());
+        assert!(sets.put(None, "sql", Vec::new()).await.is_err());
+        assert!(sets.put(None, "sql", vec![example("a", " ")]).await.is_err());
+        sets.put(None, "sql", vec![example("All orders", "SELECT * FROM orders;")]).await.unwrap();
+
+        let reloaded = load_example_sets(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(reloaded.list(None), vec![("sql".to_string(), 1)]);
+        assert!(reloaded.remove(None, "sql").await);
+        assert!(reloaded.get(None, "sql").is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sets_are_per_tenant() {
+        let sets = load_example_sets("").await.unwrap();
+        sets.put(Some("acme"), "sql", vec![example("a", "b")]).await.unwrap();
+        sets.put(Some("globex"), "sql", vec![example("c", "d"), example("e", "f")]).await.unwrap();
+
+        assert_eq!(sets.list(Some("acme")), vec![("sql".to_string(), 1)]);
+        assert!(sets.list(None).is_empty());
+        assert!(sets.get(Some("initech"), "sql").is_none());
+        assert!(sets.remove(Some("acme"), "sql").await);
+        assert_eq!(sets.get(Some("globex"), "sql").map(|examples| examples.len()), Some(2));
+    }
+}
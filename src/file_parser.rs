@@ -3,8 +3,11 @@ use docx_rs::{
     DocumentChild, ParagraphChild, RunChild, TableCellContent, TableChild, TableRowChild,
 };
 use pptx_to_md::{PptxContainer, ParserConfig};
-use pdf::{content::*, file::FileOptions};
+use quick_xml::events::{attributes::Attribute, BytesStart, Event};
+use pdf::{content::*, error::PdfError, file::FileOptions};
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::env::temp_dir;
 use std::fs::File;
 use std::io::Read;
@@ -14,20 +17,78 @@ use calamine::{open_workbook, Reader, Xlsx,
                Data
 };
 use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::pii::redact_pii;
 
 pub type FileCache = Arc<RwLock<HashMap<String, CacheFile>>>;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CacheFile {
     pub filename: String,
     pub content: String,
     pub extension : String,
+    pub session_id: String,
+    // 上传的原始文件大小（字节）
+    pub file_size: usize,
+    // unix 时间戳（秒）
+    pub uploaded_at: u64,
+    // 上传时按句子切好的块（相邻块有重叠），检索和截断都基于这些块
+    #[serde(default)]
+    pub chunks: Vec<String>,
+    // 文档在后台解析，解析完成前 content 为空且不会加入检索
+    #[serde(default)]
+    pub status: FileStatus,
+    // 解析失败的原因
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // 上传者的租户，只有同一租户的用户能看到和使用这个文件。未配置 api_keys 时为 None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    // 是否已切块加入该 session 的向量索引（索引只在内存中，不持久化）
+    #[serde(skip)]
+    pub indexed: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Processing,
+    #[default]
+    Ready,
+    Failed,
 }
 
 pub fn new_file_cache() -> FileCache {
     Arc::new(RwLock::new(HashMap::new()))
 }
 
+
+// dimension of the hashed bag-of-words embeddings used for retrieval
+pub const EMBEDDING_DIM: usize = 256;
+
+#[derive(Clone)]
+pub struct IndexedChunk {
+    pub file_id: String,
+    pub filename: String,
+    pub extension: String,
+    pub chunk_index: usize,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+// per-session vector index of file chunks, keyed by session id
+pub type VectorIndex = Arc<RwLock<HashMap<String, Vec<IndexedChunk>>>>;
+
+pub fn new_vector_index() -> VectorIndex {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+// 图片上传后不解析，作为视觉模型的输入（InferenceRequest.image_ids）
+pub fn is_image_extension(extension: &str) -> bool {
+    matches!(extension.to_lowercase().as_str(), "png" | "jpg" | "jpeg" | "webp" | "gif" | "bmp")
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum FileType {
     TXT,
@@ -73,12 +134,188 @@ impl FileType {
             _ => None,
         }
     }
+
+    // 内容嗅探得到的 MIME 类型对应的文档类型
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "application/pdf" => Some(FileType::PDF),
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => Some(FileType::DOCX),
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation" => Some(FileType::PPTX),
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => Some(FileType::XLSX),
+            _ => None,
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        matches!(self, FileType::TXT | FileType::CODE | FileType::MD)
+    }
+}
+
+
+/// 文件内容既不是扩展名声明的类型，也不是可以解析的文本
+#[derive(Debug)]
+pub struct BinaryContent {
+    // 嗅探出的 MIME 类型，无法识别时为 None
+    pub detected: Option<String>,
+}
+
+impl std::fmt::Display for BinaryContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.detected {
+            Some(mime) => write!(f, "File content is {}, not a supported document", mime),
+            None => write!(f, "File content is binary, not a supported document"),
+        }
+    }
 }
 
-pub async fn parse_file(path: &Path, file_bytes: &[u8]) -> Result<String> {
+impl std::error::Error for BinaryContent {}
+
+
+fn is_text_content(bytes: &[u8]) -> bool {
+    !bytes.contains(&0) && std::str::from_utf8(bytes).is_ok()
+}
+
+
+/// 按文件内容（magic bytes）确认或修正扩展名对应的类型，例如扩展名为 .txt 的 PDF
+/// 按 PDF 解析；二进制内容返回 [`BinaryContent`]
+pub fn detect_file_type(extension: &str, bytes: &[u8]) -> Result<FileType> {
+    let declared = FileType::from_extension(extension)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported file type: {}", extension))?;
+
+    // html / xml / shell 脚本等文本格式由下面的文本检查处理
+    let sniffed = infer::get(bytes).filter(|kind| kind.matcher_type() != infer::MatcherType::Text);
+
+    if let Some(kind) = sniffed {
+        if let Some(file_type) = FileType::from_mime(kind.mime_type()) {
+            return Ok(file_type);
+        }
+        // office 文档本身是 zip，infer 无法识别具体类型时相信扩展名
+        if kind.mime_type() == "application/zip"
+            && matches!(declared, FileType::DOCX | FileType::PPTX | FileType::XLSX)
+        {
+            return Ok(declared);
+        }
+    }
+
+    if is_text_content(bytes) {
+        // 文档扩展名但内容是纯文本，按文本读取
+        return Ok(if declared.is_text() { declared } else { FileType::TXT });
+    }
+
+    Err(BinaryContent { detected: sniffed.map(|kind| kind.mime_type().to_string()) }.into())
+}
+
+/// 上传时随文件提交的解析选项，来自 multipart 中的普通表单字段
+#[derive(Clone, Default)]
+pub struct ParseOptions {
+    // 加密 PDF 的密码
+    pub password: Option<String>,
+    // pptx：附加每页的演讲者备注和批注
+    pub pptx_notes: bool,
+    // xlsx：只解析这些工作表（为空时全部解析）、每个表最多的数据行数、是否在每块前重复表头
+    pub xlsx_sheets: Vec<String>,
+    pub xlsx_max_rows: Option<usize>,
+    pub xlsx_header: bool,
+    // docx：附加脚注和尾注、页眉和页脚、审阅批注
+    pub docx_notes: bool,
+    pub docx_headers: bool,
+    pub docx_comments: bool,
+    // 遮盖解析结果中的邮箱、电话号码、SSN 和信用卡号。服务端配置 redact_pii 开启时总是遮盖，
+    // 表单字段只能开启不能关闭
+    pub redact_pii: bool,
+    // 检索切块大小，重复表头时按它给行分组（由服务端配置设置，不是表单字段）
+    pub chunk_size: usize,
+}
+
+// 密码不出现在日志中
+impl std::fmt::Debug for ParseOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParseOptions")
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("pptx_notes", &self.pptx_notes)
+            .field("xlsx_sheets", &self.xlsx_sheets)
+            .field("xlsx_max_rows", &self.xlsx_max_rows)
+            .field("xlsx_header", &self.xlsx_header)
+            .field("docx_notes", &self.docx_notes)
+            .field("docx_headers", &self.docx_headers)
+            .field("docx_comments", &self.docx_comments)
+            .field("redact_pii", &self.redact_pii)
+            .field("chunk_size", &self.chunk_size)
+            .finish()
+    }
+}
+
+impl ParseOptions {
+    /// 按表单字段名设置选项，未知字段忽略
+    pub fn set(&mut self, name: &str, value: &str) {
+        match name {
+            "password" => {
+                self.password = Some(value.to_string()).filter(|password| !password.is_empty());
+            }
+            "pptx_notes" => self.pptx_notes = parse_flag(value),
+            "xlsx_sheets" => {
+                self.xlsx_sheets = value
+                    .split(',')
+                    .map(|sheet| sheet.trim().to_string())
+                    .filter(|sheet| !sheet.is_empty())
+                    .collect();
+            }
+            "xlsx_max_rows" => self.xlsx_max_rows = value.trim().parse().ok(),
+            "xlsx_header" => self.xlsx_header = parse_flag(value),
+            "docx_notes" => self.docx_notes = parse_flag(value),
+            "docx_headers" => self.docx_headers = parse_flag(value),
+            "docx_comments" => self.docx_comments = parse_flag(value),
+            "redact_pii" => self.redact_pii |= parse_flag(value),
+            _ => {}
+        }
+    }
+}
+
+
+// 表单中的布尔选项："true" / "1" / "yes" / "on"
+fn parse_flag(value: &str) -> bool {
+    matches!(value.trim().to_lowercase().as_str(), "true" | "1" | "yes" | "on")
+}
+
+
+/// PDF 已加密，且没有提供密码或密码错误
+#[derive(Debug)]
+pub struct InvalidPassword;
+
+impl std::fmt::Display for InvalidPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PDF is encrypted and the password is missing or wrong")
+    }
+}
+
+impl std::error::Error for InvalidPassword {}
+
+
+/// 上传请求中同步执行的快速检查：按内容确认文件类型，PDF 检查能否用给定的密码打开。
+/// 完整的解析在后台进行
+pub fn validate_upload(path: &Path, file_bytes: &[u8], options: &ParseOptions) -> Result<FileType> {
+    let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+    let file_type = detect_file_type(extension, file_bytes)?;
+
+    if file_type == FileType::PDF {
+        let mut pdf = FileOptions::cached();
+        if let Some(password) = options.password.as_deref() {
+            pdf = pdf.password(password.as_bytes());
+        }
+        pdf.load(file_bytes.to_vec()).map_err(pdf_error)?;
+    }
+
+    Ok(file_type)
+}
+
+
+pub async fn parse_file(path: &Path, file_bytes: &[u8], options: &ParseOptions) -> Result<String> {
     let extension = path.extension().unwrap().to_str().unwrap();
 
-    let file_type = FileType::from_extension(extension).unwrap();
+    let file_type = detect_file_type(extension, file_bytes)?;
+    if FileType::from_extension(extension).as_ref() != Some(&file_type) {
+        tracing::info!(path = %path.display(), ?file_type, extension, "File content does not match its extension, parsing it by content");
+    }
 
     let temp_dir = temp_dir();
     let temp_file = temp_dir.join(format!("upload_{}.{}", uuid::Uuid::new_v4(), extension));
@@ -86,26 +323,383 @@ pub async fn parse_file(path: &Path, file_bytes: &[u8]) -> Result<String> {
 
     let result = match file_type {
         FileType::TXT => parse_directly(&temp_file).await,
-        FileType::PDF => parse_pdf(&temp_file).await,
-        FileType::DOCX => parse_docx(&temp_file).await,
-        FileType::PPTX => parse_pptx(&temp_file).await,
-        FileType::XLSX => parse_xlsx(&temp_file).await,
+        FileType::PDF => parse_pdf(&temp_file, options.password.as_deref()).await,
+        FileType::DOCX => parse_docx(&temp_file, options).await,
+        FileType::PPTX => parse_pptx(&temp_file, options.pptx_notes).await,
+        FileType::XLSX => parse_xlsx(&temp_file, options).await,
         FileType::CODE => parse_directly(&temp_file).await,
         FileType::MD => parse_directly(&temp_file).await
     };
 
     let _ = tokio::fs::remove_file(&temp_file).await;
 
+    // 在解析结果进入缓存和切块之前遮盖，原文不会保存或发给模型
+    if options.redact_pii {
+        return result.map(|content| {
+            let (content, redacted) = redact_pii(&content);
+            tracing::info!(path = %path.display(), redacted, "Redacted PII from parsed file");
+            content
+        });
+    }
     result
 }
 
+
+// 压缩包中最多解析的文件数
+pub const MAX_ARCHIVE_ENTRIES: usize = 1000;
+
+/// 压缩包解压后超过大小限制
+#[derive(Debug)]
+pub struct ArchiveTooLarge {
+    pub limit: usize,
+}
+
+impl std::fmt::Display for ArchiveTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Archive content exceeds {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for ArchiveTooLarge {}
+
+/// 解压 zip，返回 (相对路径, 内容)。只保留可以解析的文本 / 代码文件，跳过目录、
+/// macOS 元数据和不支持的类型。单个文件或解压总大小超限时返回 [`ArchiveTooLarge`]
+pub fn extract_zip(
+    bytes: &[u8],
+    max_entry_size: usize,
+    max_total_size: usize,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))?;
+    let mut members = Vec::new();
+    let mut total_size = 0usize;
+
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+
+        // enclosed_name rejects absolute paths and ".." components
+        let name = match entry.enclosed_name() {
+            Some(path) => path.to_string_lossy().replace('\\', "/"),
+            None => continue,
+        };
+        let basename = name.rsplit('/').next().unwrap_or(&name);
+        if name.starts_with("__MACOSX/") || basename.starts_with("._") {
+            continue;
+        }
+
+        let supported = Path::new(&name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(FileType::from_extension)
+            .is_some();
+        if !supported {
+            continue;
+        }
+
+        if members.len() >= MAX_ARCHIVE_ENTRIES {
+            anyhow::bail!("Archive contains more than {} files", MAX_ARCHIVE_ENTRIES);
+        }
+
+        // the declared size can lie, so cap the actual read as well
+        let mut data = Vec::new();
+        entry.take(max_entry_size as u64 + 1).read_to_end(&mut data)?;
+        if data.len() > max_entry_size {
+            return Err(ArchiveTooLarge { limit: max_entry_size }.into());
+        }
+
+        total_size += data.len();
+        if total_size > max_total_size {
+            return Err(ArchiveTooLarge { limit: max_total_size }.into());
+        }
+
+        members.push((name, data));
+    }
+
+    Ok(members)
+}
+
+/// 按段落切分文本，每块不超过 chunk_size 个字符（超长段落按字符硬切）
+pub fn chunk_text(text: &str, chunk_size: usize) -> Vec<String> {
+    chunk_text_with_overlap(text, chunk_size, 0)
+}
+
+
+/// 按句子切块：句子（代码中为一行）不会被拆开，除非单句超过 chunk_size。
+/// 相邻块共享最多 overlap 个字符的完整句子，检索到的块不会丢失上下文
+pub fn chunk_text_with_overlap(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+    let chunk_size = chunk_size.max(1);
+    // keep every chunk making progress through the text
+    let overlap = overlap.min(chunk_size / 2);
+
+    // (text, starts a paragraph)
+    let mut units: Vec<(String, bool)> = Vec::new();
+    for paragraph in text.split("\n\n").map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let mut starts_paragraph = true;
+        for sentence in split_sentences(paragraph) {
+            for piece in hard_split(sentence, chunk_size, overlap) {
+                units.push((piece, starts_paragraph));
+                starts_paragraph = false;
+            }
+        }
+    }
+
+    let mut chunks = Vec::new();
+    let mut current: Vec<(String, bool)> = Vec::new();
+
+    for unit in units {
+        let mut candidate = current.clone();
+        candidate.push(unit.clone());
+
+        if !current.is_empty() && render_chunk(&candidate).chars().count() > chunk_size {
+            chunks.push(render_chunk(&current));
+
+            // carry the trailing sentences of the previous chunk into the next one
+            let mut carry: Vec<(String, bool)> = Vec::new();
+            for previous in current.iter().rev() {
+                let mut extended = vec![previous.clone()];
+                extended.extend(carry.iter().cloned());
+                if render_chunk(&extended).chars().count() > overlap {
+                    break;
+                }
+                carry = extended;
+            }
+            while !carry.is_empty() {
+                let mut with_unit = carry.clone();
+                with_unit.push(unit.clone());
+                if render_chunk(&with_unit).chars().count() <= chunk_size {
+                    break;
+                }
+                carry.remove(0);
+            }
+            current = carry;
+        }
+
+        current.push(unit);
+    }
+
+    if !current.is_empty() {
+        chunks.push(render_chunk(&current));
+    }
+
+    chunks
+}
+
+
+// 句子在 . ! ? 后接空白处结束，中文句号等直接结束，换行也视为边界（保持代码按行切分）。
+// 每个句子保留原文中的结尾空格
+fn split_sentences(paragraph: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = paragraph.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let ends_sentence = match c {
+            '\n' | '。' | '！' | '？' => true,
+            '.' | '!' | '?' => chars.peek().map(|(_, next)| next.is_whitespace()).unwrap_or(true),
+            _ => false,
+        };
+        if !ends_sentence {
+            continue;
+        }
+
+        let mut end = i + c.len_utf8();
+        if c != '\n' {
+            while let Some(&(j, next)) = chars.peek() {
+                if next != ' ' && next != '\t' && next != '\n' {
+                    break;
+                }
+                end = j + next.len_utf8();
+                chars.next();
+                if next == '\n' {
+                    break;
+                }
+            }
+        }
+
+        sentences.push(&paragraph[start..end]);
+        start = end;
+    }
+
+    if start < paragraph.len() {
+        sentences.push(&paragraph[start..]);
+    }
+
+    sentences
+}
+
+
+// 超过 chunk_size 的句子按字符切开，片段之间重叠 overlap 个字符
+fn hard_split(sentence: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+    let chars: Vec<char> = sentence.chars().collect();
+    if chars.len() <= chunk_size {
+        return vec![sentence.to_string()];
+    }
+
+    let stride = chunk_size - overlap;
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + chunk_size).min(chars.len());
+        pieces.push(chars[start..end].iter().collect());
+        if end == chars.len() {
+            break;
+        }
+        start += stride;
+    }
+
+    pieces
+}
+
+
+fn render_chunk(units: &[(String, bool)]) -> String {
+    let mut out = String::new();
+
+    for (i, (text, starts_paragraph)) in units.iter().enumerate() {
+        if i > 0 && *starts_paragraph {
+            out.truncate(out.trim_end().len());
+            out.push_str("\n\n");
+        }
+        out.push_str(text);
+    }
+
+    out.trim().to_string()
+}
+
+
+/// 计算文本的哈希词袋向量（L2 归一化），用于近似语义检索
+pub fn embed_text(text: &str) -> Vec<f32> {
+    let mut counts = vec![0f32; EMBEDDING_DIM];
+
+    for token in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| t.chars().count() > 1)
+        .map(|t| t.to_lowercase())
+    {
+        let mut hasher = DefaultHasher::new();
+        token.hash(&mut hasher);
+        counts[(hasher.finish() % EMBEDDING_DIM as u64) as usize] += 1.0;
+    }
+
+    // sublinear term frequency so repeated words don't dominate
+    let mut embedding: Vec<f32> = counts
+        .into_iter()
+        .map(|c| if c > 0.0 { 1.0 + c.ln() } else { 0.0 })
+        .collect();
+
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+
+    embedding
+}
+
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    // embeddings are already normalized
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+
+/// 文件用于检索的块
+pub fn retrieval_chunks(file: &CacheFile, chunk_size: usize) -> Vec<String> {
+    // files stored before chunks were persisted are chunked on the fly
+    if file.chunks.is_empty() {
+        chunk_text(&file.content, chunk_size)
+    } else {
+        file.chunks.clone()
+    }
+}
+
+pub fn build_chunks(file_id: &str, file: &CacheFile, chunk_size: usize) -> Vec<IndexedChunk> {
+    retrieval_chunks(file, chunk_size)
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| IndexedChunk {
+            file_id: file_id.to_string(),
+            filename: file.filename.clone(),
+            extension: file.extension.clone(),
+            chunk_index: i,
+            embedding: embed_text(&text),
+            text,
+        })
+        .collect()
+}
+
+
+/// 返回与 query 最相关的 k 个块，按相关度降序
+pub fn retrieve_top_k<'a>(chunks: &'a [IndexedChunk], query: &str, k: usize) -> Vec<&'a IndexedChunk> {
+    let query_embedding = embed_text(query);
+
+    let mut scored: Vec<(f32, &IndexedChunk)> = chunks
+        .iter()
+        .map(|chunk| (cosine_similarity(&query_embedding, &chunk.embedding), chunk))
+        .collect();
+
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    scored.into_iter().take(k).map(|(_, chunk)| chunk).collect()
+}
+
+
+// 文件上下文中一个文件的内容：检索到的块按相关度拼接
+pub struct FileExcerpt {
+    pub file_id: String,
+    pub uploaded_at: u64,
+    pub text: String,
+}
+
+
+/// 按约 4 个字符一个 token 估算，模型的 tokenizer 不可用时使用
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+
+/// 把各文件内容的总长度截断到 max_chars 个字符以内。最近上传的文件优先保留，
+/// 被截断的文件末尾加上 "[truncated N chars]"
+pub fn truncate_excerpts(excerpts: &mut [FileExcerpt], max_chars: usize) {
+    let mut newest_first: Vec<usize> = (0..excerpts.len()).collect();
+    newest_first.sort_by(|a, b| excerpts[*b].uploaded_at.cmp(&excerpts[*a].uploaded_at));
+
+    let mut remaining = max_chars;
+    for i in newest_first {
+        let excerpt = &mut excerpts[i];
+        let len = excerpt.text.chars().count();
+        if len <= remaining {
+            remaining -= len;
+            continue;
+        }
+
+        let kept: String = excerpt.text.chars().take(remaining).collect();
+        excerpt.text = format!("{}\n[truncated {} chars]", kept.trim_end(), len - remaining)
+            .trim_start()
+            .to_string();
+        remaining = 0;
+    }
+}
+
+
 async fn parse_directly(path: &Path) -> Result<String> {
     let content = tokio::fs::read_to_string(path).await?;
     Ok(content)
 }
 
-async fn parse_pdf(path: &Path) -> Result<String> {
-    let file = FileOptions::cached().open(path)?;
+fn pdf_error(e: PdfError) -> anyhow::Error {
+    match e {
+        PdfError::InvalidPassword => InvalidPassword.into(),
+        e => e.into(),
+    }
+}
+
+async fn parse_pdf(path: &Path, password: Option<&str>) -> Result<String> {
+    let mut options = FileOptions::cached();
+    if let Some(password) = password {
+        options = options.password(password.as_bytes());
+    }
+    let file = options.open(path).map_err(pdf_error)?;
     let resolver = file.resolver();
     let mut text_content = String::new();
 
@@ -151,7 +745,7 @@ async fn parse_pdf(path: &Path) -> Result<String> {
     Ok(cleaned)
 }
 
-async fn parse_docx(path: &Path) -> Result<String> {
+async fn parse_docx(path: &Path, options: &ParseOptions) -> Result<String> {
     let mut file = File::open(path)?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
@@ -164,6 +758,11 @@ async fn parse_docx(path: &Path) -> Result<String> {
         extract_text_from_document_child(child, &mut text_content);
     }
 
+    // 正文之外的部分直接从压缩包中的 XML 读取
+    if options.docx_notes || options.docx_headers || options.docx_comments {
+        text_content.push_str(&read_docx_extras(std::io::Cursor::new(&buf), options)?);
+    }
+
     // 清理多余空白
     let cleaned: String = text_content
         .lines()
@@ -176,6 +775,79 @@ async fn parse_docx(path: &Path) -> Result<String> {
     Ok(cleaned)
 }
 
+// 页眉页脚、脚注尾注和批注，每部分前加标题
+fn read_docx_extras<R: Read + std::io::Seek>(reader: R, options: &ParseOptions) -> Result<String> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut sections = Vec::new();
+
+    if options.docx_headers {
+        let mut parts: Vec<String> = archive
+            .file_names()
+            .filter(|name| {
+                (name.starts_with("word/header") || name.starts_with("word/footer")) && name.ends_with(".xml")
+            })
+            .map(|name| name.to_string())
+            .collect();
+        parts.sort();
+
+        // 各节的页眉页脚经常相同，只保留一份
+        let mut texts: Vec<String> = Vec::new();
+        for part in parts {
+            let text = read_zip_text(&mut archive, &part)
+                .map(|xml| xml_text(&xml, &["w:t"], &["w:p"]))
+                .unwrap_or_default();
+            if !text.is_empty() && !texts.contains(&text) {
+                texts.push(text);
+            }
+        }
+        sections.push(("Headers and footers", texts.join("\n")));
+    }
+
+    if options.docx_notes {
+        for (title, part, element) in [
+            ("Footnotes", "word/footnotes.xml", "w:footnote"),
+            ("Endnotes", "word/endnotes.xml", "w:endnote"),
+        ] {
+            let xml = read_zip_text(&mut archive, part).unwrap_or_default();
+            let notes: Vec<String> = xml_texts(&xml, Some(element), None, &["w:t"], &["w:p"])
+                .into_iter()
+                .map(|(_, text)| text.replace('\n', " "))
+                .filter(|note| !note.is_empty())
+                .enumerate()
+                .map(|(i, note)| format!("[{}] {}", i + 1, note))
+                .collect();
+            sections.push((title, notes.join("\n")));
+        }
+    }
+
+    if options.docx_comments {
+        let xml = read_zip_text(&mut archive, "word/comments.xml").unwrap_or_default();
+        let comments: Vec<String> = xml_texts(&xml, Some("w:comment"), Some("w:author"), &["w:t"], &["w:p"])
+            .into_iter()
+            .filter_map(|(author, text)| {
+                let text = text.replace('\n', " ");
+                if text.is_empty() {
+                    return None;
+                }
+                Some(match author {
+                    Some(author) => format!("{}: {}", author, text),
+                    None => text,
+                })
+            })
+            .collect();
+        sections.push(("Comments", comments.join("\n")));
+    }
+
+    let mut text = String::new();
+    for (title, content) in sections {
+        if !content.is_empty() {
+            text.push_str(&format!("\n--- {} ---\n{}\n", title, content));
+        }
+    }
+    Ok(text)
+}
+
+
 /// 从 DocumentChild 中提取文本
 fn extract_text_from_document_child(child: &DocumentChild, output: &mut String) {
     match child {
@@ -243,7 +915,7 @@ fn extract_text_from_paragraph_child(child: &ParagraphChild, output: &mut String
 }
 
 
-async fn parse_pptx(path: &Path) -> Result<String> {
+async fn parse_pptx(path: &Path, include_notes: bool) -> Result<String> {
     let config = ParserConfig::builder()
         .extract_images(false)
         .include_slide_comment(false)
@@ -252,6 +924,13 @@ async fn parse_pptx(path: &Path) -> Result<String> {
     let mut pptx_container = PptxContainer::open(path, config)?;
     let slides = pptx_container.parse_all()?;
 
+    // pptx_to_md 不解析备注，直接从压缩包中读取
+    let extras = if include_notes {
+        read_pptx_notes(File::open(path)?, slides.len())?
+    } else {
+        Vec::new()
+    };
+
     let mut text_content = String::new();
 
     for (i, slide) in slides.iter().enumerate() {
@@ -265,6 +944,15 @@ async fn parse_pptx(path: &Path) -> Result<String> {
             text_content.push_str(&plain_text);
         }
 
+        if let Some(extra) = extras.get(i) {
+            if !extra.notes.is_empty() {
+                text_content.push_str(&format!("\nNotes:\n{}", extra.notes));
+            }
+            if !extra.comments.is_empty() {
+                text_content.push_str(&format!("\nComments:\n{}", extra.comments));
+            }
+        }
+
         text_content.push_str("\n\n");
     }
 
@@ -280,6 +968,288 @@ async fn parse_pptx(path: &Path) -> Result<String> {
 }
 
 
+// 一页幻灯片的演讲者备注和批注
+#[derive(Debug, Default)]
+struct SlideExtras {
+    notes: String,
+    comments: String,
+}
+
+
+// 幻灯片按 ppt/presentation.xml 中 sldIdLst 的顺序（放映顺序，与 slideN.xml 的编号无关），
+// 通过每页的 .rels 找到对应的备注页和批注文件
+fn read_pptx_notes<R: Read + std::io::Seek>(reader: R, slide_count: usize) -> Result<Vec<SlideExtras>> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut slide_parts = pptx_slide_parts(&mut archive);
+    // 没有 presentation.xml 时按编号
+    if slide_parts.is_empty() {
+        slide_parts = (1..=slide_count).map(|n| format!("ppt/slides/slide{}.xml", n)).collect();
+    }
+    slide_parts.resize_with(slide_count, String::new);
+
+    let mut slides = Vec::with_capacity(slide_count);
+    for part in slide_parts {
+        let mut extras = SlideExtras::default();
+        let (dir, file) = part.rsplit_once('/').unwrap_or(("", &part));
+        let rels = read_zip_text(&mut archive, &format!("{}/_rels/{}.rels", dir, file))
+            .unwrap_or_default();
+
+        for relationship in xml_attributes(&rels, "Relationship") {
+            let (Some(rel_type), Some(target)) = (relationship.get("Type"), relationship.get("Target")) else {
+                continue;
+            };
+            let Some(xml) = read_zip_text(&mut archive, &resolve_part(dir, target)) else {
+                continue;
+            };
+            if rel_type.ends_with("/notesSlide") {
+                extras.notes = xml_text(&xml, &["a:t"], &["a:p"]);
+            } else if rel_type.ends_with("/comments") {
+                // 旧版批注在 <p:text> 中，新版（modern comments）在 <a:t> 中
+                let comments = xml_text(&xml, &["p:text", "a:t"], &["p:cm", "a:p"]);
+                if !comments.is_empty() {
+                    extras.comments.push_str(&comments);
+                    extras.comments.push('\n');
+                }
+            }
+        }
+
+        extras.comments = extras.comments.trim_end().to_string();
+        slides.push(extras);
+    }
+
+    Ok(slides)
+}
+
+
+// presentation.xml 的 <p:sldId r:id="..."> 按顺序通过 presentation.xml.rels 解析为幻灯片的路径
+fn pptx_slide_parts<R: Read + std::io::Seek>(archive: &mut zip::ZipArchive<R>) -> Vec<String> {
+    let presentation = read_zip_text(archive, "ppt/presentation.xml").unwrap_or_default();
+    let rels = read_zip_text(archive, "ppt/_rels/presentation.xml.rels").unwrap_or_default();
+    let targets: HashMap<String, String> = xml_attributes(&rels, "Relationship")
+        .into_iter()
+        .filter_map(|mut relationship| Some((relationship.remove("Id")?, relationship.remove("Target")?)))
+        .collect();
+
+    xml_attributes(&presentation, "p:sldId")
+        .into_iter()
+        .filter_map(|slide| {
+            // 关系 id 的命名空间前缀通常是 r:
+            let id = slide.iter().find(|(name, _)| name.ends_with(":id")).map(|(_, id)| id)?;
+            Some(resolve_part("ppt", targets.get(id)?))
+        })
+        .collect()
+}
+
+
+fn read_zip_text<R: Read + std::io::Seek>(archive: &mut zip::ZipArchive<R>, name: &str) -> Option<String> {
+    let mut entry = archive.by_name(name).ok()?;
+    let mut text = String::new();
+    entry.read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+
+// 把关系中的相对路径（如 ../notesSlides/notesSlide1.xml）解析为压缩包内的路径
+fn resolve_part(base_dir: &str, target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+
+    let mut parts: Vec<&str> = base_dir.split('/').filter(|p| !p.is_empty()).collect();
+    for segment in target.split('/') {
+        match segment {
+            ".." => {
+                parts.pop();
+            }
+            "." | "" => {}
+            segment => parts.push(segment),
+        }
+    }
+    parts.join("/")
+}
+
+
+// 元素的完整名称，带命名空间前缀（如 a:t）
+fn xml_name(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).into_owned()
+}
+
+// 文本和 CDATA 的内容，以及实体引用（quick-xml 把 &amp; 等引用作为单独的事件报告）
+fn xml_event_text(event: &Event) -> Option<String> {
+    match event {
+        Event::Text(text) => Some(String::from_utf8_lossy(text).into_owned()),
+        Event::CData(data) => Some(String::from_utf8_lossy(data).into_owned()),
+        Event::GeneralRef(reference) => {
+            let name = String::from_utf8_lossy(reference);
+            let Some(code) = name.strip_prefix('#') else {
+                return quick_xml::escape::resolve_predefined_entity(&name).map(str::to_string);
+            };
+            let code = match code.strip_prefix('x') {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code).map(String::from)
+        }
+        _ => None,
+    }
+}
+
+fn xml_attr(element: &BytesStart, name: &str) -> Option<String> {
+    element.attributes()
+        .flatten()
+        .find(|attr| attr.key.as_ref() == name.as_bytes())
+        .map(|attr| attr_value(&attr))
+}
+
+fn attr_value(attr: &Attribute) -> String {
+    let raw = String::from_utf8_lossy(&attr.value);
+    quick_xml::escape::unescape(&raw).map(|value| value.into_owned()).unwrap_or_else(|_| raw.to_string())
+}
+
+
+/// 所有名为 element 的元素的属性。XML 有错误时返回出错之前的部分
+fn xml_attributes(xml: &str, element: &str) -> Vec<HashMap<String, String>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut elements = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(start)) | Ok(Event::Empty(start)) if start.name().as_ref() == element.as_bytes() => {
+                let attributes = start.attributes()
+                    .flatten()
+                    .map(|attr| (xml_name(attr.key.as_ref()), attr_value(&attr)))
+                    .collect();
+                elements.push(attributes);
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+    elements
+}
+
+
+// 收集 OOXML 中的文本：text_tags 元素内的文本，paragraph_tags 元素结束和 <a:br>/<w:br> 时换行，
+// 跳过字段（<a:fld>，如备注页上的页码）
+struct OoxmlText<'t> {
+    text_tags: &'t [&'t str],
+    paragraph_tags: &'t [&'t str],
+    out: String,
+    text_depth: usize,
+    in_field: bool,
+}
+
+impl<'t> OoxmlText<'t> {
+    fn new(text_tags: &'t [&'t str], paragraph_tags: &'t [&'t str]) -> Self {
+        Self { text_tags, paragraph_tags, out: String::new(), text_depth: 0, in_field: false }
+    }
+
+    fn start(&mut self, name: &str, empty: bool) {
+        match name {
+            "a:fld" => self.in_field = !empty,
+            "a:br" | "w:br" => self.out.push('\n'),
+            _ if !empty && self.text_tags.contains(&name) => self.text_depth += 1,
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, name: &str) {
+        if name == "a:fld" {
+            self.in_field = false;
+        }
+        if self.text_tags.contains(&name) {
+            self.text_depth = self.text_depth.saturating_sub(1);
+        }
+        if self.paragraph_tags.contains(&name) && !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.text_depth > 0 && !self.in_field {
+            self.out.push_str(text);
+        }
+    }
+
+    fn finish(self) -> String {
+        self.out.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+
+/// 每个名为 element 的元素（不含嵌套的同名元素）的 attr 属性和其中的文本（见 OoxmlText）。
+/// element 为 None 时整个文档作为一项。XML 有错误时返回出错之前的部分
+fn xml_texts(
+    xml: &str,
+    element: Option<&str>,
+    attr: Option<&str>,
+    text_tags: &[&str],
+    paragraph_tags: &[&str],
+) -> Vec<(Option<String>, String)> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut texts = Vec::new();
+    let mut current = element.is_none().then(|| (None, OoxmlText::new(text_tags, paragraph_tags)));
+
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(event) => event,
+        };
+        match &event {
+            Event::Start(start) | Event::Empty(start) => {
+                let name = xml_name(start.name().as_ref());
+                let empty = matches!(event, Event::Empty(_));
+                if current.is_none() {
+                    if element == Some(name.as_str()) {
+                        let value = attr.and_then(|attr| xml_attr(start, attr));
+                        if empty {
+                            texts.push((value, String::new()));
+                        } else {
+                            current = Some((value, OoxmlText::new(text_tags, paragraph_tags)));
+                        }
+                    }
+                } else if let Some((_, text)) = current.as_mut() {
+                    text.start(&name, empty);
+                }
+            }
+            Event::End(end) => {
+                let name = xml_name(end.name().as_ref());
+                if element == Some(name.as_str()) {
+                    if let Some((value, text)) = current.take() {
+                        texts.push((value, text.finish()));
+                    }
+                } else if let Some((_, text)) = current.as_mut() {
+                    text.end(&name);
+                }
+            }
+            event => {
+                if let (Some((_, text)), Some(content)) = (current.as_mut(), xml_event_text(event)) {
+                    text.text(&content);
+                }
+            }
+        }
+    }
+
+    if element.is_none() {
+        if let Some((value, text)) = current {
+            texts.push((value, text.finish()));
+        }
+    }
+    texts
+}
+
+/// 整个文档的文本，见 OoxmlText
+fn xml_text(xml: &str, text_tags: &[&str], paragraph_tags: &[&str]) -> String {
+    xml_texts(xml, None, None, text_tags, paragraph_tags)
+        .pop()
+        .map(|(_, text)| text)
+        .unwrap_or_default()
+}
+
+
 fn strip_markdown(md: &str) -> String {
     let mut result = String::new();
 
@@ -316,16 +1286,15 @@ fn strip_markdown(md: &str) -> String {
 }
 
 
-async fn parse_xlsx(path: &Path) -> Result<String> {
+async fn parse_xlsx(path: &Path, options: &ParseOptions) -> Result<String> {
     let mut workbook: Xlsx<_> = open_workbook(path)?;
     let mut text_content = String::new();
 
 
-    let sheet_names = workbook.sheet_names().to_owned();
+    let sheet_names = select_sheets(workbook.sheet_names().to_owned(), &options.xlsx_sheets)?;
 
     for sheet_name in sheet_names {
-
-        text_content.push_str(&format!("--- Sheet: {} ---\n", sheet_name));
+        let mut rows = Vec::new();
 
         if let Ok(range) = workbook.worksheet_range(&sheet_name) {
             for row in range.rows() {
@@ -340,12 +1309,12 @@ async fn parse_xlsx(path: &Path) -> Result<String> {
                 }
 
 
-                text_content.push_str(&row_text.join("\t"));
-                text_content.push('\n');
+                rows.push(row_text.join("\t"));
             }
         }
 
-        text_content.push('\n');
+        text_content.push_str(&format_sheet(&sheet_name, rows, options));
+        text_content.push_str("\n\n");
     }
 
 
@@ -361,6 +1330,83 @@ async fn parse_xlsx(path: &Path) -> Result<String> {
 }
 
 
+// 按 xlsx_sheets 选择工作表，指定的表都不存在时报错并列出可用的表
+fn select_sheets(available: Vec<String>, requested: &[String]) -> Result<Vec<String>> {
+    if requested.is_empty() {
+        return Ok(available);
+    }
+
+    let selected: Vec<String> = available
+        .iter()
+        .filter(|name| requested.iter().any(|r| r.eq_ignore_ascii_case(name)))
+        .cloned()
+        .collect();
+
+    if selected.is_empty() {
+        anyhow::bail!(
+            "None of the requested sheets ({}) exist, available sheets: {}",
+            requested.join(", "),
+            available.join(", ")
+        );
+    }
+    Ok(selected)
+}
+
+
+/// 一个工作表的文本，第一行视为表头。设置 xlsx_max_rows 时只保留前 N 行数据；
+/// 设置 xlsx_header 时按 chunk_size 把行分组，每组前面重复表头，检索到的块仍能对应到列名
+fn format_sheet(sheet_name: &str, rows: Vec<String>, options: &ParseOptions) -> String {
+    let mut rows = rows.into_iter();
+    let mut text = format!("--- Sheet: {} ---\n", sheet_name);
+    let Some(header) = rows.next() else {
+        return text;
+    };
+
+    let mut data: Vec<String> = rows.collect();
+    let skipped = match options.xlsx_max_rows {
+        Some(max_rows) if data.len() > max_rows => {
+            let skipped = data.len() - max_rows;
+            data.truncate(max_rows);
+            skipped
+        }
+        _ => 0,
+    };
+
+    text.push_str(&header);
+
+    if options.xlsx_header && options.chunk_size > 0 {
+        let continued = format!("--- Sheet: {} (continued) ---\n{}", sheet_name, header);
+        let mut block_len = text.chars().count();
+        let mut block_rows = 0;
+
+        for row in data {
+            let row_len = row.chars().count() + 1;
+            if block_rows > 0 && block_len + row_len > options.chunk_size {
+                text.push_str("\n\n");
+                text.push_str(&continued);
+                block_len = continued.chars().count();
+                block_rows = 0;
+            }
+            text.push('\n');
+            text.push_str(&row);
+            block_len += row_len;
+            block_rows += 1;
+        }
+    } else {
+        for row in data {
+            text.push('\n');
+            text.push_str(&row);
+        }
+    }
+
+    if skipped > 0 {
+        text.push_str(&format!("\n[{} more rows not included]", skipped));
+    }
+
+    text
+}
+
+
 fn cell_to_string(cell: &Data) -> String {
     match cell {
         Data::Empty => String::new(),
@@ -425,6 +1471,252 @@ mod tests {
         assert_eq!(FileType::from_extension("zip"), None);
     }
 
+    fn build_zip(entries: &[(&str, &str)]) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_extract_zip_keeps_supported_members() {
+        let bytes = build_zip(&[
+            ("project/src/main.rs", "fn main() {}"),
+            ("project/README.md", "# Project"),
+            ("project/logo.png", "not text"),
+            ("__MACOSX/project/._main.rs", "metadata"),
+        ]);
+
+        let members = extract_zip(&bytes, 1024, 4096).unwrap();
+        let names: Vec<&str> = members.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["project/src/main.rs", "project/README.md"]);
+        assert_eq!(members[0].1, b"fn main() {}");
+    }
+
+    #[test]
+    fn test_extract_zip_enforces_limits() {
+        let bytes = build_zip(&[("a.txt", "0123456789"), ("b.txt", "0123456789")]);
+
+        let err = extract_zip(&bytes, 5, 1024).unwrap_err();
+        assert_eq!(err.downcast_ref::<ArchiveTooLarge>().unwrap().limit, 5);
+
+        let err = extract_zip(&bytes, 1024, 15).unwrap_err();
+        assert_eq!(err.downcast_ref::<ArchiveTooLarge>().unwrap().limit, 15);
+
+        assert_eq!(extract_zip(&bytes, 1024, 20).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_extract_zip_rejects_invalid_archive() {
+        assert!(extract_zip(b"not a zip", 1024, 1024).is_err());
+    }
+
+    #[test]
+    fn test_parse_options_password_field() {
+        let mut options = ParseOptions::default();
+        options.set("password", "secret");
+        assert_eq!(options.password.as_deref(), Some("secret"));
+
+        options.set("pptx_notes", "true");
+        assert!(options.pptx_notes);
+
+        options.set("xlsx_sheets", "Summary, Q3 ,");
+        assert_eq!(options.xlsx_sheets, vec!["Summary", "Q3"]);
+        options.set("xlsx_max_rows", "100");
+        assert_eq!(options.xlsx_max_rows, Some(100));
+
+        options.set("unknown", "ignored");
+        options.set("password", "");
+        assert!(options.password.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_parse_file_redacts_pii() {
+        let text = b"Contact jane@example.com or 555-123-4567.";
+        let plain = parse_file(Path::new("notes.txt"), text, &ParseOptions::default()).await.unwrap();
+        assert!(plain.contains("jane@example.com"));
+
+        // 服务端开启时表单字段不能关闭
+        let mut options = ParseOptions { redact_pii: true, ..Default::default() };
+        options.set("redact_pii", "false");
+        let redacted = parse_file(Path::new("notes.txt"), text, &options).await.unwrap();
+        assert_eq!(redacted.trim(), "Contact [EMAIL] or [PHONE].");
+    }
+
+    #[test]
+    fn test_xml_text_skips_fields() {
+        let xml = r#"<p:txBody><a:p><a:r><a:t>Explain the &amp; chart caf&#233;</a:t></a:r></a:p>
+            <a:p><a:r><a:t>slowly</a:t></a:r><a:br/><a:r><a:t>then stop</a:t></a:r></a:p>
+            <a:p><a:fld id="x" type="slidenum"><a:t>3</a:t></a:fld></a:p></p:txBody>"#;
+        assert_eq!(xml_text(xml, &["a:t"], &["a:p"]), "Explain the & chart café\nslowly\nthen stop");
+    }
+
+    #[test]
+    fn test_resolve_part() {
+        assert_eq!(resolve_part("ppt/slides", "../notesSlides/notesSlide2.xml"), "ppt/notesSlides/notesSlide2.xml");
+        assert_eq!(resolve_part("ppt/slides", "/ppt/comments/comment1.xml"), "ppt/comments/comment1.xml");
+    }
+
+    #[test]
+    fn test_read_pptx_notes_and_comments() {
+        let bytes = build_zip(&[
+            ("ppt/slides/_rels/slide1.xml.rels", r#"<Relationships>
+                <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/slideLayout" Target="../slideLayouts/slideLayout1.xml"/>
+                <Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/notesSlide" Target="../notesSlides/notesSlide1.xml"/>
+                <Relationship Id="rId3" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/comments" Target="../comments/comment1.xml"/>
+            </Relationships>"#),
+            ("ppt/notesSlides/notesSlide1.xml", "<p:notes><a:p><a:r><a:t>Mention Q3 revenue</a:t></a:r></a:p></p:notes>"),
+            ("ppt/comments/comment1.xml", "<p:cmLst><p:cm authorId=\"0\"><p:text>Check this number</p:text></p:cm></p:cmLst>"),
+        ]);
+
+        let slides = read_pptx_notes(std::io::Cursor::new(bytes), 2).unwrap();
+        assert_eq!(slides.len(), 2);
+        assert_eq!(slides[0].notes, "Mention Q3 revenue");
+        assert_eq!(slides[0].comments, "Check this number");
+        // slides without notes are empty, not an error
+        assert!(slides[1].notes.is_empty());
+    }
+
+    #[test]
+    fn test_xml_texts_skips_prefixed_names() {
+        let xml = r#"<w:footnotes><w:footnotePr/><w:footnote w:type="separator" w:id="-1"><w:p/></w:footnote>
+            <w:footnote w:id="1"><w:p><w:r><w:t>See Smith v. Jones</w:t></w:r></w:p></w:footnote></w:footnotes>"#;
+        let elements = xml_texts(xml, Some("w:footnote"), Some("w:id"), &["w:t"], &["w:p"]);
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements[1], (Some("1".to_string()), "See Smith v. Jones".to_string()));
+    }
+
+    #[test]
+    fn test_pptx_notes_follow_presentation_order() {
+        let notes_rels = |n: usize| format!(r#"<Relationships>
+            <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/notesSlide" Target="../notesSlides/notesSlide{}.xml"/>
+        </Relationships>"#, n);
+        let (rels1, rels2) = (notes_rels(1), notes_rels(2));
+        let bytes = build_zip(&[
+            // slide2.xml is shown first
+            ("ppt/presentation.xml", r#"<p:presentation xmlns:r="r"><p:sldIdLst>
+                <p:sldId id="257" r:id="rId3"/><p:sldId id="256" r:id="rId2"/>
+            </p:sldIdLst></p:presentation>"#),
+            ("ppt/_rels/presentation.xml.rels", r#"<Relationships>
+                <Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/slide" Target="slides/slide1.xml"/>
+                <Relationship Id="rId3" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/slide" Target="slides/slide2.xml"/>
+            </Relationships>"#),
+            ("ppt/slides/_rels/slide1.xml.rels", rels1.as_str()),
+            ("ppt/slides/_rels/slide2.xml.rels", rels2.as_str()),
+            ("ppt/notesSlides/notesSlide1.xml", "<p:notes><a:p><a:r><a:t>First file</a:t></a:r></a:p></p:notes>"),
+            ("ppt/notesSlides/notesSlide2.xml", "<p:notes><a:p><a:r><a:t>Shown first</a:t></a:r></a:p></p:notes>"),
+        ]);
+
+        let slides = read_pptx_notes(std::io::Cursor::new(bytes), 2).unwrap();
+        assert_eq!(slides[0].notes, "Shown first");
+        assert_eq!(slides[1].notes, "First file");
+    }
+
+    #[test]
+    fn test_read_docx_extras() {
+        let bytes = build_zip(&[
+            ("word/header1.xml", "<w:hdr><w:p><w:r><w:t>CONFIDENTIAL</w:t></w:r></w:p></w:hdr>"),
+            ("word/header2.xml", "<w:hdr><w:p><w:r><w:t>CONFIDENTIAL</w:t></w:r></w:p></w:hdr>"),
+            ("word/footnotes.xml", r#"<w:footnotes><w:footnote w:type="separator" w:id="-1"><w:p/></w:footnote>
+                <w:footnote w:id="1"><w:p><w:r><w:t>Case law reference</w:t></w:r></w:p></w:footnote></w:footnotes>"#),
+            ("word/comments.xml", r#"<w:comments><w:comment w:id="0" w:author="Ann"><w:p><w:r><w:t>Rephrase this clause</w:t></w:r></w:p></w:comment></w:comments>"#),
+        ]);
+
+        let all = ParseOptions { docx_notes: true, docx_headers: true, docx_comments: true, ..Default::default() };
+        let text = read_docx_extras(std::io::Cursor::new(&bytes), &all).unwrap();
+        assert_eq!(
+            text,
+            "\n--- Headers and footers ---\nCONFIDENTIAL\n\n--- Footnotes ---\n[1] Case law reference\n\n--- Comments ---\nAnn: Rephrase this clause\n",
+        );
+
+        let comments_only = ParseOptions { docx_comments: true, ..Default::default() };
+        let text = read_docx_extras(std::io::Cursor::new(&bytes), &comments_only).unwrap();
+        assert!(!text.contains("CONFIDENTIAL"));
+        assert!(text.contains("Ann: Rephrase this clause"));
+    }
+
+    fn sheet_rows(count: usize) -> Vec<String> {
+        let mut rows = vec!["name\tscore".to_string()];
+        rows.extend((1..=count).map(|i| format!("row{}\t{}", i, i * 10)));
+        rows
+    }
+
+    #[test]
+    fn test_format_sheet_limits_rows() {
+        let options = ParseOptions { xlsx_max_rows: Some(2), ..Default::default() };
+        let text = format_sheet("Scores", sheet_rows(5), &options);
+        assert_eq!(text, "--- Sheet: Scores ---\nname\tscore\nrow1\t10\nrow2\t20\n[3 more rows not included]");
+    }
+
+    #[test]
+    fn test_format_sheet_repeats_header_per_block() {
+        let options = ParseOptions { xlsx_header: true, chunk_size: 60, ..Default::default() };
+        let text = format_sheet("Scores", sheet_rows(6), &options);
+
+        let blocks: Vec<&str> = text.split("\n\n").collect();
+        assert!(blocks.len() > 1);
+        for block in &blocks {
+            assert!(block.chars().count() <= 60);
+            assert_eq!(block.lines().nth(1), Some("name\tscore"));
+        }
+        assert!(blocks[1].starts_with("--- Sheet: Scores (continued) ---"));
+        assert_eq!(text.matches("row").count(), 6);
+    }
+
+    #[test]
+    fn test_select_sheets() {
+        let available = vec!["Summary".to_string(), "Data".to_string()];
+        assert_eq!(select_sheets(available.clone(), &[]).unwrap(), available);
+        assert_eq!(select_sheets(available.clone(), &["data".to_string()]).unwrap(), vec!["Data"]);
+
+        let err = select_sheets(available, &["Missing".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Summary, Data"));
+    }
+
+    #[test]
+    fn test_detect_file_type_by_content() {
+        assert_eq!(detect_file_type("txt", b"plain notes").unwrap(), FileType::TXT);
+        assert_eq!(detect_file_type("rs", b"fn main() {}").unwrap(), FileType::CODE);
+        assert_eq!(detect_file_type("html", b"<!DOCTYPE html><html></html>").unwrap(), FileType::CODE);
+        // a PDF renamed to .txt is still parsed as a PDF
+        assert_eq!(detect_file_type("txt", b"%PDF-1.7\n%binary").unwrap(), FileType::PDF);
+        // a text file with a document extension is read as text
+        assert_eq!(detect_file_type("pdf", b"not really a pdf").unwrap(), FileType::TXT);
+    }
+
+    #[test]
+    fn test_detect_file_type_rejects_binary() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let err = detect_file_type("txt", png).unwrap_err();
+        let binary = err.downcast_ref::<BinaryContent>().unwrap();
+        assert_eq!(binary.detected.as_deref(), Some("image/png"));
+
+        let err = detect_file_type("log", b"abc\0\x01\x02").unwrap_err();
+        assert!(err.downcast_ref::<BinaryContent>().unwrap().detected.is_none());
+    }
+
+    #[test]
+    fn test_validate_upload() {
+        let options = ParseOptions::default();
+        assert_eq!(validate_upload(Path::new("notes.md"), b"# Notes", &options).unwrap(), FileType::MD);
+        assert!(validate_upload(Path::new("notes.txt"), b"\0\0\0", &options).is_err());
+        // an unreadable PDF is rejected before it is queued for parsing
+        assert!(validate_upload(Path::new("report.pdf"), b"%PDF-1.7 truncated", &options).is_err());
+    }
+
+    #[test]
+    fn test_image_extension_detection() {
+        assert!(is_image_extension("png"));
+        assert!(is_image_extension("JPG"));
+        assert!(is_image_extension("webp"));
+        assert!(!is_image_extension("pdf"));
+        assert!(!is_image_extension(""));
+    }
+
     #[test]
     fn test_cell_to_string() {
         assert_eq!(cell_to_string(&Data::Empty), "");
@@ -501,6 +1793,152 @@ Second
         assert_eq!(strip_markdown("\n\n\n"), "");
     }
 
+    fn cache_file(content: &str) -> CacheFile {
+        CacheFile {
+            filename: "doc.txt".to_string(),
+            content: content.to_string(),
+            extension: "txt".to_string(),
+            session_id: "session-1".to_string(),
+            file_size: content.len(),
+            uploaded_at: 0,
+            chunks: vec![],
+            status: FileStatus::Ready,
+            error: None,
+            tenant: None,
+            indexed: false,
+        }
+    }
+
+    #[test]
+    fn test_chunk_text_packs_paragraphs() {
+        let chunks = chunk_text("aaa\n\nbbb\n\nccc", 8);
+        assert_eq!(chunks, vec!["aaa\n\nbbb", "ccc"]);
+    }
+
+    #[test]
+    fn test_chunk_text_splits_long_paragraph() {
+        let chunks = chunk_text(&"x".repeat(25), 10);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2], "xxxxx");
+    }
+
+    #[test]
+    fn test_chunk_text_empty() {
+        assert!(chunk_text("", 100).is_empty());
+        assert!(chunk_text("\n\n  \n\n", 100).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_text_keeps_sentences_whole() {
+        let chunks = chunk_text("First sentence here. Second one! Third? Fourth.", 25);
+        assert_eq!(chunks, vec!["First sentence here.", "Second one! Third?", "Fourth."]);
+    }
+
+    #[test]
+    fn test_chunk_text_overlap_repeats_trailing_sentences() {
+        let chunks = chunk_text_with_overlap("One two. Three four. Five six. Seven.", 24, 12);
+        assert_eq!(chunks, vec!["One two. Three four.", "Three four. Five six.", "Five six. Seven."]);
+        // without overlap no sentence appears twice
+        assert_eq!(
+            chunk_text_with_overlap("One two. Three four. Five six. Seven.", 24, 0),
+            vec!["One two. Three four.", "Five six. Seven."],
+        );
+    }
+
+    #[test]
+    fn test_chunk_text_overlap_on_hard_split() {
+        let chunks = chunk_text_with_overlap(&"abcdefghij".repeat(2), 10, 4);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 10));
+        assert_eq!(chunks[0], "abcdefghij");
+        assert_eq!(chunks[1], "ghijabcdef");
+        assert!(chunks.last().unwrap().ends_with("ghij"));
+    }
+
+    #[test]
+    fn test_chunk_text_splits_code_by_line() {
+        let code = "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}";
+        let chunks = chunk_text(code, 30);
+        assert_eq!(chunks[0], "fn main() {\n    let x = 1;");
+        assert!(chunks.iter().all(|c| c.chars().count() <= 30));
+    }
+
+    #[test]
+    fn test_build_chunks_uses_stored_chunks() {
+        let mut file = cache_file("one\n\ntwo\n\nthree");
+        file.chunks = vec!["stored".to_string()];
+        let chunks = build_chunks("file-1", &file, 5);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, "stored");
+    }
+
+    fn excerpt(file_id: &str, uploaded_at: u64, text: &str) -> FileExcerpt {
+        FileExcerpt { file_id: file_id.to_string(), uploaded_at, text: text.to_string() }
+    }
+
+    #[test]
+    fn test_truncate_excerpts_prefers_recent_file() {
+        let mut excerpts = vec![
+            excerpt("old", 100, &"a".repeat(50)),
+            excerpt("new", 200, &"b".repeat(50)),
+        ];
+        truncate_excerpts(&mut excerpts, 70);
+
+        assert_eq!(excerpts[1].text, "b".repeat(50));
+        assert_eq!(excerpts[0].text, format!("{}\n[truncated 30 chars]", "a".repeat(20)));
+    }
+
+    #[test]
+    fn test_truncate_excerpts_within_budget_is_untouched() {
+        let mut excerpts = vec![excerpt("a", 1, "short"), excerpt("b", 2, "text")];
+        truncate_excerpts(&mut excerpts, 9);
+        assert_eq!(excerpts[0].text, "short");
+        assert_eq!(excerpts[1].text, "text");
+
+        truncate_excerpts(&mut excerpts, 0);
+        assert_eq!(excerpts[0].text, "[truncated 5 chars]");
+        assert_eq!(excerpts[1].text, "[truncated 4 chars]");
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+    }
+
+    #[test]
+    fn test_embed_text_is_normalized() {
+        let embedding = embed_text("The quick brown fox jumps over the lazy dog");
+        assert_eq!(embedding.len(), EMBEDDING_DIM);
+        let norm: f32 = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+
+        assert!(embed_text("").iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn test_retrieve_top_k_prefers_relevant_chunk() {
+        let file = cache_file(
+            "Rust ownership and borrowing rules prevent data races.\n\n\
+             The recipe needs flour, sugar and eggs for the cake.\n\n\
+             Tokio is an async runtime for Rust.",
+        );
+        let chunks = build_chunks("file-1", &file, 60);
+        assert_eq!(chunks.len(), 3);
+
+        let top = retrieve_top_k(&chunks, "how much sugar goes in the cake?", 1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].chunk_index, 1);
+        assert_eq!(top[0].file_id, "file-1");
+    }
+
+    #[test]
+    fn test_retrieve_top_k_limits_results() {
+        let chunks = build_chunks("file-1", &cache_file("one\n\ntwo\n\nthree"), 5);
+        assert_eq!(retrieve_top_k(&chunks, "two", 2).len(), 2);
+        assert_eq!(retrieve_top_k(&chunks, "two", 10).len(), 3);
+    }
+
     #[test]
     fn test_strip_markdown_plain_text() {
         assert_eq!(strip_markdown("Hello World"), "Hello World\n");
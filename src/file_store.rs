@@ -0,0 +1,497 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use crate::file_parser::{new_file_cache, CacheFile, FileCache, FileStatus, VectorIndex};
+
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+
+/// file_id 是上传时生成的 UUID。来自 URL 或请求体的 file_id 在访问存储之前检查，
+/// 否则 ../ 之类的路径会读到或删除 file_dir 之外的文件
+pub fn valid_file_id(file_id: &str) -> bool {
+    file_id.len() == 36 && uuid::Uuid::parse_str(file_id).is_ok()
+}
+
+fn check_file_id(file_id: &str) -> Result<()> {
+    if !valid_file_id(file_id) {
+        anyhow::bail!("Invalid file id {:?}", file_id);
+    }
+    Ok(())
+}
+
+
+// 解析后的文件以 {file_dir}/{file_id}.json 保存，服务重启后仍可通过 file_id 引用
+fn file_path(file_dir: &str, file_id: &str) -> PathBuf {
+    Path::new(file_dir).join(format!("{}.json", file_id))
+}
+
+
+pub async fn save_file(file_dir: &str, file_id: &str, file: &CacheFile) -> Result<()> {
+    fs::create_dir_all(file_dir).await?;
+
+    // write to a temp file first so a crash never leaves a truncated entry
+    let path = file_path(file_dir, file_id);
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec(file)?).await?;
+    fs::rename(&tmp_path, &path).await?;
+
+    Ok(())
+}
+
+
+// 图片不解析成文本，原始字节保存在 {file_dir}/{file_id}.bin
+fn image_path(file_dir: &str, file_id: &str) -> PathBuf {
+    Path::new(file_dir).join(format!("{}.bin", file_id))
+}
+
+
+pub async fn save_image(file_dir: &str, file_id: &str, bytes: &[u8]) -> Result<()> {
+    fs::create_dir_all(file_dir).await?;
+    fs::write(image_path(file_dir, file_id), bytes).await?;
+    Ok(())
+}
+
+
+pub async fn load_image(file_dir: &str, file_id: &str) -> Result<Vec<u8>> {
+    Ok(fs::read(image_path(file_dir, file_id)).await?)
+}
+
+
+async fn remove_if_exists(path: PathBuf) -> Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+
+pub async fn delete_file(file_dir: &str, file_id: &str) -> Result<()> {
+    remove_if_exists(image_path(file_dir, file_id)).await?;
+    remove_if_exists(file_path(file_dir, file_id)).await
+}
+
+
+/// 读取目录中保存的全部文件，目录不存在时返回空表
+pub async fn load_files(file_dir: &str) -> Result<HashMap<String, CacheFile>> {
+    let mut files = HashMap::new();
+
+    let mut entries = match fs::read_dir(file_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let file_id = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(file_id) => file_id.to_string(),
+            None => continue,
+        };
+
+        match serde_json::from_slice::<CacheFile>(&fs::read(&path).await?) {
+            Ok(file) => {
+                files.insert(file_id, file);
+            }
+            Err(e) => tracing::warn!(path = %path.display(), error = %e, "Skipping unreadable stored file"),
+        }
+    }
+
+    Ok(files)
+}
+
+
+/// 上传文件的持久化存储。默认保存在本地目录（[`DiskFileStore`]），
+/// 开启 redis feature 后可以保存到 Redis，让多个实例共享上传的文件
+#[async_trait]
+pub trait FileStore: Send + Sync {
+    async fn save(&self, file_id: &str, file: &CacheFile) -> Result<()>;
+
+    async fn load(&self, file_id: &str) -> Result<Option<CacheFile>>;
+
+    async fn load_all(&self) -> Result<HashMap<String, CacheFile>>;
+
+    /// 删除文件和图片字节，不存在时不报错
+    async fn delete(&self, file_id: &str) -> Result<()>;
+
+    async fn save_image(&self, file_id: &str, bytes: &[u8]) -> Result<()>;
+
+    async fn load_image(&self, file_id: &str) -> Result<Vec<u8>>;
+
+    fn describe(&self) -> String;
+
+    /// 是否由多个实例共用（Redis），共用时文件以存储中的为准
+    fn shared(&self) -> bool {
+        false
+    }
+}
+
+pub type SharedFileStore = Arc<dyn FileStore>;
+
+
+pub struct DiskFileStore {
+    file_dir: String,
+}
+
+impl DiskFileStore {
+    pub fn new(file_dir: &str) -> Self {
+        Self { file_dir: file_dir.to_string() }
+    }
+}
+
+#[async_trait]
+impl FileStore for DiskFileStore {
+    async fn save(&self, file_id: &str, file: &CacheFile) -> Result<()> {
+        check_file_id(file_id)?;
+        save_file(&self.file_dir, file_id, file).await
+    }
+
+    async fn load(&self, file_id: &str) -> Result<Option<CacheFile>> {
+        check_file_id(file_id)?;
+        match fs::read(file_path(&self.file_dir, file_id)).await {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn load_all(&self) -> Result<HashMap<String, CacheFile>> {
+        load_files(&self.file_dir).await
+    }
+
+    async fn delete(&self, file_id: &str) -> Result<()> {
+        check_file_id(file_id)?;
+        delete_file(&self.file_dir, file_id).await
+    }
+
+    async fn save_image(&self, file_id: &str, bytes: &[u8]) -> Result<()> {
+        check_file_id(file_id)?;
+        save_image(&self.file_dir, file_id, bytes).await
+    }
+
+    async fn load_image(&self, file_id: &str) -> Result<Vec<u8>> {
+        check_file_id(file_id)?;
+        load_image(&self.file_dir, file_id).await
+    }
+
+    fn describe(&self) -> String {
+        self.file_dir.clone()
+    }
+}
+
+
+pub async fn load_file_cache(store: &dyn FileStore) -> Result<FileCache> {
+    let mut files = store.load_all().await?;
+    tracing::info!(files = files.len(), store = %store.describe(), "Loaded stored files");
+    fail_interrupted(&mut files);
+
+    let cache = new_file_cache();
+    *cache.write().await = files;
+    Ok(cache)
+}
+
+
+// 上次运行时还在解析的文件：上传的原始内容已不在，不会再解析完成
+fn fail_interrupted(files: &mut HashMap<String, CacheFile>) {
+    for (file_id, file) in files.iter_mut().filter(|(_, file)| file.status == FileStatus::Processing) {
+        tracing::warn!(file_id = %file_id, filename = %file.filename, "File was still being parsed when the server stopped");
+        file.status = FileStatus::Failed;
+        file.error = Some("Server restarted before the file was parsed, upload it again".to_string());
+    }
+}
+
+
+/// session 已上传的文件数和总大小（字节）
+pub fn session_usage(files: &HashMap<String, CacheFile>, session_id: &str) -> (usize, usize) {
+    files
+        .values()
+        .filter(|file| file.session_id == session_id)
+        .fold((0, 0), |(count, bytes), file| (count + 1, bytes + file.file_size))
+}
+
+
+/// 删除上传时间超过 ttl_secs 的文件：从缓存、各 session 的检索索引和磁盘中删除，
+/// 返回被删除的 file_id
+pub async fn expire_files(
+    cache: &FileCache,
+    index: &VectorIndex,
+    store: &dyn FileStore,
+    ttl_secs: u64,
+    now: u64,
+) -> Vec<String> {
+    let expired: Vec<String> = {
+        let mut files = cache.write().await;
+        let expired: Vec<String> = files
+            .iter()
+            .filter(|(_, file)| file.uploaded_at.saturating_add(ttl_secs) <= now)
+            .map(|(file_id, _)| file_id.clone())
+            .collect();
+        for file_id in &expired {
+            files.remove(file_id);
+        }
+        expired
+    };
+
+    if expired.is_empty() {
+        return expired;
+    }
+
+    for chunks in index.write().await.values_mut() {
+        chunks.retain(|chunk| !expired.contains(&chunk.file_id));
+    }
+    for file_id in &expired {
+        if let Err(e) = store.delete(file_id).await {
+            tracing::warn!(file_id = %file_id, error = %e, "Failed to delete expired file");
+        }
+    }
+
+    expired
+}
+
+
+/// 后台定期清理过期文件，防止长时间运行的服务文件缓存无限增长。ttl_secs 为 0 时不清理
+pub fn spawn_file_sweeper(
+    cache: FileCache,
+    index: VectorIndex,
+    store: SharedFileStore,
+    ttl_secs: u64,
+    interval_secs: u64,
+) {
+    if ttl_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let expired = expire_files(&cache, &index, store.as_ref(), ttl_secs, unix_now()).await;
+            if !expired.is_empty() {
+                tracing::info!(files = expired.len(), ttl_secs, "Expired old files");
+            }
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("llm_file_store_{}_{}", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn cache_file(content: &str) -> CacheFile {
+        CacheFile {
+            filename: "notes.md".to_string(),
+            content: content.to_string(),
+            extension: "md".to_string(),
+            session_id: "session-1".to_string(),
+            file_size: content.len(),
+            uploaded_at: 1700000000,
+            chunks: vec![content.to_string()],
+            status: FileStatus::Ready,
+            error: None,
+            tenant: None,
+            indexed: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_roundtrip() {
+        let dir = test_dir("roundtrip");
+        save_file(&dir, "file-1", &cache_file("hello")).await.unwrap();
+        save_file(&dir, "file-2", &cache_file("world")).await.unwrap();
+
+        let files = load_files(&dir).await.unwrap();
+        assert_eq!(files.len(), 2);
+
+        let file = &files["file-1"];
+        assert_eq!(file.content, "hello");
+        assert_eq!(file.filename, "notes.md");
+        assert_eq!(file.uploaded_at, 1700000000);
+        assert_eq!(file.chunks, vec!["hello".to_string()]);
+        // the vector index is in memory, so stored files are re-indexed after a restart
+        assert!(!file.indexed);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_file_ids_outside_file_dir_are_rejected() {
+        let dir = test_dir("traversal");
+        let inner = format!("{}/files", dir);
+        save_file(&dir, "secret", &cache_file("outside")).await.unwrap();
+
+        let store = DiskFileStore::new(&inner);
+        for file_id in ["../secret", "..%2Fsecret", "", "file-1"] {
+            assert!(!valid_file_id(file_id));
+            assert!(store.load(file_id).await.is_err());
+            assert!(store.delete(file_id).await.is_err());
+        }
+        assert!(fs::try_exists(file_path(&dir, "secret")).await.unwrap());
+
+        let file_id = uuid::Uuid::new_v4().to_string();
+        assert!(valid_file_id(&file_id));
+        assert!(store.load(&file_id).await.unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_delete_file() {
+        let dir = test_dir("delete");
+        save_file(&dir, "file-1", &cache_file("hello")).await.unwrap();
+
+        delete_file(&dir, "file-1").await.unwrap();
+        // deleting twice is not an error
+        delete_file(&dir, "file-1").await.unwrap();
+
+        assert!(load_files(&dir).await.unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_image_bytes_roundtrip() {
+        let dir = test_dir("image");
+        save_image(&dir, "img-1", b"\x89PNG data").await.unwrap();
+        save_file(&dir, "img-1", &cache_file("")).await.unwrap();
+
+        assert_eq!(load_image(&dir, "img-1").await.unwrap(), b"\x89PNG data");
+        // image blobs are not mistaken for stored files
+        assert_eq!(load_files(&dir).await.unwrap().len(), 1);
+
+        delete_file(&dir, "img-1").await.unwrap();
+        assert!(load_image(&dir, "img-1").await.is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_entry_without_status_is_ready() {
+        // files stored before background parsing existed have no status field
+        let dir = test_dir("legacy");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            Path::new(&dir).join("old.json"),
+            br#"{"filename":"a.txt","content":"hi","extension":"txt","session_id":"s","file_size":2,"uploaded_at":1}"#,
+        ).unwrap();
+
+        let files = load_files(&dir).await.unwrap();
+        assert_eq!(files["old"].status, FileStatus::Ready);
+        assert!(files["old"].error.is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_interrupted_parses_are_failed() {
+        let processing = CacheFile { status: FileStatus::Processing, ..cache_file("") };
+        let mut files = HashMap::from([
+            ("parsing".to_string(), processing),
+            ("done".to_string(), cache_file("hello")),
+        ]);
+        fail_interrupted(&mut files);
+        assert_eq!(files["parsing"].status, FileStatus::Failed);
+        assert!(files["parsing"].error.is_some());
+        assert_eq!(files["done"].status, FileStatus::Ready);
+    }
+
+    #[test]
+    fn test_session_usage() {
+        let mut files = HashMap::new();
+        files.insert("a".to_string(), cache_file("12345"));
+        files.insert("b".to_string(), cache_file("123"));
+        let mut other = cache_file("1234567");
+        other.session_id = "session-2".to_string();
+        files.insert("c".to_string(), other);
+
+        assert_eq!(session_usage(&files, "session-1"), (2, 8));
+        assert_eq!(session_usage(&files, "missing"), (0, 0));
+    }
+
+    const OLD: &str = "00000000-0000-4000-8000-000000000001";
+    const FRESH: &str = "00000000-0000-4000-8000-000000000002";
+
+    #[tokio::test]
+    async fn test_expire_files_removes_old_entries() {
+        use crate::file_parser::{build_chunks, new_vector_index};
+
+        let dir = test_dir("expire");
+        let cache = new_file_cache();
+        let index = new_vector_index();
+
+        let mut old = cache_file("old text");
+        old.uploaded_at = 1000;
+        let mut fresh = cache_file("fresh text");
+        fresh.uploaded_at = 5000;
+
+        for (file_id, file) in [(OLD, &old), (FRESH, &fresh)] {
+            save_file(&dir, file_id, file).await.unwrap();
+            cache.write().await.insert(file_id.to_string(), file.clone());
+            index.write().await.entry("session-1".to_string()).or_default()
+                .extend(build_chunks(file_id, file, 100));
+        }
+
+        let expired = expire_files(&cache, &index, &DiskFileStore::new(&dir), 3600, 5000).await;
+        assert_eq!(expired, vec![OLD.to_string()]);
+
+        assert!(cache.read().await.contains_key(FRESH));
+        assert!(!cache.read().await.contains_key(OLD));
+        assert!(index.read().await["session-1"].iter().all(|chunk| chunk.file_id == FRESH));
+        assert_eq!(load_files(&dir).await.unwrap().len(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_disk_store_load_single_file() {
+        let dir = test_dir("disk_store");
+        let store = DiskFileStore::new(&dir);
+        store.save(OLD, &cache_file("hello")).await.unwrap();
+
+        assert_eq!(store.load(OLD).await.unwrap().unwrap().content, "hello");
+        assert!(store.load(FRESH).await.unwrap().is_none());
+
+        store.delete(OLD).await.unwrap();
+        assert!(store.load(OLD).await.unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_missing_dir_is_empty() {
+        let files = load_files(&test_dir("missing")).await.unwrap();
+        assert!(files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_entry_is_skipped() {
+        let dir = test_dir("corrupt");
+        save_file(&dir, "good", &cache_file("ok")).await.unwrap();
+        std::fs::write(Path::new(&dir).join("bad.json"), b"{not json").unwrap();
+
+        let files = load_files(&dir).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files.contains_key("good"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,423 +1,4761 @@
 use axum::{
-    extract::{State, Multipart},
+    extract::{State, Multipart, Query},
+    Extension,
     Json,
     Router,
-    routing::{get, post},
-    response::{sse::Event, Sse},
+    routing::{get, post, put},
+    response::{sse::Event, IntoResponse, Response, Sse},
 };
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
+use utoipa::ToSchema;
 use tokio_stream::{StreamExt};
-use std::{time::Duration};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_util::sync::CancellationToken;
+use tower_http::request_id::RequestId;
+use tracing::Instrument;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::fmt::Write;
 use std::path::Path;
+use std::sync::Arc;
 use axum::routing::delete;
 use reqwest::StatusCode;
+use axum::http::header::{self, RETRY_AFTER};
 use crate::AppState;
-use crate::error::{RemoveFileError, RemoveSessionError, UnsupportedFileError};
-use crate::file_parser::{parse_file, CacheFile};
+use crate::openapi::UploadForm;
+use crate::request_id::request_id_string;
+use crate::validation::{check_sampling, invalid, prompt_too_long, validate_prompt, validate_sampling};
+use crate::error::{
+    CancelRequestError, PullModelError, QueueFullError, RemoveFileError, RemoveSessionError,
+    UnsupportedFileError, UploadError, UploadTooLargeError, UploadNotFoundError, FileNotFoundError,
+    ImageError, InvalidPasswordError, SessionQuotaError, MessageError, SessionNotFoundError,
+    MemoryError, OllamaError, ToolError, AgentError, GenerationTimeoutError, ModelError,
+    PromptTooLongError, ValidationError, ServiceLoadingError, ExampleSetError, FileNotReadyError,
+    SummarizeError, ExtractError, ModerationError, AuditError, QuotaExceededError,
+    UsageError, BenchmarkError, EvalError,
+};
+use crate::file_parser::{
+    build_chunks, chunk_text_with_overlap, retrieval_chunks, estimate_tokens, extract_zip, is_image_extension,
+    retrieve_top_k, truncate_excerpts, ArchiveTooLarge, CacheFile, FileExcerpt,
+    BinaryContent, FileStatus, InvalidPassword, ParseOptions, validate_upload,
+};
+use crate::transcribe::is_audio_extension;
+use crate::parse_cache::parse_file_cached;
+use crate::upload::{spool_dir, valid_upload_id, SpoolFile, UploadGuard};
+use crate::response_cache;
+use crate::file_store::{session_usage, unix_now, valid_file_id, FileStore};
 use crate::types::{
     DeleteResponse, InferenceRequest, InferenceResponse, RemoveSessionResponse, UploadResponse,
-    GetSessionResponse, SyncSessionRequest, SyncSessionResponse
+    GetSessionResponse, SyncSessionRequest, SyncSessionResponse, UploadQuery, UploadProgress, ListModelsResponse,
+    ContentFormat, FileContentQuery, FileContentResponse, FileChunk,
+    SessionConfigResponse, CancelResponse, Usage, StreamEvent, PullModelRequest, PullEvent,
+    FileInfo, ListFilesQuery, ListFilesResponse, TranscribeResponse, GenerationConfig,
+    FileStatusQuery, FileStatusResponse, ListSessionsQuery, ListSessionsResponse, SessionSummary,
+    EditMessageRequest, ForkSessionQuery, ForkSessionResponse, PinMessageRequest, PinMessageResponse,
+    UpdateMemoryRequest, MemoryResponse, OpenAIModel, OpenAIModelList,
+    OllamaGenerateRequest, OllamaChatRequest, OllamaMessage, OllamaResponse, OllamaStats,
+    OllamaModel, OllamaModelDetails, OllamaTagsResponse, ToolInvocation, AgentRunRequest, AgentEvent,
+    Timings, ModelLoadResponse, ModelFilesResponse, TokenizeRequest, TokenizeResponse,
+    DetokenizeRequest, DetokenizeResponse, AdapterRequest, AdapterResponse, ChatMessage, MessageRole,
+    ModelsHealth, GpuHealth, DiskHealth, QueueHealth, CacheHealth, ReadinessResponse,
+    UpdateExampleSetRequest, ExampleSetResponse, ExampleSetInfo, ListExampleSetsResponse, RemoveExampleSetResponse,
+    SummarizeRequest, SummarizeResponse, SummarizeEvent, ExtractRequest, ExtractResponse, ExtractFieldError,
+    AuditQuery, AuditLogResponse, UsageQuery, UsageResponse, BatchRequest, BatchItem, AcceptedResponse,
+    BenchmarkRequest, BenchmarkQuery, BenchmarkHistoryResponse,
+    EvalSuiteResponse, EvalSuiteInfo, ListEvalSuitesResponse, RemoveEvalSuiteResponse, EvalRunRequest,
+    EvalCaseResult, EvalReport,
+};
+use crate::engine::{get_or_load_engine, run_inference_collect, run_inference_stream, InferenceEngine, StreamChunk};
+use crate::mistral_runner::{delete_model_files, list_models, pull_model, register_local_model};
+use crate::session::{clean_title, drop_session_index, SessionMessageError, SessionConfig, SessionHelper};
+use crate::queue::QueueTicket;
+use crate::registry::{Device, DEFAULT_CONTEXT_LENGTH};
+use crate::metrics::GenerationTimer;
+use crate::health::{free_disk_mb, gpu_memory};
+use crate::service_state::ServiceState;
+use crate::sse::{self, flush_timer, TokenCoalescer};
+use crate::auth::{is_admin, Caller};
+use crate::memory::{extraction_prompt, memory_prompt, parse_facts};
+use crate::few_shot::{examples_within, insert_examples, Example};
+use crate::summarize::{
+    combine_prompt, document_prompt, fits_in_one_pass, group_summaries, section_chars, section_prompt,
+    split_sections, DEFAULT_SUMMARY_WORDS, MAX_SUMMARY_WORDS, SECTION_SUMMARY_MAX_TOKENS,
+};
+use crate::pii::redact_pii;
+use crate::audit::{last_user_prompt, AuditRecord};
+use crate::usage::ANONYMOUS;
+use crate::webhook::WebhookPayload;
+use crate::events::{Event, FILE_INGESTED, GENERATION_COMPLETED, SESSION_CREATED};
+use crate::evals::{check_case, CaseOutcome, EvalCase};
+use crate::benchmark::{
+    run_benchmark, BenchmarkOptions, BenchmarkRun, DEFAULT_MAX_TOKENS as DEFAULT_BENCHMARK_TOKENS,
+    MAX_RUNS as MAX_BENCHMARK_RUNS,
+};
+use crate::moderation::{
+    classifier_flags, classifier_prompt, keyword_matches, ModerationMode, ModerationRecord, ModerationTarget,
+    CLASSIFIER_MAX_TOKENS,
+};
+use crate::extract::{field_prompt, field_query, parse_schema, parse_value, FieldSpec, FIELD_MAX_TOKENS};
+use crate::agent::{agent_conversation, next_step, AgentStep, DEFAULT_AGENT_ITERATIONS, MAX_AGENT_ITERATIONS};
+use crate::tools::{
+    parse_tool_call, run_tool, select_tools, tool_result_message, tool_timeout, with_tool_prompt,
+    Tool, ToolCall, ToolCallFilter,
 };
-use crate::mistral_runner::{run_inference_collect, run_inference_stream};
-use crate::session::{ChatMessage, SessionConfig, SessionHelper};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, ToSchema)]
 pub struct HealthResponse {
     pub is_healthy: bool,
     pub status: String,
+    // 启动时没有找到可用的 GPU，模型在 CPU 上运行（速度慢很多）
+    pub cpu_fallback: bool,
+    // 默认模型已加载（且没有在重新加载），与 /health/ready 相同
+    pub ready: bool,
+    // ready / loading（加载的模型、阶段和已用时间）/ error（最近一次加载失败的原因）
+    pub service: ServiceState,
+    pub models: ModelsHealth,
+    pub gpu: GpuHealth,
+    pub disk: DiskHealth,
+    pub queue: QueueHealth,
+    pub caches: CacheHealth,
+}
+
+
+// 默认模型（经过别名解析）已加载，且没有在重新加载。其他模型的加载不影响就绪。缓存正被写锁住时不等待
+async fn service_ready(state: &AppState) -> (String, bool) {
+    let model = resolve_model(state, "").await;
+    let loaded = state.model_cache.try_read().is_ok_and(|engines| engines.contains_key(&model));
+    (model, loaded && !state.service_status.is_loading(&model))
 }
 
+/// 服务的状态：模型、GPU 显存、磁盘空间、推理队列和缓存。服务在运行就返回 200，
+/// 是否可以处理请求见 ready 或 /health/ready
+#[utoipa::path(get, path = "/health", tag = "system",
+    responses((status = 200, description = "The server is up", body = HealthResponse)))]
+pub async fn healthy(State(state): State<AppState>) -> Json<HealthResponse>{
+    let (default_model, ready) = service_ready(&state).await;
+    let cpu_fallback = state.registry.read().await.cpu_fallback();
+    let (default_model_loaded, loaded, memory_used_mb) = match state.model_cache.try_read() {
+        Ok(engines) => (engines.contains_key(&default_model), Some(engines.names()), Some(engines.used() / (1024 * 1024))),
+        Err(_) => (false, None, None),
+    };
+
+    let (parsed_files, parse_cache_bytes) = {
+        let parse_cache = state.parse_cache.lock().await;
+        (parse_cache.entries(), parse_cache.bytes())
+    };
+
+    let gpu_enabled = state.config.device != Device::Cpu && !cpu_fallback;
+    let vram = if gpu_enabled { gpu_memory(state.config.gpu_index).await } else { None };
 
-pub async fn healthy(State(_state): State<AppState>) -> Json<HealthResponse>{
     Json(HealthResponse{
         is_healthy : true,
-        status: "OK".to_string(),
+        status: if ready { "OK" } else { "loading" }.to_string(),
+        cpu_fallback,
+        ready,
+        service: state.service_status.get(),
+        models: ModelsHealth {
+            default_model,
+            default_model_loaded,
+            loaded,
+            memory_used_mb,
+            memory_budget_mb: state.config.model_memory_budget_mb,
+        },
+        gpu: GpuHealth {
+            enabled: gpu_enabled,
+            index: state.config.gpu_index,
+            free_vram_mb: vram.map(|memory| memory.free_mb),
+            total_vram_mb: vram.map(|memory| memory.total_mb),
+        },
+        disk: DiskHealth {
+            model_dir_free_mb: free_disk_mb(&state.config.model_dir),
+            file_dir_free_mb: free_disk_mb(&state.config.file_dir),
+        },
+        queue: QueueHealth {
+            pending: state.inference_queue.pending(),
+            capacity: state.inference_queue.capacity(),
+            max_concurrent: state.inference_queue.max_concurrent(),
+        },
+        caches: CacheHealth {
+            sessions: state.session_manager.len(),
+            files: state.file_cache.read().await.len(),
+            active_generations: state.active_generations.read().await.len(),
+            parsed_files,
+            parse_cache_bytes,
+        },
     })
 }
 
-//modified to join the inferrence part
-pub async fn infer_handler(
-    Json(req): Json<InferenceRequest>,
-) -> Json<InferenceResponse> {
-    let text = run_inference_collect(req.model.as_str(), req.prompt.as_str())
-        .await
-        .unwrap_or_else(|_| "Inference failed".to_string());
+/// 就绪检查（负载均衡、Kubernetes readinessProbe）：默认模型加载完成前返回 503
+#[utoipa::path(get, path = "/health/ready", tag = "system",
+    responses(
+        (status = 200, description = "The default model is loaded", body = ReadinessResponse),
+        (status = 503, description = "The default model is not loaded yet", body = ReadinessResponse),
+    ))]
+pub async fn ready_handler(State(state): State<AppState>) -> Response {
+    let (default_model, ready) = service_ready(&state).await;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(ReadinessResponse { ready, default_model, service: state.service_status.get() })).into_response()
+}
 
-    Json(InferenceResponse {
-        text,
-        session_id: None,
+/// Prometheus 文本格式的运行指标
+#[utoipa::path(get, path = "/metrics", tag = "system",
+    responses((status = 200, description = "Prometheus metrics", content_type = "text/plain", body = String)))]
+pub async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let active_sessions = state.session_manager.len();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(active_sessions),
+    )
+}
+
+/// 返回支持的模型列表，供前端模型选择器使用
+#[utoipa::path(get, path = "/models", tag = "models",
+    responses((status = 200, description = "Registered models", body = ListModelsResponse)))]
+pub async fn list_models_handler(State(state): State<AppState>) -> Json<ListModelsResponse> {
+    Json(ListModelsResponse {
+        models: list_models(&state.model_cache, &state.registry, &state.config.model_dir).await,
     })
 }
 
-pub async fn infer_stream_handler(
-    State(state): State<AppState>,
-    Json(req): Json<InferenceRequest>,
-) -> Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>
-{
-    println!("infer_stream_handler entered!");
-    let (tx, rx) = tokio::sync::mpsc::channel::<String>(32);
 
-    let model = req.model;
-    let user_prompt = req.prompt;
+fn model_error(status: StatusCode, error: String, model: &str) -> Response {
+    (status, Json(ModelError { error, model: model.to_string() })).into_response()
+}
 
-    let session_id = req.session_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+// 别名换成模型名，模型不存在时返回 404
+async fn registered_model(state: &AppState, name: &str) -> Result<String, Response> {
+    let registry = state.registry.read().await;
+    let model = registry.resolve(name);
+    if registry.get(model).is_none() {
+        return Err(model_error(StatusCode::NOT_FOUND, "Unknown model".to_string(), name));
+    }
+    Ok(model.to_string())
+}
+
+// 加载、卸载、删除、下载和注册模型影响所有租户，只有管理员可以调用
+fn require_model_admin(state: &AppState, caller: &Caller, name: &str) -> Result<(), Response> {
+    if is_admin(&state.config.api_keys, &state.config.admin_users, caller) {
+        return Ok(());
+    }
+    Err(model_error(StatusCode::FORBIDDEN, "Only admin users can manage models".to_string(), name))
+}
 
-    let config = SessionConfig::default();
+/// 预先加载模型（需要时先下载），在流量到来之前完成加载。加载完成后返回
+#[utoipa::path(post, path = "/models/{name}/load", tag = "models",
+    params(("name" = String, Path, description = "model_name or alias")),
+    responses(
+        (status = 200, description = "The model is loaded", body = ModelLoadResponse),
+        (status = 403, description = "Not an admin user", body = ModelError),
+        (status = 404, description = "Unknown model", body = ModelError),
+        (status = 500, description = "The model failed to load", body = ModelError),
+    ))]
+pub async fn load_model_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Json<ModelLoadResponse>, Response> {
+    require_model_admin(&state, &caller, &name)?;
+    let model = registered_model(&state, &name).await?;
 
-    let mut session = SessionHelper::get_or_create(
-        &state.session_manager,
-        &session_id,
-        config
-    ).await;
+    get_or_load_engine(&state.model_cache, &state.registry, &state.config.model_dir, &model)
+        .await
+        .map_err(|e| {
+            tracing::error!(model = %model, error = %e, "Failed to load model");
+            model_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load model: {}", e), &model)
+        })?;
+
+    Ok(Json(ModelLoadResponse { model, loaded: true }))
+}
 
-    // 如果有文件，先添加文件内容作为单独的 user message
-    if let Some(file_context) = build_file_context(&state).await {
-        println!("Adding file context to session: {} bytes", file_context.len());
-        session.add_user_message(file_context);
+/// 卸载模型释放显存。正在进行的生成不受影响，结束后才真正释放；之后的请求会重新加载
+#[utoipa::path(post, path = "/models/{name}/unload", tag = "models",
+    params(("name" = String, Path, description = "model_name or alias")),
+    responses(
+        (status = 200, description = "The model is unloaded", body = ModelLoadResponse),
+        (status = 403, description = "Not an admin user", body = ModelError),
+        (status = 404, description = "Unknown model", body = ModelError),
+    ))]
+pub async fn unload_model_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Json<ModelLoadResponse>, Response> {
+    require_model_admin(&state, &caller, &name)?;
+    let model = registered_model(&state, &name).await?;
+
+    if state.model_cache.write().await.remove(&model) {
+        tracing::info!(model = %model, "Model unloaded on request");
     }
-    
-    // 添加用户的实际 prompt
-    session.add_user_message(user_prompt);
 
-    // 保存 session（包含文件内容和用户消息）
-    SessionHelper::update(&state.session_manager, session.clone()).await;
+    Ok(Json(ModelLoadResponse { model, loaded: false }))
+}
 
-    let messages: Vec<ChatMessage> = session.get_messages().to_vec();
-    
-    println!("Total messages in session: {}", messages.len());
-    for (i, msg) in messages.iter().enumerate() {
-        println!("  Message {}: role={:?}, content_len={}", i, msg.role, msg.content.len());
+/// 删除模型下载的文件释放磁盘空间，模型先被卸载。之后使用该模型时会重新下载
+#[utoipa::path(delete, path = "/models/{name}/files", tag = "models",
+    params(("name" = String, Path, description = "model_name or alias")),
+    responses(
+        (status = 200, description = "Downloaded files removed", body = ModelFilesResponse),
+        (status = 400, description = "Local, vision or remote model", body = ModelError),
+        (status = 403, description = "Not an admin user", body = ModelError),
+        (status = 404, description = "Unknown model", body = ModelError),
+    ))]
+pub async fn delete_model_files_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Json<ModelFilesResponse>, Response> {
+    require_model_admin(&state, &caller, &name)?;
+    let model = registered_model(&state, &name).await?;
+    let spec = state.registry.read().await.get(&model).cloned()
+        .ok_or_else(|| model_error(StatusCode::NOT_FOUND, "Unknown model".to_string(), &name))?;
+    if spec.path.is_some() || spec.vision || spec.remote.is_some() {
+        return Err(model_error(StatusCode::BAD_REQUEST,
+            "Only GGUF files downloaded into model_dir can be deleted".to_string(), &model));
     }
 
-    let session_manager = state.session_manager.clone();
-    let session_id_clone = session_id.clone();
+    if state.model_cache.write().await.remove(&model) {
+        tracing::info!(model = %model, "Model unloaded before deleting its files");
+    }
 
-    tokio::spawn(async move {
-        let mut full_response = String::new();
+    let freed_bytes = delete_model_files(&state.config.model_dir, &spec).await.map_err(|e| {
+        tracing::error!(model = %model, error = %e, "Failed to delete model files");
+        model_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete model files: {}", e), &model)
+    })?;
+    tracing::info!(model = %model, freed_mb = freed_bytes / (1024 * 1024), "Deleted model files");
 
-        if let Ok(mut stream) = run_inference_stream(&model, &messages).await {
-            while let Some(token) = stream.next().await {
-                full_response.push_str(&token);
-                if tx.send(token).await.is_err() {
-                    break;
-                }
-            }
-        }
+    Ok(Json(ModelFilesResponse { model, deleted: freed_bytes > 0, freed_bytes }))
+}
 
-        if !full_response.is_empty() {
-            let mut session = SessionHelper::get_or_create(
-                &session_manager,
-                &session_id_clone,
-                SessionConfig::default(),
-            ).await;
-            session.add_assistant_message(full_response);
-            SessionHelper::update(&session_manager, session).await;
-        }
 
-        // 发送会话 ID（作为特殊消息）
-        let session_info = serde_json::json!({
-            "session_id": session_id_clone,
-            "type": "session_info"
-        }).to_string();
-        let _ = tx.send(format!("__SESSION__:{}", session_info)).await;
+/// 在 GGUF 模型上附加 LoRA / X-LoRA adapter，以 name 注册为新模型。第一次使用时才加载，
+/// adapter 模型和基础模型分别加载，各占一份显存
+#[utoipa::path(post, path = "/models/{name}/adapters", tag = "models",
+    params(("name" = String, Path, description = "base model")),
+    request_body = AdapterRequest,
+    responses(
+        (status = 200, description = "The adapter model is registered", body = AdapterResponse),
+        (status = 400, description = "Invalid adapter or base model", body = ModelError),
+        (status = 403, description = "Not an admin user", body = ModelError),
+        (status = 404, description = "Unknown base model", body = ModelError),
+        (status = 409, description = "The name is already registered", body = ModelError),
+    ))]
+pub async fn add_adapter_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    Json(req): Json<AdapterRequest>,
+) -> Result<Json<AdapterResponse>, Response> {
+    require_model_admin(&state, &caller, &name)?;
+    let base = registered_model(&state, &name).await?;
 
-        let _ = tx.send("[DONE]".to_string()).await;
-    });
+    if req.name.trim().is_empty() {
+        return Err(model_error(StatusCode::BAD_REQUEST, "name must not be empty".to_string(), &base));
+    }
+    if !tokio::fs::try_exists(&req.adapter.ordering).await.unwrap_or(false) {
+        return Err(model_error(StatusCode::BAD_REQUEST,
+            format!("Ordering file {} not found", req.adapter.ordering), &base));
+    }
 
-    let sse_stream = tokio_stream::wrappers::ReceiverStream::new(rx)
-        .map(|token| {
-            if token == "[DONE]" {
-                return Ok(Event::default().data("[DONE]"));
-            }
+    let mut registry = state.registry.write().await;
+    if registry.get(&req.name).is_some() || registry.aliases().contains_key(&req.name) {
+        return Err(model_error(StatusCode::CONFLICT, "Model already exists".to_string(), &req.name));
+    }
+    registry.register_adapter(&base, &req.name, req.adapter.clone())
+        .map_err(|e| model_error(StatusCode::BAD_REQUEST, e.to_string(), &base))?;
 
-            if token.starts_with("__SESSION__:") {
-                let session_data = &token["__SESSION__:".len()..];
-                return Ok(Event::default().event("session").data(session_data));
-            }
+    tracing::info!(model = %req.name, kind = ?req.adapter.kind, adapter = %req.adapter.model_id, base = %base, "Adapter model registered");
+    Ok(Json(AdapterResponse { model: req.name, base }))
+}
 
-            let json = serde_json::json!({
-            "content": token
-        })
-                .to_string();
 
-            Ok(Event::default().data(json))
-        });
+// 请求使用的模型（未指定时为默认模型），需要时先加载
+async fn request_engine(state: &AppState, requested: &str) -> Result<(String, Arc<dyn InferenceEngine>), Response> {
+    let model = registered_model(state, &resolve_model(state, requested).await).await?;
+    let engine = get_or_load_engine(&state.model_cache, &state.registry, &state.config.model_dir, &model)
+        .await
+        .map_err(|e| {
+            tracing::error!(model = %model, error = %e, "Failed to load model");
+            model_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load model: {}", e), &model)
+        })?;
+    Ok((model, engine))
+}
 
-    println!("1111");
+/// 用模型的 tokenizer 切分文本，前端可以实时显示 token 数或在发送前截断输入
+#[utoipa::path(post, path = "/tokenize", tag = "models",
+    request_body = TokenizeRequest,
+    responses(
+        (status = 200, body = TokenizeResponse),
+        (status = 400, description = "The model does not expose its tokenizer", body = ModelError),
+        (status = 404, description = "Unknown model", body = ModelError),
+    ))]
+pub async fn tokenize_handler(
+    State(state): State<AppState>,
+    Json(req): Json<TokenizeRequest>,
+) -> Result<Json<TokenizeResponse>, Response> {
+    let (model, engine) = request_engine(&state, &req.model).await?;
 
-    Sse::new(sse_stream).keep_alive(
-        axum::response::sse::KeepAlive::new()
-            .interval(Duration::from_secs(10))
-            .text("keep-alive"),
-    )
+    let tokens = engine.tokenize(&req.text).await.map_err(|e| {
+        model_error(StatusCode::BAD_REQUEST, format!("Failed to tokenize: {}", e), &model)
+    })?;
 
+    Ok(Json(TokenizeResponse { model, count: tokens.len(), tokens }))
 }
 
+/// token id 还原成文本，id 不在词表中时返回 400
+#[utoipa::path(post, path = "/detokenize", tag = "models",
+    request_body = DetokenizeRequest,
+    responses(
+        (status = 200, body = DetokenizeResponse),
+        (status = 400, description = "Token id not in the vocabulary", body = ModelError),
+        (status = 404, description = "Unknown model", body = ModelError),
+    ))]
+pub async fn detokenize_handler(
+    State(state): State<AppState>,
+    Json(req): Json<DetokenizeRequest>,
+) -> Result<Json<DetokenizeResponse>, Response> {
+    let (model, engine) = request_engine(&state, &req.model).await?;
 
-/// 构建文件内容的 prompt（如果有文件的话）
-async fn build_file_context(state: &AppState) -> Option<String> {
-    let mut cache = state.file_cache.write().await;
-    
-    println!("build_file_context: cache size = {}", cache.len());
-    
-    if cache.is_empty() {
-        println!("build_file_context: no files in cache");
-        return None;
-    }
-    
-    let mut file_context = String::from("I'm sharing the following file(s) with you:\n\n");
-    
-    for (_, value) in cache.iter() {
-        println!("build_file_context: processing file {} ({}), content_len={}", 
-            value.filename, value.extension, value.content.len());
-        match value.extension.as_str() {
-            "txt" => {
-                file_context.push_str(
-                    format!("=== Text File: {} ===\n{}\n\n", value.filename, value.content)
-                        .as_str());
-            }
-            "md" => {
-                file_context.push_str(
-                    format!("=== Markdown File: {} ===\n{}\n\n", value.filename, value.content)
-                        .as_str());
-            }
-            "pdf" => {
-                file_context.push_str(
-                    format!("=== PDF File: {} ===\n{}\n\n", value.filename, value.content)
-                        .as_str());
-            }
-            "docx" => {
-                file_context.push_str(
-                    format!("=== Word Document: {} ===\n{}\n\n", value.filename, value.content)
-                        .as_str());
-            }
-            "pptx" => {
-                file_context.push_str(
-                    format!("=== PowerPoint: {} ===\n{}\n\n", value.filename, value.content)
-                        .as_str());
-            }
-            "xlsx" => {
-                file_context.push_str(
-                    format!("=== Excel Spreadsheet: {} ===\n{}\n\n", value.filename, value.content)
-                        .as_str());
-            }
-            "py" | "js" | "ts" | "jsx" | "tsx" | "vue" | "svelte" |
-            "rs" | "go" | "java" | "kt" | "scala" |
-            "c" | "cpp" | "cc" | "cxx" | "h" | "hpp" | "hxx" |
-            "cs" | "fs" | "rb" | "php" | "pl" | "pm" |
-            "swift" | "m" | "mm" | "r" | "R" | "jl" |
-            "lua" | "tcl" | "awk" | "sed" |
-            "hs" | "ml" | "elm" | "clj" | "cljs" | "ex" | "exs" |
-            "sh" | "bash" | "zsh" | "fish" | "bat" | "cmd" | "ps1" |
-            "sql" | "prisma" | "graphql" | "gql" |
-            "html" | "htm" | "css" | "scss" | "sass" | "less" |
-            "xml" | "xsl" | "xslt" |
-            "json" | "yaml" | "yml" | "toml" | "ini" | "cfg" | "conf" |
-            "log" | "env" | "makefile" | "cmake" | "dockerfile" |
-            "gitignore" | "editorconfig"
-            => {
-                file_context.push_str(
-                    format!("=== {} Code File: {} ===\n{}\n\n", 
-                        value.extension.to_uppercase(), value.filename, value.content)
-                        .as_str());
-            }
-            _ => {
-                file_context.push_str(
-                    format!("=== File: {} ===\n{}\n\n", value.filename, value.content)
-                        .as_str());
-            }
-        }
-    }
-    
-    file_context.push_str("Please refer to the above file content(s) when answering my questions.");
-    
-    cache.clear();
-    
-    Some(file_context)
+    let text = engine.detokenize(&req.tokens).await.map_err(|e| {
+        model_error(StatusCode::BAD_REQUEST, format!("Failed to detokenize: {}", e), &model)
+    })?;
+
+    Ok(Json(DetokenizeResponse { model, text }))
 }
 
 
-pub async fn upload_handler(
-    State(state): State<AppState>,
-    mut multipart : Multipart)
-    -> Result<Json<UploadResponse>, (StatusCode, Json<UnsupportedFileError>)> {
-    let item = multipart.next_field().await.unwrap().unwrap();
-    let filename = item
-        .file_name()
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "".to_string());
+/// OpenAI 格式的模型列表（`GET /v1/models`），别名也作为模型列出
+#[utoipa::path(get, path = "/v1/models", tag = "openai",
+    responses((status = 200, body = OpenAIModelList)))]
+pub async fn list_openai_models_handler(State(state): State<AppState>) -> Json<OpenAIModelList> {
+    let registry = state.registry.read().await;
 
-    let extension = Path::new(&filename)
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("");
+    let owner = |name: &str| {
+        registry.get(name)
+            .and_then(|spec| spec.repo.split('/').next())
+            .filter(|owner| !owner.is_empty())
+            .unwrap_or("local")
+            .to_string()
+    };
 
-    let allowed_text_file = vec!["txt", "pdf", "docx", "pptx", "xlsx", "md"];
-    let allowed_code_file = vec![
-            "py", "js", "ts", "jsx", "tsx", "vue", "svelte",      // Web
-            "rs",                                                 // Rust
-            "go",                                                 // go
-            "java", "kt", "scala",                                // java
-            "c", "cpp", "cc", "cxx", "h", "hpp", "hxx",           // C/C++
-            "cs", "fs",                                           // .NET
-            "rb", "php", "pl", "pm",                              // php
-            "swift", "m", "mm",                                   // Apple
-            "r", "R", "jl",                                       // data science
-            "lua", "tcl", "awk", "sed",                           // Script
-            "hs", "ml", "elm", "clj", "cljs", "ex", "exs",        // function
-            "sh", "bash", "zsh", "fish", "bat", "cmd", "ps1",     // Shell
-            "sql", "prisma", "graphql", "gql",                    // database
-            "html", "htm", "css", "scss", "sass", "less",         // Web page
-            "xml", "xsl", "xslt",                                 // XML
-            "json", "yaml", "yml", "toml", "ini", "cfg", "conf",  // config
-            "log", "env",                                         // log
-            "makefile", "cmake", "dockerfile",                    // build
-            "gitignore", "editorconfig"                           // git
-    ];
-    if !allowed_text_file.contains(&extension.to_lowercase().as_str())
-    && !allowed_code_file.contains(&extension.to_lowercase().as_str()){
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(UnsupportedFileError {
-                error : "Unsupported file type".to_string(),
-                file_type : extension.to_string()
-            })
-        ))
+    let mut data: Vec<OpenAIModel> = registry.models().iter()
+        .map(|spec| OpenAIModel::new(spec.name.clone(), owner(&spec.name)))
+        .collect();
+    let mut aliases: Vec<(&String, &String)> = registry.aliases().iter().collect();
+    aliases.sort();
+    data.extend(aliases.into_iter().map(|(alias, target)| OpenAIModel::new(alias.clone(), owner(target))));
+
+    Json(OpenAIModelList {
+        object: "list",
+        data,
+    })
+}
+
+
+// 下载进度事件的最小间隔（字节）
+const PULL_PROGRESS_STEP: u64 = 1024 * 1024;
+
+// repo 必须是 owner/name，file 必须是 repo 根目录下的 .gguf 文件
+fn validate_pull_request(req: &PullModelRequest) -> Result<(), String> {
+    if req.alias.trim().is_empty() {
+        return Err("alias must not be empty".to_string());
+    }
+    if let Some(path) = &req.path {
+        if !req.repo.is_empty() || !req.file.is_empty() {
+            return Err("path cannot be combined with repo and file".to_string());
+        }
+        if !path.to_lowercase().ends_with(".gguf") {
+            return Err("path must be a .gguf file".to_string());
+        }
+        return Ok(());
+    }
+    let repo_parts: Vec<&str> = req.repo.split('/').collect();
+    if repo_parts.len() != 2 || repo_parts.iter().any(|p| p.is_empty() || *p == "..") {
+        return Err("repo must have the form owner/name".to_string());
+    }
+    if req.file.contains('/') || req.file.contains('\\') || req.file.contains("..") {
+        return Err("file must be a plain file name".to_string());
     }
+    if !req.file.to_lowercase().ends_with(".gguf") {
+        return Err("file must be a .gguf file".to_string());
+    }
+    Ok(())
+}
 
-    let data = item.bytes().await.unwrap();
-    let file_size = data.len();
+/// 下载并注册任意 Hugging Face GGUF 模型，通过 SSE 返回下载进度。
+/// 请求带 path 时直接注册服务器上的文件，只返回 success 或 error
+#[utoipa::path(post, path = "/models/pull", tag = "models",
+    request_body = PullModelRequest,
+    responses(
+        (status = 200, description = "SSE stream of download progress, see PullEvent", content_type = "text/event-stream", body = PullEvent),
+        (status = 400, description = "Invalid request", body = PullModelError),
+        (status = 403, description = "Not an admin user", body = PullModelError),
+        (status = 409, description = "The alias is already registered", body = PullModelError),
+    ))]
+pub async fn pull_model_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<PullModelRequest>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, (StatusCode, Json<PullModelError>)>
+{
+    if !is_admin(&state.config.api_keys, &state.config.admin_users, &caller) {
+        return Err((StatusCode::FORBIDDEN,
+            Json(PullModelError {
+                error: "Only admin users can manage models".to_string(),
+                alias: req.alias,
+            })));
+    }
+    if let Err(error) = validate_pull_request(&req) {
+        return Err((StatusCode::BAD_REQUEST, Json(PullModelError { error, alias: req.alias })));
+    }
 
-    let content = parse_file(Path::new(&filename), &data).await.unwrap();
-    let file_id = uuid::Uuid::new_v4().to_string();
-    {
-        println!("file_id: {}, file_content: {}", file_id, content);
+    // the alias is reserved until the pull ends, so concurrent pulls of the same alias get 409
+    let reserved = state.registry.write().await.reserve(&req.alias);
+    if !reserved {
+        return Err((StatusCode::CONFLICT,
+            Json(PullModelError {
+                error: "Model already exists".to_string(),
+                alias: req.alias,
+            })));
     }
-    let cache_file = CacheFile {
-        filename: filename.clone(),
-        content,
-        extension : extension.to_string(),
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<PullEvent>(sse::buffer_size(&state.config));
+    let registry = state.registry.clone();
+    let model_dir = state.config.model_dir.clone();
+
+    tokio::spawn(async move {
+        let progress_tx = tx.clone();
+        let mut last_reported = 0u64;
+
+        let result = if let Some(path) = &req.path {
+            register_local_model(&registry, path, &req.alias).await
+        } else {
+            pull_model(
+                &registry,
+                &model_dir,
+                &req.repo,
+                &req.file,
+                &req.alias,
+                |downloaded, total| {
+                    if downloaded - last_reported >= PULL_PROGRESS_STEP || downloaded == total {
+                        last_reported = downloaded;
+                        // progress is best effort, skip it if the client is slow
+                        let _ = progress_tx.try_send(PullEvent::Downloading { downloaded, total });
+                    }
+                },
+            ).await
+        };
+
+        let event = match result {
+            Ok(()) => PullEvent::Success { model: req.alias },
+            Err(e) => {
+                registry.write().await.release(&req.alias);
+                match &req.path {
+                    Some(path) => tracing::error!(path = %path, error = %e, "Registering local model failed"),
+                    None => tracing::error!(repo = %req.repo, file = %req.file, error = %e, "Model pull failed"),
+                }
+                PullEvent::Error { error: e.to_string() }
+            }
+        };
+        let _ = tx.send(event).await;
+    });
+
+    let sse_stream = tokio_stream::wrappers::ReceiverStream::new(rx)
+        .map(|event| Event::default().event(event.name()).json_data(&event));
+
+    Ok(Sse::new(sse_stream).keep_alive(sse::keep_alive(&state.config)))
+}
+
+
+// 请求未指定模型时使用配置中的默认模型，别名换成对应的模型名
+async fn resolve_model(state: &AppState, requested: &str) -> String {
+    let requested = if requested.is_empty() {
+        state.config.default_model.as_str()
+    } else {
+        requested
     };
-    {
-        let mut cache = state.file_cache.write().await;
-        cache.insert(file_id.clone(), cache_file);
-        println!("Current number of files in cache: {}", cache.len());
+    state.registry.read().await.resolve(requested).to_string()
+}
+
+// 请求体中的模型（未指定时为默认模型），模型未注册时返回 400
+async fn requested_model(state: &AppState, requested: &str) -> Result<String, Response> {
+    let model = resolve_model(state, requested).await;
+    if state.registry.read().await.get(&model).is_none() {
+        return Err(model_error(StatusCode::BAD_REQUEST, format!("Unknown model {}", model), &model));
     }
-    Ok(Json(UploadResponse {
-        file_id,
-        filename,
-        file_size
-    }))
+    Ok(model)
 }
 
+// 生成请求的校验，在进入队列和修改 session 之前调用：prompt 非空且不超过 max_prompt_chars /
+// max_prompt_tokens，模型已注册，采样参数在有效范围内。返回解析后的模型名
+async fn validate_generation(
+    state: &AppState,
+    requested: &str,
+    field: &str,
+    prompt: &str,
+    generation_config: &GenerationConfig,
+) -> Result<String, Response> {
+    validate_prompt(field, prompt, state.config.max_prompt_chars)?;
+    validate_sampling(generation_config)?;
+    let model = requested_model(state, requested).await?;
 
-pub async fn remove_handler(State(state): State<AppState>,
-                            axum::extract::Path(file_id): axum::extract::Path<String>)
-    -> Result<Json<DeleteResponse>, (StatusCode, Json<RemoveFileError>)> {
-    let mut cache = state.file_cache.write().await;
-    match cache.get(&file_id) {
-        Some(_) => {
-            cache.remove(&file_id);
-        }
-        None => {
-            return Err((StatusCode::BAD_REQUEST,
-                Json(RemoveFileError {
-                error : "File does not exist".to_string(),
-                file_id : file_id.to_string()
-            })))
+    let limit = state.config.max_prompt_tokens;
+    if limit > 0 {
+        // 模型已加载时用它的 tokenizer 计数，否则按字符数估算，不会为了校验去加载模型
+        let engine = state.model_cache.read().await.get(&model);
+        let tokens = count_tokens(engine.as_deref(), prompt).await;
+        if tokens > limit {
+            return Err(prompt_too_long(field, tokens, limit, "tokens"));
         }
     }
-    println!("Current number of files in cache: {}", cache.len());
+    check_service_ready(state, &model)?;
+    Ok(model)
+}
 
-    let delete_response = DeleteResponse {
-        file_id,
-        result: true,
+// 请求的模型正在加载时，请求会一直等到加载结束，直接返回 503 和加载进度。
+// 已加载的模型和没有在加载的模型照常处理，不受其他模型加载的影响
+fn check_service_ready(state: &AppState, model: &str) -> Result<(), Response> {
+    let Some(status) = state.service_status.loading(model) else {
+        return Ok(());
     };
 
-    Ok(Json(delete_response))
+    let retry_after = state.config.queue_retry_after_secs;
+    Err((
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, retry_after.to_string())],
+        Json(ServiceLoadingError {
+            error: format!("Model {} is loading, try again later", model),
+            retry_after,
+            status,
+        }),
+    ).into_response())
 }
 
+// 指定 seed 的请求单独运行，不和其他请求一起 batch，相同的输入得到相同的输出
+async fn wait_turn(ticket: &QueueTicket, generation_config: &GenerationConfig) -> OwnedSemaphorePermit {
+    if generation_config.seed.is_some() {
+        ticket.wait_exclusive().await
+    } else {
+        ticket.wait().await
+    }
+}
 
-pub async fn remove_session_handler(State(state): State<AppState>,
-                                    axum::extract::Path(session_id): axum::extract::Path<String>)
-    -> Result<Json<RemoveSessionResponse>, (StatusCode, Json<RemoveSessionError>)> {
-    if !SessionHelper::remove(&state.session_manager, &session_id).await {
-        return Err(
-            (StatusCode::BAD_REQUEST,
-            Json(RemoveSessionError {
-                error : "Session does not exist".to_string(),
-                session_id : session_id.to_string()
-            }))
-        )
+// 服务器的 max_tokens_limit 限制每个请求的生成长度
+async fn limit_generation(state: &AppState, model: &str, generation_config: &GenerationConfig) -> GenerationConfig {
+    let default_max_tokens = state.registry.read().await.get(model).and_then(|spec| spec.defaults.max_tokens);
+    generation_config.capped(default_max_tokens, state.config.max_tokens_limit)
+}
+
+// 超过 generation_timeout_secs 后完成，未配置超时时永远不会完成。
+// 和生成放在同一个 select 中，模型卡住时丢弃生成（mistralrs 随之中止请求）并释放队列的位置
+async fn watchdog(state: &AppState) {
+    match state.config.generation_timeout_secs {
+        0 => std::future::pending().await,
+        secs => tokio::time::sleep(Duration::from_secs(secs)).await,
     }
+}
 
-    Ok(Json(RemoveSessionResponse {
-        session_id,
-        cleared: true
-    }))
+fn timeout_message(state: &AppState) -> String {
+    format!("Generation timed out after {}s", state.config.generation_timeout_secs)
 }
 
+// 推理队列已满时返回 429，并通过 Retry-After 提示客户端稍后重试
+fn queue_full_response(state: &AppState) -> Response {
+    let retry_after = state.config.queue_retry_after_secs;
+    tracing::warn!(pending = state.inference_queue.pending(), "Inference queue full, rejecting request");
 
-/// 获取 session 信息
-pub async fn get_session_handler(
-    State(state): State<AppState>,
-    axum::extract::Path(session_id): axum::extract::Path<String>
-) -> Json<GetSessionResponse> {
-    match SessionHelper::get(&state.session_manager, &session_id).await {
-        Some(session) => {
-            Json(GetSessionResponse {
-                session_id,
-                messages: session.messages,
-                exists: true,
-            })
-        }
-        None => {
-            Json(GetSessionResponse {
-                session_id,
-                messages: vec![],
-                exists: false,
-            })
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.to_string())],
+        Json(QueueFullError {
+            error: "Too many inference requests, try again later".to_string(),
+            retry_after,
+        }),
+    )
+        .into_response()
+}
+
+// 内容审核：按 moderation_keywords 匹配关键词，配置了 moderation_model 时再让该模型分类。
+// 命中时写审核日志；block 模式下返回命中的原因，由调用方拒绝 prompt 或拦截回答，flag 模式下只记录。
+// 分类模型出错或超时时只按关键词的结果处理，不因为审核失败拒绝请求
+async fn moderate(
+    state: &AppState,
+    target: ModerationTarget,
+    text: &str,
+    request_id: &str,
+    owner: Option<&str>,
+    session_id: Option<&str>,
+) -> Option<Vec<String>> {
+    let config = &state.config;
+    let enabled = match target {
+        ModerationTarget::Prompt => config.moderate_prompts,
+        ModerationTarget::Output => config.moderate_outputs,
+    };
+    if config.moderation == ModerationMode::Off || !enabled || text.trim().is_empty() {
+        return None;
+    }
+
+    let mut reasons: Vec<String> = keyword_matches(&config.moderation_keywords, text)
+        .into_iter()
+        .map(|keyword| format!("keyword:{}", keyword))
+        .collect();
+
+    if !config.moderation_model.is_empty() {
+        // 分类不经过推理队列：被审核的请求已经占着队列的位置，再排队可能等不到
+        let generation_config = GenerationConfig {
+            temperature: Some(0.0),
+            max_tokens: Some(CLASSIFIER_MAX_TOKENS),
+            ..Default::default()
+        };
+        let messages = classifier_prompt(text);
+        let classification = with_watchdog(state, run_inference_collect(
+            &state.model_cache,
+            &state.registry,
+            &config.model_dir,
+            &config.moderation_model,
+            &messages,
+            Vec::new(),
+            &generation_config,
+        )).await;
+        match classification {
+            Ok((reply, _)) if classifier_flags(&reply) => reasons.push("classifier".to_string()),
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!(request_id, model = %config.moderation_model, error = %e, "Moderation classifier failed");
+            }
         }
     }
+
+    if reasons.is_empty() {
+        return None;
+    }
+    let blocked = config.moderation == ModerationMode::Block;
+    let record = ModerationRecord::new(request_id, owner, session_id, target, blocked, reasons.clone(), text);
+    state.moderation_log.record(&record).await;
+    blocked.then_some(reasons)
+}
+
+// 在进入队列和修改 session 之前审核 prompt，block 模式下命中时返回 400
+async fn moderate_prompt(
+    state: &AppState,
+    prompt: &str,
+    request_id: &str,
+    owner: Option<&str>,
+    session_id: Option<&str>,
+) -> Result<(), Response> {
+    match moderate(state, ModerationTarget::Prompt, prompt, request_id, owner, session_id).await {
+        Some(reasons) => Err((StatusCode::BAD_REQUEST, Json(ModerationError {
+            error: "Prompt was blocked by content moderation".to_string(),
+            reasons,
+        })).into_response()),
+        None => Ok(()),
+    }
 }
 
+// 调用方当天或当月的 token 配额已用完时返回 429，Retry-After 为到配额恢复的秒数
+fn check_quota(state: &AppState, owner: Option<&str>) -> Result<(), Response> {
+    let now = chrono::Utc::now();
+    let Err(exceeded) = state.usage.check(owner, state.config.quota_for(owner), now) else {
+        return Ok(());
+    };
 
-/// 同步 session 消息（前端切换 session 时调用）
-pub async fn sync_session_handler(
+    let retry_after = (exceeded.reset_at - now.timestamp()).max(1);
+    tracing::warn!(owner = owner.unwrap_or(ANONYMOUS), period = exceeded.period, used = exceeded.used, "Token quota exceeded");
+    Err((
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.to_string())],
+        Json(QuotaExceededError {
+            error: format!("Token quota for this {} exhausted ({} of {})", exceeded.period, exceeded.used, exceeded.limit),
+            period: exceeded.period.to_string(),
+            limit: exceeded.limit,
+            used: exceeded.used,
+            reset_at: exceeded.reset_at,
+        }),
+    ).into_response())
+}
+
+// 请求中的 callback_url：未设置时为 None，未启用 webhook 或地址不被允许时返回 400
+fn check_callback_url(state: &AppState, callback_url: Option<&str>) -> Result<Option<reqwest::Url>, Response> {
+    callback_url
+        .map(|url| state.webhooks.check_url(url).map_err(|e| invalid("callback_url", e)))
+        .transpose()
+}
+
+// 生成结束：写审计日志，把 token 计入调用方的用量，并发布 generation.completed 事件（内容只有哈希）
+async fn finish_generation(state: &AppState, record: &AuditRecord) {
+    state.usage.add(record.owner.as_deref(), record.prompt_tokens, record.completion_tokens).await;
+    state.audit_log.record(record).await;
+    let tenant = record.owner.as_deref().map(|owner| state.config.tenant_of(owner));
+    state.events.publish(Event::new(GENERATION_COMPLETED, record.owner.as_deref(), tenant.as_deref(), record));
+}
+
+//modified to join the inferrence part
+/// 设置 callback_url 时返回 202，结果以 generation.completed / generation.failed 事件 POST 到 callback_url
+#[utoipa::path(post, path = "/generate", tag = "generation",
+    request_body = InferenceRequest,
+    responses(
+        (status = 200, body = InferenceResponse),
+        (status = 202, description = "Queued; the result is sent to callback_url as a signed WebhookPayload", body = AcceptedResponse),
+        (status = 400, description = "Empty prompt, unknown model, invalid sampling parameter or callback_url", body = ValidationError),
+        (status = 400, description = "Prompt blocked by content moderation", body = ModerationError),
+        (status = 413, description = "Prompt too long", body = PromptTooLongError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+        (status = 504, description = "Generation timed out", body = GenerationTimeoutError),
+    ))]
+pub async fn infer_handler(
     State(state): State<AppState>,
-    Json(req): Json<SyncSessionRequest>
-) -> Json<SyncSessionResponse> {
-
-    let messages: Vec<ChatMessage> = req.messages.into_iter().map(|msg| {
-        ChatMessage {
-            role: msg.role,
-            content: msg.content,
-        }
-    }).collect();
-    
-    let message_count = messages.len();
-    
-    let session_manager = state.session_manager.read().await;
-    let session = session_manager.get(req.session_id.as_str()).unwrap();
-    
-    println!("Session {} synced with {} messages", req.session_id, session.messages.len());
-    
-    Json(SyncSessionResponse {
-        session_id: req.session_id,
-        synced: true,
-        message_count,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    Json(mut req): Json<InferenceRequest>,
+) -> Result<Response, Response> {
+    let timer = GenerationTimer::new();
+    let request_id = request_id_string(&request_id);
+    let model = validate_generation(&state, &req.model, "prompt", &req.prompt, &req.generation_config()).await?;
+    let callback_url = check_callback_url(&state, req.callback_url.as_deref())?;
+    moderate_prompt(&state, &req.prompt, &request_id, caller.owner(), req.session_id.as_deref()).await?;
+    check_quota(&state, caller.owner())?;
+    let tools = select_tools(&req.tools, &state.config).map_err(tool_error)?;
+    let examples = select_examples(&state, &caller, req.examples.as_deref())?;
+    check_file_ids(&state, &caller, &req.file_ids).await?;
+    let images = load_images(&state, &caller, &req.image_ids).await?;
+
+    let session_id = req.session_id.take().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    claim_session(&state, &caller, &session_id).await?;
+
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+    let Some(callback_url) = callback_url else {
+        return match generate(state, caller, request_id, req, model, session_id, tools, examples, images, ticket, timer).await {
+            Ok(response) => Ok(Json(response).into_response()),
+            Err(timeout) => Err((StatusCode::GATEWAY_TIMEOUT, Json(timeout)).into_response()),
+        };
+    };
+
+    // 带 callback_url 时立即返回 202，生成结束后把结果 POST 到 callback_url
+    let accepted = AcceptedResponse { request_id: request_id.clone(), session_id: Some(session_id.clone()) };
+    tokio::spawn(async move {
+        let webhooks = state.webhooks.clone();
+        let payload = match generate(state, caller, request_id.clone(), req, model, session_id, tools, examples, images, ticket, timer).await {
+            Ok(response) if response.usage.finish_reason.as_deref() == Some("error") => {
+                WebhookPayload::failed("generation.failed", &request_id, response.text)
+            }
+            Ok(response) => WebhookPayload::completed("generation.completed", &request_id, &response),
+            Err(timeout) => WebhookPayload::failed("generation.failed", &request_id, timeout.error),
+        };
+        webhooks.deliver(&callback_url, &payload).await;
+    }.in_current_span());
+
+    Ok((StatusCode::ACCEPTED, Json(accepted)).into_response())
+}
+
+
+// /generate 在排队之后的部分：等待推理的名额，生成并保存回答。超时时返回 Err
+#[allow(clippy::too_many_arguments)]
+async fn generate(
+    state: AppState,
+    caller: Caller,
+    request_id: String,
+    req: InferenceRequest,
+    model: String,
+    session_id: String,
+    tools: Vec<Arc<dyn Tool>>,
+    examples: Vec<Example>,
+    images: Vec<Vec<u8>>,
+    ticket: QueueTicket,
+    mut timer: GenerationTimer,
+) -> Result<InferenceResponse, GenerationTimeoutError> {
+    let generation_config = limit_generation(&state, &model, &req.generation_config()).await;
+    // 可以缓存的请求先查缓存再等待推理，命中时不占用推理的名额
+    let cacheable = state.response_cache.enabled()
+        && response_cache::cacheable(&generation_config)
+        && tools.is_empty()
+        && images.is_empty();
+    let mut permit = None;
+    if !cacheable {
+        permit = Some(wait_turn(&ticket, &generation_config).await);
+        timer.start();
+    }
+
+    let (messages, config) = prepare_conversation(
+        &state, &session_id, caller.tenant(), &model, &generation_config, req.system_prompt, req.prompt.clone(), &req.file_ids, examples).await;
+
+    let cache_key = cacheable.then(|| response_cache::cache_key(caller.tenant(), &model, &messages, &generation_config));
+    if let Some(key) = &cache_key {
+        if let Some((text, usage)) = state.response_cache.get(key).await {
+            tracing::debug!(request_id = %request_id, session_id = %session_id, model = %model, "Response cache hit");
+            save_assistant_message(&state, &session_id, &model, config, text.clone()).await;
+            let timings = timer.finish(0);
+            log_timings(&request_id, &session_id, &model, &usage, &timings);
+            // 命中缓存时没有调用模型，写审计日志但不计入 token 用量
+            let record = AuditRecord::new(
+                "/generate", &request_id, caller.owner(), Some(&session_id), &model, &req.prompt, &text, &usage, &timings);
+            state.audit_log.record(&record).await;
+
+            return Ok(InferenceResponse {
+                text,
+                session_id: Some(session_id),
+                usage,
+                tool_calls: Vec::new(),
+                timings: Some(timings),
+                cached: true,
+            });
+        }
+        permit = Some(wait_turn(&ticket, &generation_config).await);
+        timer.start();
+    }
+    let _permit = permit;
+
+    let generation = collect_with_tools(
+        &state, &session_id, &model, &config, messages, images, &generation_config, &tools);
+    let result = tokio::select! {
+        result = generation => result,
+        _ = watchdog(&state) => {
+            tracing::warn!(request_id = %request_id, session_id = %session_id, model = %model, "Generation timed out");
+            return Err(GenerationTimeoutError {
+                error: timeout_message(&state),
+                finish_reason: "timeout".to_string(),
+            });
+        }
+    };
+
+    let (text, usage, tool_calls) = match result {
+        Ok((text, mut usage, tool_calls)) => {
+            let blocked = moderate(
+                &state, ModerationTarget::Output, &text, &request_id, caller.owner(), Some(&session_id)).await;
+            if blocked.is_some() {
+                // 被拦截的回答不返回给客户端，也不写入 session
+                usage.finish_reason = Some("content_filter".to_string());
+                (String::new(), usage, tool_calls)
+            } else {
+                save_assistant_message(&state, &session_id, &model, config, text.clone()).await;
+                // 被取消的回答不完整，不缓存
+                let finished = usage.finish_reason.as_deref() != Some("canceled");
+                if let Some(key) = cache_key.filter(|_| finished) {
+                    state.response_cache.put(key, text.clone(), usage.clone()).await;
+                }
+                (text, usage, tool_calls)
+            }
+        }
+        Err(e) => {
+            tracing::error!(request_id = %request_id, session_id = %session_id, model = %model, error = %e, "Generation failed");
+            let usage = Usage { finish_reason: Some("error".to_string()), ..Default::default() };
+            ("Inference failed".to_string(), usage, Vec::new())
+        }
+    };
+    let timings = timer.finish(usage.completion_tokens);
+    log_timings(&request_id, &session_id, &model, &usage, &timings);
+    let record = AuditRecord::new(
+        "/generate", &request_id, caller.owner(), Some(&session_id), &model, &req.prompt, &text, &usage, &timings);
+    finish_generation(&state, &record).await;
+
+    Ok(InferenceResponse {
+        text,
+        session_id: Some(session_id),
+        usage,
+        tool_calls,
+        timings: Some(timings),
+        cached: false,
     })
 }
 
 
+// 一次批量请求最多的 prompt 数
+const MAX_BATCH_PROMPTS: usize = 256;
+
+/// 批量生成：每个 prompt 单独通过推理队列生成（不使用 session），和其他请求轮流使用推理的名额，
+/// 最多同时生成 max_concurrent_inferences 个。返回按 prompts 顺序排列的数组；
+/// `"stream": true` 时每完成一项返回一行 JSON（application/x-ndjson），顺序为完成的顺序。
+/// 某一项失败不影响其他项。设置 callback_url 时返回 202，全部完成后以 batch.completed 事件发送结果
+#[utoipa::path(post, path = "/generate/batch", tag = "generation",
+    request_body = BatchRequest,
+    responses(
+        (status = 200, description = "The items in prompt order, or with stream=true one BatchItem per line as they finish (application/x-ndjson)", body = Vec<BatchItem>),
+        (status = 202, description = "Queued; the items are sent to callback_url as a signed WebhookPayload", body = AcceptedResponse),
+        (status = 400, description = "No or too many prompts, an empty prompt, unknown model, example set, invalid sampling parameter or callback_url", body = ValidationError),
+        (status = 400, description = "A prompt blocked by content moderation", body = ModerationError),
+        (status = 413, description = "A prompt too long", body = PromptTooLongError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn batch_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<BatchRequest>,
+) -> Result<Response, Response> {
+    let request_id = request_id_string(&request_id);
+    if req.prompts.is_empty() || req.prompts.len() > MAX_BATCH_PROMPTS {
+        return Err(invalid("prompts", format!("prompts must have 1 to {} items", MAX_BATCH_PROMPTS)));
+    }
+    let mut model = String::new();
+    for prompt in &req.prompts {
+        model = validate_generation(&state, &req.model, "prompts", prompt, &req.generation).await?;
+    }
+    for prompt in &req.prompts {
+        moderate_prompt(&state, prompt, &request_id, caller.owner(), None).await?;
+    }
+    check_quota(&state, caller.owner())?;
+    let callback_url = check_callback_url(&state, req.callback_url.as_deref())?;
+    if callback_url.is_some() && req.stream {
+        return Err(invalid("callback_url", "callback_url cannot be combined with stream"));
+    }
+    let examples = select_examples(&state, &caller, req.examples.as_deref())?;
+
+    // 整个批量请求在队列中占一个位置
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+    let generation_config = limit_generation(&state, &model, &req.generation).await;
+    let conversations: Vec<Vec<ChatMessage>> = req.prompts.into_iter()
+        .map(|prompt| {
+            let mut messages = Vec::new();
+            if let Some(system_prompt) = &req.system_prompt {
+                messages.push(ChatMessage::new(MessageRole::System, system_prompt.clone()));
+            }
+            messages.push(ChatMessage::new(MessageRole::User, prompt));
+            insert_examples(&mut messages, &examples);
+            messages
+        })
+        .collect();
+    tracing::info!(request_id = %request_id, model = %model, prompts = conversations.len(), "Batch started");
+
+    if let Some(callback_url) = callback_url {
+        let accepted = AcceptedResponse { request_id: request_id.clone(), session_id: None };
+        tokio::spawn(async move {
+            let results = run_batch(&state, &ticket, &caller, &request_id, &model, conversations, &generation_config).await;
+            let payload = WebhookPayload::completed("batch.completed", &request_id, &results);
+            state.webhooks.deliver(&callback_url, &payload).await;
+        }.in_current_span());
+        return Ok((StatusCode::ACCEPTED, Json(accepted)).into_response());
+    }
+    if !req.stream {
+        let results = run_batch(&state, &ticket, &caller, &request_id, &model, conversations, &generation_config).await;
+        return Ok(Json(results).into_response());
+    }
+
+    let concurrency = state.inference_queue.max_concurrent();
+    let (tx, rx) = tokio::sync::mpsc::channel::<String>(sse::buffer_size(&state.config));
+    tokio::spawn(async move {
+        let items = conversations.into_iter()
+            .enumerate()
+            .map(|(index, messages)| {
+                batch_item(&state, &ticket, &caller, &request_id, &model, index, messages, &generation_config)
+            });
+        // 客户端断开时丢弃还没完成的项
+        let results = futures::StreamExt::buffer_unordered(tokio_stream::iter(items), concurrency);
+        tokio::pin!(results);
+        while let Some(item) = results.next().await {
+            if tx.send(ndjson(&item)).await.is_err() {
+                tracing::info!(request_id = %request_id, "Batch client disconnected");
+                break;
+            }
+        }
+    }.in_current_span());
+
+    let body = axum::body::Body::from_stream(
+        tokio_stream::wrappers::ReceiverStream::new(rx).map(Ok::<_, std::convert::Infallible>));
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+// 生成批量请求的所有项，按 prompts 的顺序返回
+async fn run_batch(
+    state: &AppState,
+    ticket: &QueueTicket,
+    caller: &Caller,
+    request_id: &str,
+    model: &str,
+    conversations: Vec<Vec<ChatMessage>>,
+    generation_config: &GenerationConfig,
+) -> Vec<BatchItem> {
+    let items = conversations.into_iter()
+        .enumerate()
+        .map(|(index, messages)| {
+            batch_item(state, ticket, caller, request_id, model, index, messages, generation_config)
+        });
+    let mut results: Vec<BatchItem> =
+        futures::StreamExt::buffer_unordered(tokio_stream::iter(items), state.inference_queue.max_concurrent())
+            .collect()
+            .await;
+    results.sort_by_key(|item| item.index);
+    results
+}
+
+// 批量请求中的一项：命中 response cache 时直接返回，否则等待推理的名额后生成
+#[allow(clippy::too_many_arguments)]
+async fn batch_item(
+    state: &AppState,
+    ticket: &QueueTicket,
+    caller: &Caller,
+    request_id: &str,
+    model: &str,
+    index: usize,
+    messages: Vec<ChatMessage>,
+    generation_config: &GenerationConfig,
+) -> BatchItem {
+    let mut timer = GenerationTimer::new();
+    let prompt = last_user_prompt(&messages).to_string();
+    let cache_key = (state.response_cache.enabled() && response_cache::cacheable(generation_config))
+        .then(|| response_cache::cache_key(caller.tenant(), model, &messages, generation_config));
+
+    let cached = match &cache_key {
+        Some(key) => state.response_cache.get(key).await,
+        None => None,
+    };
+    if let Some((text, usage)) = cached {
+        let record = AuditRecord::new(
+            "/generate/batch", request_id, caller.owner(), None, model, &prompt, &text, &usage, &timer.finish(0));
+        state.audit_log.record(&record).await;
+        return BatchItem { index, text, error: None, usage, cached: true };
+    }
+
+    let _permit = wait_turn(ticket, generation_config).await;
+    timer.start();
+    let (text, usage, error) = match generation_step(state, model, &messages, generation_config).await {
+        Ok((text, mut usage)) => {
+            if moderate(state, ModerationTarget::Output, &text, request_id, caller.owner(), None).await.is_some() {
+                usage.finish_reason = Some("content_filter".to_string());
+                (String::new(), usage, None)
+            } else {
+                if let Some(key) = cache_key {
+                    state.response_cache.put(key, text.clone(), usage.clone()).await;
+                }
+                (text, usage, None)
+            }
+        }
+        Err(e) => {
+            tracing::error!(request_id, index, model, error = %e, "Batch item failed");
+            (String::new(), Usage::default(), Some(e.to_string()))
+        }
+    };
+
+    let timings = timer.finish(usage.completion_tokens);
+    let record = AuditRecord::new(
+        "/generate/batch", request_id, caller.owner(), None, model, &prompt, &text, &usage, &timings);
+    finish_generation(state, &record).await;
+    BatchItem { index, text, error, usage, cached: false }
+}
+
+
+// 每次生成结束后输出一行 key=value 格式的计时日志
+fn log_timings(request_id: &str, session_id: &str, model: &str, usage: &Usage, timings: &Timings) {
+    tracing::info!(
+        request_id,
+        session_id,
+        model,
+        queue_ms = timings.queue_ms,
+        ttft_ms = timings.ttft_ms,
+        duration_ms = timings.total_ms,
+        prompt_tokens = usage.prompt_tokens,
+        completion_tokens = usage.completion_tokens,
+        total_tokens = usage.total_tokens,
+        tokens_per_second = timings.tokens_per_second,
+        finish_reason = usage.finish_reason.as_deref(),
+        "generation finished",
+    );
+}
+
+
+fn tool_error(error: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(ToolError { error })).into_response()
+}
+
+
+// 请求引用的调用方租户的 few-shot 示例组，不存在时返回 400
+fn select_examples(state: &AppState, caller: &Caller, name: Option<&str>) -> Result<Vec<Example>, Response> {
+    let Some(name) = name else {
+        return Ok(Vec::new());
+    };
+    state.few_shot.get(caller.tenant(), name)
+        .ok_or_else(|| invalid("examples", format!("Unknown example set \"{}\"", name)))
+}
+
+
+// 一次请求中最多执行的工具调用轮数，之后模型的回复按普通文本返回
+const MAX_TOOL_ROUNDS: usize = 4;
+
+/// 非流式生成：模型调用工具时执行工具，把结果加入对话后继续生成，直到模型给出回答
+#[allow(clippy::too_many_arguments)]
+async fn collect_with_tools(
+    state: &AppState,
+    session_id: &str,
+    model: &str,
+    config: &SessionConfig,
+    messages: Vec<ChatMessage>,
+    mut images: Vec<Vec<u8>>,
+    generation_config: &GenerationConfig,
+    tools: &[Arc<dyn Tool>],
+) -> anyhow::Result<(String, Usage, Vec<ToolInvocation>)> {
+    let mut messages = with_tool_prompt(&messages, tools);
+    let mut usage = Usage::default();
+    let mut invocations = Vec::new();
+
+    loop {
+        // 图片只随第一轮发送
+        let (text, round_usage) = run_inference_collect(
+            &state.model_cache,
+            &state.registry,
+            &state.config.model_dir,
+            model,
+            &messages,
+            std::mem::take(&mut images),
+            generation_config,
+        ).await?;
+        usage.add(&round_usage);
+
+        let call = (!tools.is_empty() && invocations.len() < MAX_TOOL_ROUNDS)
+            .then(|| parse_tool_call(&text))
+            .flatten();
+        let Some((before, call)) = call else {
+            return Ok((text, usage, invocations));
+        };
+
+        let (output, success) = run_tool(tools, &call, tool_timeout(&state.config)).await;
+        tracing::info!(session_id = %session_id, tool = %call.name, success, "Tool called");
+        record_tool_round(state, session_id, config, &before, &call, &output, &mut messages).await;
+        invocations.push(ToolInvocation {
+            name: call.name,
+            arguments: call.arguments,
+            output,
+            success,
+        });
+    }
+}
+
+
+/// 把一次工具调用（模型的调用和工具的结果）写入 session，并加入本次生成的对话
+async fn record_tool_round(
+    state: &AppState,
+    session_id: &str,
+    config: &SessionConfig,
+    before: &str,
+    call: &ToolCall,
+    output: &str,
+    messages: &mut Vec<ChatMessage>,
+) {
+    let call_text = format!("{}\n{}", before, call.render()).trim_start().to_string();
+    let result = tool_result_message(call, output);
+
+    SessionHelper::with_session(&state.session_manager, session_id, config.clone(), |session| {
+        session.add_assistant_message(call_text.clone());
+        session.add_user_message(result.content.clone());
+    }).await;
+
+    messages.push(ChatMessage::new(MessageRole::Assistant, call_text));
+    messages.push(result);
+}
+
+
+fn session_not_found(session_id: &str) -> Response {
+    (StatusCode::NOT_FOUND, Json(SessionNotFoundError {
+        error: "Session does not exist".to_string(),
+        session_id: session_id.to_string(),
+    })).into_response()
+}
+
+
+// 使用或创建 session 之前检查归属，其他用户的 session 视为不存在
+async fn claim_session(state: &AppState, caller: &Caller, session_id: &str) -> Result<(), Response> {
+    let (owned, created) = SessionHelper::claim_or_create(&state.session_manager, session_id, caller.owner()).await;
+    if created {
+        state.events.publish(Event::new(
+            SESSION_CREATED, caller.owner(), caller.tenant(), serde_json::json!({ "session_id": session_id })));
+    }
+    if owned {
+        Ok(())
+    } else {
+        Err(session_not_found(session_id))
+    }
+}
+
+
+// 文件不在本实例的缓存中时从存储中读取。使用共享存储时总是以存储中的为准：
+// 其他实例上传、解析完成或删除的文件在这里同步到本实例的缓存
+async fn ensure_cached(state: &AppState, file_id: &str) {
+    // 不是 UUID 的 file_id 不会存在，也不能拿去访问存储
+    if !valid_file_id(file_id) {
+        return;
+    }
+    let shared = state.file_store.shared();
+    if !shared && state.file_cache.read().await.contains_key(file_id) {
+        return;
+    }
+
+    match state.file_store.load(file_id).await {
+        Ok(Some(mut file)) => {
+            let mut cache = state.file_cache.write().await;
+            // 检索索引只在本实例的内存中
+            if let Some(local) = cache.get(file_id) {
+                file.indexed = local.indexed;
+            }
+            cache.insert(file_id.to_string(), file);
+        }
+        Ok(None) if shared => forget_file(state, file_id).await,
+        Ok(None) => {}
+        Err(e) => tracing::error!(file_id = %file_id, error = %e, "Failed to load file from store"),
+    }
+}
+
+
+// 使用共享存储时，用存储中的文件列表更新本实例的缓存
+async fn sync_file_cache(state: &AppState) {
+    if !state.file_store.shared() {
+        return;
+    }
+    let mut stored = match state.file_store.load_all().await {
+        Ok(stored) => stored,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to list files in store, using local files");
+            return;
+        }
+    };
+
+    let removed: Vec<String> = {
+        let mut cache = state.file_cache.write().await;
+        for (file_id, file) in stored.iter_mut() {
+            file.indexed = cache.get(file_id).is_some_and(|local| local.indexed);
+        }
+        let removed = cache.keys().filter(|file_id| !stored.contains_key(*file_id)).cloned().collect();
+        *cache = stored;
+        removed
+    };
+    for file_id in &removed {
+        forget_file(state, file_id).await;
+    }
+}
+
+
+// 从本实例的缓存和检索索引中删除文件（不删除存储中的文件）
+async fn forget_file(state: &AppState, file_id: &str) {
+    state.file_cache.write().await.remove(file_id);
+    for chunks in state.vector_index.write().await.values_mut() {
+        chunks.retain(|chunk| chunk.file_id != file_id);
+    }
+}
+
+
+// 文件只对上传者所在租户的用户可见，其他租户访问时和文件不存在一样
+fn file_visible(file: &CacheFile, caller: &Caller) -> bool {
+    file.tenant.as_deref() == caller.tenant()
+}
+
+
+// 请求中 file_ids 引用的文件必须存在
+async fn check_file_ids(state: &AppState, caller: &Caller, file_ids: &[String]) -> Result<(), Response> {
+    for file_id in file_ids {
+        ensure_cached(state, file_id).await;
+    }
+    let cache = state.file_cache.read().await;
+
+    match file_ids.iter().find(|id| !cache.get(*id).is_some_and(|file| file_visible(file, caller))) {
+        Some(file_id) => Err((StatusCode::NOT_FOUND,
+            Json(FileNotFoundError {
+                error: "File does not exist".to_string(),
+                file_id: file_id.clone(),
+            })).into_response()),
+        None => Ok(()),
+    }
+}
+
+
+fn image_error(status: StatusCode, error: String, image_id: &str) -> Response {
+    (status, Json(ImageError { error, image_id: image_id.to_string() })).into_response()
+}
+
+
+// 读取 image_ids 对应的图片字节
+async fn load_images(state: &AppState, caller: &Caller, image_ids: &[String]) -> Result<Vec<Vec<u8>>, Response> {
+    let mut images = Vec::with_capacity(image_ids.len());
+
+    for image_id in image_ids {
+        ensure_cached(state, image_id).await;
+        let is_image = state.file_cache.read().await
+            .get(image_id)
+            .filter(|file| file_visible(file, caller))
+            .map(|file| is_image_extension(&file.extension));
+
+        match is_image {
+            None => return Err((StatusCode::NOT_FOUND,
+                Json(FileNotFoundError {
+                    error: "File does not exist".to_string(),
+                    file_id: image_id.clone(),
+                })).into_response()),
+            Some(false) => return Err(image_error(
+                StatusCode::BAD_REQUEST, "File is not an image".to_string(), image_id)),
+            Some(true) => {}
+        }
+
+        match state.file_store.load_image(image_id).await {
+            Ok(bytes) => images.push(bytes),
+            Err(e) => return Err(image_error(
+                StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read image: {}", e), image_id)),
+        }
+    }
+
+    Ok(images)
+}
+
+
+/// 把用户 prompt 加入 session，返回发送给模型的完整对话和 session 配置。
+/// 请求带 system_prompt 时先替换 session 的系统消息。相关的文件内容和 few-shot 示例只放进本次发送的对话，不保存到 session
+#[allow(clippy::too_many_arguments)]
+async fn prepare_conversation(
+    state: &AppState,
+    session_id: &str,
+    tenant: Option<&str>,
+    model: &str,
+    generation_config: &GenerationConfig,
+    system_prompt: Option<String>,
+    user_prompt: String,
+    file_ids: &[String],
+    examples: Vec<Example>,
+) -> (Vec<ChatMessage>, SessionConfig) {
+    // 使用 session 已保存的配置（通过 PUT /sessions/{id}/config 设置）
+    let config = SessionHelper::get_config(&state.session_manager, session_id).await;
+
+    let snapshot = SessionHelper::get_or_create(
+        &state.session_manager,
+        session_id,
+        config.clone()
+    ).await;
+
+    // 如果有文件，先准备文件内容（按当前对话长度截断），之后作为单独的 user message 放在本次 prompt 之前
+    let file_context = match build_file_context(state, session_id, tenant, &user_prompt, file_ids).await {
+        Some(mut excerpts) => {
+            // 请求带的系统提示与 session 的不同时会替换它，这里按两者都在计算，宁可多截一点
+            let mut conversation = snapshot.conversation();
+            if let Some(system_prompt) = system_prompt.as_ref()
+                .filter(|prompt| snapshot.config.system_prompt.as_ref() != Some(*prompt)) {
+                conversation.insert(0, ChatMessage::new(MessageRole::System, system_prompt.clone()));
+            }
+            fit_file_context(state, model, generation_config, &conversation, &user_prompt, &mut excerpts).await;
+            Some(render_file_context(&excerpts))
+        }
+        None => None,
+    };
+
+    // 在 session 锁内一次完成修改，同一 session 的并发请求不会互相覆盖
+    let (mut messages, config) = SessionHelper::with_session(&state.session_manager, session_id, config, |session| {
+        // 新的 session 带上用户的长期记忆
+        if session.memory.is_none() && session.message_count() == 0 {
+            session.memory = memory_prompt(&state.memory.get(session.owner.as_deref()));
+        }
+
+        if let Some(system_prompt) = system_prompt {
+            if session.config.system_prompt.as_deref() != Some(system_prompt.as_str()) {
+                session.set_system_prompt(system_prompt);
+            }
+        }
+
+        // 添加用户的实际 prompt
+        session.add_user_message(user_prompt);
+
+        (session.conversation(), session.config.clone())
+    }).await;
+
+    // 文件内容只属于这一轮，之后的请求重新检索
+    if let Some(file_context) = file_context {
+        tracing::debug!(session_id = %session_id, bytes = file_context.len(), "Adding file context to conversation");
+        let idx = messages.len().saturating_sub(1);
+        messages.insert(idx, ChatMessage::new(MessageRole::User, file_context));
+    }
+
+    if !examples.is_empty() {
+        let examples = fit_examples(state, model, generation_config, &messages, examples).await;
+        insert_examples(&mut messages, &examples);
+    }
+
+    tracing::debug!(
+        session_id = %session_id,
+        messages = messages.len(),
+        chars = messages.iter().map(|msg| msg.content.len()).sum::<usize>(),
+        "Conversation prepared",
+    );
+
+    (messages, config)
+}
+
+
+// 生成结束后把模型回复写回 session，第一轮对话结束后在后台生成标题，有消息被裁剪时在后台更新摘要，
+// 开启 memory_extraction 时在后台从用户的问题中提取长期记忆
+async fn save_assistant_message(
+    state: &AppState,
+    session_id: &str,
+    model: &str,
+    config: SessionConfig,
+    text: String,
+) {
+    if text.is_empty() {
+        return;
+    }
+
+    let (title_prompt, summary_prompt, memory_source) = SessionHelper::with_session(
+        &state.session_manager, session_id, config, |session| {
+            let memory_source = state.config.memory_extraction
+                .then(|| session.messages.iter().rev()
+                    .find(|m| m.role == MessageRole::User && !m.content.starts_with("<tool_result")))
+                .flatten()
+                .map(|m| (session.owner.clone(), m.content.clone()));
+
+            session.add_assistant_message(text);
+            (session.title_prompt(), session.summary_prompt(), memory_source)
+        }).await;
+
+    if let Some(messages) = title_prompt {
+        spawn_title_generation(state.clone(), session_id.to_string(), model.to_string(), messages);
+    }
+    if let Some((messages, summarized)) = summary_prompt {
+        spawn_summarization(state.clone(), session_id.to_string(), model.to_string(), messages, summarized);
+    }
+    if let Some((owner, user_message)) = memory_source {
+        spawn_memory_extraction(state.clone(), owner, model.to_string(), user_message);
+    }
+}
+
+
+// 标题只需要几个词
+const TITLE_MAX_TOKENS: usize = 16;
+const SUMMARY_MAX_TOKENS: usize = 320;
+const MEMORY_MAX_TOKENS: usize = 128;
+
+/// 在后台用同一个模型做一次短的生成（标题、摘要）。和普通请求一样经过推理队列，
+/// 队列已满时返回 None，由调用方在下一轮对话后再试
+async fn background_generation(
+    state: &AppState,
+    model: &str,
+    messages: &[ChatMessage],
+    max_tokens: usize,
+) -> Option<anyhow::Result<String>> {
+    let ticket = state.inference_queue.enter()?;
+    let _permit = ticket.wait().await;
+
+    let generation_config = GenerationConfig {
+        temperature: Some(0.3),
+        max_tokens: Some(max_tokens),
+        ..Default::default()
+    };
+    let result = run_inference_collect(
+        &state.model_cache,
+        &state.registry,
+        &state.config.model_dir,
+        model,
+        messages,
+        Vec::new(),
+        &generation_config,
+    ).await;
+
+    Some(result.map(|(text, _)| text))
+}
+
+fn spawn_title_generation(state: AppState, session_id: String, model: String, messages: Vec<ChatMessage>) {
+    tokio::spawn(async move {
+        match background_generation(&state, &model, &messages, TITLE_MAX_TOKENS).await {
+            Some(Ok(text)) => {
+                if let Some(title) = clean_title(&text) {
+                    tracing::info!(session_id = %session_id, title = %title, "Session titled");
+                    SessionHelper::set_title(&state.session_manager, &session_id, title).await;
+                }
+            }
+            Some(Err(e)) => tracing::warn!(session_id = %session_id, error = %e, "Title generation failed"),
+            None => {}
+        }
+    });
+}
+
+/// 把裁剪掉的消息合并进 session 的摘要（summarize_history 模式）
+fn spawn_summarization(
+    state: AppState,
+    session_id: String,
+    model: String,
+    messages: Vec<ChatMessage>,
+    summarized: usize,
+) {
+    tokio::spawn(async move {
+        match background_generation(&state, &model, &messages, SUMMARY_MAX_TOKENS).await {
+            Some(Ok(text)) if !text.trim().is_empty() => {
+                tracing::info!(session_id = %session_id, messages = summarized, "Session summary updated");
+                SessionHelper::apply_summary(&state.session_manager, &session_id, text.trim().to_string(), summarized).await;
+            }
+            Some(Err(e)) => tracing::warn!(session_id = %session_id, error = %e, "Summarization failed"),
+            Some(Ok(_)) | None => {}
+        }
+    });
+}
+
+/// 从用户的问题中提取长期记忆，合并到该用户的记忆中
+fn spawn_memory_extraction(state: AppState, owner: Option<String>, model: String, user_message: String) {
+    tokio::spawn(async move {
+        let messages = extraction_prompt(&state.memory.get(owner.as_deref()), &user_message);
+        match background_generation(&state, &model, &messages, MEMORY_MAX_TOKENS).await {
+            Some(Ok(text)) => {
+                let changed = state.memory.remember(owner.as_deref(), parse_facts(&text)).await;
+                if changed > 0 {
+                    tracing::info!(facts = changed, "Remembered facts about the user");
+                }
+            }
+            Some(Err(e)) => tracing::warn!(error = %e, "Memory extraction failed"),
+            None => {}
+        }
+    });
+}
+
+#[utoipa::path(post, path = "/generate/stream", tag = "generation",
+    request_body = InferenceRequest,
+    responses(
+        (status = 200, description = "SSE stream, see StreamEvent for the events", content_type = "text/event-stream", body = StreamEvent),
+        (status = 400, description = "Empty prompt, unknown model or invalid sampling parameter", body = ValidationError),
+        (status = 400, description = "Prompt blocked by content moderation", body = ModerationError),
+        (status = 413, description = "Prompt too long", body = PromptTooLongError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn infer_stream_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<InferenceRequest>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, Response>
+{
+    // 在修改 session 之前校验请求、检查队列，被拒绝的请求不会留下用户消息
+    let model = validate_generation(&state, &req.model, "prompt", &req.prompt, &req.generation_config()).await?;
+    moderate_prompt(&state, &req.prompt, &request_id_string(&request_id), caller.owner(), req.session_id.as_deref()).await?;
+    check_quota(&state, caller.owner())?;
+    let tools = select_tools(&req.tools, &state.config).map_err(tool_error)?;
+    let examples = select_examples(&state, &caller, req.examples.as_deref())?;
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+
+    let generation_config = limit_generation(&state, &model, &req.generation_config()).await;
+    let user_prompt = req.prompt;
+
+    let session_id = req.session_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    check_file_ids(&state, &caller, &req.file_ids).await?;
+    let images = load_images(&state, &caller, &req.image_ids).await?;
+    claim_session(&state, &caller, &session_id).await?;
+
+    let (messages, config) = prepare_conversation(
+        &state, &session_id, caller.tenant(), &model, &generation_config, req.system_prompt, user_prompt, &req.file_ids, examples).await;
+
+    let request_id = request_id_string(&request_id);
+    Ok(stream_generation(
+        state, ticket, "/generate/stream", request_id, caller.owner, session_id, model, messages, config, images, generation_config, tools).await)
+}
+
+
+// 登记一个可以取消的生成。客户端重复使用了仍在进行中的 x-request-id 时改用新的 id，
+// 客户端从 request 事件中得到实际的 id
+async fn register_generation(state: &AppState, request_id: String, cancel: CancellationToken) -> String {
+    let mut active = state.active_generations.write().await;
+    let request_id = if active.contains_key(&request_id) {
+        uuid::Uuid::new_v4().to_string()
+    } else {
+        request_id
+    };
+    active.insert(request_id.clone(), cancel);
+    request_id
+}
+
+
+/// 在后台生成回答并以 SSE 推送（事件见 [`StreamEvent`]），结束后把回答写回 session。
+/// 启用工具时模型的工具调用不作为 token 输出，执行后发送 tool_call / tool_result 事件并继续生成
+#[allow(clippy::too_many_arguments)]
+async fn stream_generation(
+    state: AppState,
+    ticket: QueueTicket,
+    endpoint: &'static str,
+    request_id: String,
+    owner: Option<String>,
+    session_id: String,
+    model: String,
+    messages: Vec<ChatMessage>,
+    config: SessionConfig,
+    mut images: Vec<Vec<u8>>,
+    generation_config: GenerationConfig,
+    tools: Vec<Arc<dyn Tool>>,
+) -> Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<StreamEvent>(sse::buffer_size(&state.config));
+    let mut timer = GenerationTimer::new();
+
+    let task_state = state.clone();
+    let model_cache = state.model_cache.clone();
+    let registry = state.registry.clone();
+    let model_dir = state.config.model_dir.clone();
+    let session_id_clone = session_id.clone();
+    let cancel_token = CancellationToken::new();
+    let prompt = last_user_prompt(&messages).to_string();
+
+    // 使用本次请求的 x-request-id，可通过 POST /generate/cancel/{request_id} 取消
+    let request_id = register_generation(&state, request_id, cancel_token.clone()).await;
+    tracing::info!(request_id = %request_id, session_id = %session_id, model = %model, "Generation started");
+    let active_generations = state.active_generations.clone();
+
+    tokio::spawn(async move {
+        let mut full_response = String::new();
+        let mut usage: Option<Usage> = None;
+
+        let _ = tx.send(StreamEvent::Request { request_id: request_id.clone() }).await;
+
+        // 排队等待，期间客户端断开或取消则直接退出
+        let _permit = tokio::select! {
+            permit = wait_turn(&ticket, &generation_config) => permit,
+            _ = tx.closed() => {
+                active_generations.write().await.remove(&request_id);
+                return;
+            }
+            _ = cancel_token.cancelled() => {
+                active_generations.write().await.remove(&request_id);
+                let _ = tx.send(StreamEvent::Done { timings: None }).await;
+                return;
+            }
+        };
+        timer.start();
+
+        let mut messages = with_tool_prompt(&messages, &tools);
+        let mut tool_rounds = 0;
+        let mut timed_out = false;
+        let deadline = watchdog(&task_state);
+        tokio::pin!(deadline);
+
+        'rounds: loop {
+            let calls_allowed = !tools.is_empty() && tool_rounds < MAX_TOOL_ROUNDS;
+            let mut filter = ToolCallFilter::default();
+            let mut round_text = String::new();
+            // 已经作为 token 发送的长度，总是 round_text 的前缀
+            let mut sent = 0;
+            // 下一个 token 的 log probability（请求 logprobs 时）
+            let mut logprobs = None;
+            // 按 sse_coalesce_tokens / sse_coalesce_ms 把 token 合并后发送
+            let mut coalescer = TokenCoalescer::from_config(&task_state.config, generation_config.logprobs == Some(true));
+
+            // 图片只随第一轮发送
+            match run_inference_stream(
+                &model_cache,
+                &registry,
+                &model_dir,
+                &model,
+                &messages,
+                std::mem::take(&mut images),
+                &generation_config,
+                cancel_token.clone(),
+            ).await {
+                Ok(mut stream) => loop {
+                    tokio::select! {
+                        // 客户端断开连接（EventSource 关闭）时立即停止生成
+                        _ = tx.closed() => {
+                            tracing::info!(request_id = %request_id, session_id = %session_id_clone, "Client disconnected, cancelling generation");
+                            cancel_token.cancel();
+                            full_response = round_text;
+                            break 'rounds;
+                        }
+                        _ = &mut deadline => {
+                            tracing::warn!(request_id = %request_id, session_id = %session_id_clone, model = %model, "Generation timed out");
+                            cancel_token.cancel();
+                            timed_out = true;
+                            if let Some(content) = coalescer.flush() {
+                                let _ = tx.send(StreamEvent::Token { content, logprobs: None }).await;
+                            }
+                            full_response = round_text;
+                            break 'rounds;
+                        }
+                        // 合并的 token 等待超过 sse_coalesce_ms 时发出，不等下一个 token
+                        _ = flush_timer(coalescer.deadline()) => {
+                            if let Some(content) = coalescer.flush() {
+                                if tx.send(StreamEvent::Token { content, logprobs: None }).await.is_err() {
+                                    cancel_token.cancel();
+                                    full_response = round_text;
+                                    break 'rounds;
+                                }
+                            }
+                        }
+                        chunk = stream.next() => {
+                            match chunk {
+                                Some(StreamChunk::Token(token)) => {
+                                    timer.first_token();
+                                    round_text.push_str(&token);
+                                    let visible = if calls_allowed { filter.push(&token) } else { token };
+                                    if visible.is_empty() {
+                                        continue;
+                                    }
+                                    sent += visible.len();
+                                    let Some(content) = coalescer.push(&visible) else {
+                                        continue;
+                                    };
+                                    let event = StreamEvent::Token { content, logprobs: logprobs.take() };
+                                    if tx.send(event).await.is_err() {
+                                        cancel_token.cancel();
+                                        full_response = round_text;
+                                        break 'rounds;
+                                    }
+                                }
+                                Some(StreamChunk::Logprobs(token_logprobs)) => {
+                                    logprobs = Some(token_logprobs);
+                                }
+                                Some(StreamChunk::Usage(chunk_usage)) => {
+                                    usage.get_or_insert_with(Usage::default).add(&chunk_usage);
+                                }
+                                // 已生成的部分不写入 session
+                                Some(StreamChunk::Error(error)) => {
+                                    tracing::error!(request_id = %request_id, session_id = %session_id_clone, model = %model, error = %error, "Generation failed");
+                                    if let Some(content) = coalescer.flush() {
+                                        let _ = tx.send(StreamEvent::Token { content, logprobs: None }).await;
+                                    }
+                                    let _ = tx.send(StreamEvent::Error {
+                                        error,
+                                        request_id: request_id.clone(),
+                                        finish_reason: None,
+                                    }).await;
+                                    break 'rounds;
+                                }
+                                None => {
+                                    if let Some(content) = coalescer.flush() {
+                                        let _ = tx.send(StreamEvent::Token { content, logprobs: None }).await;
+                                    }
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                },
+                Err(e) => {
+                    tracing::error!(request_id = %request_id, session_id = %session_id_clone, model = %model, error = %e, "Generation failed");
+                    let _ = tx.send(StreamEvent::Error {
+                        error: e.to_string(),
+                        request_id: request_id.clone(),
+                        finish_reason: None,
+                    }).await;
+                    break;
+                }
+            }
+
+            let call = (calls_allowed && !cancel_token.is_cancelled())
+                .then(|| parse_tool_call(&round_text))
+                .flatten();
+            let Some((before, call)) = call else {
+                // 不是工具调用：把过滤时保留的文本发出去
+                if sent < round_text.len() {
+                    let _ = tx.send(StreamEvent::Token { content: round_text[sent..].to_string(), logprobs: None }).await;
+                }
+                full_response = round_text;
+                break;
+            };
+
+            tool_rounds += 1;
+            let _ = tx.send(StreamEvent::ToolCall {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            }).await;
+            let (output, success) = run_tool(&tools, &call, tool_timeout(&task_state.config)).await;
+            tracing::info!(session_id = %session_id_clone, tool = %call.name, success, "Tool called");
+            let _ = tx.send(StreamEvent::ToolResult {
+                name: call.name.clone(),
+                output: output.clone(),
+                success,
+            }).await;
+
+            record_tool_round(&task_state, &session_id_clone, &config, &before, &call, &output, &mut messages).await;
+        }
+
+        if timed_out {
+            let _ = tx.send(StreamEvent::Error {
+                error: timeout_message(&task_state),
+                request_id: request_id.clone(),
+                finish_reason: Some("timeout".to_string()),
+            }).await;
+        }
+
+        // token 已经发出，被拦截的回答只能事后告知客户端，并且不写入 session
+        let blocked = moderate(
+            &task_state, ModerationTarget::Output, &full_response, &request_id, owner.as_deref(), Some(&session_id_clone)).await;
+        if let Some(reasons) = blocked {
+            let _ = tx.send(StreamEvent::Error {
+                error: format!("Response was blocked by content moderation ({})", reasons.join(", ")),
+                request_id: request_id.clone(),
+                finish_reason: Some("content_filter".to_string()),
+            }).await;
+        } else {
+            save_assistant_message(&task_state, &session_id_clone, &model, config, full_response.clone()).await;
+        }
+
+        let completion_tokens = usage.as_ref().map_or(0, |usage| usage.completion_tokens);
+        let timings = timer.finish(completion_tokens);
+        log_timings(&request_id, &session_id_clone, &model, usage.as_ref().unwrap_or(&Usage::default()), &timings);
+        let record = AuditRecord::new(
+            endpoint, &request_id, owner.as_deref(), Some(&session_id_clone), &model, &prompt, &full_response,
+            usage.as_ref().unwrap_or(&Usage::default()), &timings);
+        finish_generation(&task_state, &record).await;
+
+        if let Some(usage) = usage {
+            let _ = tx.send(StreamEvent::Usage(usage)).await;
+        }
+
+        let _ = tx.send(StreamEvent::Session { session_id: session_id_clone }).await;
+
+        let _ = tx.send(StreamEvent::Done { timings: Some(timings) }).await;
+
+        active_generations.write().await.remove(&request_id);
+    }.in_current_span());
+
+    let sse_stream = tokio_stream::wrappers::ReceiverStream::new(rx)
+        .map(|event| Event::default().event(event.name()).json_data(&event));
+
+    Sse::new(sse_stream).keep_alive(sse::keep_alive(&state.config))
+}
+
+
+fn agent_error(error: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(AgentError { error })).into_response()
+}
+
+
+/// 运行一个 agent：模型反复思考、调用工具、查看结果，直到给出答案或用完 max_iterations。
+/// 每一步以 SSE 事件推送（见 [`AgentEvent`]），不使用 session
+#[utoipa::path(post, path = "/agent/run", tag = "generation",
+    request_body = AgentRunRequest,
+    responses(
+        (status = 200, description = "SSE stream, see AgentEvent for the events", content_type = "text/event-stream", body = AgentEvent),
+        (status = 400, description = "Invalid task, tools or max_iterations", body = AgentError),
+        (status = 413, description = "Task too long", body = PromptTooLongError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn agent_run_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<AgentRunRequest>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, Response> {
+    if req.task.trim().is_empty() {
+        return Err(agent_error("task must not be empty".to_string()));
+    }
+    let max_iterations = req.max_iterations.unwrap_or(DEFAULT_AGENT_ITERATIONS);
+    if !(1..=MAX_AGENT_ITERATIONS).contains(&max_iterations) {
+        return Err(agent_error(format!("max_iterations must be between 1 and {}", MAX_AGENT_ITERATIONS)));
+    }
+    let model = validate_generation(&state, &req.model, "task", &req.task, &req.generation).await?;
+    moderate_prompt(&state, &req.task, &request_id_string(&request_id), caller.owner(), None).await?;
+    check_quota(&state, caller.owner())?;
+    let tools = select_tools(&req.tools, &state.config).map_err(tool_error)?;
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+
+    let mut messages = agent_conversation(&req.task, req.system_prompt.as_deref(), &tools);
+    let generation_config = limit_generation(&state, &model, &req.generation).await;
+    let mut timer = GenerationTimer::new();
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<AgentEvent>(sse::buffer_size(&state.config));
+    let keep_alive = sse::keep_alive(&state.config);
+    let cancel_token = CancellationToken::new();
+    let request_id = register_generation(&state, request_id_string(&request_id), cancel_token.clone()).await;
+    tracing::info!(request_id = %request_id, model = %model, tools = tools.len(), max_iterations, "Agent run started");
+
+    tokio::spawn(async move {
+        let _ = tx.send(AgentEvent::Request { request_id: request_id.clone() }).await;
+
+        let permit = tokio::select! {
+            permit = wait_turn(&ticket, &generation_config) => Some(permit),
+            _ = tx.closed() => None,
+            _ = cancel_token.cancelled() => None,
+        };
+        timer.start();
+
+        let mut usage = Usage::default();
+        let mut iteration = 0;
+        let mut answer = String::new();
+        while permit.is_some() && iteration < max_iterations {
+            iteration += 1;
+
+            let generation = run_inference_collect(
+                &state.model_cache,
+                &state.registry,
+                &state.config.model_dir,
+                &model,
+                &messages,
+                Vec::new(),
+                &generation_config,
+            );
+            // 客户端断开或取消时停止，不再执行后面的步骤
+            let result = tokio::select! {
+                result = generation => result,
+                _ = tx.closed() => break,
+                _ = cancel_token.cancelled() => break,
+                _ = watchdog(&state) => {
+                    tracing::warn!(request_id = %request_id, "Agent run timed out");
+                    let _ = tx.send(AgentEvent::Error { error: timeout_message(&state), request_id: request_id.clone() }).await;
+                    break;
+                }
+            };
+            let text = match result {
+                Ok((text, round_usage)) => {
+                    usage.add(&round_usage);
+                    text
+                }
+                Err(e) => {
+                    tracing::error!(request_id = %request_id, error = %e, "Agent run failed");
+                    let _ = tx.send(AgentEvent::Error { error: e.to_string(), request_id: request_id.clone() }).await;
+                    break;
+                }
+            };
+
+            let (thought, call) = match next_step(&text) {
+                AgentStep::Act { thought, call } if iteration < max_iterations => (thought, call),
+                AgentStep::Act { thought, .. } => {
+                    // 用完迭代次数时模型仍要调用工具，把已有的思考作为结果
+                    answer = thought.clone();
+                    let _ = tx.send(AgentEvent::Final {
+                        content: thought, iterations: iteration, completed: false, usage: usage.clone(),
+                    }).await;
+                    break;
+                }
+                AgentStep::Finish { answer: final_answer } => {
+                    answer = final_answer.clone();
+                    let _ = tx.send(AgentEvent::Final {
+                        content: final_answer, iterations: iteration, completed: true, usage: usage.clone(),
+                    }).await;
+                    break;
+                }
+            };
+
+            if !thought.is_empty() {
+                let _ = tx.send(AgentEvent::Thought { iteration, content: thought.clone() }).await;
+            }
+            let _ = tx.send(AgentEvent::ToolCall {
+                iteration,
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            }).await;
+
+            let (output, success) = run_tool(&tools, &call, tool_timeout(&state.config)).await;
+            tracing::info!(request_id = %request_id, iteration, tool = %call.name, success, "Agent called tool");
+            let _ = tx.send(AgentEvent::ToolResult {
+                iteration,
+                name: call.name.clone(),
+                output: output.clone(),
+                success,
+            }).await;
+
+            let call_text = format!("{}\n{}", thought, call.render()).trim_start().to_string();
+            messages.push(ChatMessage::new(MessageRole::Assistant, call_text));
+            messages.push(tool_result_message(&call, &output));
+        }
+
+        let _ = tx.send(AgentEvent::Done {}).await;
+        state.active_generations.write().await.remove(&request_id);
+
+        if iteration > 0 {
+            let timings = timer.finish(usage.completion_tokens);
+            let record = AuditRecord::new(
+                "/agent/run", &request_id, caller.owner(), None, &model, &req.task, &answer, &usage, &timings);
+            finish_generation(&state, &record).await;
+        }
+    }.in_current_span());
+
+    let sse_stream = tokio_stream::wrappers::ReceiverStream::new(rx)
+        .map(|event| Event::default().event(event.name()).json_data(&event));
+
+    Ok(Sse::new(sse_stream).keep_alive(keep_alive))
+}
+
+
+fn message_error(e: SessionMessageError, session_id: &str, message_id: &str) -> Response {
+    let (status, error) = match e {
+        SessionMessageError::SessionNotFound => (StatusCode::NOT_FOUND, "Session does not exist"),
+        SessionMessageError::MessageNotFound => (StatusCode::NOT_FOUND, "Message does not exist"),
+        SessionMessageError::NotUserMessage => (StatusCode::BAD_REQUEST, "Only user messages can be edited"),
+    };
+    (status, Json(MessageError {
+        error: error.to_string(),
+        session_id: session_id.to_string(),
+        message_id: message_id.to_string(),
+    })).into_response()
+}
+
+
+/// 编辑一条 user message：替换内容、删除之后的消息，然后像 /generate/stream 一样流式生成新的回答
+#[utoipa::path(post, path = "/sessions/{session_id}/messages/{message_id}/edit", tag = "sessions",
+    params(("session_id" = String, Path), ("message_id" = String, Path)),
+    request_body = EditMessageRequest,
+    responses(
+        (status = 200, description = "SSE stream, see StreamEvent for the events", content_type = "text/event-stream", body = StreamEvent),
+        (status = 400, description = "Not a user message or invalid request", body = MessageError),
+        (status = 404, description = "Unknown session or message", body = MessageError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn edit_message_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    axum::extract::Path((session_id, message_id)): axum::extract::Path<(String, String)>,
+    Json(req): Json<EditMessageRequest>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, Response> {
+    if !SessionHelper::owns(&state.session_manager, &session_id, caller.owner()).await {
+        return Err(message_error(SessionMessageError::SessionNotFound, &session_id, &message_id));
+    }
+    let model = validate_generation(&state, &req.model, "content", &req.content, &req.generation).await?;
+    moderate_prompt(&state, &req.content, &request_id_string(&request_id), caller.owner(), Some(&session_id)).await?;
+    check_quota(&state, caller.owner())?;
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+
+    let session = SessionHelper::edit_message(&state.session_manager, &session_id, &message_id, req.content)
+        .await
+        .map_err(|e| message_error(e, &session_id, &message_id))?;
+    tracing::info!(session_id = %session_id, message_id = %message_id, "Message edited, regenerating");
+
+    let generation_config = limit_generation(&state, &model, &req.generation).await;
+    let messages = session.conversation();
+    let config = session.config.clone();
+
+    let request_id = request_id_string(&request_id);
+    Ok(stream_generation(
+        state, ticket, "/sessions/{session_id}/messages/{message_id}/edit", request_id, caller.owner, session_id, model,
+        messages, config, Vec::new(), generation_config, Vec::new()).await)
+}
+
+
+/// 取消正在进行的生成
+#[utoipa::path(post, path = "/generate/cancel/{request_id}", tag = "generation",
+    params(("request_id" = String, Path, description = "From the request event or the X-Request-Id header")),
+    responses(
+        (status = 200, body = CancelResponse),
+        (status = 404, description = "No running generation with this id", body = CancelRequestError),
+    ))]
+pub async fn cancel_handler(
+    State(state): State<AppState>,
+    axum::extract::Path(request_id): axum::extract::Path<String>,
+) -> Result<Json<CancelResponse>, (StatusCode, Json<CancelRequestError>)> {
+    match state.active_generations.read().await.get(&request_id) {
+        Some(token) => token.cancel(),
+        None => {
+            return Err((StatusCode::NOT_FOUND,
+                Json(CancelRequestError {
+                    error: "Request not found or already finished".to_string(),
+                    request_id,
+                })))
+        }
+    }
+
+    tracing::info!(request_id = %request_id, "Generation cancelled");
+
+    Ok(Json(CancelResponse {
+        request_id,
+        cancelled: true,
+    }))
+}
+
+
+/// 文件类型在 prompt 中的显示名称
+fn file_label(extension: &str) -> String {
+    match extension {
+        "txt" => "Text File".to_string(),
+        "md" => "Markdown File".to_string(),
+        "pdf" => "PDF File".to_string(),
+        "docx" => "Word Document".to_string(),
+        "pptx" => "PowerPoint".to_string(),
+        "xlsx" => "Excel Spreadsheet".to_string(),
+        "py" | "js" | "ts" | "jsx" | "tsx" | "vue" | "svelte" |
+        "rs" | "go" | "java" | "kt" | "scala" |
+        "c" | "cpp" | "cc" | "cxx" | "h" | "hpp" | "hxx" |
+        "cs" | "fs" | "rb" | "php" | "pl" | "pm" |
+        "swift" | "m" | "mm" | "r" | "R" | "jl" |
+        "lua" | "tcl" | "awk" | "sed" |
+        "hs" | "ml" | "elm" | "clj" | "cljs" | "ex" | "exs" |
+        "sh" | "bash" | "zsh" | "fish" | "bat" | "cmd" | "ps1" |
+        "sql" | "prisma" | "graphql" | "gql" |
+        "html" | "htm" | "css" | "scss" | "sass" | "less" |
+        "xml" | "xsl" | "xslt" |
+        "json" | "yaml" | "yml" | "toml" | "ini" | "cfg" | "conf" |
+        "log" | "env" | "makefile" | "cmake" | "dockerfile" |
+        "gitignore" | "editorconfig"
+        => format!("{} Code File", extension.to_uppercase()),
+        "wav" | "mp3" | "m4a" => "Audio Transcript".to_string(),
+        _ => "File".to_string(),
+    }
+}
+
+
+/// 构建文件内容的 prompt（如果该 session 有文件的话）
+///
+/// 新上传的文件先被切块并加入该 session 的向量索引，然后只取出与 query 最相关的
+/// top-k 个块，避免大文件撑爆上下文。索引会保留，后续提问仍能检索到之前的文件。
+async fn build_file_context(
+    state: &AppState,
+    session_id: &str,
+    tenant: Option<&str>,
+    query: &str,
+    file_ids: &[String],
+) -> Option<Vec<FileExcerpt>> {
+    {
+        let mut cache = state.file_cache.write().await;
+
+        let mut index = state.vector_index.write().await;
+        let session_chunks = index.entry(session_id.to_string()).or_default();
+
+        // 本 session 新上传的文件，以及请求中通过 file_ids 附加、尚未在本 session 索引中的文件
+        let pending: Vec<String> = cache.iter()
+            .filter(|(_, file)| !is_image_extension(&file.extension))
+            // 还在后台解析的文件在解析完成后的下一轮对话中加入
+            .filter(|(_, file)| file.status == FileStatus::Ready)
+            // 其他租户上传到这个 session id 的文件不会加入
+            .filter(|(_, file)| file.tenant.as_deref() == tenant)
+            .filter(|(id, file)| (file.session_id == session_id && !file.indexed) || file_ids.contains(id))
+            .filter(|(id, _)| !session_chunks.iter().any(|chunk| &chunk.file_id == *id))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for file_id in &pending {
+            if let Some(file) = cache.get_mut(file_id) {
+                let chunks = build_chunks(file_id, file, state.config.rag_chunk_size);
+                tracing::debug!(session_id, file_id = %file_id, chunks = chunks.len(), "Indexed file");
+                session_chunks.extend(chunks);
+
+                // 文件保留在缓存中以便查询元数据和重新附加，只是不再重复索引
+                if file.session_id == session_id {
+                    file.indexed = true;
+                }
+            }
+        }
+    }
+
+    let index = state.vector_index.read().await;
+    let chunks = match index.get(session_id) {
+        Some(chunks) if !chunks.is_empty() => chunks,
+        _ => {
+            tracing::debug!(session_id, "No files indexed for session");
+            return None;
+        }
+    };
+
+    let relevant = retrieve_top_k(chunks, query, state.config.rag_top_k);
+    let cache = state.file_cache.read().await;
+
+    // 同一文件的块合并在一起，方便按文件截断
+    let mut excerpts: Vec<FileExcerpt> = Vec::new();
+    for chunk in relevant {
+        let section = format!("{}\n{}\n\n",
+            chunk_heading(&chunk.extension, &chunk.filename, chunk.chunk_index), chunk.text);
+
+        match excerpts.iter_mut().find(|excerpt| excerpt.file_id == chunk.file_id) {
+            Some(excerpt) => excerpt.text.push_str(&section),
+            None => excerpts.push(FileExcerpt {
+                file_id: chunk.file_id.clone(),
+                uploaded_at: cache.get(&chunk.file_id).map(|file| file.uploaded_at).unwrap_or(0),
+                text: section,
+            }),
+        }
+    }
+
+    Some(excerpts)
+}
+
+
+// prompt 中每个文件块前的标题
+fn chunk_heading(extension: &str, filename: &str, chunk_index: usize) -> String {
+    format!("=== {}: {} (part {}) ===", file_label(extension), filename, chunk_index + 1)
+}
+
+
+fn render_file_context(excerpts: &[FileExcerpt]) -> String {
+    let mut file_context = String::from(
+        "I'm sharing the following excerpt(s) from my file(s) that are relevant to my question:\n\n");
+
+    for excerpt in excerpts {
+        file_context.push_str(&excerpt.text);
+        if !excerpt.text.ends_with("\n\n") {
+            file_context.push_str("\n\n");
+        }
+    }
+
+    file_context.push_str("Please refer to the above file content(s) when answering my questions.");
+    file_context
+}
+
+
+async fn count_tokens(engine: Option<&dyn InferenceEngine>, text: &str) -> usize {
+    match engine {
+        Some(engine) => engine.count_tokens(text).await.unwrap_or_else(|_| estimate_tokens(text)),
+        None => estimate_tokens(text),
+    }
+}
+
+
+// 为回复预留的 token 数（请求和模型注册表都没有设置 max_tokens 时）
+const DEFAULT_REPLY_TOKENS: usize = 512;
+
+// 对话模板给每条消息加上的角色标记和分隔符（如 ChatML 的 <|im_start|>user\n ... <|im_end|>\n）
+const TEMPLATE_TOKENS_PER_MESSAGE: usize = 8;
+
+// 模型上下文中除去回复之后可用于输入的 token 数。模型没有设置上下文长度时使用默认值
+async fn input_budget(state: &AppState, model: &str, generation_config: &GenerationConfig) -> (usize, usize) {
+    let (context_length, default_max_tokens) = match state.registry.read().await.get(model) {
+        Some(spec) => (spec.context_length, spec.defaults.max_tokens),
+        None => (0, None),
+    };
+    let context_length = if context_length > 0 { context_length } else { DEFAULT_CONTEXT_LENGTH };
+    let reply_tokens = generation_config.max_tokens
+        .or(default_max_tokens)
+        .unwrap_or(DEFAULT_REPLY_TOKENS);
+    (context_length, context_length.saturating_sub(reply_tokens))
+}
+
+/// 文件内容加上对话历史（包括系统消息）和对话模板超出模型上下文长度时，按文件截断文件内容。
+/// 模型已加载时用它的 tokenizer 计数，否则按字符数估算
+async fn fit_file_context(
+    state: &AppState,
+    model: &str,
+    generation_config: &GenerationConfig,
+    history: &[ChatMessage],
+    user_prompt: &str,
+    excerpts: &mut [FileExcerpt],
+) {
+    let (context_length, input_tokens) = input_budget(state, model, generation_config).await;
+    let engine = state.model_cache.read().await.get(model);
+
+    let mut conversation: String = history.iter().map(|message| message.content.as_str()).collect();
+    conversation.push_str(user_prompt);
+    // the history, the file context and the prompt, each wrapped by the chat template
+    let template_tokens = (history.len() + 2) * TEMPLATE_TOKENS_PER_MESSAGE;
+    let conversation_tokens = count_tokens(engine.as_deref(), &conversation).await + template_tokens;
+
+    let file_context = render_file_context(excerpts);
+    let context_chars = file_context.chars().count();
+    let context_tokens = count_tokens(engine.as_deref(), &file_context).await;
+
+    let budget = input_tokens.saturating_sub(conversation_tokens);
+    if context_tokens <= budget {
+        return;
+    }
+
+    // 用这段文件内容自身的 字符/token 比例把 token 预算换算成字符数
+    let excerpt_chars: usize = excerpts.iter().map(|excerpt| excerpt.text.chars().count()).sum();
+    let chars_per_token = context_chars as f64 / context_tokens.max(1) as f64;
+    let max_chars = ((budget as f64 * chars_per_token) as usize)
+        .saturating_sub(context_chars - excerpt_chars);
+
+    tracing::info!(model, context_tokens, budget, context_length, max_chars, "File context does not fit, truncating");
+    truncate_excerpts(excerpts, max_chars);
+}
+
+
+/// 对话历史（包括文件内容）和对话模板之外剩下的上下文放不下全部示例时，从后往前丢弃示例，不裁剪对话历史
+async fn fit_examples(
+    state: &AppState,
+    model: &str,
+    generation_config: &GenerationConfig,
+    conversation: &[ChatMessage],
+    mut examples: Vec<Example>,
+) -> Vec<Example> {
+    let (_, input_tokens) = input_budget(state, model, generation_config).await;
+    let engine = state.model_cache.read().await.get(model);
+
+    let template_tokens = conversation.len() * TEMPLATE_TOKENS_PER_MESSAGE;
+    let conversation: String = conversation.iter().map(|message| message.content.as_str()).collect();
+    let conversation_tokens = count_tokens(engine.as_deref(), &conversation).await + template_tokens;
+
+    // each example is a user and an assistant message
+    let mut example_tokens = Vec::with_capacity(examples.len());
+    for example in &examples {
+        let text = format!("{}{}", example.input, example.output);
+        example_tokens.push(count_tokens(engine.as_deref(), &text).await + 2 * TEMPLATE_TOKENS_PER_MESSAGE);
+    }
+
+    let budget = input_tokens.saturating_sub(conversation_tokens);
+    let kept = examples_within(&example_tokens, budget);
+    if kept < examples.len() {
+        tracing::info!(model, budget, kept, dropped = examples.len() - kept, "Few-shot examples do not fit, dropping");
+        examples.truncate(kept);
+    }
+    examples
+}
+
+
+fn is_supported_extension(extension: &str) -> bool {
+    let allowed_text_file = vec!["txt", "pdf", "docx", "pptx", "xlsx", "md", "zip"];
+    let allowed_code_file = vec![
+            "py", "js", "ts", "jsx", "tsx", "vue", "svelte",      // Web
+            "rs",                                                 // Rust
+            "go",                                                 // go
+            "java", "kt", "scala",                                // java
+            "c", "cpp", "cc", "cxx", "h", "hpp", "hxx",           // C/C++
+            "cs", "fs",                                           // .NET
+            "rb", "php", "pl", "pm",                              // php
+            "swift", "m", "mm",                                   // Apple
+            "r", "R", "jl",                                       // data science
+            "lua", "tcl", "awk", "sed",                           // Script
+            "hs", "ml", "elm", "clj", "cljs", "ex", "exs",        // function
+            "sh", "bash", "zsh", "fish", "bat", "cmd", "ps1",     // Shell
+            "sql", "prisma", "graphql", "gql",                    // database
+            "html", "htm", "css", "scss", "sass", "less",         // Web page
+            "xml", "xsl", "xslt",                                 // XML
+            "json", "yaml", "yml", "toml", "ini", "cfg", "conf",  // config
+            "log", "env",                                         // log
+            "makefile", "cmake", "dockerfile",                    // build
+            "gitignore", "editorconfig"                           // git
+    ];
+    allowed_text_file.contains(&extension.to_lowercase().as_str())
+    || allowed_code_file.contains(&extension.to_lowercase().as_str())
+}
+
+
+fn upload_error(status: StatusCode, error: String, filename: &str) -> Response {
+    (status, Json(UploadError { error, filename: filename.to_string() })).into_response()
+}
+
+
+// 文件解析失败，加密 PDF 的密码问题单独返回 InvalidPasswordError
+fn parse_error(e: anyhow::Error, filename: &str, options: &ParseOptions) -> Response {
+    if let Some(binary) = e.downcast_ref::<BinaryContent>() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(UnsupportedFileError {
+                error: binary.to_string(),
+                file_type: Path::new(filename)
+                    .extension()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("")
+                    .to_string(),
+                detected_type: binary.detected.clone(),
+            }),
+        )
+            .into_response();
+    }
+
+    if e.downcast_ref::<InvalidPassword>().is_some() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(InvalidPasswordError {
+                error: e.to_string(),
+                filename: filename.to_string(),
+                password_provided: options.password.is_some(),
+            }),
+        )
+            .into_response();
+    }
+
+    upload_error(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Failed to parse file: {}", e),
+        filename,
+    )
+}
+
+
+fn upload_too_large(error: &str, filename: &str, limit: usize) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(UploadTooLargeError {
+            error: error.to_string(),
+            filename: filename.to_string(),
+            limit,
+        }),
+    )
+        .into_response()
+}
+
+
+// 解压压缩包，每个成员文件单独在后台解析，未通过检查的成员跳过
+fn expand_archive(
+    state: &AppState,
+    filename: &str,
+    data: &[u8],
+    session_id: &str,
+    tenant: Option<&str>,
+    options: &ParseOptions,
+) -> Result<Vec<(CacheFile, UploadBody)>, Response> {
+    let members = match extract_zip(data, state.config.max_file_size, state.config.max_upload_size) {
+        Ok(members) => members,
+        Err(e) => {
+            return Err(match e.downcast_ref::<ArchiveTooLarge>() {
+                Some(too_large) => upload_too_large("Archive too large", filename, too_large.limit),
+                None => upload_error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Failed to read archive: {}", e),
+                    filename,
+                ),
+            })
+        }
+    };
+
+    let mut files = Vec::with_capacity(members.len());
+    for (path, bytes) in members {
+        if let Err(e) = validate_upload(Path::new(&path), &bytes, options) {
+            tracing::info!(path = %path, archive = %filename, error = %e, "Skipping archive member");
+            continue;
+        }
+
+        let extension = Path::new(&path)
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_string();
+
+        files.push((CacheFile {
+            filename: path,
+            content: String::new(),
+            extension,
+            session_id: session_id.to_string(),
+            file_size: bytes.len(),
+            uploaded_at: unix_now(),
+            chunks: Vec::new(),
+            status: FileStatus::Processing,
+            error: None,
+            tenant: tenant.map(str::to_string),
+            indexed: false,
+        }, UploadBody::Document(UploadData::Memory(bytes), options.clone())));
+    }
+
+    tracing::info!(archive = %filename, files = files.len(), "Archive extracted");
+    Ok(files)
+}
+
+
+// 边读边检查大小，超限的文件不会被完整缓存到内存。request_size 累计整个请求已读取的字节数
+async fn read_field_limited(
+    state: &AppState,
+    item: &mut axum::extract::multipart::Field<'_>,
+    filename: &str,
+    request_size: &mut usize,
+) -> Result<Vec<u8>, Response> {
+    let max_file_size = state.config.max_file_size;
+    let max_upload_size = state.config.max_upload_size;
+    let mut data = Vec::new();
+
+    loop {
+        let chunk = match item.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return Err(upload_too_large("Upload too large", filename, max_upload_size))
+            }
+            Err(e) => return Err(upload_error(e.status(), e.body_text(), filename)),
+        };
+
+        *request_size += chunk.len();
+        if data.len() + chunk.len() > max_file_size {
+            return Err(upload_too_large("File too large", filename, max_file_size));
+        }
+        if *request_size > max_upload_size {
+            return Err(upload_too_large("Upload too large", filename, max_upload_size));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+
+// 和 read_field_limited 相同的大小检查，但收到的内容直接写入 spool 目录，并更新上传进度
+async fn spool_field(
+    state: &AppState,
+    item: &mut axum::extract::multipart::Field<'_>,
+    filename: &str,
+    request_size: &mut usize,
+    progress: Option<&UploadGuard>,
+) -> Result<SpoolFile, Response> {
+    let max_file_size = state.config.max_file_size;
+    let max_upload_size = state.config.max_upload_size;
+    let spool_error = |e: std::io::Error| {
+        tracing::error!(filename = %filename, error = %e, "Failed to spool upload");
+        upload_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to store file: {}", e), filename)
+    };
+    let mut spool = SpoolFile::create(&spool_dir(&state.config)).await.map_err(spool_error)?;
+
+    loop {
+        let chunk = match item.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return Err(upload_too_large("Upload too large", filename, max_upload_size))
+            }
+            Err(e) => return Err(upload_error(e.status(), e.body_text(), filename)),
+        };
+
+        *request_size += chunk.len();
+        if spool.size() + chunk.len() > max_file_size {
+            return Err(upload_too_large("File too large", filename, max_file_size));
+        }
+        if *request_size > max_upload_size {
+            return Err(upload_too_large("Upload too large", filename, max_upload_size));
+        }
+        spool.write(&chunk).await.map_err(spool_error)?;
+        if let Some(progress) = progress {
+            progress.received(chunk.len());
+        }
+    }
+
+    spool.finish().await.map_err(spool_error)?;
+    if let Some(progress) = progress {
+        progress.file_received();
+    }
+    Ok(spool)
+}
+
+
+/// 检查加入 new_count 个、共 new_size 字节的文件后，session 是否超出文件数或总大小限制
+fn check_session_quota(
+    state: &AppState,
+    files: &HashMap<String, CacheFile>,
+    session_id: &str,
+    new_count: usize,
+    new_size: usize,
+) -> Result<(), Response> {
+    let (count, size) = session_usage(files, session_id);
+    let config = &state.config;
+
+    if count + new_count <= config.max_files_per_session && size + new_size <= config.max_session_file_size {
+        return Ok(());
+    }
+
+    tracing::warn!(session_id, files = count, bytes = size, "Session file quota exceeded");
+    Err((
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(SessionQuotaError {
+            error: "Session file quota exceeded, remove some files first".to_string(),
+            session_id: session_id.to_string(),
+            max_files: config.max_files_per_session,
+            max_size: config.max_session_file_size,
+        }),
+    ).into_response())
+}
+
+
+// 上传的一个文件：图片直接保存原始字节，文档在后台解析
+enum UploadBody {
+    Image(SpoolFile),
+    Document(UploadData, ParseOptions),
+}
+
+// 直接上传的文件在 spool 目录中，压缩包的成员解压在内存中
+enum UploadData {
+    Spooled(SpoolFile),
+    Memory(Vec<u8>),
+}
+
+impl UploadData {
+    async fn into_bytes(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            UploadData::Spooled(spool) => spool.read().await,
+            UploadData::Memory(bytes) => Ok(bytes),
+        }
+    }
+}
+
+
+// 文件可以使用时（文档解析完成、图片或转写保存后）发布 file.ingested 事件
+fn file_ingested(state: &AppState, file_id: &str, file: &CacheFile) {
+    state.events.publish(Event::new(FILE_INGESTED, None, file.tenant.as_deref(), serde_json::json!({
+        "file_id": file_id,
+        "filename": file.filename,
+        "extension": file.extension,
+        "file_size": file.file_size,
+        "session_id": file.session_id,
+        "chunks": file.chunks.len(),
+    })));
+}
+
+
+async fn store_image(store: &dyn FileStore, file_id: &str, file: &CacheFile, bytes: &[u8]) -> anyhow::Result<()> {
+    store.save_image(file_id, bytes).await?;
+    store.save(file_id, file).await
+}
+
+
+/// 在后台解析文档，完成后更新缓存中的状态，成功时保存到磁盘。
+/// 解析是 CPU 密集的同步代码，放在 blocking 线程上执行
+/// 同时解析的文档数由 max_parse_tasks 限制，等待期间文件留在 spool 目录中。
+/// 缓存只在更新状态时短暂加锁，保存到存储时不持有锁
+fn spawn_parse(state: AppState, file_id: String, filename: String, data: UploadData, options: ParseOptions) {
+    tokio::spawn(async move {
+        let Ok(_permit) = state.parse_permits.clone().acquire_owned().await else {
+            return;
+        };
+
+        let result = match data.into_bytes().await {
+            Ok(bytes) => {
+                let runtime = tokio::runtime::Handle::current();
+                let parse_cache = state.parse_cache.clone();
+                let path = filename.clone();
+                tokio::task::spawn_blocking(move || runtime.block_on(async move {
+                    parse_file_cached(&parse_cache, Path::new(&path), &bytes, &options).await
+                }))
+                    .await
+                    .unwrap_or_else(|e| Err(e.into()))
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        let file = {
+            let mut cache = state.file_cache.write().await;
+            // 解析期间文件可能已被删除
+            let Some(file) = cache.get_mut(&file_id) else {
+                return;
+            };
+            match result {
+                Ok(content) => {
+                    file.chunks = file_chunks(&state, &content);
+                    file.content = content;
+                    file.status = FileStatus::Ready;
+                    tracing::info!(file_id = %file_id, filename = %filename, chars = file.content.chars().count(), "File parsed");
+                }
+                Err(e) => {
+                    tracing::warn!(file_id = %file_id, filename = %filename, error = %e, "Failed to parse file");
+                    file.status = FileStatus::Failed;
+                    file.error = Some(e.to_string());
+                }
+            }
+            file.clone()
+        };
+
+        if let Err(e) = state.file_store.save(&file_id, &file).await {
+            tracing::error!(file_id = %file_id, error = %e, "Failed to store parsed file");
+        }
+        if file.status == FileStatus::Ready {
+            file_ingested(&state, &file_id, &file);
+        }
+    }.in_current_span());
+}
+
+
+/// 上传一个或多个文件（multipart 中每个带文件名的字段为一个文件）。
+/// 任意一个文件无效时整个请求失败，不会缓存任何文件。
+/// 文件边接收边写入磁盘；带 upload_id 时可在上传期间通过 GET /uploads/{upload_id} 查询进度。
+/// 文档在后台解析，返回时 status 为 processing，可通过 GET /files/{file_id}/status 等待完成
+#[utoipa::path(post, path = "/upload", tag = "files",
+    params(UploadQuery),
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = Vec<UploadResponse>),
+        (status = 400, description = "Unsupported or invalid file", body = UploadError),
+        (status = 400, description = "Invalid upload_id or one already in progress", body = ValidationError),
+        (status = 404, description = "The session belongs to another user", body = SessionNotFoundError),
+        (status = 413, description = "Upload or session quota too large", body = UploadTooLargeError),
+    ))]
+pub async fn upload_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<UploadQuery>,
+    headers: axum::http::HeaderMap,
+    multipart : Multipart)
+    -> Result<Json<Vec<UploadResponse>>, Response> {
+    let progress = match query.upload_id.as_deref() {
+        None => None,
+        Some(upload_id) if !valid_upload_id(upload_id) => {
+            return Err(invalid("upload_id", "upload_id must be 1-64 letters, digits, '-' or '_'"));
+        }
+        Some(upload_id) => {
+            // Content-Length 包括 multipart 的分隔，只用于估计进度
+            let total_bytes = headers.get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            let progress = state.uploads.start(upload_id, caller.tenant(), total_bytes)
+                .ok_or_else(|| invalid("upload_id", "An upload with this upload_id is in progress"))?;
+            Some(progress)
+        }
+    };
+
+    let result = receive_upload(&state, &caller, query.session_id, multipart, progress.as_ref()).await;
+    if let Some(progress) = progress {
+        match &result {
+            Ok(_) => progress.complete(),
+            Err(response) => progress.fail(format!("Upload failed with status {}", response.status())),
+        }
+    }
+    result.map(Json)
+}
+
+async fn receive_upload(
+    state: &AppState,
+    caller: &Caller,
+    session_id: Option<String>,
+    mut multipart: Multipart,
+    progress: Option<&UploadGuard>,
+) -> Result<Vec<UploadResponse>, Response> {
+    // 文件只注入到所属 session 的下一次对话中，其他用户的 session 视为不存在
+    let session_id = session_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    claim_session(state, caller, &session_id).await?;
+    let mut files: Vec<(CacheFile, UploadBody)> = Vec::new();
+    let mut request_size = 0usize;
+    let mut parse_options = ParseOptions {
+        chunk_size: state.config.rag_chunk_size,
+        redact_pii: state.config.redact_pii,
+        ..Default::default()
+    };
+
+    loop {
+        let mut item = match multipart.next_field().await {
+            Ok(Some(item)) => item,
+            Ok(None) => break,
+            // DefaultBodyLimit 触发时返回 413
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return Err(upload_too_large("Upload too large", "", state.config.max_upload_size))
+            }
+            Err(e) => return Err(upload_error(e.status(), e.body_text(), "")),
+        };
+
+        // 没有文件名的普通表单字段是解析选项（如 password），作用于它之后的文件
+        let filename = match item.file_name() {
+            Some(name) => name.to_string(),
+            None => {
+                let name = item.name().unwrap_or("").to_string();
+                let value = item.text().await
+                    .map_err(|e| upload_error(e.status(), e.body_text(), ""))?;
+                parse_options.set(&name, &value);
+                continue;
+            }
+        };
+
+        let extension = Path::new(&filename)
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_string();
+
+        if !is_supported_extension(&extension) && !is_image_extension(&extension) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(UnsupportedFileError {
+                    error : "Unsupported file type".to_string(),
+                    file_type : extension,
+                    detected_type : None,
+                })
+            ).into_response())
+        }
+
+        let spool = spool_field(state, &mut item, &filename, &mut request_size, progress).await?;
+        let file_size = spool.size();
+
+        // 图片保存原始字节，不解析成文本；文档先做快速检查，解析在后台进行
+        let (status, body) = if is_image_extension(&extension) {
+            (FileStatus::Ready, UploadBody::Image(spool))
+        } else {
+            // 检查和解压时只读回这一个文件，请求中的其他文件仍在磁盘上
+            let data = spool.read().await.map_err(|e| {
+                upload_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read file: {}", e), &filename)
+            })?;
+
+            // zip 解压后每个成员文件单独缓存，文件名为压缩包内的相对路径
+            if extension.eq_ignore_ascii_case("zip") {
+                files.extend(expand_archive(state, &filename, &data, &session_id, caller.tenant(), &parse_options)?);
+                continue;
+            }
+
+            if let Err(e) = validate_upload(Path::new(&filename), &data, &parse_options) {
+                return Err(parse_error(e, &filename, &parse_options));
+            }
+            (FileStatus::Processing, UploadBody::Document(UploadData::Spooled(spool), parse_options.clone()))
+        };
+
+        files.push((CacheFile {
+            filename,
+            content: String::new(),
+            extension,
+            session_id: session_id.clone(),
+            file_size,
+            uploaded_at: unix_now(),
+            chunks: Vec::new(),
+            status,
+            error: None,
+            tenant: caller.tenant.clone(),
+            indexed: false,
+        }, body));
+    }
+
+    if files.is_empty() {
+        return Err(upload_error(StatusCode::BAD_REQUEST, "No file in request".to_string(), ""));
+    }
+
+    let upload_size = files.iter().map(|(file, _)| file.file_size).sum();
+    check_session_quota(state, &*state.file_cache.read().await, &session_id, files.len(), upload_size)?;
+
+    // 先在不持有缓存锁的情况下保存图片和文档的 processing 状态（重启后或在其他实例上也能查到），
+    // 任意一个失败时删除已保存的文件，整个请求失败
+    let mut stored: Vec<(String, CacheFile, UploadBody)> = Vec::with_capacity(files.len());
+    for (cache_file, body) in files {
+        let file_id = uuid::Uuid::new_v4().to_string();
+        let saved = match &body {
+            UploadBody::Image(spool) => match spool.read().await {
+                Ok(bytes) => store_image(state.file_store.as_ref(), &file_id, &cache_file, &bytes).await,
+                Err(e) => Err(e.into()),
+            },
+            UploadBody::Document(..) => state.file_store.save(&file_id, &cache_file).await,
+        };
+        if let Err(e) = saved {
+            tracing::error!(file_id = %file_id, filename = %cache_file.filename, error = %e, "Failed to store upload");
+            discard_stored(state, stored.iter().map(|(file_id, _, _)| file_id)).await;
+            return Err(upload_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store file: {}", e),
+                &cache_file.filename,
+            ));
+        }
+        stored.push((file_id, cache_file, body));
+    }
+
+    // 配额在插入时的锁内再检查一次，并发的上传不会一起超出限制
+    let quota = {
+        let mut cache = state.file_cache.write().await;
+        let quota = check_session_quota(state, &cache, &session_id, stored.len(), upload_size);
+        if quota.is_ok() {
+            for (file_id, cache_file, _) in &stored {
+                cache.insert(file_id.clone(), cache_file.clone());
+            }
+            tracing::debug!(files = cache.len(), "File cache size");
+        }
+        quota
+    };
+    if let Err(response) = quota {
+        discard_stored(state, stored.iter().map(|(file_id, _, _)| file_id)).await;
+        return Err(response);
+    }
+
+    let mut responses = Vec::with_capacity(stored.len());
+    for (file_id, cache_file, body) in stored {
+        tracing::info!(file_id = %file_id, filename = %cache_file.filename, status = ?cache_file.status, session_id = %session_id, "File uploaded");
+        responses.push(UploadResponse {
+            file_id: file_id.clone(),
+            filename: cache_file.filename.clone(),
+            file_size: cache_file.file_size,
+            session_id: session_id.clone(),
+            status: cache_file.status,
+        });
+
+        match body {
+            UploadBody::Image(_) => file_ingested(state, &file_id, &cache_file),
+            UploadBody::Document(data, options) => {
+                spawn_parse(state.clone(), file_id, cache_file.filename, data, options);
+            }
+        }
+    }
+
+    Ok(responses)
+}
+
+
+// 上传失败时删除已经保存的文件
+async fn discard_stored(state: &AppState, file_ids: impl Iterator<Item = &String>) {
+    for file_id in file_ids {
+        if let Err(e) = state.file_store.delete(file_id).await {
+            tracing::warn!(file_id = %file_id, error = %e, "Failed to delete stored upload");
+        }
+    }
+}
+
+
+/// 带 upload_id 的上传的进度，上传结束后保留 10 分钟
+#[utoipa::path(get, path = "/uploads/{upload_id}", tag = "files",
+    params(("upload_id" = String, Path)),
+    responses(
+        (status = 200, body = UploadProgress),
+        (status = 404, body = UploadNotFoundError),
+    ))]
+pub async fn upload_progress_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(upload_id): axum::extract::Path<String>,
+) -> Result<Json<UploadProgress>, (StatusCode, Json<UploadNotFoundError>)> {
+    match state.uploads.get(caller.tenant(), &upload_id) {
+        Some(progress) => Ok(Json(progress)),
+        None => Err((StatusCode::NOT_FOUND,
+            Json(UploadNotFoundError {
+                error: "Upload not found".to_string(),
+                upload_id,
+            }))),
+    }
+}
+
+
+// 上传时切块并随文件保存
+fn file_chunks(state: &AppState, content: &str) -> Vec<String> {
+    chunk_text_with_overlap(content, state.config.rag_chunk_size, state.config.rag_chunk_overlap)
+}
+
+
+fn file_info(file_id: &str, file: &CacheFile) -> FileInfo {
+    FileInfo {
+        file_id: file_id.to_string(),
+        filename: file.filename.clone(),
+        extension: file.extension.clone(),
+        file_size: file.file_size,
+        char_count: file.content.chars().count(),
+        uploaded_at: file.uploaded_at,
+        session_id: file.session_id.clone(),
+        indexed: file.indexed,
+        status: file.status,
+        error: file.error.clone(),
+    }
+}
+
+
+/// 列出调用方租户上传的文件，可按 session 过滤，按上传时间排序
+#[utoipa::path(get, path = "/files", tag = "files",
+    params(ListFilesQuery),
+    responses((status = 200, body = ListFilesResponse)))]
+pub async fn list_files_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<ListFilesQuery>,
+) -> Json<ListFilesResponse> {
+    sync_file_cache(&state).await;
+    let cache = state.file_cache.read().await;
+
+    let mut files: Vec<FileInfo> = cache.iter()
+        .filter(|(_, file)| file_visible(file, &caller))
+        .filter(|(_, file)| query.session_id.as_deref().map_or(true, |id| file.session_id == id))
+        .map(|(file_id, file)| file_info(file_id, file))
+        .collect();
+    files.sort_by(|a, b| a.uploaded_at.cmp(&b.uploaded_at).then_with(|| a.filename.cmp(&b.filename)));
+
+    Json(ListFilesResponse { files })
+}
+
+
+#[utoipa::path(get, path = "/files/{file_id}", tag = "files",
+    params(("file_id" = String, Path)),
+    responses(
+        (status = 200, body = FileInfo),
+        (status = 404, body = FileNotFoundError),
+    ))]
+pub async fn get_file_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(file_id): axum::extract::Path<String>,
+) -> Result<Json<FileInfo>, (StatusCode, Json<FileNotFoundError>)> {
+    ensure_cached(&state, &file_id).await;
+    match state.file_cache.read().await.get(&file_id).filter(|file| file_visible(file, &caller)) {
+        Some(file) => Ok(Json(file_info(&file_id, file))),
+        None => Err((StatusCode::NOT_FOUND,
+            Json(FileNotFoundError {
+                error: "File does not exist".to_string(),
+                file_id,
+            }))),
+    }
+}
+
+
+/// 文件解析后的文本，即模型看到的内容，而不是原始文件。
+/// format 为 text（默认）、markdown 或 json；chunked 时按检索使用的块返回
+#[utoipa::path(get, path = "/files/{file_id}/content", tag = "files",
+    params(("file_id" = String, Path), FileContentQuery),
+    responses(
+        (status = 200, description = "The parsed text as text/plain, text/markdown or JSON", body = FileContentResponse),
+        (status = 400, description = "A file without text, such as an image", body = ValidationError),
+        (status = 404, body = FileNotFoundError),
+        (status = 409, description = "The file is still being parsed or could not be parsed", body = FileNotReadyError),
+    ))]
+pub async fn file_content_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(file_id): axum::extract::Path<String>,
+    Query(query): Query<FileContentQuery>,
+) -> Result<Response, Response> {
+    let file = readable_file(&state, &caller, &file_id).await?;
+    let chunks = if query.chunked {
+        retrieval_chunks(&file, state.config.rag_chunk_size)
+    } else {
+        Vec::new()
+    };
+
+    let (content_type, body): (&str, String) = match query.format {
+        ContentFormat::Json => {
+            let response = FileContentResponse {
+                file_id,
+                filename: file.filename,
+                char_count: file.content.chars().count(),
+                content: (!query.chunked).then_some(file.content),
+                chunks: query.chunked.then(|| {
+                    chunks.into_iter()
+                        .enumerate()
+                        .map(|(index, text)| FileChunk { index, text })
+                        .collect()
+                }),
+            };
+            return Ok(Json(response).into_response());
+        }
+        ContentFormat::Text if query.chunked => ("text/plain; charset=utf-8", render_chunks(&file, &chunks, false)),
+        ContentFormat::Text => ("text/plain; charset=utf-8", file.content),
+        ContentFormat::Markdown if query.chunked => ("text/markdown; charset=utf-8", render_chunks(&file, &chunks, true)),
+        ContentFormat::Markdown => ("text/markdown; charset=utf-8", format!("# {}\n\n{}\n", file.filename, file.content)),
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+// 分块的文本，每块前是 prompt 中使用的标题，markdown 中作为二级标题放在文件名标题下
+fn render_chunks(file: &CacheFile, chunks: &[String], markdown: bool) -> String {
+    let mut out = String::with_capacity(chunks.iter().map(|text| text.len() + file.filename.len() + 32).sum());
+    if markdown {
+        let _ = writeln!(out, "# {}", file.filename);
+    }
+    for (index, text) in chunks.iter().enumerate() {
+        let heading = chunk_heading(&file.extension, &file.filename, index);
+        if markdown {
+            let _ = write!(out, "\n## {}\n\n{}\n", heading, text);
+        } else {
+            let _ = write!(out, "{}\n{}\n\n", heading, text);
+        }
+    }
+    out
+}
+
+
+// 需要文件文本的接口（摘要、抽取）使用的文件：必须已解析完成且有文本内容
+async fn readable_file(state: &AppState, caller: &Caller, file_id: &str) -> Result<CacheFile, Response> {
+    ensure_cached(state, file_id).await;
+    let cache = state.file_cache.read().await;
+    let Some(file) = cache.get(file_id).filter(|file| file_visible(file, caller)) else {
+        return Err((StatusCode::NOT_FOUND,
+            Json(FileNotFoundError {
+                error: "File does not exist".to_string(),
+                file_id: file_id.to_string(),
+            })).into_response());
+    };
+
+    if file.status != FileStatus::Ready {
+        let error = match file.status {
+            FileStatus::Processing => "File is still being processed",
+            _ => "File could not be parsed",
+        };
+        return Err((StatusCode::CONFLICT,
+            Json(FileNotReadyError {
+                error: error.to_string(),
+                file_id: file_id.to_string(),
+                status: file.status,
+            })).into_response());
+    }
+    if file.content.trim().is_empty() {
+        return Err(invalid("file_id", "File has no text content"));
+    }
+    Ok(file.clone())
+}
+
+
+/// 对一个已解析的文件做 map-reduce 摘要：文档按模型的上下文长度切成不重叠的段，先对每段做摘要，
+/// 再把各段的摘要合并成整篇文档的摘要（摘要太多时先分组合并）。和 session 无关。
+/// `"stream": true` 时以 SSE 推送每段的摘要和最终摘要的 token（见 [`SummarizeEvent`]）
+#[utoipa::path(post, path = "/files/{file_id}/summarize", tag = "files",
+    params(("file_id" = String, Path)),
+    request_body = SummarizeRequest,
+    responses(
+        (status = 200, description = "The summary, or with stream=true an SSE stream, see SummarizeEvent", body = SummarizeResponse),
+        (status = 400, description = "Unknown model, invalid max_words or sampling parameter, or a file without text", body = ValidationError),
+        (status = 404, body = FileNotFoundError),
+        (status = 409, description = "The file is still being parsed or could not be parsed", body = FileNotReadyError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 500, description = "A generation failed or timed out", body = SummarizeError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn summarize_file_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    axum::extract::Path(file_id): axum::extract::Path<String>,
+    Json(req): Json<SummarizeRequest>,
+) -> Result<Response, Response> {
+    let max_words = req.max_words.unwrap_or(DEFAULT_SUMMARY_WORDS);
+    if !(1..=MAX_SUMMARY_WORDS).contains(&max_words) {
+        return Err(invalid("max_words", format!("max_words must be between 1 and {}", MAX_SUMMARY_WORDS)));
+    }
+    validate_sampling(&req.generation)?;
+    let model = requested_model(&state, &req.model).await?;
+    let file = readable_file(&state, &caller, &file_id).await?;
+    check_service_ready(&state, &model)?;
+    check_quota(&state, caller.owner())?;
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+    let generation_config = limit_generation(&state, &model, &req.generation).await;
+
+    if !req.stream {
+        let _permit = wait_turn(&ticket, &generation_config).await;
+        return match summarize_document(&state, &model, &file.filename, &file.content, max_words, &generation_config, None).await {
+            Ok((summary, sections, usage)) => {
+                tracing::info!(file_id = %file_id, model = %model, sections, total_tokens = usage.total_tokens, "File summarized");
+                state.usage.add(caller.owner(), usage.prompt_tokens, usage.completion_tokens).await;
+                Ok(Json(SummarizeResponse { file_id, summary, sections, usage }).into_response())
+            }
+            Err(e) => {
+                tracing::error!(file_id = %file_id, model = %model, error = %e, "Summarization failed");
+                Err((StatusCode::INTERNAL_SERVER_ERROR, Json(SummarizeError { error: e.to_string(), file_id })).into_response())
+            }
+        };
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<SummarizeEvent>(sse::buffer_size(&state.config));
+    let keep_alive = sse::keep_alive(&state.config);
+    let cancel_token = CancellationToken::new();
+    let request_id = register_generation(&state, request_id_string(&request_id), cancel_token.clone()).await;
+    tracing::info!(request_id = %request_id, file_id = %file_id, model = %model, "Summarization started");
+
+    tokio::spawn(async move {
+        let _ = tx.send(SummarizeEvent::Request { request_id: request_id.clone() }).await;
+
+        let permit = tokio::select! {
+            permit = wait_turn(&ticket, &generation_config) => Some(permit),
+            _ = tx.closed() => None,
+            _ = cancel_token.cancelled() => None,
+        };
+
+        if permit.is_some() {
+            // 客户端断开或取消时丢弃进行中的生成，不再处理后面的段
+            let summarization = summarize_document(
+                &state, &model, &file.filename, &file.content, max_words, &generation_config, Some(&tx));
+            let result = tokio::select! {
+                result = summarization => Some(result),
+                _ = tx.closed() => None,
+                _ = cancel_token.cancelled() => None,
+            };
+            match result {
+                Some(Ok((summary, sections, usage))) => {
+                    tracing::info!(request_id = %request_id, file_id = %file_id, sections, total_tokens = usage.total_tokens, "File summarized");
+                    state.usage.add(caller.owner(), usage.prompt_tokens, usage.completion_tokens).await;
+                    let _ = tx.send(SummarizeEvent::Summary { summary, sections, usage }).await;
+                }
+                Some(Err(e)) => {
+                    tracing::error!(request_id = %request_id, file_id = %file_id, error = %e, "Summarization failed");
+                    let _ = tx.send(SummarizeEvent::Error { error: e.to_string(), request_id: request_id.clone() }).await;
+                }
+                None => {}
+            }
+        }
+
+        let _ = tx.send(SummarizeEvent::Done {}).await;
+        state.active_generations.write().await.remove(&request_id);
+    }.in_current_span());
+
+    let sse_stream = tokio_stream::wrappers::ReceiverStream::new(rx)
+        .map(|event| Event::default().event(event.name()).json_data(&event));
+
+    Ok(Sse::new(sse_stream).keep_alive(keep_alive).into_response())
+}
+
+
+/// map-reduce 摘要，返回最终摘要、文档的段数和全部生成的 usage。events 不为空时推送每段的摘要
+/// 和最终摘要的 token。每次生成单独受 generation_timeout_secs 限制
+async fn summarize_document(
+    state: &AppState,
+    model: &str,
+    filename: &str,
+    content: &str,
+    max_words: usize,
+    generation_config: &GenerationConfig,
+    events: Option<&tokio::sync::mpsc::Sender<SummarizeEvent>>,
+) -> anyhow::Result<(String, usize, Usage)> {
+    let (context_length, default_max_tokens) = match state.registry.read().await.get(model) {
+        Some(spec) => (spec.context_length, spec.defaults.max_tokens),
+        None => anyhow::bail!("Unknown model {}", model),
+    };
+    let reply_tokens = generation_config.max_tokens
+        .or(default_max_tokens)
+        .unwrap_or(DEFAULT_REPLY_TOKENS);
+    let max_chars = section_chars(context_length, reply_tokens);
+
+    let sections = split_sections(content, max_chars);
+    let total = sections.len();
+    let mut usage = Usage::default();
+
+    let final_prompt = if total <= 1 {
+        document_prompt(filename, content, max_words)
+    } else {
+        let section_config = GenerationConfig {
+            max_tokens: Some(SECTION_SUMMARY_MAX_TOKENS),
+            ..generation_config.clone()
+        };
+
+        let mut summaries = Vec::with_capacity(total);
+        for (index, section) in sections.iter().enumerate() {
+            let prompt = section_prompt(filename, section, index, total);
+            let (summary, step_usage) = generation_step(state, model, &prompt, &section_config).await?;
+            usage.add(&step_usage);
+            if let Some(events) = events {
+                let _ = events.send(SummarizeEvent::Section { index, total, summary: summary.clone() }).await;
+            }
+            summaries.push(summary);
+        }
+
+        // 各段摘要合起来太长时分组合并，直到能放进一次生成（分组不能再减少段数时直接合并）
+        while !fits_in_one_pass(&summaries, max_chars) {
+            let groups = group_summaries(&summaries, max_chars);
+            if groups.len() >= summaries.len() {
+                break;
+            }
+            tracing::debug!(model, summaries = summaries.len(), groups = groups.len(), "Merging section summaries");
+
+            let mut merged = Vec::with_capacity(groups.len());
+            for (index, group) in groups.iter().enumerate() {
+                let prompt = section_prompt(filename, group, index, groups.len());
+                let (summary, step_usage) = generation_step(state, model, &prompt, &section_config).await?;
+                usage.add(&step_usage);
+                merged.push(summary);
+            }
+            summaries = merged;
+        }
+
+        combine_prompt(filename, &summaries, max_words)
+    };
+
+    let final_generation = async {
+        let mut stream = run_inference_stream(
+            &state.model_cache,
+            &state.registry,
+            &state.config.model_dir,
+            model,
+            &final_prompt,
+            Vec::new(),
+            generation_config,
+            CancellationToken::new(),
+        ).await?;
+
+        let mut summary = String::new();
+        let mut final_usage = Usage::default();
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                StreamChunk::Token(token) => {
+                    if let Some(events) = events {
+                        let _ = events.send(SummarizeEvent::Token { content: token.clone() }).await;
+                    }
+                    summary.push_str(&token);
+                }
+                StreamChunk::Usage(chunk_usage) => final_usage = chunk_usage,
+                StreamChunk::Logprobs(_) => {}
+                StreamChunk::Error(error) => anyhow::bail!(error),
+            }
+        }
+        anyhow::Ok((summary, final_usage))
+    };
+    let (summary, final_usage) = with_watchdog(state, final_generation).await?;
+    usage.add(&final_usage);
+
+    Ok((summary.trim().to_string(), total, usage))
+}
+
+
+// 一次不流式、不使用 session 的生成（段落摘要、分组合并、抽取一个字段、批量生成的一项）
+async fn generation_step(
+    state: &AppState,
+    model: &str,
+    messages: &[ChatMessage],
+    generation_config: &GenerationConfig,
+) -> anyhow::Result<(String, Usage)> {
+    let generation = run_inference_collect(
+        &state.model_cache,
+        &state.registry,
+        &state.config.model_dir,
+        model,
+        messages,
+        Vec::new(),
+        generation_config,
+    );
+    let (text, usage) = with_watchdog(state, generation).await?;
+    Ok((text.trim().to_string(), usage))
+}
+
+
+/// 按 JSON schema 从文件中抽取结构化数据：每个字段检索文档中相关的块，让模型只回答这个字段，
+/// 按字段的类型校验回答，不符合时带着错误原因重试一次。和 session 无关
+#[utoipa::path(post, path = "/files/{file_id}/extract", tag = "files",
+    params(("file_id" = String, Path)),
+    request_body = ExtractRequest,
+    responses(
+        (status = 200, body = ExtractResponse),
+        (status = 400, description = "Invalid schema, unknown model or invalid sampling parameter, or a file without text", body = ValidationError),
+        (status = 404, body = FileNotFoundError),
+        (status = 409, description = "The file is still being parsed or could not be parsed", body = FileNotReadyError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 500, description = "A generation failed or timed out", body = ExtractError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn extract_file_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(file_id): axum::extract::Path<String>,
+    Json(req): Json<ExtractRequest>,
+) -> Result<Json<ExtractResponse>, Response> {
+    let fields = parse_schema(&req.schema).map_err(|e| invalid("schema", e))?;
+    validate_sampling(&req.generation)?;
+    let model = requested_model(&state, &req.model).await?;
+    let file = readable_file(&state, &caller, &file_id).await?;
+    check_service_ready(&state, &model)?;
+    check_quota(&state, caller.owner())?;
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+
+    // 未指定 temperature 时用 0，同一文档多次抽取的结果一致
+    let generation_config = limit_generation(&state, &model, &req.generation).await;
+    let field_config = GenerationConfig {
+        temperature: generation_config.temperature.or(Some(0.0)),
+        max_tokens: Some(FIELD_MAX_TOKENS),
+        ..generation_config
+    };
+    let _permit = wait_turn(&ticket, &field_config).await;
+
+    let chunks = build_chunks(&file_id, &file, state.config.rag_chunk_size);
+    let mut data = serde_json::Map::new();
+    let mut missing = Vec::new();
+    let mut errors = Vec::new();
+    let mut usage = Usage::default();
+
+    for field in &fields {
+        // 相关的块按它们在文档中的顺序给模型
+        let mut relevant = retrieve_top_k(&chunks, &field_query(field), state.config.rag_top_k);
+        relevant.sort_by_key(|chunk| chunk.chunk_index);
+        let excerpts: Vec<&str> = relevant.iter().map(|chunk| chunk.text.as_str()).collect();
+
+        let value = match extract_field(&state, &model, &file.filename, field, &excerpts, &field_config, &mut usage).await {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::error!(file_id = %file_id, model = %model, field = %field.name, error = %e, "Extraction failed");
+                state.usage.add(caller.owner(), usage.prompt_tokens, usage.completion_tokens).await;
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(ExtractError { error: e.to_string(), file_id })).into_response());
+            }
+        };
+        let value = match value {
+            Ok(value) => value,
+            Err(error) => {
+                errors.push(ExtractFieldError { field: field.name.clone(), error });
+                serde_json::Value::Null
+            }
+        };
+        if value.is_null() && field.required {
+            missing.push(field.name.clone());
+        }
+        data.insert(field.name.clone(), value);
+    }
+
+    tracing::info!(
+        file_id = %file_id,
+        model = %model,
+        fields = fields.len(),
+        missing = missing.len(),
+        invalid = errors.len(),
+        total_tokens = usage.total_tokens,
+        "File extracted",
+    );
+    state.usage.add(caller.owner(), usage.prompt_tokens, usage.completion_tokens).await;
+    Ok(Json(ExtractResponse {
+        file_id,
+        data: serde_json::Value::Object(data),
+        missing,
+        errors,
+        usage,
+    }))
+}
+
+
+// 抽取一个字段。外层的错误为生成失败；内层为两次回答都不符合字段类型时最后一次的原因
+async fn extract_field(
+    state: &AppState,
+    model: &str,
+    filename: &str,
+    field: &FieldSpec,
+    excerpts: &[&str],
+    generation_config: &GenerationConfig,
+    usage: &mut Usage,
+) -> anyhow::Result<Result<serde_json::Value, String>> {
+    let mut previous_error = None;
+    for _ in 0..2 {
+        let prompt = field_prompt(field, filename, excerpts, previous_error.as_deref());
+        let (reply, step_usage) = generation_step(state, model, &prompt, generation_config).await?;
+        usage.add(&step_usage);
+
+        match parse_value(field, &reply) {
+            Ok(value) => return Ok(Ok(value)),
+            Err(error) => {
+                tracing::debug!(model, field = %field.name, error = %error, "Extracted value is invalid");
+                previous_error = Some(error);
+            }
+        }
+    }
+    Ok(Err(previous_error.unwrap_or_default()))
+}
+
+
+// 超过 generation_timeout_secs 时放弃这次生成
+async fn with_watchdog<T>(
+    state: &AppState,
+    generation: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::select! {
+        result = generation => result,
+        _ = watchdog(state) => Err(anyhow::anyhow!(timeout_message(state))),
+    }
+}
+
+
+fn file_status(file_id: &str, file: &CacheFile) -> FileStatusResponse {
+    FileStatusResponse {
+        file_id: file_id.to_string(),
+        status: file.status,
+        error: file.error.clone(),
+    }
+}
+
+
+// 轮询后台解析状态的间隔
+const FILE_STATUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 文件的解析状态。`?stream=true` 时返回 SSE：先发送当前状态，
+/// 解析结束（ready / failed）时再发送一次并关闭
+#[utoipa::path(get, path = "/files/{file_id}/status", tag = "files",
+    params(("file_id" = String, Path), FileStatusQuery),
+    responses(
+        (status = 200, description = "The status, or with stream=true an SSE stream of status events", body = FileStatusResponse),
+        (status = 404, body = FileNotFoundError),
+    ))]
+pub async fn file_status_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(file_id): axum::extract::Path<String>,
+    Query(query): Query<FileStatusQuery>,
+) -> Response {
+    ensure_cached(&state, &file_id).await;
+    let status = match state.file_cache.read().await.get(&file_id).filter(|file| file_visible(file, &caller)) {
+        Some(file) => file_status(&file_id, file),
+        None => {
+            return (StatusCode::NOT_FOUND,
+                Json(FileNotFoundError {
+                    error: "File does not exist".to_string(),
+                    file_id,
+                })).into_response()
+        }
+    };
+
+    if !query.stream {
+        return Json(status).into_response();
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<FileStatusResponse>(2);
+    let keep_alive = sse::keep_alive(&state.config);
+
+    tokio::spawn(async move {
+        let mut current = status;
+        if tx.send(current.clone()).await.is_err() {
+            return;
+        }
+
+        while current.status == FileStatus::Processing {
+            tokio::time::sleep(FILE_STATUS_POLL_INTERVAL).await;
+            if tx.is_closed() {
+                return;
+            }
+
+            // 文件可能在其他实例上解析
+            ensure_cached(&state, &file_id).await;
+            current = match state.file_cache.read().await.get(&file_id) {
+                Some(file) => file_status(&file_id, file),
+                None => FileStatusResponse {
+                    file_id: file_id.clone(),
+                    status: FileStatus::Failed,
+                    error: Some("File was deleted".to_string()),
+                },
+            };
+            if current.status != FileStatus::Processing {
+                let _ = tx.send(current.clone()).await;
+            }
+        }
+    });
+
+    let sse_stream = tokio_stream::wrappers::ReceiverStream::new(rx)
+        .map(|status| Event::default().event("status").json_data(&status));
+
+    Sse::new(sse_stream).keep_alive(keep_alive).into_response()
+}
+
+
+/// 语音转文字。带 session_id 时转写结果作为文件加入该 session，下一次对话会用到
+#[utoipa::path(post, path = "/transcribe", tag = "files",
+    params(UploadQuery),
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = TranscribeResponse),
+        (status = 400, description = "Not an audio file", body = UnsupportedFileError),
+        (status = 404, description = "The session belongs to another user", body = SessionNotFoundError),
+        (status = 413, description = "Audio file or session quota too large", body = UploadTooLargeError),
+        (status = 422, description = "Transcription failed", body = UploadError),
+        (status = 501, description = "Built without speech to text support", body = UploadError),
+    ))]
+pub async fn transcribe_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> Result<Json<TranscribeResponse>, Response> {
+    if !state.transcriber.enabled() {
+        return Err(upload_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Transcription is not available in this build".to_string(),
+            "",
+        ));
+    }
+    if let Some(session_id) = &query.session_id {
+        claim_session(&state, &caller, session_id).await?;
+    }
+
+    // 使用第一个带文件名的字段
+    let mut item = loop {
+        match multipart.next_field().await {
+            Ok(Some(item)) if item.file_name().is_some() => break item,
+            Ok(Some(_)) => continue,
+            Ok(None) => {
+                return Err(upload_error(StatusCode::BAD_REQUEST, "No file in request".to_string(), ""))
+            }
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return Err(upload_too_large("Upload too large", "", state.config.max_upload_size))
+            }
+            Err(e) => return Err(upload_error(e.status(), e.body_text(), "")),
+        }
+    };
+
+    let filename = item.file_name().unwrap_or_default().to_string();
+    let extension = Path::new(&filename)
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    if !is_audio_extension(&extension) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(UnsupportedFileError {
+                error : "Unsupported audio type".to_string(),
+                file_type : extension,
+                detected_type : None,
+            })
+        ).into_response())
+    }
+
+    let mut request_size = 0usize;
+    let data = read_field_limited(&state, &mut item, &filename, &mut request_size).await?;
+    let file_size = data.len();
+
+    if let Some(session_id) = &query.session_id {
+        check_session_quota(&state, &*state.file_cache.read().await, session_id, 1, file_size)?;
+    }
+
+    let text = match state.transcriber.transcribe(data, &extension).await {
+        Ok(text) if state.config.redact_pii => redact_pii(&text).0,
+        Ok(text) => text,
+        Err(e) => {
+            tracing::warn!(filename = %filename, error = %e, "Transcription failed");
+            return Err(upload_error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Transcription failed: {}", e),
+                &filename,
+            ));
+        }
+    };
+
+    let mut file_id = None;
+    if let Some(session_id) = &query.session_id {
+        let id = uuid::Uuid::new_v4().to_string();
+        let cache_file = CacheFile {
+            filename: filename.clone(),
+            content: text.clone(),
+            extension,
+            session_id: session_id.clone(),
+            file_size,
+            uploaded_at: unix_now(),
+            chunks: file_chunks(&state, &text),
+            status: FileStatus::Ready,
+            error: None,
+            tenant: caller.tenant.clone(),
+            indexed: false,
+        };
+
+        if let Err(e) = state.file_store.save(&id, &cache_file).await {
+            return Err(upload_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store transcript: {}", e),
+                &filename,
+            ));
+        }
+
+        // 转写期间同一 session 可能有其他上传，配额在插入时的锁内再检查一次
+        let quota = {
+            let mut cache = state.file_cache.write().await;
+            let quota = check_session_quota(&state, &cache, session_id, 1, file_size);
+            if quota.is_ok() {
+                cache.insert(id.clone(), cache_file.clone());
+            }
+            quota
+        };
+        if let Err(response) = quota {
+            discard_stored(&state, std::iter::once(&id)).await;
+            return Err(response);
+        }
+        file_ingested(&state, &id, &cache_file);
+        file_id = Some(id);
+    }
+
+    Ok(Json(TranscribeResponse {
+        text,
+        filename,
+        file_id,
+        session_id: query.session_id,
+    }))
+}
+
+
+#[utoipa::path(delete, path = "/files/{file_id}", tag = "files",
+    params(("file_id" = String, Path)),
+    responses(
+        (status = 200, body = DeleteResponse),
+        (status = 400, description = "Unknown file", body = RemoveFileError),
+    ))]
+pub async fn remove_handler(State(state): State<AppState>,
+                            Extension(caller): Extension<Caller>,
+                            axum::extract::Path(file_id): axum::extract::Path<String>)
+    -> Result<Json<DeleteResponse>, (StatusCode, Json<RemoveFileError>)> {
+    ensure_cached(&state, &file_id).await;
+    let visible = state.file_cache.read().await.get(&file_id).is_some_and(|file| file_visible(file, &caller));
+    if !visible {
+        return Err((StatusCode::BAD_REQUEST,
+            Json(RemoveFileError {
+            error : "File does not exist".to_string(),
+            file_id : file_id.to_string()
+        })))
+    }
+
+    // 存储中的删除（可能是 Redis 的网络请求）不持有缓存的锁
+    forget_file(&state, &file_id).await;
+    if let Err(e) = state.file_store.delete(&file_id).await {
+        tracing::error!(file_id = %file_id, error = %e, "Failed to delete stored file");
+    }
+    tracing::debug!(files = state.file_cache.read().await.len(), "File cache size");
+
+    let delete_response = DeleteResponse {
+        file_id,
+        result: true,
+    };
+
+    Ok(Json(delete_response))
+}
+
+
+#[utoipa::path(delete, path = "/sessions/{session_id}", tag = "sessions",
+    params(("session_id" = String, Path)),
+    responses(
+        (status = 200, body = RemoveSessionResponse),
+        (status = 404, body = RemoveSessionError),
+    ))]
+pub async fn remove_session_handler(State(state): State<AppState>,
+                                    Extension(caller): Extension<Caller>,
+                                    axum::extract::Path(session_id): axum::extract::Path<String>)
+    -> Result<Json<RemoveSessionResponse>, (StatusCode, Json<RemoveSessionError>)> {
+    if !SessionHelper::owns(&state.session_manager, &session_id, caller.owner()).await
+        || !SessionHelper::remove(&state.session_manager, &session_id).await {
+        return Err(
+            (StatusCode::NOT_FOUND,
+            Json(RemoveSessionError {
+                error : "Session does not exist".to_string(),
+                session_id : session_id.to_string()
+            }))
+        )
+    }
+
+    drop_session_index(&state.vector_index, &state.file_cache, &session_id).await;
+
+    Ok(Json(RemoveSessionResponse {
+        session_id,
+        cleared: true
+    }))
+}
+
+
+const DEFAULT_SESSION_PAGE: usize = 20;
+const MAX_SESSION_PAGE: usize = 100;
+
+/// 列出 session，供前端的会话侧边栏使用
+#[utoipa::path(get, path = "/sessions", tag = "sessions",
+    params(ListSessionsQuery),
+    responses((status = 200, body = ListSessionsResponse)))]
+pub async fn list_sessions_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<ListSessionsQuery>,
+) -> Json<ListSessionsResponse> {
+    let limit = query.limit.unwrap_or(DEFAULT_SESSION_PAGE).clamp(1, MAX_SESSION_PAGE);
+    let (total, sessions) = SessionHelper::list(&state.session_manager, caller.owner(), query.offset, limit).await;
+
+    Json(ListSessionsResponse {
+        sessions: sessions.iter().map(|session| SessionSummary {
+            session_id: session.id.clone(),
+            title: session.title.clone(),
+            message_count: session.message_count(),
+            created_at: session.created_at,
+            updated_at: session.last_active,
+        }).collect(),
+        total,
+        offset: query.offset,
+        limit,
+    })
+}
+
+
+/// 获取 session 信息
+#[utoipa::path(get, path = "/sessions/{session_id}", tag = "sessions",
+    params(("session_id" = String, Path)),
+    responses((status = 200, description = "exists is false for unknown sessions", body = GetSessionResponse)))]
+pub async fn get_session_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(session_id): axum::extract::Path<String>
+) -> Json<GetSessionResponse> {
+    match SessionHelper::get(&state.session_manager, &session_id).await {
+        Some(session) if session.is_owned_by(caller.owner()) => {
+            Json(GetSessionResponse {
+                session_id,
+                messages: session.messages,
+                exists: true,
+            })
+        }
+        _ => {
+            Json(GetSessionResponse {
+                session_id,
+                messages: vec![],
+                exists: false,
+            })
+        }
+    }
+}
+
+
+/// 置顶或取消置顶一条消息，置顶的消息不会因为超出 max_turns 被删除
+#[utoipa::path(put, path = "/sessions/{session_id}/messages/{message_id}/pin", tag = "sessions",
+    params(("session_id" = String, Path), ("message_id" = String, Path)),
+    request_body = PinMessageRequest,
+    responses(
+        (status = 200, body = PinMessageResponse),
+        (status = 404, body = MessageError),
+    ))]
+pub async fn pin_message_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path((session_id, message_id)): axum::extract::Path<(String, String)>,
+    Json(req): Json<PinMessageRequest>,
+) -> Result<Json<PinMessageResponse>, Response> {
+    if !SessionHelper::owns(&state.session_manager, &session_id, caller.owner()).await {
+        return Err(message_error(SessionMessageError::SessionNotFound, &session_id, &message_id));
+    }
+    let session = SessionHelper::set_pinned(&state.session_manager, &session_id, &message_id, req.pinned)
+        .await
+        .map_err(|e| message_error(e, &session_id, &message_id))?;
+
+    Ok(Json(PinMessageResponse {
+        message_count: session.message_count(),
+        session_id,
+        message_id,
+        pinned: req.pinned,
+    }))
+}
+
+
+/// 复制 session 到新的 session id（可截止到某条消息），原 session 不受影响。
+/// 新 session 同时复制检索索引，原 session 的文件仍然可用
+#[utoipa::path(post, path = "/sessions/{session_id}/fork", tag = "sessions",
+    params(("session_id" = String, Path), ForkSessionQuery),
+    responses(
+        (status = 200, body = ForkSessionResponse),
+        (status = 404, body = MessageError),
+    ))]
+pub async fn fork_session_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<ForkSessionQuery>,
+) -> Result<Json<ForkSessionResponse>, Response> {
+    let new_id = uuid::Uuid::new_v4().to_string();
+    let at_message_id = query.at_message_id.as_deref();
+
+    if !SessionHelper::owns(&state.session_manager, &session_id, caller.owner()).await {
+        return Err(message_error(SessionMessageError::SessionNotFound, &session_id, at_message_id.unwrap_or_default()));
+    }
+
+    let forked = SessionHelper::fork(&state.session_manager, &session_id, new_id, at_message_id)
+        .await
+        .map_err(|e| message_error(e, &session_id, at_message_id.unwrap_or_default()))?;
+
+    {
+        let mut index = state.vector_index.write().await;
+        if let Some(chunks) = index.get(&session_id).cloned() {
+            index.insert(forked.id.clone(), chunks);
+        }
+    }
+    tracing::info!(session_id = %session_id, forked = %forked.id, "Session forked");
+
+    Ok(Json(ForkSessionResponse {
+        message_count: forked.message_count(),
+        session_id: forked.id,
+        forked_from: session_id,
+    }))
+}
+
+
+/// 同步 session 消息（前端切换 session 时调用）：创建 session 或替换它的历史，
+/// 返回裁剪后实际保存的消息数
+#[utoipa::path(post, path = "/sessions/sync", tag = "sessions",
+    request_body = SyncSessionRequest,
+    responses(
+        (status = 200, body = SyncSessionResponse),
+        (status = 404, description = "The session belongs to another user", body = SessionNotFoundError),
+    ))]
+pub async fn sync_session_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<SyncSessionRequest>
+) -> Result<Json<SyncSessionResponse>, Response> {
+    claim_session(&state, &caller, &req.session_id).await?;
+
+    // 保留 session 已有的配置（系统提示词、最大轮数）
+    let config = SessionHelper::get_config(&state.session_manager, &req.session_id).await;
+
+    let session = SessionHelper::sync_messages(&state.session_manager, &req.session_id, req.messages, config).await;
+
+    tracing::info!(session_id = %req.session_id, messages = session.messages.len(), "Session synced");
+
+    Ok(Json(SyncSessionResponse {
+        session_id: req.session_id,
+        synced: true,
+        message_count: session.messages.len(),
+    }))
+}
+
+
+/// 设置 session 的配置（系统提示词、最大轮数）
+#[utoipa::path(put, path = "/sessions/{session_id}/config", tag = "sessions",
+    params(("session_id" = String, Path)),
+    request_body = SessionConfig,
+    responses(
+        (status = 200, body = SessionConfigResponse),
+        (status = 404, body = SessionNotFoundError),
+    ))]
+pub async fn update_session_config_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Json(config): Json<SessionConfig>,
+) -> Result<Json<SessionConfigResponse>, Response> {
+    claim_session(&state, &caller, &session_id).await?;
+
+    let session = SessionHelper::set_config(&state.session_manager, &session_id, config).await;
+
+    Ok(Json(SessionConfigResponse {
+        session_id,
+        config: session.config,
+    }))
+}
+
+
+// Ollama 的模型名带 tag（qwen:latest），latest 对应注册表中的同名模型
+fn ollama_model_name(model: &str) -> &str {
+    model.strip_suffix(":latest").unwrap_or(model)
+}
+
+fn ollama_timestamp(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
+
+fn ollama_error(status: StatusCode, error: String) -> Response {
+    (status, Json(OllamaError { error })).into_response()
+}
+
+fn decode_ollama_images(images: &[String]) -> Result<Vec<Vec<u8>>, Response> {
+    use base64::Engine;
+
+    images.iter()
+        .map(|image| base64::engine::general_purpose::STANDARD
+            .decode(image)
+            .map_err(|e| ollama_error(StatusCode::BAD_REQUEST, format!("Invalid base64 image: {}", e))))
+        .collect()
+}
+
+// generate 的内容放在 response 字段，chat 的内容放在 message 字段；stats 只在最后一行
+fn ollama_line(model: &str, chat: bool, content: String, stats: Option<OllamaStats>) -> OllamaResponse {
+    OllamaResponse {
+        model: model.to_string(),
+        created_at: ollama_timestamp(chrono::Utc::now()),
+        response: (!chat).then(|| content.clone()),
+        message: chat.then(|| OllamaMessage {
+            role: MessageRole::Assistant,
+            content,
+            images: Vec::new(),
+        }),
+        done: stats.is_some(),
+        stats,
+    }
+}
+
+fn ollama_stats(usage: &Usage, started: Instant) -> OllamaStats {
+    OllamaStats {
+        done_reason: usage.finish_reason.clone().unwrap_or_else(|| "stop".to_string()),
+        total_duration: started.elapsed().as_nanos() as u64,
+        prompt_eval_count: usage.prompt_tokens,
+        eval_count: usage.completion_tokens,
+    }
+}
+
+fn ndjson<T: Serialize>(value: &T) -> String {
+    let mut line = serde_json::to_string(value).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// Ollama 兼容的生成（不使用 session）。stream 为 true 时以 JSON lines 逐个返回 token，
+/// 最后一行 done 为 true 并带有统计；否则返回一个完整的 JSON
+#[allow(clippy::too_many_arguments)]
+async fn ollama_generation(
+    state: AppState,
+    caller: Caller,
+    request_id: String,
+    requested: String,
+    messages: Vec<ChatMessage>,
+    images: Vec<Vec<u8>>,
+    generation_config: GenerationConfig,
+    stream: bool,
+    chat: bool,
+) -> Result<Response, Response> {
+    let model = resolve_model(&state, ollama_model_name(&requested)).await;
+    if state.registry.read().await.get(&model).is_none() {
+        return Err(ollama_error(StatusCode::NOT_FOUND, format!("model \"{}\" not found", requested)));
+    }
+    if let Err((_, error)) = check_sampling(&generation_config) {
+        return Err(ollama_error(StatusCode::BAD_REQUEST, error.to_string()));
+    }
+    let prompt_chars: usize = messages.iter().map(|message| message.content.chars().count()).sum();
+    let max_chars = state.config.max_prompt_chars;
+    if max_chars > 0 && prompt_chars > max_chars {
+        return Err(ollama_error(StatusCode::PAYLOAD_TOO_LARGE,
+            format!("prompt is too long: {} chars, at most {} allowed", prompt_chars, max_chars)));
+    }
+    check_service_ready(&state, &model)?;
+    check_quota(&state, caller.owner())?;
+    let generation_config = limit_generation(&state, &model, &generation_config).await;
+
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+    let started = Instant::now();
+    let mut timer = GenerationTimer::new();
+    let endpoint = if chat { "/api/chat" } else { "/api/generate" };
+    let prompt = last_user_prompt(&messages).to_string();
+
+    if !stream {
+        let _permit = wait_turn(&ticket, &generation_config).await;
+        timer.start();
+        let generation = run_inference_collect(
+            &state.model_cache,
+            &state.registry,
+            &state.config.model_dir,
+            &model,
+            &messages,
+            images,
+            &generation_config,
+        );
+        let (text, usage) = tokio::select! {
+            result = generation => result.map_err(|e| ollama_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+            _ = watchdog(&state) => return Err(ollama_error(StatusCode::GATEWAY_TIMEOUT, timeout_message(&state))),
+        };
+        let timings = timer.finish(usage.completion_tokens);
+        let record = AuditRecord::new(endpoint, &request_id, caller.owner(), None, &model, &prompt, &text, &usage, &timings);
+        finish_generation(&state, &record).await;
+
+        return Ok(Json(ollama_line(&requested, chat, text, Some(ollama_stats(&usage, started)))).into_response());
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<String>(sse::buffer_size(&state.config));
+    let cancel_token = CancellationToken::new();
+
+    tokio::spawn(async move {
+        let _permit = tokio::select! {
+            permit = wait_turn(&ticket, &generation_config) => permit,
+            _ = tx.closed() => return,
+        };
+        timer.start();
+
+        match run_inference_stream(
+            &state.model_cache,
+            &state.registry,
+            &state.config.model_dir,
+            &model,
+            &messages,
+            images,
+            &generation_config,
+            cancel_token.clone(),
+        ).await {
+            Ok(mut stream) => {
+                let mut usage = Usage::default();
+                let mut text = String::new();
+                // 客户端断开或超时时不发送最后一行，但仍写入审计日志
+                let mut finished = false;
+                let deadline = watchdog(&state);
+                tokio::pin!(deadline);
+                loop {
+                    tokio::select! {
+                        _ = tx.closed() => {
+                            cancel_token.cancel();
+                            break;
+                        }
+                        _ = &mut deadline => {
+                            cancel_token.cancel();
+                            let _ = tx.send(ndjson(&OllamaError { error: timeout_message(&state) })).await;
+                            break;
+                        }
+                        chunk = stream.next() => {
+                            match chunk {
+                                Some(StreamChunk::Token(token)) => {
+                                    timer.first_token();
+                                    text.push_str(&token);
+                                    if tx.send(ndjson(&ollama_line(&requested, chat, token, None))).await.is_err() {
+                                        cancel_token.cancel();
+                                        break;
+                                    }
+                                }
+                                Some(StreamChunk::Usage(chunk_usage)) => usage = chunk_usage,
+                                Some(StreamChunk::Logprobs(_)) => {}
+                                Some(StreamChunk::Error(error)) => {
+                                    tracing::error!(model = %model, error = %error, "Ollama generation failed");
+                                    let _ = tx.send(ndjson(&OllamaError { error })).await;
+                                    break;
+                                }
+                                None => {
+                                    finished = true;
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+                if finished {
+                    let last = ollama_line(&requested, chat, String::new(), Some(ollama_stats(&usage, started)));
+                    let _ = tx.send(ndjson(&last)).await;
+                }
+
+                let timings = timer.finish(usage.completion_tokens);
+                let record = AuditRecord::new(endpoint, &request_id, caller.owner(), None, &model, &prompt, &text, &usage, &timings);
+                finish_generation(&state, &record).await;
+            }
+            Err(e) => {
+                tracing::error!(model = %model, error = %e, "Ollama generation failed");
+                let _ = tx.send(ndjson(&OllamaError { error: e.to_string() })).await;
+            }
+        }
+    });
+
+    let body = axum::body::Body::from_stream(
+        tokio_stream::wrappers::ReceiverStream::new(rx).map(Ok::<_, std::convert::Infallible>));
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+
+/// Ollama `POST /api/generate`：单个 prompt（可带 system 和 base64 图片）
+#[utoipa::path(post, path = "/api/generate", tag = "ollama",
+    request_body = OllamaGenerateRequest,
+    responses(
+        (status = 200, description = "One OllamaResponse, or with stream=true one per line (application/x-ndjson)", body = OllamaResponse),
+        (status = 404, description = "Unknown model", body = OllamaError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn ollama_generate_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<OllamaGenerateRequest>,
+) -> Result<Response, Response> {
+    let images = decode_ollama_images(&req.images)?;
+
+    let mut messages = Vec::new();
+    if let Some(system) = req.system {
+        messages.push(ChatMessage::new(MessageRole::System, system));
+    }
+    messages.push(ChatMessage::new(MessageRole::User, req.prompt));
+
+    let request_id = request_id_string(&request_id);
+    ollama_generation(
+        state, caller, request_id, req.model, messages, images, req.options.generation_config(), req.stream, false).await
+}
+
+
+/// Ollama `POST /api/chat`：完整的对话历史由客户端发送，服务端不保存
+#[utoipa::path(post, path = "/api/chat", tag = "ollama",
+    request_body = OllamaChatRequest,
+    responses(
+        (status = 200, description = "One OllamaResponse, or with stream=true one per line (application/x-ndjson)", body = OllamaResponse),
+        (status = 404, description = "Unknown model", body = OllamaError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn ollama_chat_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<OllamaChatRequest>,
+) -> Result<Response, Response> {
+    let mut images = Vec::new();
+    let mut messages = Vec::with_capacity(req.messages.len());
+    for message in req.messages {
+        images.extend(decode_ollama_images(&message.images)?);
+        messages.push(ChatMessage::new(message.role, message.content));
+    }
+
+    let request_id = request_id_string(&request_id);
+    ollama_generation(
+        state, caller, request_id, req.model, messages, images, req.options.generation_config(), req.stream, true).await
+}
+
+
+/// Ollama `GET /api/tags`：注册表中的模型，名称带 `:latest`
+#[utoipa::path(get, path = "/api/tags", tag = "ollama",
+    responses((status = 200, body = OllamaTagsResponse)))]
+pub async fn ollama_tags_handler(State(state): State<AppState>) -> Json<OllamaTagsResponse> {
+    let specs = state.registry.read().await.models().to_vec();
+
+    let mut models = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let metadata = tokio::fs::metadata(spec.gguf_path(&state.config.model_dir)).await.ok()
+            .filter(|metadata| !spec.vision && metadata.is_file());
+        let modified_at = metadata.as_ref()
+            .and_then(|metadata| metadata.modified().ok())
+            .map(chrono::DateTime::<chrono::Utc>::from)
+            .unwrap_or_else(chrono::Utc::now);
+
+        // split models report the size of all shards
+        let mut size = 0;
+        if metadata.is_some() {
+            for path in spec.gguf_paths(&state.config.model_dir) {
+                size += tokio::fs::metadata(path).await.map(|metadata| metadata.len()).unwrap_or(0);
+            }
+        }
+
+        let name = format!("{}:latest", spec.name);
+        models.push(OllamaModel {
+            model: name.clone(),
+            name,
+            modified_at: ollama_timestamp(modified_at),
+            size,
+            digest: String::new(),
+            details: OllamaModelDetails {
+                format: if spec.vision { "safetensors" } else { "gguf" }.to_string(),
+                family: String::new(),
+                parameter_size: String::new(),
+                quantization_level: spec.quantization.clone(),
+            },
+        });
+    }
+
+    Json(OllamaTagsResponse { models })
+}
+
+
+/// 当前用户的长期记忆
+#[utoipa::path(get, path = "/memory", tag = "memory",
+    responses((status = 200, body = MemoryResponse)))]
+pub async fn get_memory_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Json<MemoryResponse> {
+    Json(MemoryResponse {
+        facts: state.memory.get(caller.owner()),
+    })
+}
+
+
+/// 添加、修改或删除（value 为 null）记忆条目，之后新建的 session 使用新的记忆
+#[utoipa::path(put, path = "/memory", tag = "memory",
+    request_body = UpdateMemoryRequest,
+    responses(
+        (status = 200, body = MemoryResponse),
+        (status = 400, body = MemoryError),
+    ))]
+pub async fn update_memory_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<UpdateMemoryRequest>,
+) -> Result<Json<MemoryResponse>, (StatusCode, Json<MemoryError>)> {
+    match state.memory.update(caller.owner(), req.facts).await {
+        Ok(facts) => Ok(Json(MemoryResponse { facts })),
+        Err(error) => Err((StatusCode::BAD_REQUEST, Json(MemoryError { error }))),
+    }
+}
+
+
+/// 清空当前用户的全部记忆
+#[utoipa::path(delete, path = "/memory", tag = "memory",
+    responses((status = 200, body = MemoryResponse)))]
+pub async fn clear_memory_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Json<MemoryResponse> {
+    state.memory.clear(caller.owner()).await;
+    Json(MemoryResponse {
+        facts: Default::default(),
+    })
+}
+
+
+/// 调用方租户已注册的 few-shot 示例组
+#[utoipa::path(get, path = "/examples", tag = "examples",
+    responses((status = 200, body = ListExampleSetsResponse)))]
+pub async fn list_example_sets_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Json<ListExampleSetsResponse> {
+    Json(ListExampleSetsResponse {
+        sets: state.few_shot.list(caller.tenant()).into_iter()
+            .map(|(name, examples)| ExampleSetInfo { name, examples })
+            .collect(),
+    })
+}
+
+
+#[utoipa::path(get, path = "/examples/{name}", tag = "examples",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = ExampleSetResponse),
+        (status = 404, body = ExampleSetError),
+    ))]
+pub async fn get_example_set_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Json<ExampleSetResponse>, (StatusCode, Json<ExampleSetError>)> {
+    match state.few_shot.get(caller.tenant(), &name) {
+        Some(examples) => Ok(Json(ExampleSetResponse { name, examples })),
+        None => Err((StatusCode::NOT_FOUND, Json(ExampleSetError {
+            error: "Example set not found".to_string(),
+            name,
+        }))),
+    }
+}
+
+
+/// 创建或替换一组示例，请求用 `"examples": "{name}"` 引用
+#[utoipa::path(put, path = "/examples/{name}", tag = "examples",
+    params(("name" = String, Path)),
+    request_body = UpdateExampleSetRequest,
+    responses(
+        (status = 200, body = ExampleSetResponse),
+        (status = 400, body = ExampleSetError),
+    ))]
+pub async fn put_example_set_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    Json(req): Json<UpdateExampleSetRequest>,
+) -> Result<Json<ExampleSetResponse>, (StatusCode, Json<ExampleSetError>)> {
+    match state.few_shot.put(caller.tenant(), &name, req.examples.clone()).await {
+        Ok(()) => {
+            tracing::info!(name = %name, tenant = caller.tenant(), examples = req.examples.len(), "Example set saved");
+            Ok(Json(ExampleSetResponse { name, examples: req.examples }))
+        }
+        Err(error) => Err((StatusCode::BAD_REQUEST, Json(ExampleSetError { error, name }))),
+    }
+}
+
+
+#[utoipa::path(delete, path = "/examples/{name}", tag = "examples",
+    params(("name" = String, Path)),
+    responses((status = 200, body = RemoveExampleSetResponse)))]
+pub async fn remove_example_set_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Json<RemoveExampleSetResponse> {
+    let removed = state.few_shot.remove(caller.tenant(), &name).await;
+    Json(RemoveExampleSetResponse { name, removed })
+}
+
+
+fn eval_error(status: StatusCode, error: impl Into<String>, name: String) -> Response {
+    (status, Json(EvalError { error: error.into(), name })).into_response()
+}
+
+/// 调用方租户已上传的 eval 集合
+#[utoipa::path(get, path = "/evals", tag = "evals",
+    responses((status = 200, body = ListEvalSuitesResponse)))]
+pub async fn list_eval_suites_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Json<ListEvalSuitesResponse> {
+    Json(ListEvalSuitesResponse {
+        suites: state.evals.list(caller.tenant()).into_iter()
+            .map(|(name, cases)| EvalSuiteInfo { name, cases })
+            .collect(),
+    })
+}
+
+
+#[utoipa::path(get, path = "/evals/{name}", tag = "evals",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = EvalSuiteResponse),
+        (status = 404, body = EvalError),
+    ))]
+pub async fn get_eval_suite_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Json<EvalSuiteResponse>, Response> {
+    match state.evals.get(caller.tenant(), &name) {
+        Some(suite) => Ok(Json(EvalSuiteResponse { name, suite })),
+        None => Err(eval_error(StatusCode::NOT_FOUND, "Eval suite not found", name)),
+    }
+}
+
+
+/// 上传（创建或替换）一个 eval 集合，body 为 YAML：可选的 model、system_prompt 和 generation，
+/// 以及 cases（每个用例有 prompt 和 pattern / expected）
+#[utoipa::path(put, path = "/evals/{name}", tag = "evals",
+    params(("name" = String, Path)),
+    request_body(content = String, content_type = "application/yaml"),
+    responses(
+        (status = 200, body = EvalSuiteResponse),
+        (status = 400, description = "Invalid name, YAML, pattern or no cases", body = EvalError),
+    ))]
+pub async fn put_eval_suite_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    body: String,
+) -> Result<Json<EvalSuiteResponse>, Response> {
+    match state.evals.put(caller.tenant(), &name, &body).await {
+        Ok(suite) => {
+            tracing::info!(name = %name, tenant = caller.tenant(), cases = suite.cases.len(), "Eval suite saved");
+            Ok(Json(EvalSuiteResponse { name, suite }))
+        }
+        Err(error) => Err(eval_error(StatusCode::BAD_REQUEST, error, name)),
+    }
+}
+
+
+#[utoipa::path(delete, path = "/evals/{name}", tag = "evals",
+    params(("name" = String, Path)),
+    responses((status = 200, body = RemoveEvalSuiteResponse)))]
+pub async fn remove_eval_suite_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Json<RemoveEvalSuiteResponse> {
+    let removed = state.evals.remove(caller.tenant(), &name).await;
+    Json(RemoveEvalSuiteResponse { name, removed })
+}
+
+
+/// 对一个模型运行 eval 集合的全部用例，返回每个用例是否通过，失败时带期望和回答的对比。
+/// 用例像 /generate/batch 的项一样单独生成（不使用 session），未设置 temperature 时按 0 生成
+#[utoipa::path(post, path = "/evals/{name}/run", tag = "evals",
+    params(("name" = String, Path)),
+    request_body = EvalRunRequest,
+    responses(
+        (status = 200, body = EvalReport),
+        (status = 400, description = "Unknown model or invalid sampling parameter", body = ValidationError),
+        (status = 400, description = "A prompt blocked by content moderation", body = ModerationError),
+        (status = 404, body = EvalError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 429, description = "Token quota exhausted", body = QuotaExceededError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn run_eval_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Extension(request_id): Extension<RequestId>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    Json(req): Json<EvalRunRequest>,
+) -> Result<Json<EvalReport>, Response> {
+    let request_id = request_id_string(&request_id);
+    let Some(suite) = state.evals.get(caller.tenant(), &name) else {
+        return Err(eval_error(StatusCode::NOT_FOUND, "Eval suite not found", name));
+    };
+    let requested = if req.model.is_empty() { suite.model.clone().unwrap_or_default() } else { req.model };
+    validate_sampling(&suite.generation)?;
+    let model = requested_model(&state, &requested).await?;
+    check_service_ready(&state, &model)?;
+    for case in &suite.cases {
+        moderate_prompt(&state, &case.prompt, &request_id, caller.owner(), None).await?;
+    }
+    check_quota(&state, caller.owner())?;
+
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+    let started = Instant::now();
+    let generation_config = limit_generation(&state, &model, &suite.generation).await;
+    let generation_config = GenerationConfig {
+        temperature: generation_config.temperature.or(Some(0.0)),
+        ..generation_config
+    };
+    tracing::info!(request_id = %request_id, suite = %name, model = %model, cases = suite.cases.len(), "Eval started");
+
+    let cases = suite.cases.iter()
+        .enumerate()
+        .map(|(index, case)| {
+            eval_case(&state, &ticket, &caller, &request_id, &model, index, case, suite.messages(case), &generation_config)
+        });
+    let mut results: Vec<EvalCaseResult> =
+        futures::StreamExt::buffer_unordered(tokio_stream::iter(cases), state.inference_queue.max_concurrent())
+            .collect()
+            .await;
+    results.sort_by_key(|result| result.index);
+
+    let passed = results.iter().filter(|result| result.passed).count();
+    let mut usage = Usage::default();
+    for result in &results {
+        usage.add(&result.usage);
+    }
+    usage.finish_reason = None;
+    tracing::info!(request_id = %request_id, suite = %name, model = %model, passed, failed = results.len() - passed, "Eval finished");
+
+    Ok(Json(EvalReport {
+        suite: name,
+        model,
+        total: results.len(),
+        passed,
+        failed: results.len() - passed,
+        results,
+        usage,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }))
+}
+
+// eval 的一个用例：等待推理的名额后生成并检查回答
+#[allow(clippy::too_many_arguments)]
+async fn eval_case(
+    state: &AppState,
+    ticket: &QueueTicket,
+    caller: &Caller,
+    request_id: &str,
+    model: &str,
+    index: usize,
+    case: &EvalCase,
+    messages: Vec<ChatMessage>,
+    generation_config: &GenerationConfig,
+) -> EvalCaseResult {
+    let mut timer = GenerationTimer::new();
+    let _permit = wait_turn(ticket, generation_config).await;
+    timer.start();
+    let (output, usage, error) = match generation_step(state, model, &messages, generation_config).await {
+        Ok((output, usage)) => (output, usage, None),
+        Err(e) => {
+            tracing::error!(request_id, index, model, error = %e, "Eval case failed");
+            (String::new(), Usage::default(), Some(e.to_string()))
+        }
+    };
+
+    let timings = timer.finish(usage.completion_tokens);
+    let record = AuditRecord::new(
+        "/evals/run", request_id, caller.owner(), None, model, &case.prompt, &output, &usage, &timings);
+    finish_generation(state, &record).await;
+
+    let outcome = match error {
+        None => check_case(case, &output),
+        Some(_) => CaseOutcome { passed: false, diff: None },
+    };
+    EvalCaseResult {
+        index,
+        name: case.name.clone(),
+        passed: outcome.passed,
+        output,
+        diff: outcome.diff,
+        error,
+        usage,
+    }
+}
+
+
+/// 调用方的 token 用量和配额，以及所在租户全部用户的用量合计。管理员可以用 `?owner=` 查询其他用户
+#[utoipa::path(get, path = "/usage", tag = "usage",
+    params(UsageQuery),
+    responses(
+        (status = 200, body = UsageResponse),
+        (status = 403, description = "Only admin users can read another user's usage", body = UsageError),
+    ))]
+pub async fn usage_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageResponse>, Response> {
+    let owner = match query.owner {
+        Some(owner) if caller.owner() != Some(owner.as_str()) => {
+            if !is_admin(&state.config.api_keys, &state.config.admin_users, &caller) {
+                return Err((StatusCode::FORBIDDEN, Json(UsageError {
+                    error: "Only admin users can read another user's usage".to_string(),
+                })).into_response());
+            }
+            owner
+        }
+        _ => caller.owner().unwrap_or(ANONYMOUS).to_string(),
+    };
+
+    // 未配置 api_keys 时所有请求都计入 ANONYMOUS，按默认配额检查，也没有租户
+    let now = chrono::Utc::now();
+    let quota = state.config.quota_for(caller.owner().is_some().then_some(owner.as_str()));
+    let tenant = caller.owner().is_some().then(|| state.config.tenant_of(&owner));
+    let tenant_usage = tenant.as_ref()
+        .map(|tenant| state.usage.total(|user| state.config.tenant_of(user) == *tenant, now));
+    Ok(Json(UsageResponse {
+        usage: state.usage.get(&owner, now),
+        owner,
+        quota,
+        tenant,
+        tenant_usage,
+    }))
+}
+
+
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+const MAX_AUDIT_LIMIT: usize = 1000;
+
+fn audit_error(status: StatusCode, error: &str) -> Response {
+    (status, Json(AuditError { error: error.to_string() })).into_response()
+}
+
+/// 查询审计日志，最新的记录在前。配置了 api_keys 时只有 admin_users 中的用户可以查询
+#[utoipa::path(get, path = "/admin/audit", tag = "admin",
+    params(AuditQuery),
+    responses(
+        (status = 200, body = AuditLogResponse),
+        (status = 403, description = "The caller is not in admin_users", body = AuditError),
+        (status = 404, description = "audit_log_path is not set", body = AuditError),
+    ))]
+pub async fn audit_log_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditLogResponse>, Response> {
+    if !is_admin(&state.config.api_keys, &state.config.admin_users, &caller) {
+        return Err(audit_error(StatusCode::FORBIDDEN, "Only admin users can read the audit log"));
+    }
+    if !state.audit_log.enabled() {
+        return Err(audit_error(StatusCode::NOT_FOUND, "Audit log is disabled"));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT).clamp(1, MAX_AUDIT_LIMIT);
+    match state.audit_log.query(&query, limit).await {
+        Ok(records) => Ok(Json(AuditLogResponse { records })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to read audit log");
+            Err(audit_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read audit log"))
+        }
+    }
+}
+
+
+const DEFAULT_BENCHMARK_LIMIT: usize = 20;
+
+fn benchmark_error(status: StatusCode, error: impl Into<String>) -> Response {
+    (status, Json(BenchmarkError { error: error.into() })).into_response()
+}
+
+/// 用固定的 prompt 测试模型的首 token 时间、生成速度和峰值内存，结果保存到 benchmark_path。
+/// 运行期间独占推理的名额，其他生成请求排队等待。配置了 api_keys 时只有 admin_users 可以运行
+#[utoipa::path(post, path = "/admin/benchmark", tag = "admin",
+    request_body = BenchmarkRequest,
+    responses(
+        (status = 200, body = BenchmarkRun),
+        (status = 400, description = "Unknown model", body = ModelError),
+        (status = 403, description = "The caller is not in admin_users", body = BenchmarkError),
+        (status = 429, description = "Inference queue full", body = QueueFullError),
+        (status = 500, description = "The model failed to load or generate", body = BenchmarkError),
+        (status = 503, description = "The requested model is loading", body = ServiceLoadingError),
+    ))]
+pub async fn benchmark_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<BenchmarkRequest>,
+) -> Result<Json<BenchmarkRun>, Response> {
+    if !is_admin(&state.config.api_keys, &state.config.admin_users, &caller) {
+        return Err(benchmark_error(StatusCode::FORBIDDEN, "Only admin users can run benchmarks"));
+    }
+    let model = requested_model(&state, &req.model).await?;
+    check_service_ready(&state, &model)?;
+    let options = BenchmarkOptions {
+        label: req.label,
+        runs: req.runs.unwrap_or(1).clamp(1, MAX_BENCHMARK_RUNS),
+        max_tokens: match state.config.max_tokens_limit {
+            0 => req.max_tokens.unwrap_or(DEFAULT_BENCHMARK_TOKENS).max(1),
+            limit => req.max_tokens.unwrap_or(DEFAULT_BENCHMARK_TOKENS).clamp(1, limit),
+        },
+    };
+
+    let ticket = state.inference_queue.enter().ok_or_else(|| queue_full_response(&state))?;
+    let _permit = ticket.wait_exclusive().await;
+    tracing::info!(model = %model, runs = options.runs, "Benchmark started");
+
+    let run = run_benchmark(&state.model_cache, &state.registry, &state.config.model_dir, &model, options)
+        .await
+        .map_err(|e| {
+            tracing::error!(model = %model, error = %e, "Benchmark failed");
+            benchmark_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Benchmark failed: {}", e))
+        })?;
+    if let Err(e) = state.benchmarks.record(&run).await {
+        tracing::error!(error = %e, "Failed to save benchmark result");
+    }
+    Ok(Json(run))
+}
+
+/// 保存的 benchmark 结果，最新的在前，可以按模型过滤
+#[utoipa::path(get, path = "/admin/benchmark", tag = "admin",
+    params(BenchmarkQuery),
+    responses(
+        (status = 200, body = BenchmarkHistoryResponse),
+        (status = 403, description = "The caller is not in admin_users", body = BenchmarkError),
+    ))]
+pub async fn benchmark_history_handler(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<BenchmarkQuery>,
+) -> Result<Json<BenchmarkHistoryResponse>, Response> {
+    if !is_admin(&state.config.api_keys, &state.config.admin_users, &caller) {
+        return Err(benchmark_error(StatusCode::FORBIDDEN, "Only admin users can read benchmark results"));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_BENCHMARK_LIMIT).clamp(1, MAX_AUDIT_LIMIT);
+    match state.benchmarks.list(query.model.as_deref(), limit).await {
+        Ok(runs) => Ok(Json(BenchmarkHistoryResponse { runs })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to read benchmark results");
+            Err(benchmark_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read benchmark results"))
+        }
+    }
+}
+
+
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/generate", post(infer_handler))
         .route("/generate/stream", post(infer_stream_handler))
+        .route("/generate/batch", post(batch_handler))
+        .route("/generate/cancel/{request_id}", post(cancel_handler))
+        .route("/agent/run", post(agent_run_handler))
         .route("/health", get(healthy))
+        .route("/health/ready", get(ready_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/models", get(list_models_handler))
+        .route("/models/pull", post(pull_model_handler))
+        .route("/models/{name}/load", post(load_model_handler))
+        .route("/models/{name}/unload", post(unload_model_handler))
+        .route("/models/{name}/files", delete(delete_model_files_handler))
+        .route("/models/{name}/adapters", post(add_adapter_handler))
+        .route("/tokenize", post(tokenize_handler))
+        .route("/detokenize", post(detokenize_handler))
+        .route("/v1/models", get(list_openai_models_handler))
+        .route("/api/generate", post(ollama_generate_handler))
+        .route("/api/chat", post(ollama_chat_handler))
+        .route("/api/tags", get(ollama_tags_handler))
         .route("/upload", post(upload_handler))
-        .route("/files/{file_id}", delete(remove_handler))
+        .route("/uploads/{upload_id}", get(upload_progress_handler))
+        .route("/transcribe", post(transcribe_handler))
+        .route("/files", get(list_files_handler))
+        .route("/files/{file_id}", get(get_file_handler).delete(remove_handler))
+        .route("/files/{file_id}/status", get(file_status_handler))
+        .route("/files/{file_id}/content", get(file_content_handler))
+        .route("/files/{file_id}/summarize", post(summarize_file_handler))
+        .route("/files/{file_id}/extract", post(extract_file_handler))
+        .route("/sessions", get(list_sessions_handler))
         .route("/sessions/{session_id}", delete(remove_session_handler))
         .route("/sessions/{session_id}", get(get_session_handler))
         .route("/sessions/sync", post(sync_session_handler))
+        .route("/sessions/{session_id}/config", put(update_session_config_handler))
+        .route("/sessions/{session_id}/messages/{message_id}/edit", post(edit_message_handler))
+        .route("/sessions/{session_id}/messages/{message_id}/pin", put(pin_message_handler))
+        .route("/sessions/{session_id}/fork", post(fork_session_handler))
+        .route("/memory", get(get_memory_handler).put(update_memory_handler).delete(clear_memory_handler))
+        .route("/examples", get(list_example_sets_handler))
+        .route("/examples/{name}", get(get_example_set_handler).put(put_example_set_handler).delete(remove_example_set_handler))
+        .route("/evals", get(list_eval_suites_handler))
+        .route("/evals/{name}", get(get_eval_suite_handler).put(put_eval_suite_handler).delete(remove_eval_suite_handler))
+        .route("/evals/{name}/run", post(run_eval_handler))
+        .route("/usage", get(usage_handler))
+        .route("/admin/audit", get(audit_log_handler))
+        .route("/admin/benchmark", post(benchmark_handler).get(benchmark_history_handler))
 }
\ No newline at end of file
@@ -0,0 +1,71 @@
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+
+// nvidia-smi 卡住（驱动异常）时不能拖住 /health
+const GPU_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+
+/// GPU 的显存（MB）
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpuMemory {
+    pub free_mb: u64,
+    pub total_mb: u64,
+}
+
+// `nvidia-smi --query-gpu=memory.free,memory.total --format=csv,noheader,nounits` 的一行，例如 "10240, 24576"
+fn parse_gpu_memory(output: &str) -> Option<GpuMemory> {
+    let line = output.lines().next()?;
+    let mut values = line.split(',').map(|value| value.trim().parse::<u64>());
+    let free_mb = values.next()?.ok()?;
+    let total_mb = values.next()?.ok()?;
+    Some(GpuMemory { free_mb, total_mb })
+}
+
+/// 通过 nvidia-smi 查询第 index 块 GPU 的显存。没有 NVIDIA GPU 或驱动（例如 Metal）时返回 None
+pub async fn gpu_memory(index: usize) -> Option<GpuMemory> {
+    let query = Command::new("nvidia-smi")
+        .arg("--query-gpu=memory.free,memory.total")
+        .arg("--format=csv,noheader,nounits")
+        .arg(format!("--id={}", index))
+        .kill_on_drop(true)
+        .output();
+
+    let output = tokio::time::timeout(GPU_QUERY_TIMEOUT, query).await.ok()?.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_gpu_memory(&String::from_utf8_lossy(&output.stdout))
+}
+
+
+/// path 所在磁盘的可用空间（MB），目录还不存在时查询最近的已存在的上级目录
+pub fn free_disk_mb(path: &str) -> Option<u64> {
+    let mut path = Path::new(path);
+    while !path.exists() {
+        path = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    fs2::available_space(path).ok().map(|bytes| bytes / (1024 * 1024))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gpu_memory() {
+        assert_eq!(parse_gpu_memory("10240, 24576\n"), Some(GpuMemory { free_mb: 10240, total_mb: 24576 }));
+        assert_eq!(parse_gpu_memory("[N/A], [N/A]\n"), None);
+        assert_eq!(parse_gpu_memory(""), None);
+    }
+
+    #[test]
+    fn test_free_disk_of_missing_directory() {
+        assert!(free_disk_mb("models/not/created/yet").is_some());
+    }
+}
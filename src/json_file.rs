@@ -0,0 +1,64 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+
+/// 以 JSON 文件保存的状态（记忆、用量、few-shot 示例、eval 集合）。
+/// 保存串行进行，快照在锁内获取，并发保存时旧的快照不会覆盖新的
+pub struct JsonFile {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl JsonFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, write_lock: Mutex::new(()) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 取快照并写入文件
+    pub async fn save<T: Serialize>(&self, snapshot: impl FnOnce() -> T) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        write_json_atomic(&self.path, &snapshot()).await
+    }
+}
+
+
+/// 先写入临时文件再 rename，写到一半崩溃时不会留下不完整的文件
+pub async fn write_json_atomic<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).await?;
+    }
+
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(value)?).await?;
+    fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_save_replaces_the_file() {
+        let dir = std::env::temp_dir().join(format!("json-file-test-{}", uuid::Uuid::new_v4()));
+        let file = JsonFile::new(dir.join("state.json"));
+
+        file.save(|| BTreeMap::from([("a", 1)])).await.unwrap();
+        file.save(|| BTreeMap::from([("b", 2)])).await.unwrap();
+
+        let saved: BTreeMap<String, u32> = serde_json::from_slice(&fs::read(file.path()).await.unwrap()).unwrap();
+        assert_eq!(saved, BTreeMap::from([("b".to_string(), 2)]));
+        assert!(!file.path().with_extension("json.tmp").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
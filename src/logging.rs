@@ -0,0 +1,54 @@
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+
+// RUST_LOG 未设置时的日志级别
+const DEFAULT_FILTER: &str = "info";
+
+
+/// 日志格式：text 为便于阅读的单行文本，json 为每行一个 JSON 对象（带 session_id、model、
+/// duration_ms、token 数等字段），便于日志系统收集和查询
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Option<LogFormat> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+
+/// 初始化全局的 tracing subscriber，级别由 RUST_LOG 控制（默认 info）
+pub fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        // 事件所在的 span（请求的 method、uri、request_id）也写入每一行
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).init(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!(LogFormat::parse("json"), Some(LogFormat::Json));
+        assert_eq!(LogFormat::parse(" Text "), Some(LogFormat::Text));
+        assert_eq!(LogFormat::parse("yaml"), None);
+        assert_eq!(LogFormat::default(), LogFormat::Text);
+    }
+}
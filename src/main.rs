@@ -3,51 +3,275 @@ mod error;
 mod types;
 mod mistral_runner;
 mod file_parser;
+mod file_store;
+mod parse_cache;
+mod response_cache;
 mod session;
+mod config;
+mod engine;
+mod registry;
+mod queue;
+mod transcribe;
+mod metrics;
+mod health;
+mod service_state;
+mod sse;
+mod logging;
+mod auth;
+mod rate_limit;
+mod request_id;
+mod validation;
+mod openapi;
+mod web;
+mod json_file;
+mod memory;
+mod few_shot;
+mod moderation;
+mod audit;
+mod usage;
+mod webhook;
+mod events;
+mod benchmark;
+mod evals;
+mod upload;
+mod tools;
+mod agent;
+mod summarize;
+mod extract;
+mod pii;
+mod chat_template;
+mod remote;
+mod store;
+#[cfg(feature = "redis")]
+mod redis_store;
 
+use std::net::SocketAddr;
+use std::sync::Arc;
 use axum::{
     Router,
+    extract::DefaultBodyLimit,
+    middleware,
 };
-use axum::http::Method;
+use axum::http::{HeaderValue, Method};
 use tokio::net::TcpListener;
 use tower_http::{
-    cors::{Any, CorsLayer},
+    cors::{AllowOrigin, Any, CorsLayer},
     trace::TraceLayer,
     compression::CompressionLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
 };
-use tracing_subscriber;
-use crate::file_parser::{new_file_cache, FileCache};
+use crate::config::ServerConfig;
+use crate::logging::init_logging;
+use crate::file_parser::{new_vector_index, FileCache, VectorIndex};
+use crate::file_store::{load_file_cache, spawn_file_sweeper, SharedFileStore};
+use crate::store::open_stores;
+use crate::parse_cache::{new_parse_cache, ParseCache};
+use crate::response_cache::{new_response_cache, SharedResponseCache};
 use crate::handler::routes;
-use crate::session::{new_session_manager, SessionManager};
+use crate::openapi::openapi_routes;
+use crate::web::web_routes;
+use crate::engine::{new_active_generations, new_model_cache, spawn_preload, ActiveGenerations, ModelCache};
+use crate::queue::InferenceQueue;
+use crate::mistral_runner::gpu_available;
+use crate::transcribe::Transcriber;
+use crate::registry::{new_shared_registry, Device, ModelRegistry, SharedRegistry};
+use crate::session::{new_session_manager, new_shared_session_manager, spawn_session_sweeper, SessionManager};
+use crate::metrics::{new_metrics, SharedMetrics};
+use crate::auth::require_api_key;
+use crate::rate_limit::{rate_limit, RateLimiter, SharedRateLimiter};
+use crate::request_id::{drop_invalid_request_id, request_span};
+use crate::validation::limit_json_body;
+use crate::memory::{load_memory, SharedMemory};
+use crate::few_shot::{load_example_sets, SharedExampleSets};
+use crate::evals::{load_eval_suites, SharedEvalSuites};
+use crate::upload::{new_upload_tracker, SharedUploadTracker};
+use crate::moderation::{new_moderation_log, SharedModerationLog};
+use crate::audit::{new_audit_log, spawn_audit_pruner, SharedAuditLog};
+use crate::usage::{load_usage, SharedUsageTracker};
+use crate::webhook::{new_webhook_sender, SharedWebhookSender};
+use crate::events::{connect_event_bus, SharedEventBus};
+use crate::benchmark::{new_benchmark_history, parse_cli, run_benchmark, BenchmarkOptions, SharedBenchmarkHistory};
+use crate::service_state::{new_service_status, SharedServiceStatus};
 
 #[derive(Clone)]
 pub struct AppState {
     pub file_cache: FileCache,
+    pub file_store: SharedFileStore,
+    pub uploads: SharedUploadTracker,
+    pub vector_index: VectorIndex,
+    pub parse_cache: ParseCache,
+    // 后台解析文档的并发数限制
+    pub parse_permits: Arc<tokio::sync::Semaphore>,
+    pub response_cache: SharedResponseCache,
     pub session_manager: SessionManager,
+    pub model_cache: ModelCache,
+    pub registry: SharedRegistry,
+    pub active_generations: ActiveGenerations,
+    pub inference_queue: InferenceQueue,
+    pub transcriber: Arc<Transcriber>,
+    pub metrics: SharedMetrics,
+    pub memory: SharedMemory,
+    pub few_shot: SharedExampleSets,
+    pub evals: SharedEvalSuites,
+    pub moderation_log: SharedModerationLog,
+    pub audit_log: SharedAuditLog,
+    pub usage: SharedUsageTracker,
+    pub webhooks: SharedWebhookSender,
+    pub events: SharedEventBus,
+    pub benchmarks: SharedBenchmarkHistory,
+    pub rate_limiter: SharedRateLimiter,
+    pub service_status: SharedServiceStatus,
+    pub config: Arc<ServerConfig>,
+}
+
+// 返回进程的退出码
+async fn benchmark_cli(state: &AppState, model: &str, options: BenchmarkOptions) -> i32 {
+    let model = state.registry.read().await.resolve(model).to_string();
+    match run_benchmark(&state.model_cache, &state.registry, &state.config.model_dir, &model, options).await {
+        Ok(run) => {
+            if let Err(e) = state.benchmarks.record(&run).await {
+                tracing::error!(error = %e, "Failed to save benchmark result");
+            }
+            println!("{}", serde_json::to_string_pretty(&run).unwrap_or_default());
+            0
+        }
+        Err(e) => {
+            tracing::error!(model = %model, error = %e, "Benchmark failed");
+            1
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    let config = ServerConfig::load().expect("Failed to load server config");
+    let benchmark = parse_cli(&std::env::args().collect::<Vec<_>>()).expect("Invalid command line");
+    init_logging(config.log_format);
+    if std::path::Path::new(&ServerConfig::path()).exists() {
+        tracing::info!(path = %ServerConfig::path(), "Loaded config");
+    }
 
-    tracing_subscriber::fmt::init();
+    let mut registry = ModelRegistry::load(&config.registry_path).expect("Failed to load model registry");
+    registry.set_default_placement(config.placement());
+    if config.device != Device::Cpu && !gpu_available(config.gpu_index) {
+        tracing::warn!(device = ?config.device, gpu_index = config.gpu_index,
+                       "No usable GPU found, running all models on the CPU");
+        registry.fall_back_to_cpu();
+    }
 
+    let stores = open_stores(&config).await.expect("Failed to open state store");
+    let session_manager = match stores.session_store {
+        Some(store) => new_shared_session_manager(store),
+        None => new_session_manager(),
+    };
+
+    let service_status = new_service_status();
     let state = AppState {
-        file_cache: new_file_cache(),
-        session_manager : new_session_manager(),
+        file_cache: load_file_cache(stores.file_store.as_ref()).await.expect("Failed to load stored files"),
+        file_store: stores.file_store.clone(),
+        uploads: new_upload_tracker(),
+        vector_index: new_vector_index(),
+        parse_cache: new_parse_cache(config.parse_cache_size, config.parse_cache_max_bytes),
+        parse_permits: Arc::new(tokio::sync::Semaphore::new(config.max_parse_tasks.max(1))),
+        response_cache: new_response_cache(config.response_cache_size, config.response_cache_ttl_secs),
+        session_manager,
+        model_cache: new_model_cache(config.model_memory_budget_mb * 1024 * 1024, service_status.clone()),
+        registry: new_shared_registry(registry),
+        active_generations: new_active_generations(),
+        inference_queue: InferenceQueue::new(config.max_concurrent_inferences, config.max_queue_depth),
+        transcriber: Arc::new(Transcriber::new(&config)),
+        metrics: new_metrics(),
+        memory: load_memory(&config.memory_path).await.expect("Failed to load memory"),
+        few_shot: load_example_sets(&config.few_shot_path).await.expect("Failed to load few-shot examples"),
+        evals: load_eval_suites(&config.evals_path).await.expect("Failed to load eval suites"),
+        moderation_log: new_moderation_log(&config.moderation_log_path),
+        audit_log: new_audit_log(&config.audit_log_path, config.audit_retention_days),
+        usage: load_usage(&config.usage_path).await.expect("Failed to load usage counters"),
+        webhooks: new_webhook_sender(&config.webhook_secret, &config.webhook_allowed_hosts, config.webhook_timeout_secs),
+        events: connect_event_bus(&config).await.expect("Failed to connect to event bus"),
+        benchmarks: new_benchmark_history(&config.benchmark_path),
+        rate_limiter: RateLimiter::new(config.rate_limit_per_minute, config.max_concurrent_streams, config.auth_failures_per_minute),
+        service_status,
+        config: Arc::new(config.clone()),
+    };
+
+    // --benchmark <model>：不启动服务器，运行 benchmark 后打印并保存结果
+    if let Some((model, options)) = benchmark {
+        std::process::exit(benchmark_cli(&state, &model, options).await);
+    }
+
+    // 在开始接受请求的同时加载，加载完成前 /health/ready 返回 503
+    spawn_preload(
+        state.model_cache.clone(),
+        state.registry.clone(),
+        config.model_dir.clone(),
+        config.preload_models.clone(),
+    );
+    spawn_file_sweeper(
+        state.file_cache.clone(),
+        state.vector_index.clone(),
+        state.file_store.clone(),
+        config.file_ttl_secs,
+        config.file_sweep_interval_secs,
+    );
+    spawn_audit_pruner(state.audit_log.clone());
+    spawn_session_sweeper(
+        state.session_manager.clone(),
+        state.vector_index.clone(),
+        state.file_cache.clone(),
+        state.metrics.clone(),
+        config.session_ttl_secs,
+        config.session_sweep_interval_secs,
+    );
+
+    let allow_origin = if config.allows_any_origin() {
+        AllowOrigin::from(Any)
+    } else {
+        AllowOrigin::list(
+            config.cors_origins
+                .iter()
+                // checked by ServerConfig::validate when the config is loaded
+                .map(|origin| origin.parse::<HeaderValue>().unwrap())
+        )
     };
 
     let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(vec![Method::GET, Method::POST, Method::DELETE])
+        .allow_origin(allow_origin)
+        .allow_methods(vec![Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_headers(Any);
 
-    let app = Router::new()
+    let mut app = Router::new()
         .merge(routes())
+        .merge(openapi_routes());
+    if config.web_ui {
+        app = app.merge(web_routes());
+    }
+
+    let app = app
+        .layer(middleware::from_fn_with_state(state.clone(), limit_json_body))
+        // runs after authentication, so it can limit per user; rejected keys are limited per IP in require_api_key
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+        .layer(middleware::from_fn_with_state(state.clone(), require_api_key))
+        .layer(DefaultBodyLimit::max(config.max_upload_size))
         .layer(CompressionLayer::new())
-        .layer(TraceLayer::new_for_http())
+        // 每个请求的 x-request-id（客户端传入的或新生成的）记录在 tracing span 中并返回给客户端
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(middleware::map_request(drop_invalid_request_id))
         .layer(cors)
         .with_state(state);
 
-    let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    if !config.api_keys.is_empty() {
+        tracing::info!(keys = config.api_keys.len(), "API key authentication enabled");
+    }
+    if config.rate_limit_per_minute > 0 || config.max_concurrent_streams > 0 {
+        tracing::info!(requests_per_minute = config.rate_limit_per_minute, max_streams = config.max_concurrent_streams,
+                       "Rate limiting enabled (0 = no limit)");
+    }
+
+    let listener = TcpListener::bind(config.bind_address()).await.unwrap();
+    tracing::info!(address = %config.bind_address(), "Listening");
+    // the client address is needed to rate limit by IP when authentication is off
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,306 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use crate::json_file::JsonFile;
+use crate::types::{ChatMessage, MessageRole};
+
+
+// 每个用户最多保存的条目数，以及 key / value 的最大字符数
+pub const MAX_FACTS_PER_USER: usize = 50;
+pub const MAX_FACT_KEY_CHARS: usize = 64;
+pub const MAX_FACT_VALUE_CHARS: usize = 300;
+
+// 提取记忆时用户消息最多取的字符数
+const EXTRACTION_SOURCE_CHARS: usize = 2000;
+
+/// 一个用户的长期记忆，例如 `name` -> `Ann`、`preferred_language` -> `Rust`
+pub type Facts = BTreeMap<String, String>;
+
+
+/// 按用户保存的长期记忆，新的 session 开始时注入系统提示词。
+/// 未开启认证时所有请求共用同一份记忆。设置 path 时每次修改后写入该 JSON 文件，重启后仍然保留
+pub struct MemoryTable {
+    users: DashMap<String, Facts>,
+    file: Option<JsonFile>,
+}
+
+pub type SharedMemory = Arc<MemoryTable>;
+
+/// 读取保存的记忆，path 为空时只保存在内存中，文件不存在时返回空表
+pub async fn load_memory(path: &str) -> Result<SharedMemory> {
+    let users = DashMap::new();
+    let path = (!path.is_empty()).then(|| PathBuf::from(path));
+
+    if let Some(path) = &path {
+        match fs::read(path).await {
+            Ok(data) => {
+                let saved: HashMap<String, Facts> = serde_json::from_slice(&data)?;
+                tracing::info!(users = saved.len(), path = %path.display(), "Loaded memory");
+                for (owner, facts) in saved {
+                    users.insert(owner, facts);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(Arc::new(MemoryTable {
+        users,
+        file: path.map(JsonFile::new),
+    }))
+}
+
+fn user_key(owner: Option<&str>) -> String {
+    owner.unwrap_or_default().to_string()
+}
+
+impl MemoryTable {
+    pub fn get(&self, owner: Option<&str>) -> Facts {
+        self.users.get(&user_key(owner)).map(|facts| facts.clone()).unwrap_or_default()
+    }
+
+    /// 合并修改：value 为 None 时删除该条目。校验失败时不做任何修改
+    pub async fn update(&self, owner: Option<&str>, changes: HashMap<String, Option<String>>) -> Result<Facts, String> {
+        let facts = {
+            let mut facts = self.users.entry(user_key(owner)).or_default();
+            let mut updated = facts.clone();
+            for (key, value) in changes {
+                let key = normalize_key(&key).ok_or_else(|| format!("Invalid memory key \"{}\"", key))?;
+                match value {
+                    Some(value) => {
+                        let value = value.trim();
+                        if value.is_empty() || value.chars().count() > MAX_FACT_VALUE_CHARS {
+                            return Err(format!(
+                                "Memory values must be 1 to {} characters (key \"{}\")", MAX_FACT_VALUE_CHARS, key));
+                        }
+                        updated.insert(key, value.to_string());
+                    }
+                    None => {
+                        updated.remove(&key);
+                    }
+                }
+            }
+            if updated.len() > MAX_FACTS_PER_USER {
+                return Err(format!("At most {} memory entries can be kept", MAX_FACTS_PER_USER));
+            }
+
+            *facts = updated;
+            facts.clone()
+        };
+
+        self.save().await;
+        Ok(facts)
+    }
+
+    /// 合并模型提取的记忆，超出条目数上限的新条目被忽略，返回新增或修改的条目数
+    pub async fn remember(&self, owner: Option<&str>, extracted: Vec<(String, String)>) -> usize {
+        let changed = {
+            let mut facts = self.users.entry(user_key(owner)).or_default();
+            let mut changed = 0;
+            for (key, value) in extracted {
+                if !facts.contains_key(&key) && facts.len() >= MAX_FACTS_PER_USER {
+                    continue;
+                }
+                if facts.get(&key) != Some(&value) {
+                    facts.insert(key, value);
+                    changed += 1;
+                }
+            }
+            changed
+        };
+
+        if changed > 0 {
+            self.save().await;
+        }
+        changed
+    }
+
+    pub async fn clear(&self, owner: Option<&str>) -> bool {
+        let existed = self.users.remove(&user_key(owner)).is_some();
+        if existed {
+            self.save().await;
+        }
+        existed
+    }
+
+    async fn save(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let snapshot = || -> HashMap<String, Facts> {
+            self.users.iter()
+                .filter(|entry| !entry.value().is_empty())
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect()
+        };
+        if let Err(e) = file.save(snapshot).await {
+            tracing::error!(path = %file.path().display(), error = %e, "Failed to save memory");
+        }
+    }
+}
+
+
+/// key 统一为小写、下划线分隔，例如 "Preferred Language" -> "preferred_language"
+pub fn normalize_key(key: &str) -> Option<String> {
+    let key = key.trim()
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_");
+
+    let valid = !key.is_empty()
+        && key.chars().count() <= MAX_FACT_KEY_CHARS
+        && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+    valid.then_some(key)
+}
+
+
+/// 注入新 session 的系统消息，没有记忆时返回 None
+pub fn memory_prompt(facts: &Facts) -> Option<String> {
+    if facts.is_empty() {
+        return None;
+    }
+
+    let mut prompt = "Things you remember about the user from earlier conversations:\n".to_string();
+    for (key, value) in facts {
+        prompt.push_str(&format!("- {}: {}\n", key, value));
+    }
+    Some(prompt.trim_end().to_string())
+}
+
+
+/// 从用户的一条消息中提取值得长期记住的信息，已有的记忆一起发给模型以便更新
+pub fn extraction_prompt(facts: &Facts, user_message: &str) -> Vec<ChatMessage> {
+    let known = match memory_prompt(facts) {
+        Some(prompt) => format!("{}\n\n", prompt),
+        None => String::new(),
+    };
+    let message = match user_message.char_indices().nth(EXTRACTION_SOURCE_CHARS) {
+        Some((idx, _)) => &user_message[..idx],
+        None => user_message,
+    };
+
+    vec![
+        ChatMessage::new(
+            MessageRole::System,
+            "You maintain long-term memory about a user. Extract lasting personal facts and preferences \
+             (name, job, location, languages, tools, style preferences) stated by the user. Ignore \
+             one-off requests and anything about other people. Reply with one `key: value` line per \
+             new or changed fact, using short snake_case keys, or NONE.".to_string(),
+        ),
+        ChatMessage::new(
+            MessageRole::User,
+            format!("{}User message:\n{}", known, message),
+        ),
+    ]
+}
+
+
+/// 解析模型返回的 `key: value` 行，忽略格式不对的行
+pub fn parse_facts(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(|line| line.trim().trim_start_matches(['-', '*']).trim())
+        .filter_map(|line| line.split_once(':'))
+        .filter_map(|(key, value)| {
+            let key = normalize_key(key.trim_matches('`'))?;
+            let value = value.trim().trim_matches('`').trim();
+            let valid = !value.is_empty()
+                && value.chars().count() <= MAX_FACT_VALUE_CHARS
+                && !value.eq_ignore_ascii_case("none");
+            valid.then(|| (key, value.to_string()))
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_key() {
+        assert_eq!(normalize_key(" Preferred Language "), Some("preferred_language".to_string()));
+        assert_eq!(normalize_key("home-city"), Some("home_city".to_string()));
+        assert_eq!(normalize_key(""), None);
+        assert_eq!(normalize_key("a/b"), None);
+        assert_eq!(normalize_key(&"k".repeat(MAX_FACT_KEY_CHARS + 1)), None);
+    }
+
+    #[test]
+    fn test_parse_facts() {
+        let text = "- name: Ann\n* Preferred Language: `Rust`\nNONE\nno separator here\nempty:\n";
+        assert_eq!(parse_facts(text), vec![
+            ("name".to_string(), "Ann".to_string()),
+            ("preferred_language".to_string(), "Rust".to_string()),
+        ]);
+        assert!(parse_facts("NONE").is_empty());
+    }
+
+    #[test]
+    fn test_memory_prompt_lists_facts() {
+        assert_eq!(memory_prompt(&Facts::new()), None);
+
+        let facts = Facts::from([("name".to_string(), "Ann".to_string())]);
+        let prompt = memory_prompt(&facts).unwrap();
+        assert!(prompt.ends_with("- name: Ann"));
+    }
+
+    #[tokio::test]
+    async fn test_update_is_scoped_to_user() {
+        let memory = load_memory("").await.unwrap();
+
+        let changes = HashMap::from([("Name".to_string(), Some("Ann".to_string()))]);
+        let facts = memory.update(Some("alice"), changes).await.unwrap();
+        assert_eq!(facts.get("name").map(String::as_str), Some("Ann"));
+        assert!(memory.get(Some("bob")).is_empty());
+        assert!(memory.get(None).is_empty());
+
+        let changes = HashMap::from([("name".to_string(), None)]);
+        assert!(memory.update(Some("alice"), changes).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_update_changes_nothing() {
+        let memory = load_memory("").await.unwrap();
+        memory.remember(None, vec![("name".to_string(), "Ann".to_string())]).await;
+
+        let changes = HashMap::from([
+            ("name".to_string(), None),
+            ("bad/key".to_string(), Some("x".to_string())),
+        ]);
+        assert!(memory.update(None, changes).await.is_err());
+        assert_eq!(memory.get(None).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remember_respects_limit() {
+        let memory = load_memory("").await.unwrap();
+        let extracted = (0..MAX_FACTS_PER_USER + 5)
+            .map(|i| (format!("fact_{}", i), "x".to_string()))
+            .collect();
+
+        assert_eq!(memory.remember(None, extracted).await, MAX_FACTS_PER_USER);
+        assert_eq!(memory.remember(None, vec![("fact_0".to_string(), "x".to_string())]).await, 0);
+        assert_eq!(memory.get(None).len(), MAX_FACTS_PER_USER);
+    }
+
+    #[tokio::test]
+    async fn test_memory_survives_reload() {
+        let path = std::env::temp_dir().join(format!("memory-test-{}.json", uuid::Uuid::new_v4()));
+        let path_str = path.to_str().unwrap();
+
+        let memory = load_memory(path_str).await.unwrap();
+        memory.remember(Some("alice"), vec![("name".to_string(), "Ann".to_string())]).await;
+
+        let reloaded = load_memory(path_str).await.unwrap();
+        assert_eq!(reloaded.get(Some("alice")).get("name").map(String::as_str), Some("Ann"));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::types::Timings;
+
+
+/// 服务运行指标，`GET /metrics` 以 Prometheus 文本格式输出
+#[derive(Default)]
+pub struct Metrics {
+    // 因空闲超时被清理的 session 数
+    sessions_evicted: AtomicU64,
+}
+
+pub type SharedMetrics = Arc<Metrics>;
+
+pub fn new_metrics() -> SharedMetrics {
+    Arc::new(Metrics::default())
+}
+
+impl Metrics {
+    pub fn record_sessions_evicted(&self, count: usize) {
+        self.sessions_evicted.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn sessions_evicted(&self) -> u64 {
+        self.sessions_evicted.load(Ordering::Relaxed)
+    }
+
+    /// 渲染为 Prometheus 文本格式，active_sessions 等瞬时值由调用方传入
+    pub fn render(&self, active_sessions: usize) -> String {
+        let mut out = String::new();
+        write_metric(&mut out, "llm_sessions_active", "gauge",
+                     "Sessions currently held in memory", active_sessions as u64);
+        write_metric(&mut out, "llm_sessions_evicted_total", "counter",
+                     "Sessions evicted after being idle longer than session_ttl_secs", self.sessions_evicted());
+        out
+    }
+}
+
+/// 一次生成的计时：创建时开始排队，start 时开始生成，first_token 记录第一个 token 的时间
+pub struct GenerationTimer {
+    queued: Instant,
+    started: Option<Instant>,
+    first_token: Option<Instant>,
+}
+
+impl GenerationTimer {
+    pub fn new() -> Self {
+        Self {
+            queued: Instant::now(),
+            started: None,
+            first_token: None,
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.started.get_or_insert_with(Instant::now);
+    }
+
+    pub fn first_token(&mut self) {
+        self.first_token.get_or_insert_with(Instant::now);
+    }
+
+    /// 生成结束，tokens_per_second 按第一个 token 之后的时间计算（没有流式 token 时按整个生成时间）
+    pub fn finish(&self, completion_tokens: usize) -> Timings {
+        let finished = Instant::now();
+        let started = self.started.unwrap_or(finished);
+        let decode_from = self.first_token.unwrap_or(started);
+        let decode_secs = finished.duration_since(decode_from).as_secs_f64();
+
+        Timings {
+            queue_ms: millis(started.duration_since(self.queued)),
+            ttft_ms: self.first_token.map(|first| millis(first.duration_since(started))),
+            total_ms: millis(finished.duration_since(self.queued)),
+            tokens_per_second: if decode_secs > 0.0 {
+                (completion_tokens as f64 / decode_secs * 10.0).round() / 10.0
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+impl Default for GenerationTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counts_evictions() {
+        let metrics = Metrics::default();
+        metrics.record_sessions_evicted(2);
+        metrics.record_sessions_evicted(1);
+
+        let text = metrics.render(5);
+        assert!(text.contains("# TYPE llm_sessions_evicted_total counter\n"));
+        assert!(text.contains("\nllm_sessions_evicted_total 3\n"));
+        assert!(text.contains("\nllm_sessions_active 5\n"));
+    }
+
+    #[test]
+    fn test_timer_splits_queue_and_generation() {
+        let now = Instant::now();
+        let timer = GenerationTimer {
+            queued: now - Duration::from_millis(300),
+            started: Some(now - Duration::from_millis(200)),
+            first_token: Some(now - Duration::from_millis(100)),
+        };
+
+        let timings = timer.finish(10);
+        assert!(timings.queue_ms >= 100 && timings.queue_ms < 150);
+        assert!(timings.ttft_ms.unwrap() >= 100 && timings.ttft_ms.unwrap() < 150);
+        assert!(timings.total_ms >= 300);
+        // 10 tokens in a little over 100ms
+        assert!(timings.tokens_per_second > 50.0 && timings.tokens_per_second <= 100.0);
+    }
+
+    #[test]
+    fn test_timer_without_tokens() {
+        let mut timer = GenerationTimer::new();
+        timer.start();
+
+        let timings = timer.finish(0);
+        assert_eq!(timings.ttft_ms, None);
+        assert_eq!(timings.tokens_per_second, 0.0);
+    }
+}
@@ -1,34 +1,321 @@
 use anyhow::Result;
 use futures::StreamExt;
-use std::path::Path;
-use tokio::{fs, io::AsyncWriteExt};
+use std::path::{Path, PathBuf};
+use tokio::{fs, io::{AsyncReadExt, AsyncWriteExt}};
 use indicatif::{ProgressBar, ProgressStyle};
-use mistralrs::{GgufModelBuilder, TextMessages, TextMessageRole, Response};
-use reqwest::header::CONTENT_LENGTH;
+use mistralrs::{
+    DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, GgufLoraModelBuilder, GgufModelBuilder,
+    GgufXLoraModelBuilder, IsqType, Model, Ordering, RequestBuilder, Response, SamplingParams,
+    TextMessageRole, TextMessages, VisionMessages, VisionModelBuilder,
+};
+use reqwest::header::{CONTENT_LENGTH, RANGE};
+use reqwest::StatusCode;
+
+use either::Either;
+use async_trait::async_trait;
+use std::sync::{Arc, LazyLock};
+use dashmap::DashMap;
+use tokio::sync::{mpsc, oneshot, Mutex, OwnedMutexGuard};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use crate::chat_template::template_file;
+use crate::engine::{ChunkStream, InferenceEngine, ModelCache, StreamChunk};
+use crate::registry::{split_gguf_files, Adapter, AdapterKind, Device, ModelSpec, Placement, SharedRegistry};
+use crate::types::{ChatMessage, MessageRole};
+use crate::types::{GenerationConfig, ModelInfo, TokenLogprobs, TopLogprob, Usage};
+
+// the KV cache of recent sequences is kept so the next turn of a conversation only encodes
+// the new messages; mistralrs matches the longest shared token prefix
+fn prefix_cache(spec: &ModelSpec) -> Option<usize> {
+    (spec.prefix_cache > 0).then_some(spec.prefix_cache)
+}
+
+
+/// 检查指定编号的 GPU（CUDA 或 Metal）能否使用。没有 GPU、驱动不可用或编译时未启用对应后端时返回 false
+pub fn gpu_available(index: usize) -> bool {
+    mistralrs::Device::new_cuda(index).is_ok() || mistralrs::Device::new_metal(index).is_ok()
+}
+
+
+// more layers than any model has, so every layer goes to the chosen GPU
+const ALL_LAYERS: usize = 999;
+
+// explicit layer mapping when only part of the model is offloaded or a GPU other than the
+// first one is used; otherwise mistralrs places the model itself
+fn device_map(placement: &Placement) -> Option<DeviceMapSetting> {
+    if placement.device == Device::Cpu || (placement.gpu_layers.is_none() && placement.gpu_index == 0) {
+        return None;
+    }
+
+    Some(DeviceMapSetting::Map(DeviceMapMetadata::from_num_device_layers(vec![
+        DeviceLayerMapMetadata {
+            ordinal: placement.gpu_index,
+            layers: placement.gpu_layers.unwrap_or(ALL_LAYERS),
+        },
+    ])))
+}
+
+
+/// 确保模型的 GGUF 文件（分片模型为所有分片）存在：本地模型只检查文件，其余模型未下载时从 Hugging Face 下载
+pub async fn fetch_gguf(model_dir: &str, spec: &ModelSpec) -> Result<Vec<PathBuf>> {
+    let paths = spec.gguf_paths(model_dir);
+    if spec.path.is_some() {
+        for path in &paths {
+            if !fs::try_exists(path).await? {
+                anyhow::bail!("Model file {} not found", path.display());
+            }
+        }
+        return Ok(paths);
+    }
+
+    for (file, path) in split_gguf_files(&spec.file).iter().zip(&paths) {
+        download_model(&spec.repo, file, &path.to_string_lossy()).await?;
+    }
+    Ok(paths)
+}
+
+
+/// 下载（如有需要）并加载 GGUF 模型
+pub async fn load_gguf_engine(model_dir: &str, spec: &ModelSpec, placement: Placement) -> Result<Arc<dyn InferenceEngine>> {
+    let paths = fetch_gguf(model_dir, spec).await?;
+
+    // mistralrs takes the directory and the file names inside it, all shards of a split model
+    let dir = paths[0].parent()
+        .map(|dir| dir.to_string_lossy().to_string())
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| ".".to_string());
+    let files = paths.iter()
+        .filter_map(|path| path.file_name())
+        .map(|file| file.to_string_lossy().to_string())
+        .collect();
+    let mut builder = GgufModelBuilder::new(dir, files)
+        .with_prefix_cache_n(prefix_cache(spec))
+        .with_logging();
+    if let Some(template) = template_file(model_dir, spec).await? {
+        builder = builder.with_chat_template(template);
+    }
+    if placement.device == Device::Cpu {
+        builder = builder.with_force_cpu();
+    }
+    if let Some(map) = device_map(&placement) {
+        builder = builder.with_device_mapping(map);
+    }
+    let model = match &spec.adapter {
+        Some(adapter) => build_with_adapter(builder, adapter).await?,
+        None => builder.build().await?,
+    };
+    let model = Arc::new(model);
+
+    Ok(Arc::new(GgufEngine {
+        model,
+        defaults: spec.defaults.clone(),
+    }))
+}
+
+
+// the adapter weights come from Hugging Face, the ordering file says which layers they apply to
+async fn build_with_adapter(builder: GgufModelBuilder, adapter: &Adapter) -> Result<Model> {
+    let ordering: Ordering = serde_json::from_slice(&fs::read(&adapter.ordering).await?)
+        .map_err(|e| anyhow::anyhow!("Invalid adapter ordering file {}: {}", adapter.ordering, e))?;
+    tracing::info!(kind = ?adapter.kind, adapter = %adapter.model_id, "Attaching adapter");
+
+    match adapter.kind {
+        AdapterKind::Lora => {
+            GgufLoraModelBuilder::from_gguf_model_builder(builder, adapter.model_id.clone(), ordering)
+                .build()
+                .await
+        }
+        AdapterKind::Xlora => {
+            GgufXLoraModelBuilder::from_gguf_model_builder(
+                builder,
+                adapter.model_id.clone(),
+                ordering,
+                adapter.tgt_non_granular_index,
+            )
+                .build()
+                .await
+        }
+    }
+}
+
+
+/// 加载视觉模型（llava 等）。mistralrs 的多模态 pipeline 从 Hugging Face 加载原始权重，
+/// 加载时做 Q4K 量化
+pub async fn load_vision_engine(model_dir: &str, spec: &ModelSpec, placement: Placement) -> Result<Arc<dyn InferenceEngine>> {
+    tracing::info!(model = %spec.name, repo = %spec.repo, "Loading vision model");
+
+    let mut builder = VisionModelBuilder::new(&spec.repo)
+        .with_isq(IsqType::Q4K)
+        .with_prefix_cache_n(prefix_cache(spec))
+        .with_logging();
+    if let Some(template) = template_file(model_dir, spec).await? {
+        builder = builder.with_chat_template(template);
+    }
+    if placement.device == Device::Cpu {
+        builder = builder.with_force_cpu();
+    }
+    if let Some(map) = device_map(&placement) {
+        builder = builder.with_device_mapping(map);
+    }
+    let model = Arc::new(builder.build().await?);
+
+    Ok(Arc::new(VisionEngine {
+        model,
+        defaults: spec.defaults.clone(),
+    }))
+}
+
+
+/// 列出所有支持的模型及其下载 / 加载状态
+pub async fn list_models(cache: &ModelCache, registry: &SharedRegistry, model_dir: &str) -> Vec<ModelInfo> {
+    let loaded = cache.read().await;
+    let registry = registry.read().await;
+
+    registry
+        .models()
+        .iter()
+        .map(|spec| ModelInfo {
+            name: spec.name.clone(),
+            repo: spec.repo.clone(),
+            file: spec.file.clone(),
+            quantization: spec.quantization.clone(),
+            context_length: spec.context_length,
+            // vision models are fetched into the Hugging Face cache, not model_dir
+            downloaded: !spec.vision && spec.remote.is_none()
+                && spec.gguf_paths(model_dir).iter().all(|path| path.exists()),
+            loaded: loaded.contains_key(&spec.name),
+            vision: spec.vision,
+            device: match &spec.remote {
+                Some(_) => "remote".to_string(),
+                None => registry.placement(spec).describe(),
+            },
+        })
+        .collect()
+}
+
+
+// every GGUF file starts with these bytes
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+// keep some room on the disk after a download, for logs, uploads and the like
+const DISK_SPACE_MARGIN: u64 = 256 * 1024 * 1024;
+
+
+/// 下载前检查磁盘剩余空间，不够时返回说明需要多少空间以及如何释放
+fn check_disk_space(dir: &Path, file: &str, needed: u64, available: u64) -> Result<()> {
+    if needed + DISK_SPACE_MARGIN <= available {
+        return Ok(());
+    }
+
+    let mb = |bytes: u64| bytes / (1024 * 1024);
+    anyhow::bail!(
+        "Not enough disk space in {} to download {}: {} MB needed, {} MB free. \
+         Delete unused models with DELETE /models/{{name}}/files or point model_dir at a larger disk",
+        dir.display(), file, mb(needed + DISK_SPACE_MARGIN), mb(available)
+    )
+}
 
-use async_stream::stream;
-use futures::Stream;
-use std::pin::Pin;
-use std::sync::Arc;
-use crate::session::{ChatMessage, MessageRole};
 
 // download model if missing
 pub async fn download_model(repo: &str, file: &str, path: &str) -> Result<()> {
+    download_model_with_progress(repo, file, path, |_, _| {}).await
+}
+
+
+// 同一个文件同时只有一个下载，否则并发的 pull（或加载）会写同一个 .part 文件。key 为目标路径
+static DOWNLOADS: LazyLock<DashMap<String, Arc<Mutex<()>>>> = LazyLock::new(DashMap::new);
+
+struct DownloadLock {
+    path: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl DownloadLock {
+    async fn acquire(path: &str) -> Self {
+        let lock = DOWNLOADS.entry(path.to_string()).or_default().clone();
+        let guard = lock.lock_owned().await;
+        Self { path: path.to_string(), guard: Some(guard) }
+    }
+}
+
+impl Drop for DownloadLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        DOWNLOADS.remove_if(&self.path, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+/// 下载模型，每收到一块数据调用 on_progress(已下载字节, 总字节)，总字节未知时为 0
+pub async fn download_model_with_progress<F>(
+    repo: &str,
+    file: &str,
+    path: &str,
+    mut on_progress: F,
+) -> Result<()>
+where
+    F: FnMut(u64, u64),
+{
+    // a download of the same file that finished while we waited leaves the file in place
+    let _download = DownloadLock::acquire(path).await;
     if Path::new(path).exists() {
         return Ok(());
     }
 
-    println!("Downloading model {file}…");
-
+    // download into a .part file and only rename it once complete, so an
+    // interrupted download is never mistaken for a finished model
+    let part_path = format!("{path}.part");
     let url = format!("https://huggingface.co/{repo}/resolve/main/{file}");
-    let response = reqwest::get(&url).await?;
+    let client = reqwest::Client::new();
 
-    let total_size = response
+    let mut resume_from = match fs::metadata(&part_path).await {
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    };
+
+    let mut response = if resume_from > 0 {
+        tracing::info!(file, resume_from, "Resuming model download");
+        client.get(&url).header(RANGE, format!("bytes={resume_from}-")).send().await?
+    } else {
+        tracing::info!(file, "Downloading model");
+        client.get(&url).send().await?
+    };
+
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // the partial file is not a prefix of the remote file, start over
+        tracing::warn!(file, "Cannot resume model download, restarting");
+        resume_from = 0;
+        response = client.get(&url).send().await?;
+    }
+    let response = response.error_for_status()?;
+
+    // a server that ignores Range answers 200 with the whole file
+    let resumed = resume_from > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+    if !resumed {
+        resume_from = 0;
+    }
+
+    let remaining: u64 = response
         .headers()
         .get(CONTENT_LENGTH)
         .and_then(|v| v.to_str().ok())
         .and_then(|s| s.parse().ok())
         .unwrap_or(0);
+    let total_size = if remaining > 0 { resume_from + remaining } else { 0 };
+
+    // fail before writing gigabytes rather than when the disk is full
+    if remaining > 0 {
+        let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        if let Ok(available) = fs2::available_space(dir) {
+            check_disk_space(dir, file, remaining, available)?;
+        }
+    }
+
+    let mut file_out = if resumed {
+        fs::OpenOptions::new().append(true).open(&part_path).await?
+    } else {
+        fs::File::create(&part_path).await?
+    };
 
     let pb = ProgressBar::new(total_size);
     pb.set_style(
@@ -36,66 +323,209 @@ pub async fn download_model(repo: &str, file: &str, path: &str) -> Result<()> {
             "[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
         )?
     );
+    pb.set_position(resume_from);
 
-    let mut file_out = fs::File::create(path).await?;
     let mut stream = response.bytes_stream();
+    let mut downloaded = resume_from;
 
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         file_out.write_all(&chunk).await?;
         pb.inc(chunk.len() as u64);
+        downloaded += chunk.len() as u64;
+        on_progress(downloaded, total_size);
     }
 
+    if total_size > 0 && downloaded != total_size {
+        anyhow::bail!("Download of {file} incomplete: {downloaded}/{total_size} bytes");
+    }
+
+    file_out.flush().await?;
+    file_out.sync_all().await?;
+    drop(file_out);
+    fs::rename(&part_path, path).await?;
+
     pb.finish_with_message("Download complete.");
     Ok(())
 }
 
 
-// non-streaming inference
-pub async fn run_inference_collect(model_name: &str, prompt: &str) -> Result<String> {
-    let model_dir = "models";
+/// 检查文件头是否为 GGUF magic bytes
+pub async fn is_gguf_file(path: &str) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = fs::File::open(path).await?;
 
-    //models available: - GGUF
-    let models = [
-        ("qwen", ("bartowski/Qwen2.5-3B-Instruct-GGUF", "Qwen2.5-3B-Instruct-Q4_K_M.gguf")),
-        ("smollm2", ("bartowski/SmolLM2-1.7B-Instruct-GGUF", "SmolLM2-1.7B-Instruct-Q4_K_M.gguf")),
-        ("llama8b", ("bartowski/Meta-Llama-3.1-8B-Instruct-GGUF", "Meta-Llama-3.1-8B-Instruct-Q4_K_M.gguf")),
-    ];
+    match file.read_exact(&mut magic).await {
+        Ok(_) => Ok(&magic == GGUF_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    let (repo, file) = models
-        .iter()
-        .find(|m| m.0 == model_name)
-        .expect("Unknown model")
-        .1;
 
-    let path = format!("{}/{}", model_dir, file);
+/// 下载任意 Hugging Face GGUF 文件并以 alias 注册到模型表，alias 须已用 ModelRegistry::reserve 预留。
+/// file 为分片模型的第一个分片时下载所有分片，进度为所有分片累计的字节数
+pub async fn pull_model<F>(
+    registry: &SharedRegistry,
+    model_dir: &str,
+    repo: &str,
+    file: &str,
+    alias: &str,
+    mut on_progress: F,
+) -> Result<()>
+where
+    F: FnMut(u64, u64),
+{
+    fs::create_dir_all(model_dir).await?;
+
+    let mut finished = 0u64;
+    for shard in split_gguf_files(file) {
+        let path = format!("{}/{}", model_dir, shard);
+        download_model_with_progress(repo, &shard, &path, |downloaded, total| {
+            let total = if total > 0 { finished + total } else { 0 };
+            on_progress(finished + downloaded, total);
+        }).await?;
+
+        if !is_gguf_file(&path).await? {
+            // don't leave a bad file behind, it would count as downloaded
+            fs::remove_file(&path).await?;
+            anyhow::bail!("{} is not a GGUF file", shard);
+        }
+        finished += fs::metadata(&path).await?.len();
+    }
 
-    download_model(repo, file, path.as_str()).await?;
+    registry.write().await.register_reserved(ModelSpec::new(alias, repo, file))?;
 
-    let builder = GgufModelBuilder::new(model_dir, vec![file]).with_logging();
-    let model = builder.build().await?;
+    tracing::info!(model = %alias, repo, file, "Model registered");
+    Ok(())
+}
 
-    let messages = TextMessages::new()
-        .add_message(TextMessageRole::User, prompt);
 
-    let mut stream = model.stream_chat_request(messages).await?;
+/// 删除下载的 GGUF 文件（包括未完成的 .part 文件），返回释放的字节数。
+/// 本地模型的文件不属于服务器，不会删除
+pub async fn delete_model_files(model_dir: &str, spec: &ModelSpec) -> Result<u64> {
+    if spec.path.is_some() {
+        anyhow::bail!("Model {} uses a local file, which is never deleted", spec.name);
+    }
+    if spec.vision {
+        anyhow::bail!("Vision model {} is kept in the Hugging Face cache, not in model_dir", spec.name);
+    }
+    if spec.remote.is_some() {
+        anyhow::bail!("Model {} runs on a remote server and has no local files", spec.name);
+    }
 
-    let mut output = String::new();
+    let paths = spec.gguf_paths(model_dir).into_iter()
+        .flat_map(|path| [PathBuf::from(format!("{}.part", path.display())), path]);
 
-    while let Some(resp) = stream.next().await {
-        if let Response::Chunk(chunk) = resp {
-            if let Some(choice) = chunk.choices.get(0) {
-                if let Some(text) = &choice.delta.content {
-                    output.push_str(text);
-                }
+    let mut freed = 0;
+    for path in paths {
+        match fs::metadata(&path).await {
+            Ok(meta) => {
+                fs::remove_file(&path).await?;
+                freed += meta.len();
             }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
         }
     }
+    Ok(freed)
+}
+
+
+/// 以 alias（须已预留）注册服务器上已有的 GGUF 文件，不下载（离线部署）。path 为第一个分片时其余分片须在同一目录
+pub async fn register_local_model(registry: &SharedRegistry, path: &str, alias: &str) -> Result<()> {
+    let spec = ModelSpec::local(alias, path);
+    for shard in spec.gguf_paths("") {
+        let shard = shard.to_string_lossy();
+        if !fs::try_exists(shard.as_ref()).await? {
+            anyhow::bail!("{} not found", shard);
+        }
+        if !is_gguf_file(&shard).await? {
+            anyhow::bail!("{} is not a GGUF file", shard);
+        }
+    }
+
+    registry.write().await.register_reserved(spec)?;
 
-    Ok(output)
+    tracing::info!(model = %alias, path, "Model registered from local file");
+    Ok(())
+}
+
+
+// OpenAI allows at most 20 alternatives per token
+const MAX_TOP_LOGPROBS: usize = 20;
+
+fn sampling_params(config: &GenerationConfig) -> SamplingParams {
+    let top_n_logprobs = config.top_logprobs.unwrap_or(0).min(MAX_TOP_LOGPROBS);
+
+    // mistralrs has no per-request RNG seed, so a seeded request falls back
+    // to greedy decoding to keep its output reproducible (and runs alone, see
+    // QueueTicket::wait_exclusive, so batching does not change the numerics).
+    if config.seed.is_some() {
+        return SamplingParams {
+            max_len: config.max_tokens,
+            repetition_penalty: config.repetition_penalty,
+            presence_penalty: config.presence_penalty,
+            frequency_penalty: config.frequency_penalty,
+            top_n_logprobs,
+            ..SamplingParams::deterministic()
+        };
+    }
+
+    SamplingParams {
+        temperature: config.temperature,
+        top_k: config.top_k,
+        top_p: config.top_p,
+        max_len: config.max_tokens,
+        repetition_penalty: config.repetition_penalty,
+        presence_penalty: config.presence_penalty,
+        frequency_penalty: config.frequency_penalty,
+        top_n_logprobs,
+        ..SamplingParams::deterministic()
+    }
 }
 
 
+// top_logprobs only makes sense together with logprobs, so asking for it turns logprobs on
+fn wants_logprobs(config: &GenerationConfig) -> bool {
+    config.logprobs.unwrap_or(false) || config.top_logprobs.is_some_and(|n| n > 0)
+}
+
+
+fn to_logprobs(logprobs: &mistralrs::ResponseLogprob) -> TokenLogprobs {
+    TokenLogprobs {
+        token: logprobs.token.clone(),
+        logprob: logprobs.logprob,
+        top_logprobs: logprobs.top_logprobs
+            .iter()
+            .flatten()
+            .map(|top| TopLogprob {
+                token: top.bytes.clone().unwrap_or_default(),
+                logprob: top.logprob,
+            })
+            .collect(),
+    }
+}
+
+
+fn to_usage(usage: &mistralrs::Usage, finish_reason: Option<String>) -> Usage {
+    Usage {
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+        total_tokens: usage.total_tokens,
+        finish_reason,
+    }
+}
+
+
+fn build_request(messages: TextMessages, config: &GenerationConfig) -> RequestBuilder {
+    RequestBuilder::from(messages)
+        .set_sampling(sampling_params(config))
+        .return_logprobs(wants_logprobs(config))
+}
+
+
+// map the full conversation (system prompt, user and assistant turns) onto mistralrs roles
 fn build_text_messages(messages: &[ChatMessage]) -> TextMessages {
     let mut text_messages = TextMessages::new();
 
@@ -112,54 +542,231 @@ fn build_text_messages(messages: &[ChatMessage]) -> TextMessages {
 }
 
 
-// streaming inference
-pub async fn run_inference_stream(
-    model_name: &str,
+// build mistralrs vision messages, attaching the images to the last user message
+fn build_vision_messages(
     messages: &[ChatMessage],
-) -> Result<Pin<Box<dyn Stream<Item = String> + Send>>> {
+    mut images: Vec<image::DynamicImage>,
+    model: &Model,
+) -> Result<VisionMessages> {
+    let image_turn = messages.iter().rposition(|m| m.role == MessageRole::User);
+    let mut vision_messages = VisionMessages::new();
 
-    //download model
-    let models = [
-        ("qwen", ("bartowski/Qwen2.5-3B-Instruct-GGUF", "Qwen2.5-3B-Instruct-Q4_K_M.gguf")),
-        ("smollm2", ("bartowski/SmolLM2-1.7B-Instruct-GGUF", "SmolLM2-1.7B-Instruct-Q4_K_M.gguf")),
-        ("llama8b", ("bartowski/Meta-Llama-3.1-8B-Instruct-GGUF", "Meta-Llama-3.1-8B-Instruct-Q4_K_M.gguf")),
-    ];
+    for (i, msg) in messages.iter().enumerate() {
+        let role = match msg.role {
+            MessageRole::System => TextMessageRole::System,
+            MessageRole::User => TextMessageRole::User,
+            MessageRole::Assistant => TextMessageRole::Assistant,
+        };
 
-    let (repo, file) = models
-        .iter()
-        .find(|m| m.0 == model_name)
-        .ok_or_else(|| anyhow::anyhow!("Unknown model"))?
-        .1;
+        vision_messages = if Some(i) == image_turn {
+            vision_messages.add_image_message(role, &msg.content, std::mem::take(&mut images), model)?
+        } else {
+            vision_messages.add_message(role, &msg.content)
+        };
+    }
 
-    let model_dir = "models";
-    let path = format!("{}/{}", model_dir, file);
+    Ok(vision_messages)
+}
 
-    download_model(repo, file, path.as_str()).await?;
 
-    let builder = GgufModelBuilder::new(model_dir, vec![file]).with_logging();
-    let model = Arc::new(builder.build().await?);
+// 只统计文本本身，不加特殊 token 和对话模板
+async fn count_tokens(model: &Model, text: &str) -> Result<usize> {
+    let tokens = model
+        .tokenize(Either::Right(text.to_string()), None, false, false, None)
+        .await?;
+    Ok(tokens.len())
+}
 
-    let text_messages = build_text_messages(messages);
+async fn tokenize(model: &Model, text: &str) -> Result<Vec<u32>> {
+    model.tokenize(Either::Right(text.to_string()), None, false, false, None).await
+}
 
-    let model_for_stream = model.clone();
+// special tokens are kept so that ids from /tokenize round-trip
+async fn detokenize(model: &Model, tokens: &[u32]) -> Result<String> {
+    model.detokenize(tokens.to_vec(), false, None).await
+}
 
-    let output_stream = stream! {
-        let mut mistral_stream = model_for_stream
-            .stream_chat_request(text_messages)
-            .await
-            .unwrap();
 
-        while let Some(resp) = mistral_stream.next().await {
-            if let Response::Chunk(chunk) = resp {
-                if let Some(choice) = chunk.choices.get(0) {
-                    if let Some(text) = &choice.delta.content {
-                        yield text.clone();
-                    }
+// run a chat request and adapt the mistralrs response stream to StreamChunk.
+// 请求在返回前提交给 mistralrs，提交失败时返回 Err；生成过程中模型出错时流以 StreamChunk::Error 结束
+async fn stream_chat(model: Arc<Model>, request: RequestBuilder, cancel: CancellationToken) -> Result<ChunkStream> {
+    let (started_tx, started_rx) = oneshot::channel();
+    let (tx, rx) = mpsc::channel(64);
+
+    // mistralrs 的流借用 Model，在持有 Arc<Model> 的任务中读取并转发
+    tokio::spawn(async move {
+        let mut mistral_stream = match model.stream_chat_request(request).await {
+            Ok(mistral_stream) => {
+                let _ = started_tx.send(Ok(()));
+                mistral_stream
+            }
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let mut finish_reason = None;
+        loop {
+            // dropping mistral_stream on cancel or when the receiver is gone aborts the request inside mistralrs
+            let resp = tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tx.closed() => break,
+                resp = mistral_stream.next() => resp,
+            };
+
+            let chunks = match resp {
+                Some(Response::Chunk(chunk)) => {
+                    let mut chunks = Vec::new();
+                    if let Some(choice) = chunk.choices.first() {
+                        if choice.finish_reason.is_some() {
+                            finish_reason = choice.finish_reason.clone();
+                        }
+                        if let Some(logprobs) = &choice.logprobs {
+                            chunks.push(StreamChunk::Logprobs(to_logprobs(logprobs)));
+                        }
+                        if let Some(text) = &choice.delta.content {
+                            chunks.push(StreamChunk::Token(text.clone()));
+                        }
+                    }
+                    // the final chunk carries the token counts
+                    if let Some(usage) = &chunk.usage {
+                        chunks.push(StreamChunk::Usage(to_usage(usage, finish_reason.clone())));
+                    }
+                    chunks
+                }
+                Some(Response::ModelError(message, _)) => {
+                    let _ = tx.send(StreamChunk::Error(message)).await;
+                    break;
+                }
+                Some(Response::InternalError(e)) | Some(Response::ValidationError(e)) => {
+                    let _ = tx.send(StreamChunk::Error(e.to_string())).await;
+                    break;
+                }
+                Some(_) => continue,
+                None => break,
+            };
+            for chunk in chunks {
+                if tx.send(chunk).await.is_err() {
+                    return;
                 }
             }
         }
-    };
+    }.in_current_span());
+
+    match started_rx.await {
+        Ok(Ok(())) => Ok(Box::pin(ReceiverStream::new(rx))),
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "Failed to start generation");
+            Err(e)
+        }
+        Err(_) => anyhow::bail!("Generation task ended before the request started"),
+    }
+}
+
 
-    Ok(Box::pin(output_stream))
+// mistralrs GGUF backend
+pub struct GgufEngine {
+    model: Arc<Model>,
+    // sampling defaults from the model registry
+    defaults: GenerationConfig,
 }
 
+#[async_trait]
+impl InferenceEngine for GgufEngine {
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> Result<ChunkStream> {
+        let config = config.or(&self.defaults);
+        let request = build_request(build_text_messages(messages), &config);
+
+        stream_chat(self.model.clone(), request, cancel).await
+    }
+
+    async fn count_tokens(&self, text: &str) -> Result<usize> {
+        count_tokens(&self.model, text).await
+    }
+
+    async fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
+        tokenize(&self.model, text).await
+    }
+
+    async fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        detokenize(&self.model, tokens).await
+    }
+}
+
+
+// mistralrs vision backend, accepts image input
+pub struct VisionEngine {
+    model: Arc<Model>,
+    defaults: GenerationConfig,
+}
+
+#[async_trait]
+impl InferenceEngine for VisionEngine {
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> Result<ChunkStream> {
+        let config = config.or(&self.defaults);
+        let request = build_request(build_text_messages(messages), &config);
+
+        stream_chat(self.model.clone(), request, cancel).await
+    }
+
+    async fn stream_with_images(
+        &self,
+        messages: &[ChatMessage],
+        images: Vec<Vec<u8>>,
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> Result<ChunkStream> {
+        let images = images
+            .iter()
+            .map(|bytes| image::load_from_memory(bytes))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let config = config.or(&self.defaults);
+        let vision_messages = build_vision_messages(messages, images, &self.model)?;
+        let request = RequestBuilder::from(vision_messages)
+            .set_sampling(sampling_params(&config))
+            .return_logprobs(wants_logprobs(&config));
+
+        stream_chat(self.model.clone(), request, cancel).await
+    }
+
+    async fn count_tokens(&self, text: &str) -> Result<usize> {
+        count_tokens(&self.model, text).await
+    }
+
+    async fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
+        tokenize(&self.model, text).await
+    }
+
+    async fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        detokenize(&self.model, tokens).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_space_check_keeps_a_margin() {
+        let dir = Path::new("models");
+        let gb = 1024 * 1024 * 1024;
+        assert!(check_disk_space(dir, "a.gguf", 2 * gb, 4 * gb).is_ok());
+
+        let error = check_disk_space(dir, "a.gguf", 2 * gb, 2 * gb).unwrap_err().to_string();
+        assert!(error.contains("2304 MB needed, 2048 MB free"));
+        assert!(error.contains("DELETE /models/{name}/files"));
+    }
+}
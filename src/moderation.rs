@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use crate::file_store::unix_now;
+use crate::types::{ChatMessage, MessageRole};
+
+
+// 发给分类模型的文本最多的字符数，以及审核日志中保留的原文片段
+const CLASSIFIER_SOURCE_CHARS: usize = 4000;
+const LOG_EXCERPT_CHARS: usize = 200;
+// 分类模型只需要回答 safe / unsafe
+pub const CLASSIFIER_MAX_TOKENS: usize = 4;
+
+
+/// 内容审核模式：off 不检查，flag 只记录命中的内容，block 还会拒绝 prompt、拦截回答
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationMode {
+    #[default]
+    Off,
+    Flag,
+    Block,
+}
+
+impl ModerationMode {
+    pub fn parse(name: &str) -> Option<ModerationMode> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(ModerationMode::Off),
+            "flag" => Some(ModerationMode::Flag),
+            "block" => Some(ModerationMode::Block),
+            _ => None,
+        }
+    }
+}
+
+
+/// 被审核的内容：用户的 prompt 或模型的回答
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationTarget {
+    Prompt,
+    Output,
+}
+
+
+// 小写，非字母数字的字符换成空格，前后各加一个空格，用于按整词匹配
+fn normalize(text: &str) -> String {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    format!(" {} ", words.join(" "))
+}
+
+/// 文本中出现的关键词（不区分大小写，按整词匹配，可以是多个词的短语）
+pub fn keyword_matches(keywords: &[String], text: &str) -> Vec<String> {
+    let text = normalize(text);
+    keywords.iter()
+        .filter(|keyword| {
+            let keyword = normalize(keyword);
+            !keyword.trim().is_empty() && text.contains(&keyword)
+        })
+        .cloned()
+        .collect()
+}
+
+
+/// 让分类模型判断一段文本是否违规
+pub fn classifier_prompt(text: &str) -> Vec<ChatMessage> {
+    let text = match text.char_indices().nth(CLASSIFIER_SOURCE_CHARS) {
+        Some((idx, _)) => &text[..idx],
+        None => text,
+    };
+    vec![
+        ChatMessage::new(
+            MessageRole::System,
+            "You are a content safety classifier. Decide whether the text contains violence, hate, \
+             harassment, sexual content involving minors, self-harm instructions or instructions for \
+             weapons or crimes. Reply with exactly one word: safe or unsafe.".to_string(),
+        ),
+        ChatMessage::new(MessageRole::User, format!("Text:\n{}", text)),
+    ]
+}
+
+/// 分类模型的回答是否为 unsafe
+pub fn classifier_flags(reply: &str) -> bool {
+    reply.trim().to_lowercase().starts_with("unsafe")
+}
+
+
+/// 审核日志的一行
+#[derive(Serialize)]
+pub struct ModerationRecord {
+    pub timestamp: u64,
+    pub request_id: String,
+    pub owner: Option<String>,
+    pub session_id: Option<String>,
+    pub target: ModerationTarget,
+    // "flagged" 或 "blocked"
+    pub action: &'static str,
+    // 命中的原因："keyword:<关键词>" 或 "classifier"
+    pub reasons: Vec<String>,
+    pub excerpt: String,
+}
+
+impl ModerationRecord {
+    pub fn new(
+        request_id: &str,
+        owner: Option<&str>,
+        session_id: Option<&str>,
+        target: ModerationTarget,
+        blocked: bool,
+        reasons: Vec<String>,
+        text: &str,
+    ) -> Self {
+        Self {
+            timestamp: unix_now(),
+            request_id: request_id.to_string(),
+            owner: owner.map(str::to_string),
+            session_id: session_id.map(str::to_string),
+            target,
+            action: if blocked { "blocked" } else { "flagged" },
+            reasons,
+            excerpt: text.chars().take(LOG_EXCERPT_CHARS).collect(),
+        }
+    }
+}
+
+
+/// 追加写入的审核日志（JSON Lines），path 为空时只输出到 tracing 日志
+pub struct ModerationLog {
+    path: Option<PathBuf>,
+    // 串行追加，避免并发写入的行交错
+    write_lock: Mutex<()>,
+}
+
+pub type SharedModerationLog = Arc<ModerationLog>;
+
+pub fn new_moderation_log(path: &str) -> SharedModerationLog {
+    Arc::new(ModerationLog {
+        path: (!path.is_empty()).then(|| PathBuf::from(path)),
+        write_lock: Mutex::new(()),
+    })
+}
+
+impl ModerationLog {
+    pub async fn record(&self, record: &ModerationRecord) {
+        tracing::warn!(
+            request_id = %record.request_id,
+            session_id = record.session_id.as_deref(),
+            target = ?record.target,
+            action = record.action,
+            reasons = ?record.reasons,
+            "Content moderation hit",
+        );
+
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _guard = self.write_lock.lock().await;
+        if let Err(e) = append_line(path, record).await {
+            tracing::error!(path = %path.display(), error = %e, "Failed to write moderation log");
+        }
+    }
+}
+
+async fn append_line(path: &Path, record: &ModerationRecord) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyword_matches_whole_words() {
+        let keywords = vec!["bomb".to_string(), "credit card dump".to_string()];
+        assert_eq!(keyword_matches(&keywords, "How do I build a BOMB?"), vec!["bomb".to_string()]);
+        assert!(keyword_matches(&keywords, "The bombastic speech").is_empty());
+        assert_eq!(keyword_matches(&keywords, "buy a credit-card dump"), vec!["credit card dump".to_string()]);
+    }
+
+    #[test]
+    fn test_classifier_reply() {
+        assert!(classifier_flags(" Unsafe."));
+        assert!(!classifier_flags("safe"));
+        assert!(!classifier_flags(""));
+    }
+
+    #[tokio::test]
+    async fn test_log_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("moderation-test-{}.jsonl", uuid::Uuid::new_v4()));
+        let log = new_moderation_log(path.to_str().unwrap());
+        for target in [ModerationTarget::Prompt, ModerationTarget::Output] {
+            let record = ModerationRecord::new("r1", Some("alice"), None, target, true, vec!["classifier".to_string()], "text");
+            log.record(&record).await;
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["target"], "output");
+        assert_eq!(lines[0]["action"], "blocked");
+        std::fs::remove_file(path).unwrap();
+    }
+}
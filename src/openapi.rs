@@ -0,0 +1,148 @@
+use axum::Router;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+use crate::AppState;
+use crate::handler;
+
+
+/// `/openapi.json` 描述的 API。SSE 接口的事件见 StreamEvent、AgentEvent、PullEvent 和 SummarizeEvent 的 schema
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "LLM Inference Service", description = "Local LLM inference with sessions, file context, tools and agents"),
+    paths(
+        handler::healthy,
+        handler::ready_handler,
+        handler::metrics_handler,
+        handler::infer_handler,
+        handler::infer_stream_handler,
+        handler::batch_handler,
+        handler::cancel_handler,
+        handler::agent_run_handler,
+        handler::list_models_handler,
+        handler::pull_model_handler,
+        handler::load_model_handler,
+        handler::unload_model_handler,
+        handler::delete_model_files_handler,
+        handler::add_adapter_handler,
+        handler::tokenize_handler,
+        handler::detokenize_handler,
+        handler::list_openai_models_handler,
+        handler::ollama_generate_handler,
+        handler::ollama_chat_handler,
+        handler::ollama_tags_handler,
+        handler::upload_handler,
+        handler::upload_progress_handler,
+        handler::transcribe_handler,
+        handler::list_files_handler,
+        handler::get_file_handler,
+        handler::remove_handler,
+        handler::file_status_handler,
+        handler::file_content_handler,
+        handler::summarize_file_handler,
+        handler::extract_file_handler,
+        handler::list_sessions_handler,
+        handler::get_session_handler,
+        handler::remove_session_handler,
+        handler::sync_session_handler,
+        handler::update_session_config_handler,
+        handler::edit_message_handler,
+        handler::pin_message_handler,
+        handler::fork_session_handler,
+        handler::get_memory_handler,
+        handler::update_memory_handler,
+        handler::clear_memory_handler,
+        handler::list_example_sets_handler,
+        handler::get_example_set_handler,
+        handler::put_example_set_handler,
+        handler::remove_example_set_handler,
+        handler::list_eval_suites_handler,
+        handler::get_eval_suite_handler,
+        handler::put_eval_suite_handler,
+        handler::remove_eval_suite_handler,
+        handler::run_eval_handler,
+        handler::usage_handler,
+        handler::audit_log_handler,
+        handler::benchmark_handler,
+        handler::benchmark_history_handler,
+    ),
+    // SSE 事件和 webhook 的 body 不会出现在任何响应中，需要单独列出
+    components(schemas(
+        crate::types::StreamEvent, crate::types::AgentEvent, crate::types::PullEvent, crate::types::SummarizeEvent,
+        crate::webhook::WebhookPayload,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "generation", description = "Text generation, streaming and agent runs"),
+        (name = "models", description = "Model registry, downloads and tokenizers"),
+        (name = "files", description = "Uploaded files and transcription"),
+        (name = "sessions", description = "Conversation history"),
+        (name = "memory", description = "Long-term memory of the current user"),
+        (name = "examples", description = "Named few-shot example sets"),
+        (name = "evals", description = "Prompt regression tests against a model"),
+        (name = "openai", description = "OpenAI compatible endpoints"),
+        (name = "ollama", description = "Ollama compatible endpoints"),
+        (name = "usage", description = "Token usage and quotas per API key"),
+        (name = "admin", description = "Audit log and benchmarks, for admin users"),
+        (name = "system", description = "Health and metrics"),
+    ),
+)]
+pub struct ApiDoc;
+
+
+// 配置了 api_keys 时，除 /health 和文档外的请求需要 `Authorization: Bearer <key>`
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        openapi.security = Some(vec![utoipa::openapi::security::SecurityRequirement::new("api_key", Vec::<String>::new())]);
+    }
+}
+
+
+/// POST /upload 和 /transcribe 的 multipart 表单：一个或多个文件，
+/// 没有文件名的字段（如 password）是解析选项，作用于它之后的文件
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadForm {
+    #[schema(value_type = Vec<String>, format = Binary)]
+    pub file: Vec<Vec<u8>>,
+    pub password: Option<String>,
+    pub redact_pii: Option<bool>,
+}
+
+
+/// `/openapi.json` 和 `/docs`（Swagger UI），不需要认证
+pub fn openapi_routes() -> Router<AppState> {
+    SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()).into()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openapi_covers_routes_and_events() {
+        let doc = ApiDoc::openapi();
+        for path in ["/generate", "/generate/stream", "/generate/batch", "/agent/run", "/upload", "/sessions/{session_id}", "/api/chat"] {
+            assert!(doc.paths.paths.contains_key(path), "missing {}", path);
+        }
+
+        let schemas = &doc.components.as_ref().unwrap().schemas;
+        for schema in ["StreamEvent", "AgentEvent", "PullEvent", "WebhookPayload", "InferenceRequest", "ValidationError"] {
+            assert!(schemas.contains_key(schema), "missing schema {}", schema);
+        }
+    }
+
+    #[test]
+    fn test_openapi_serializes() {
+        let json = ApiDoc::openapi().to_json().unwrap();
+        assert!(json.contains("\"text/event-stream\""));
+    }
+}
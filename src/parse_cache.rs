@@ -0,0 +1,222 @@
+use anyhow::Result;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::file_parser::{parse_file, ParseOptions};
+
+
+/// 解析结果，按条数和文本的总字节数限制，超出时删除最久未使用的结果。条数为 0 时不缓存
+pub struct ParseResults {
+    entries: Option<LruCache<String, String>>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl ParseResults {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.entries.as_mut()?.get(key).cloned()
+    }
+
+    // 比 max_bytes 还大的结果不缓存
+    fn put(&mut self, key: String, content: String) {
+        let Some(entries) = self.entries.as_mut() else {
+            return;
+        };
+        if content.len() > self.max_bytes {
+            return;
+        }
+
+        self.bytes += content.len();
+        // push 返回被替换的同 key 结果或因条数被淘汰的结果
+        if let Some((_, old)) = entries.push(key, content) {
+            self.bytes -= old.len();
+        }
+        while self.bytes > self.max_bytes {
+            let Some((_, old)) = entries.pop_lru() else { break };
+            self.bytes -= old.len();
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries.as_ref().map_or(0, LruCache::len)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+
+/// 解析结果缓存，key 为文件内容、扩展名和解析选项的 blake3 哈希。
+/// 同一文件重复上传（例如在每个 session 中重新附加）时不再重新解析
+pub type ParseCache = Arc<Mutex<ParseResults>>;
+
+// capacity 为保留的解析结果数，为 0 时不缓存；max_bytes 为结果文本的总字节数
+pub fn new_parse_cache(capacity: usize, max_bytes: usize) -> ParseCache {
+    Arc::new(Mutex::new(ParseResults {
+        entries: NonZeroUsize::new(capacity).map(LruCache::new),
+        bytes: 0,
+        max_bytes,
+    }))
+}
+
+
+// 文件名不影响解析结果，只有扩展名参与哈希。解析选项逐个字段写入（带长度，字段之间不会混淆），
+// 新增字段时这里的解构会编译失败，提醒加入哈希。密码也参与哈希：没有密码或密码错误时不能命中
+// 用正确密码解析的结果
+fn cache_key(path: &Path, bytes: &[u8], options: &ParseOptions) -> String {
+    let extension = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    let ParseOptions {
+        password,
+        pptx_notes,
+        xlsx_sheets,
+        xlsx_max_rows,
+        xlsx_header,
+        docx_notes,
+        docx_headers,
+        docx_comments,
+        redact_pii,
+        chunk_size,
+    } = options;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut field = |value: &[u8]| {
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    };
+    field(bytes);
+    field(extension.as_bytes());
+    field(password.as_deref().unwrap_or("").as_bytes());
+    field(&[*pptx_notes as u8, *xlsx_header as u8, *docx_notes as u8, *docx_headers as u8, *docx_comments as u8, *redact_pii as u8]);
+    field(&(xlsx_sheets.len() as u64).to_le_bytes());
+    for sheet in xlsx_sheets {
+        field(sheet.as_bytes());
+    }
+    match xlsx_max_rows {
+        Some(rows) => field(&(*rows as u64).to_le_bytes()),
+        None => field(&[]),
+    }
+    field(&(*chunk_size as u64).to_le_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+
+/// 与 [`parse_file`] 相同，命中缓存时直接返回之前的解析结果
+pub async fn parse_file_cached(
+    cache: &ParseCache,
+    path: &Path,
+    bytes: &[u8],
+    options: &ParseOptions,
+) -> Result<String> {
+    let key = cache_key(path, bytes, options);
+
+    if let Some(content) = cache.lock().await.get(&key) {
+        tracing::debug!(path = %path.display(), "Parse cache hit");
+        return Ok(content);
+    }
+
+    let content = parse_file(path, bytes, options).await?;
+    cache.lock().await.put(key, content.clone());
+
+    Ok(content)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key_depends_on_content_extension_and_options() {
+        let options = ParseOptions::default();
+        let key = cache_key(Path::new("a.txt"), b"hello", &options);
+
+        // the filename itself does not matter
+        assert_eq!(key, cache_key(Path::new("other.TXT"), b"hello", &options));
+        assert_ne!(key, cache_key(Path::new("a.txt"), b"hello!", &options));
+        assert_ne!(key, cache_key(Path::new("a.md"), b"hello", &options));
+
+        let with_notes = ParseOptions { pptx_notes: true, ..Default::default() };
+        assert_ne!(key, cache_key(Path::new("a.txt"), b"hello", &with_notes));
+
+        let with_password = ParseOptions { password: Some("secret".to_string()), ..Default::default() };
+        assert_ne!(key, cache_key(Path::new("a.txt"), b"hello", &with_password));
+
+        // sheet lists that join to the same text are still different
+        let sheets = |names: &[&str]| ParseOptions {
+            xlsx_sheets: names.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_ne!(
+            cache_key(Path::new("a.xlsx"), b"x", &sheets(&["a", "b"])),
+            cache_key(Path::new("a.xlsx"), b"x", &sheets(&["ab"])),
+        );
+    }
+
+    #[test]
+    fn test_debug_hides_password() {
+        let options = ParseOptions { password: Some("hunter2".to_string()), ..Default::default() };
+        let debug = format!("{:?}", options);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("<redacted>"));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_parse_hits_cache() {
+        let cache = new_parse_cache(2, 1024);
+        let options = ParseOptions::default();
+
+        let first = parse_file_cached(&cache, Path::new("notes.txt"), b"same text", &options).await.unwrap();
+        let second = parse_file_cached(&cache, Path::new("copy.txt"), b"same text", &options).await.unwrap();
+        assert_eq!(first, "same text");
+        assert_eq!(second, first);
+        assert_eq!(cache.lock().await.entries(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_bounded() {
+        let cache = new_parse_cache(2, 1024);
+        let options = ParseOptions::default();
+
+        for text in ["one", "two", "three"] {
+            parse_file_cached(&cache, Path::new("f.txt"), text.as_bytes(), &options).await.unwrap();
+        }
+
+        let mut cache = cache.lock().await;
+        assert_eq!(cache.entries(), 2);
+        assert_eq!(cache.bytes(), "two".len() + "three".len());
+        // the least recently used entry was evicted
+        assert!(cache.get(&cache_key(Path::new("f.txt"), b"one", &options)).is_none());
+    }
+
+    #[test]
+    fn test_cache_is_bounded_by_bytes() {
+        let mut results = new_parse_cache(10, 10).try_lock_owned().unwrap();
+        results.put("a".to_string(), "12345".to_string());
+        results.put("b".to_string(), "12345".to_string());
+        results.put("c".to_string(), "123".to_string());
+        assert_eq!((results.entries(), results.bytes()), (2, 8));
+        assert!(results.get("a").is_none());
+
+        // replacing an entry does not count it twice, larger than the limit is not cached
+        results.put("c".to_string(), "1234".to_string());
+        assert_eq!(results.bytes(), 9);
+        results.put("d".to_string(), "x".repeat(11));
+        assert_eq!((results.entries(), results.bytes()), (2, 9));
+    }
+
+    #[test]
+    fn test_zero_capacity_disables_cache() {
+        let mut results = new_parse_cache(0, 1024).try_lock_owned().unwrap();
+        results.put("a".to_string(), "text".to_string());
+        assert_eq!((results.entries(), results.bytes()), (0, 0));
+        assert!(results.get("a").is_none());
+    }
+}
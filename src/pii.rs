@@ -0,0 +1,95 @@
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+
+
+// 按模式识别，不识别人名、地址等需要模型才能判断的内容
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b").unwrap()
+});
+// 13 到 19 位数字，中间可以有空格或 `-`，再用 Luhn 校验排除普通的长数字
+static CARD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap());
+static SSN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{3})-(\d{2})-(\d{4})\b").unwrap());
+// 北美格式的电话号码：可选的国家代码，区号可以带括号，分隔符为空格、`.` 或 `-`
+static PHONE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{3}\)[ .-]?|\b\d{3}[ .-]?)\d{3}[ .-]?\d{4}\b").unwrap()
+});
+
+
+/// 把文本中的邮箱、信用卡号、社会安全号码（SSN）和电话号码替换为 `[EMAIL]`、`[CREDIT_CARD]`、
+/// `[SSN]`、`[PHONE]`，返回替换后的文本和替换的个数
+pub fn redact_pii(text: &str) -> (String, usize) {
+    let mut count = 0;
+    // 信用卡号在电话号码之前处理，避免长数字的一部分被当作电话号码
+    let text = replace(text, &EMAIL, "[EMAIL]", |_| true, &mut count);
+    let text = replace(&text, &CARD, "[CREDIT_CARD]", |caps| luhn_valid(&caps[0]), &mut count);
+    let text = replace(&text, &SSN, "[SSN]", valid_ssn, &mut count);
+    let text = replace(&text, &PHONE, "[PHONE]", |_| true, &mut count);
+    (text, count)
+}
+
+fn replace(
+    text: &str,
+    pattern: &Regex,
+    mask: &str,
+    valid: impl Fn(&Captures) -> bool,
+    count: &mut usize,
+) -> String {
+    pattern
+        .replace_all(text, |caps: &Captures| {
+            if valid(caps) {
+                *count += 1;
+                mask.to_string()
+            } else {
+                caps[0].to_string()
+            }
+        })
+        .into_owned()
+}
+
+
+// 区号不能是 000、666 或 9xx，组号和序号不能全为 0
+fn valid_ssn(caps: &Captures) -> bool {
+    let area = &caps[1];
+    area != "000" && area != "666" && !area.starts_with('9') && &caps[2] != "00" && &caps[3] != "0000"
+}
+
+fn luhn_valid(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match i % 2 {
+            0 => d,
+            _ if d * 2 > 9 => d * 2 - 9,
+            _ => d * 2,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacts_each_kind() {
+        let text = "Mail jane.doe@example.co.uk or call (555) 123-4567 / +1 555.123.4567. \
+                    SSN 123-45-6789, card 4111 1111 1111 1111.";
+        let (redacted, count) = redact_pii(text);
+        assert_eq!(
+            redacted,
+            "Mail [EMAIL] or call [PHONE] / [PHONE]. SSN [SSN], card [CREDIT_CARD]."
+        );
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn test_keeps_numbers_that_are_not_pii() {
+        let text = "Order 4111 1111 1111 1112 shipped on 2024-01-15, invoice 000-12-3456, total 1,250.50";
+        let (redacted, count) = redact_pii(text);
+        assert_eq!(redacted, text);
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+
+/// 推理请求队列：最多 max_concurrent 个请求同时生成，最多 max_queue_depth 个请求排队等待，
+/// 超出时 [`InferenceQueue::enter`] 返回 None，由 handler 返回 429
+#[derive(Clone)]
+pub struct InferenceQueue {
+    permits: Arc<Semaphore>,
+    // running + waiting requests
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+    max_concurrent: usize,
+}
+
+// 请求在队列中的位置，drop 时离开队列
+pub struct QueueTicket {
+    permits: Arc<Semaphore>,
+    pending: Arc<AtomicUsize>,
+    max_concurrent: usize,
+}
+
+impl InferenceQueue {
+    pub fn new(max_concurrent: usize, max_queue_depth: usize) -> Self {
+        let max_concurrent = max_concurrent.max(1);
+
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            pending: Arc::new(AtomicUsize::new(0)),
+            capacity: max_concurrent + max_queue_depth,
+            max_concurrent,
+        }
+    }
+
+    /// 进入队列，队列已满时返回 None
+    pub fn enter(&self) -> Option<QueueTicket> {
+        let entered = self.pending
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                (pending < self.capacity).then_some(pending + 1)
+            })
+            .is_ok();
+
+        entered.then(|| QueueTicket {
+            permits: self.permits.clone(),
+            pending: self.pending.clone(),
+            max_concurrent: self.max_concurrent,
+        })
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// 同时生成和排队的请求总数上限
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+}
+
+impl QueueTicket {
+    /// 等待轮到本请求，返回的 permit 在生成期间持有
+    pub async fn wait(&self) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("inference queue semaphore is never closed")
+    }
+
+    /// 等待所有正在生成的请求结束后单独运行，期间其他请求不会开始。
+    /// 用于需要可复现输出的请求：和其他请求一起 batch 时计算结果可能有细微差别
+    pub async fn wait_exclusive(&self) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_many_owned(self.max_concurrent as u32)
+            .await
+            .expect("inference queue semaphore is never closed")
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_queue_rejects_when_full() {
+        let queue = InferenceQueue::new(1, 1);
+
+        let first = queue.enter();
+        let second = queue.enter();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(queue.enter().is_none());
+        assert_eq!(queue.pending(), 2);
+
+        drop(first);
+        assert_eq!(queue.pending(), 1);
+        assert!(queue.enter().is_some());
+    }
+
+    #[test]
+    fn test_zero_concurrency_still_runs_one() {
+        let queue = InferenceQueue::new(0, 0);
+        assert_eq!((queue.max_concurrent(), queue.capacity()), (1, 1));
+        assert!(queue.enter().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_waiting_request_runs_after_permit_released() {
+        let queue = InferenceQueue::new(1, 1);
+
+        let first = queue.enter().unwrap();
+        let second = queue.enter().unwrap();
+
+        let permit = first.wait().await;
+
+        // second request has to wait while the first one holds the permit
+        let waiting = tokio::time::timeout(Duration::from_millis(50), second.wait()).await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        let acquired = tokio::time::timeout(Duration::from_millis(50), second.wait()).await;
+        assert!(acquired.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_request_runs_alone() {
+        let queue = InferenceQueue::new(2, 2);
+
+        let running = queue.enter().unwrap();
+        let exclusive = queue.enter().unwrap();
+        let other = queue.enter().unwrap();
+
+        let permit = running.wait().await;
+
+        // one slot is free, but the exclusive request needs all of them
+        let waiting = tokio::time::timeout(Duration::from_millis(50), exclusive.wait_exclusive()).await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        let exclusive_permit = tokio::time::timeout(Duration::from_millis(50), exclusive.wait_exclusive())
+            .await
+            .unwrap();
+
+        // nothing else starts while it runs
+        let blocked = tokio::time::timeout(Duration::from_millis(50), other.wait()).await;
+        assert!(blocked.is_err());
+
+        drop(exclusive_permit);
+        assert!(tokio::time::timeout(Duration::from_millis(50), other.wait()).await.is_ok());
+    }
+}
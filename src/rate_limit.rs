@@ -0,0 +1,323 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header::RETRY_AFTER, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use futures::StreamExt;
+use crate::AppState;
+use crate::auth::{is_public, Caller};
+use crate::error::RateLimitError;
+
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// expired windows are dropped once this many callers are tracked
+const PRUNE_THRESHOLD: usize = 1024;
+
+
+/// 按调用方限流：每分钟的请求数（固定窗口）和同时打开的流式请求数，0 表示不限制。
+/// 认证失败的请求还没有用户，另按客户端 IP 限制每分钟的失败次数
+pub struct RateLimiter {
+    requests_per_minute: u32,
+    max_streams: usize,
+    auth_failures_per_minute: u32,
+    windows: DashMap<String, Window>,
+    streams: DashMap<String, usize>,
+    auth_failures: DashMap<String, Window>,
+}
+
+pub type SharedRateLimiter = Arc<RateLimiter>;
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// 一次检查的结果，用于 X-RateLimit-* 响应头
+#[derive(Debug, PartialEq)]
+pub struct Quota {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // 距离当前窗口结束的秒数
+    pub reset_secs: u64,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32, max_streams: usize, auth_failures_per_minute: u32) -> SharedRateLimiter {
+        Arc::new(Self {
+            requests_per_minute,
+            max_streams,
+            auth_failures_per_minute,
+            windows: DashMap::new(),
+            streams: DashMap::new(),
+            auth_failures: DashMap::new(),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.requests_per_minute > 0 || self.max_streams > 0
+    }
+
+    /// 记录一次请求，超过每分钟的上限时 allowed 为 false（被拒绝的请求不计数）
+    pub fn check(&self, key: &str, now: Instant) -> Option<Quota> {
+        if self.requests_per_minute == 0 {
+            return None;
+        }
+        if self.windows.len() > PRUNE_THRESHOLD {
+            self.windows.retain(|_, window| now.duration_since(window.started) < WINDOW);
+        }
+
+        let mut window = self.windows.entry(key.to_string()).or_insert(Window { started: now, count: 0 });
+        if now.duration_since(window.started) >= WINDOW {
+            *window = Window { started: now, count: 0 };
+        }
+
+        let allowed = window.count < self.requests_per_minute;
+        if allowed {
+            window.count += 1;
+        }
+        Some(Quota {
+            allowed,
+            limit: self.requests_per_minute,
+            remaining: self.requests_per_minute - window.count,
+            reset_secs: reset_secs(&window, now),
+        })
+    }
+
+    /// 这个 IP 本分钟认证失败的次数已达上限时返回距离窗口结束的秒数，窗口结束前不再检查它发来的 key
+    pub fn auth_blocked(&self, key: &str, now: Instant) -> Option<u64> {
+        if self.auth_failures_per_minute == 0 {
+            return None;
+        }
+        let window = self.auth_failures.get(key)?;
+        let blocked = now.duration_since(window.started) < WINDOW && window.count >= self.auth_failures_per_minute;
+        blocked.then(|| reset_secs(&window, now))
+    }
+
+    /// 记录一次失败的认证
+    pub fn auth_failed(&self, key: &str, now: Instant) {
+        if self.auth_failures_per_minute == 0 {
+            return;
+        }
+        if self.auth_failures.len() > PRUNE_THRESHOLD {
+            self.auth_failures.retain(|_, window| now.duration_since(window.started) < WINDOW);
+        }
+
+        let mut window = self.auth_failures.entry(key.to_string()).or_insert(Window { started: now, count: 0 });
+        if now.duration_since(window.started) >= WINDOW {
+            *window = Window { started: now, count: 0 };
+        }
+        window.count += 1;
+    }
+
+    /// 占用一个流式请求的名额，已达上限时返回 None。名额在 StreamSlot 被 drop 时归还
+    pub fn open_stream(self: &Arc<Self>, key: &str) -> Option<StreamSlot> {
+        if self.max_streams == 0 {
+            return Some(StreamSlot { limiter: None, key: String::new() });
+        }
+
+        let mut open = self.streams.entry(key.to_string()).or_insert(0);
+        if *open >= self.max_streams {
+            return None;
+        }
+        *open += 1;
+        Some(StreamSlot { limiter: Some(self.clone()), key: key.to_string() })
+    }
+}
+
+
+// seconds until the window restarts, rounded up
+fn reset_secs(window: &Window, now: Instant) -> u64 {
+    let reset = WINDOW.saturating_sub(now.duration_since(window.started));
+    reset.as_secs() + u64::from(reset.subsec_nanos() > 0)
+}
+
+
+/// 一个打开的流式请求，响应结束（或客户端断开）时归还名额
+pub struct StreamSlot {
+    limiter: Option<SharedRateLimiter>,
+    key: String,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.streams.remove_if_mut(&self.key, |_, open| {
+                *open -= 1;
+                *open == 0
+            });
+        }
+    }
+}
+
+
+// 返回 SSE / NDJSON 的路由，计入 max_concurrent_streams。Ollama 的路由不带 stream 参数时也计入，直到响应结束
+fn is_stream_route(method: &Method, path: &str) -> bool {
+    *method == Method::POST && (
+        matches!(path, "/generate/stream" | "/generate/batch" | "/agent/run" | "/models/pull" | "/api/chat" | "/api/generate")
+            || (path.starts_with("/sessions/") && path.ends_with("/edit"))
+    )
+}
+
+// 开启认证时按用户限流（同一用户的多个 key 共享额度），否则按客户端 IP
+pub fn caller_key(caller: Option<&Caller>, addr: Option<SocketAddr>) -> String {
+    match (caller.and_then(Caller::owner), addr) {
+        (Some(owner), _) => format!("user:{}", owner),
+        (None, Some(addr)) => format!("ip:{}", addr.ip()),
+        (None, None) => "ip:unknown".to_string(),
+    }
+}
+
+fn quota_headers(headers: &mut HeaderMap, quota: &Quota) {
+    headers.insert("x-ratelimit-limit", HeaderValue::from(quota.limit));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from(quota.remaining));
+    headers.insert("x-ratelimit-reset", HeaderValue::from(quota.reset_secs));
+}
+
+pub fn too_many_requests(error: &str, retry_after: u64, quota: Option<&Quota>) -> Response {
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.to_string())],
+        Json(RateLimitError {
+            error: error.to_string(),
+            retry_after,
+        }),
+    ).into_response();
+    if let Some(quota) = quota {
+        quota_headers(response.headers_mut(), quota);
+    }
+    response
+}
+
+
+/// 限流中间件，在认证之后运行（认证失败的请求由 require_api_key 按 IP 限制）。/health、API 文档和聊天页面不限流
+pub async fn rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let limiter = state.rate_limiter.clone();
+    if !limiter.enabled() || is_public(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let addr = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    let key = caller_key(request.extensions().get::<Caller>(), addr);
+
+    let quota = limiter.check(&key, Instant::now());
+    if let Some(quota) = quota.as_ref().filter(|quota| !quota.allowed) {
+        tracing::warn!(caller = %key, "Rate limit reached");
+        return too_many_requests("Rate limit exceeded, try again later", quota.reset_secs, Some(quota));
+    }
+
+    let slot = if is_stream_route(request.method(), request.uri().path()) {
+        match limiter.open_stream(&key) {
+            Some(slot) => Some(slot),
+            None => {
+                tracing::warn!(caller = %key, "Too many open streams");
+                return too_many_requests("Too many streaming requests open at once", 1, quota.as_ref());
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut response = next.run(request).await;
+    if let Some(quota) = &quota {
+        quota_headers(response.headers_mut(), quota);
+    }
+
+    match slot {
+        // the slot is held by the body, so it is returned once the stream ends or the client disconnects
+        Some(slot) => {
+            let (parts, body) = response.into_parts();
+            let body = body.into_data_stream().map(move |chunk| {
+                let _ = &slot;
+                chunk
+            });
+            Response::from_parts(parts, Body::from_stream(body))
+        }
+        None => response,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_per_minute() {
+        let limiter = RateLimiter::new(2, 0, 0);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("a", start).unwrap().remaining, 1);
+        assert!(limiter.check("a", start).unwrap().allowed);
+        let limited = limiter.check("a", start + Duration::from_secs(20)).unwrap();
+        assert!(!limited.allowed);
+        assert_eq!(limited.reset_secs, 40);
+
+        // other callers have their own window, and the window starts over after a minute
+        assert!(limiter.check("b", start).unwrap().allowed);
+        assert!(limiter.check("a", start + WINDOW).unwrap().allowed);
+    }
+
+    #[test]
+    fn test_disabled_limits() {
+        let limiter = RateLimiter::new(0, 0, 0);
+        assert!(!limiter.enabled());
+        assert_eq!(limiter.check("a", Instant::now()), None);
+        assert!(limiter.open_stream("a").is_some());
+        limiter.auth_failed("ip:a", Instant::now());
+        assert_eq!(limiter.auth_blocked("ip:a", Instant::now()), None);
+    }
+
+    #[test]
+    fn test_auth_failures_per_ip() {
+        let limiter = RateLimiter::new(0, 0, 2);
+        let start = Instant::now();
+
+        limiter.auth_failed("ip:10.0.0.7", start);
+        assert_eq!(limiter.auth_blocked("ip:10.0.0.7", start), None);
+        limiter.auth_failed("ip:10.0.0.7", start);
+        assert_eq!(limiter.auth_blocked("ip:10.0.0.7", start + Duration::from_secs(20)), Some(40));
+
+        // other addresses are not affected, and the block ends with the window
+        assert_eq!(limiter.auth_blocked("ip:10.0.0.8", start), None);
+        assert_eq!(limiter.auth_blocked("ip:10.0.0.7", start + WINDOW), None);
+    }
+
+    #[test]
+    fn test_stream_slots_are_returned_on_drop() {
+        let limiter = RateLimiter::new(0, 1, 0);
+        let slot = limiter.open_stream("a").unwrap();
+        assert!(limiter.open_stream("a").is_none());
+        assert!(limiter.open_stream("b").is_some());
+
+        drop(slot);
+        assert!(limiter.open_stream("a").is_some());
+        assert!(limiter.streams.is_empty());
+    }
+
+    #[test]
+    fn test_caller_key() {
+        let alice = Caller { owner: Some("alice".to_string()), ..Default::default() };
+        let addr: SocketAddr = "10.0.0.7:51234".parse().unwrap();
+        assert_eq!(caller_key(Some(&alice), Some(addr)), "user:alice");
+        assert_eq!(caller_key(Some(&Caller::default()), Some(addr)), "ip:10.0.0.7");
+        assert_eq!(caller_key(None, None), "ip:unknown");
+    }
+
+    #[test]
+    fn test_stream_routes() {
+        assert!(is_stream_route(&Method::POST, "/generate/stream"));
+        assert!(is_stream_route(&Method::POST, "/generate/batch"));
+        assert!(is_stream_route(&Method::POST, "/sessions/s1/messages/m1/edit"));
+        assert!(!is_stream_route(&Method::POST, "/generate"));
+        assert!(!is_stream_route(&Method::GET, "/api/chat"));
+    }
+}
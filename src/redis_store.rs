@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use crate::file_parser::CacheFile;
+use crate::file_store::FileStore;
+use crate::session::{Session, SessionStore};
+
+
+/// 保存在 Redis 中的 session 和上传文件，多个实例连接同一个 Redis 即可共享状态。
+///
+/// - `{prefix}session:{id}`：session JSON，`{prefix}sessions`：按最近使用时间排序的 session id（sorted set）
+/// - `{prefix}sessions:owner:{owner}`：同上，只包含该用户的 session；未开启认证时为 `{prefix}sessions:unowned`
+/// - `{prefix}file:{id}`：文件 JSON，`{prefix}image:{id}`：图片字节，`{prefix}files`：全部 file id（set）
+pub struct RedisStore {
+    conn: ConnectionManager,
+    prefix: String,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str, prefix: &str) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        tracing::info!("Connected to Redis");
+
+        Ok(Self {
+            conn,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn session_key(&self, session_id: &str) -> String {
+        format!("{}session:{}", self.prefix, session_id)
+    }
+
+    fn sessions_key(&self) -> String {
+        format!("{}sessions", self.prefix)
+    }
+
+    fn owner_sessions_key(&self, owner: Option<&str>) -> String {
+        match owner {
+            Some(owner) => format!("{}sessions:owner:{}", self.prefix, owner),
+            None => format!("{}sessions:unowned", self.prefix),
+        }
+    }
+
+    fn file_key(&self, file_id: &str) -> String {
+        format!("{}file:{}", self.prefix, file_id)
+    }
+
+    fn image_key(&self, file_id: &str) -> String {
+        format!("{}image:{}", self.prefix, file_id)
+    }
+
+    fn files_key(&self) -> String {
+        format!("{}files", self.prefix)
+    }
+}
+
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn load(&self, session_id: &str) -> Result<Option<Session>> {
+        let mut conn = self.conn.clone();
+        let data: Option<Vec<u8>> = conn.get(self.session_key(session_id)).await?;
+        Ok(data.map(|data| serde_json::from_slice(&data)).transpose()?)
+    }
+
+    async fn save(&self, session: &Session) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = redis::pipe()
+            .atomic()
+            .set(self.session_key(&session.id), serde_json::to_vec(session)?)
+            .ignore()
+            .zadd(self.sessions_key(), &session.id, session.last_active)
+            .ignore()
+            .zadd(self.owner_sessions_key(session.owner.as_deref()), &session.id, session.last_active)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_id: &str) -> Result<bool> {
+        // 读取 owner 以便从该用户的列表中删除
+        let owner = SessionStore::load(self, session_id).await?.and_then(|session| session.owner);
+
+        let mut conn = self.conn.clone();
+        let (deleted, _, _): (usize, usize, usize) = redis::pipe()
+            .atomic()
+            .del(self.session_key(session_id))
+            .zrem(self.sessions_key(), session_id)
+            .zrem(self.owner_sessions_key(owner.as_deref()), session_id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn list(&self, owner: Option<&str>, offset: usize, limit: usize) -> Result<(usize, Vec<Session>)> {
+        let key = self.owner_sessions_key(owner);
+        let mut conn = self.conn.clone();
+        let total: usize = conn.zcard(&key).await?;
+        if limit == 0 || offset >= total {
+            return Ok((total, Vec::new()));
+        }
+
+        let session_ids: Vec<String> = conn
+            .zrevrange(&key, offset as isize, (offset + limit - 1) as isize)
+            .await?;
+
+        let mut sessions = Vec::with_capacity(session_ids.len());
+        for session_id in session_ids {
+            if let Some(session) = SessionStore::load(self, &session_id).await? {
+                sessions.push(session);
+            }
+        }
+        Ok((total, sessions))
+    }
+
+    async fn expire(&self, ttl_secs: u64, now: u64) -> Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        // last_active + ttl_secs <= now
+        let cutoff = now.saturating_sub(ttl_secs);
+        let expired: Vec<String> = conn.zrangebyscore(self.sessions_key(), "-inf", cutoff).await?;
+
+        for session_id in &expired {
+            SessionStore::delete(self, session_id).await?;
+        }
+        Ok(expired)
+    }
+}
+
+
+#[async_trait]
+impl FileStore for RedisStore {
+    async fn save(&self, file_id: &str, file: &CacheFile) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = redis::pipe()
+            .atomic()
+            .set(self.file_key(file_id), serde_json::to_vec(file)?)
+            .ignore()
+            .sadd(self.files_key(), file_id)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn load(&self, file_id: &str) -> Result<Option<CacheFile>> {
+        let mut conn = self.conn.clone();
+        let data: Option<Vec<u8>> = conn.get(self.file_key(file_id)).await?;
+        Ok(data.map(|data| serde_json::from_slice(&data)).transpose()?)
+    }
+
+    async fn load_all(&self) -> Result<HashMap<String, CacheFile>> {
+        let mut conn = self.conn.clone();
+        let file_ids: Vec<String> = conn.smembers(self.files_key()).await?;
+
+        let mut files = HashMap::new();
+        for file_id in file_ids {
+            match FileStore::load(self, &file_id).await {
+                Ok(Some(file)) => {
+                    files.insert(file_id, file);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!(file_id = %file_id, error = %e, "Skipping unreadable stored file"),
+            }
+        }
+        Ok(files)
+    }
+
+    async fn delete(&self, file_id: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = redis::pipe()
+            .atomic()
+            .del(self.file_key(file_id))
+            .ignore()
+            .del(self.image_key(file_id))
+            .ignore()
+            .srem(self.files_key(), file_id)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn save_image(&self, file_id: &str, bytes: &[u8]) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = conn.set(self.image_key(file_id), bytes).await?;
+        Ok(())
+    }
+
+    async fn load_image(&self, file_id: &str) -> Result<Vec<u8>> {
+        let mut conn = self.conn.clone();
+        let bytes: Option<Vec<u8>> = conn.get(self.image_key(file_id)).await?;
+        bytes.ok_or_else(|| anyhow!("Image {} not found in Redis", file_id))
+    }
+
+    fn describe(&self) -> String {
+        format!("Redis ({}*)", self.prefix)
+    }
+
+    fn shared(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,834 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+use crate::chat_template::ChatTemplate;
+use crate::types::GenerationConfig;
+
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelSpec {
+    pub name: String,
+    // Hugging Face repo and GGUF file name inside it
+    #[serde(default)]
+    pub repo: String,
+    #[serde(default)]
+    pub file: String,
+    // 本地 GGUF 文件路径：设置后直接加载该文件，不从 Hugging Face 下载，repo 可以省略
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub quantization: String,
+    #[serde(default = "default_context_length")]
+    pub context_length: usize,
+    // optional chat template file passed to mistralrs
+    #[serde(default)]
+    pub chat_template: Option<String>,
+    // 内置或自定义的对话模板（见 [`ChatTemplate`]），chat_template 文件优先
+    #[serde(default)]
+    pub template: Option<ChatTemplate>,
+    // sampling defaults, overridden by per-request values
+    #[serde(default)]
+    pub defaults: GenerationConfig,
+    // 前缀缓存保留的序列数：同一 session 的下一轮只需编码新增的消息，0 表示关闭（节省显存）
+    #[serde(default = "default_prefix_cache")]
+    pub prefix_cache: usize,
+    // 覆盖服务器配置的设备设置（见 [`Placement`]）
+    #[serde(default)]
+    pub device: Option<Device>,
+    #[serde(default)]
+    pub gpu_layers: Option<usize>,
+    #[serde(default)]
+    pub gpu_index: Option<usize>,
+    // 加载后占用的显存 / 内存（MB），用于 model_memory_budget_mb；未设置时按 GGUF 文件大小估算
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    // 视觉模型：repo 为 Hugging Face 模型 id，通过 mistralrs 的 vision pipeline 加载，file 不使用
+    #[serde(default)]
+    pub vision: bool,
+    // 加载 GGUF 时附加的 LoRA / X-LoRA adapter，模型以自己的 name 对外提供
+    #[serde(default)]
+    pub adapter: Option<Adapter>,
+    // 远程模型：请求转发给 OpenAI 兼容的服务，repo / file 不使用
+    #[serde(default)]
+    pub remote: Option<RemoteBackend>,
+}
+
+// 模型注册表没有设置 context_length 时使用
+pub const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+
+fn default_context_length() -> usize {
+    DEFAULT_CONTEXT_LENGTH
+}
+
+fn default_prefix_cache() -> usize {
+    16
+}
+
+impl ModelSpec {
+    pub fn new(name: &str, repo: &str, file: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            repo: repo.to_string(),
+            file: file.to_string(),
+            path: None,
+            quantization: String::new(),
+            context_length: default_context_length(),
+            chat_template: None,
+            template: None,
+            defaults: GenerationConfig::default(),
+            prefix_cache: default_prefix_cache(),
+            memory_mb: None,
+            device: None,
+            gpu_layers: None,
+            gpu_index: None,
+            vision: false,
+            adapter: None,
+            remote: None,
+        }
+    }
+
+    /// 使用本地 GGUF 文件的模型，file 为路径中的文件名
+    pub fn local(name: &str, path: &str) -> Self {
+        let file = Path::new(path)
+            .file_name()
+            .map(|file| file.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Self {
+            path: Some(path.to_string()),
+            ..Self::new(name, "", &file)
+        }
+    }
+
+    /// GGUF 文件的位置：本地模型为 path，其余为 model_dir 下下载的文件。分片模型为第一个分片
+    pub fn gguf_path(&self, model_dir: &str) -> PathBuf {
+        match &self.path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(model_dir).join(&self.file),
+        }
+    }
+
+    /// 模型的所有 GGUF 文件，分片模型的其余分片和第一个分片在同一目录
+    pub fn gguf_paths(&self, model_dir: &str) -> Vec<PathBuf> {
+        let first = self.gguf_path(model_dir);
+        let dir = first.parent().map(Path::to_path_buf).unwrap_or_default();
+        split_gguf_files(&self.file).iter().map(|file| dir.join(file)).collect()
+    }
+}
+
+
+/// 大模型分成多个文件发布，例如 `model-00001-of-00003.gguf`。file 为第一个分片时返回所有分片的文件名，
+/// 否则只返回 file 本身
+pub fn split_gguf_files(file: &str) -> Vec<String> {
+    let shards = file.len().checked_sub("-00001-of-00003.gguf".len())
+        .filter(|&start| file.is_char_boundary(start))
+        .map(|start| (&file[..start], &file[start..]))
+        .and_then(|(stem, suffix)| {
+            let suffix = suffix.strip_prefix('-')?;
+            let (index, rest) = suffix.split_once("-of-")?;
+            let (count, extension) = rest.split_once('.')?;
+            let digits = |s: &str| s.len() == 5 && s.bytes().all(|b| b.is_ascii_digit());
+            if index != "00001" || !digits(count) || !extension.eq_ignore_ascii_case("gguf") {
+                return None;
+            }
+            let count: usize = count.parse().ok().filter(|&count| count > 1)?;
+            Some((1..=count)
+                .map(|i| format!("{}-{:05}-of-{:05}.{}", stem, i, count, extension))
+                .collect())
+        });
+    shards.unwrap_or_else(|| vec![file.to_string()])
+}
+
+
+/// 模型加载到的设备："auto" 有 GPU 时使用 GPU，"cpu" 只用 CPU，"cuda" 使用 NVIDIA GPU
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    #[default]
+    Auto,
+    Cpu,
+    Cuda,
+}
+
+impl Device {
+    pub fn parse(name: &str) -> Option<Device> {
+        match name.trim().to_lowercase().as_str() {
+            "auto" => Some(Device::Auto),
+            "cpu" => Some(Device::Cpu),
+            "cuda" | "gpu" => Some(Device::Cuda),
+            _ => None,
+        }
+    }
+}
+
+
+/// adapter 的类型：lora 为普通 LoRA，xlora 为多个 LoRA 专家按 token 混合的 X-LoRA
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AdapterKind {
+    #[default]
+    Lora,
+    Xlora,
+}
+
+
+/// 附加在 GGUF 模型上的 adapter，由 mistralrs 加载
+#[derive(Clone, Debug, Deserialize, PartialEq, ToSchema)]
+pub struct Adapter {
+    #[serde(default)]
+    pub kind: AdapterKind,
+    // Hugging Face 上 adapter 的模型 id
+    pub model_id: String,
+    // mistralrs 的 ordering 文件（JSON），列出 adapter 及其作用的层
+    pub ordering: String,
+    // X-LoRA：只按前这么多个 token 计算一次专家权重，之后复用
+    #[serde(default)]
+    pub tgt_non_granular_index: Option<usize>,
+}
+
+
+/// 远程 OpenAI 兼容服务（vLLM、llama.cpp server、OpenAI 等），请求发到 `{base_url}/chat/completions`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RemoteBackend {
+    // 例如 "http://10.0.0.5:8000/v1"
+    pub base_url: String,
+    // 远程服务上的模型名，未设置时使用 name
+    #[serde(default)]
+    pub model: Option<String>,
+    // 保存 API key 的环境变量名，key 本身不写进 models.toml
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+}
+
+
+/// 模型的设备设置：gpu_layers 为放到 GPU 的层数（未设置时全部，其余层在 CPU 上），
+/// gpu_index 为使用的 GPU 编号
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Placement {
+    pub device: Device,
+    pub gpu_layers: Option<usize>,
+    pub gpu_index: usize,
+}
+
+impl Placement {
+    /// GET /models 中显示的设备，例如 "cpu"、"cuda:1"、"cuda:0, 20 GPU layers"
+    pub fn describe(&self) -> String {
+        let device = match self.device {
+            Device::Cpu => return "cpu".to_string(),
+            Device::Auto if self.gpu_index == 0 => "auto".to_string(),
+            Device::Auto | Device::Cuda => format!("cuda:{}", self.gpu_index),
+        };
+        match self.gpu_layers {
+            Some(layers) => format!("{}, {} GPU layers", device, layers),
+            None => device,
+        }
+    }
+}
+
+
+#[derive(Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    models: Vec<ModelSpec>,
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct ModelRegistry {
+    models: Vec<ModelSpec>,
+    // 别名 -> 模型名，让 OpenAI 客户端默认的模型名（如 gpt-3.5-turbo）直接可用
+    aliases: HashMap<String, String>,
+    // 服务器配置的设备设置，模型未设置的字段使用它
+    placement: Placement,
+    // 启动时没有找到可用的 GPU，所有模型都在 CPU 上运行
+    cpu_fallback: bool,
+    // 正在下载的模型名（POST /models/pull），下载结束前其他请求不能使用这个名称
+    reserved: HashSet<String>,
+}
+
+fn default_aliases() -> HashMap<String, String> {
+    HashMap::from([
+        ("gpt-3.5-turbo".to_string(), "qwen".to_string()),
+        ("gpt-4o-mini".to_string(), "qwen".to_string()),
+    ])
+}
+
+impl ModelRegistry {
+    /// 内置的默认模型列表，没有 models.toml 时使用
+    pub fn builtin() -> Self {
+        let spec = |name: &str, repo: &str, file: &str, context_length: usize, template| ModelSpec {
+            quantization: "Q4_K_M".to_string(),
+            context_length,
+            template: Some(template),
+            ..ModelSpec::new(name, repo, file)
+        };
+
+        Self {
+            models: vec![
+                spec("qwen", "bartowski/Qwen2.5-3B-Instruct-GGUF",
+                     "Qwen2.5-3B-Instruct-Q4_K_M.gguf", 32768, ChatTemplate::Chatml),
+                spec("smollm2", "bartowski/SmolLM2-1.7B-Instruct-GGUF",
+                     "SmolLM2-1.7B-Instruct-Q4_K_M.gguf", 8192, ChatTemplate::Chatml),
+                spec("llama8b", "bartowski/Meta-Llama-3.1-8B-Instruct-GGUF",
+                     "Meta-Llama-3.1-8B-Instruct-Q4_K_M.gguf", 131072, ChatTemplate::Llama3),
+            ],
+            aliases: default_aliases(),
+            placement: Placement::default(),
+            cpu_fallback: false,
+            reserved: HashSet::new(),
+        }
+    }
+
+    /// 读取模型注册表文件，文件不存在时使用内置列表
+    pub fn load(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            tracing::info!(path, "Model registry not found, using built-in models");
+            return Ok(Self::builtin());
+        }
+
+        tracing::info!(path, "Loading model registry");
+        Self::from_toml_str(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self> {
+        let mut file: RegistryFile = toml::from_str(content)?;
+
+        for (i, spec) in file.models.iter().enumerate() {
+            if file.models[..i].iter().any(|m| m.name == spec.name) {
+                anyhow::bail!("Duplicate model name in registry: {}", spec.name);
+            }
+        }
+
+        for spec in &mut file.models {
+            if let Some(remote) = &spec.remote {
+                if spec.vision || spec.adapter.is_some() || spec.path.is_some() {
+                    anyhow::bail!("Remote model {} cannot also be local (vision, adapter or path)", spec.name);
+                }
+                if !remote.base_url.starts_with("http://") && !remote.base_url.starts_with("https://") {
+                    anyhow::bail!("base_url of remote model {} must be an http(s) URL", spec.name);
+                }
+                continue;
+            }
+            match &spec.path {
+                Some(path) => {
+                    // file is only informational for local models (GET /models)
+                    if spec.file.is_empty() {
+                        spec.file = ModelSpec::local(&spec.name, path).file;
+                    }
+                }
+                None if spec.repo.is_empty() => {
+                    anyhow::bail!("Model {} needs either repo or path", spec.name);
+                }
+                None if spec.file.is_empty() && !spec.vision => {
+                    anyhow::bail!("Model {} needs a GGUF file", spec.name);
+                }
+                None => {}
+            }
+            if spec.vision && spec.adapter.is_some() {
+                anyhow::bail!("Adapters are only supported on GGUF models ({})", spec.name);
+            }
+        }
+
+        for (alias, target) in &file.aliases {
+            if file.models.iter().any(|m| &m.name == alias) {
+                anyhow::bail!("Alias {} has the same name as a model", alias);
+            }
+            if !file.models.iter().any(|m| &m.name == target) {
+                anyhow::bail!("Alias {} points to unknown model {}", alias, target);
+            }
+        }
+
+        Ok(Self {
+            models: file.models,
+            aliases: file.aliases,
+            placement: Placement::default(),
+            cpu_fallback: false,
+            reserved: HashSet::new(),
+        })
+    }
+
+    /// 运行时注册新模型，名称不能与已有模型、别名或正在下载的模型重复
+    pub fn register(&mut self, spec: ModelSpec) -> Result<()> {
+        if self.reserved.contains(&spec.name) {
+            anyhow::bail!("Model {} is being pulled", spec.name);
+        }
+        self.register_reserved(spec)
+    }
+
+    /// 为下载预留模型名，名称已被使用时返回 false。下载成功后用 register_reserved 注册，失败时 release
+    pub fn reserve(&mut self, name: &str) -> bool {
+        if self.get(name).is_some() || self.aliases.contains_key(name) {
+            return false;
+        }
+        self.reserved.insert(name.to_string())
+    }
+
+    pub fn release(&mut self, name: &str) {
+        self.reserved.remove(name);
+    }
+
+    /// 注册预留过名称的模型（POST /models/pull），并释放预留
+    pub fn register_reserved(&mut self, spec: ModelSpec) -> Result<()> {
+        if self.get(&spec.name).is_some() || self.aliases.contains_key(&spec.name) {
+            anyhow::bail!("Model {} already exists", spec.name);
+        }
+        self.reserved.remove(&spec.name);
+        self.models.push(spec);
+        Ok(())
+    }
+
+    /// 在已注册的 GGUF 模型上附加 adapter，以 name 注册为新模型（POST /models/{name}/adapters）
+    pub fn register_adapter(&mut self, base: &str, name: &str, adapter: Adapter) -> Result<()> {
+        let Some(base_spec) = self.get(base) else {
+            anyhow::bail!("Model {} does not exist", base);
+        };
+        if base_spec.vision || base_spec.remote.is_some() {
+            anyhow::bail!("Adapters are only supported on GGUF models");
+        }
+        if base_spec.adapter.is_some() {
+            anyhow::bail!("Model {} already has an adapter", base);
+        }
+
+        let spec = ModelSpec {
+            name: name.to_string(),
+            adapter: Some(adapter),
+            ..base_spec.clone()
+        };
+        self.register(spec)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ModelSpec> {
+        self.models.iter().find(|m| m.name == name)
+    }
+
+    /// 别名换成对应的模型名，其他名称原样返回
+    pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    pub fn set_default_placement(&mut self, placement: Placement) {
+        self.placement = placement;
+    }
+
+    /// 没有可用的 GPU：之后加载的模型都放在 CPU 上，包括设置了 device = "cuda" 的模型
+    pub fn fall_back_to_cpu(&mut self) {
+        self.cpu_fallback = true;
+    }
+
+    pub fn cpu_fallback(&self) -> bool {
+        self.cpu_fallback
+    }
+
+    /// 模型的设备设置：模型的设置优先，其余使用服务器配置
+    pub fn placement(&self, spec: &ModelSpec) -> Placement {
+        if self.cpu_fallback {
+            return Placement {
+                device: Device::Cpu,
+                ..Placement::default()
+            };
+        }
+        Placement {
+            device: spec.device.unwrap_or(self.placement.device),
+            gpu_layers: spec.gpu_layers.or(self.placement.gpu_layers),
+            gpu_index: spec.gpu_index.unwrap_or(self.placement.gpu_index),
+        }
+    }
+
+    pub fn aliases(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+
+    pub fn models(&self) -> &[ModelSpec] {
+        &self.models
+    }
+}
+
+
+pub type SharedRegistry = Arc<RwLock<ModelRegistry>>;
+
+pub fn new_shared_registry(registry: ModelRegistry) -> SharedRegistry {
+    Arc::new(RwLock::new(registry))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_registry() {
+        let registry = ModelRegistry::builtin();
+        assert_eq!(registry.models().len(), 3);
+        assert_eq!(registry.get("qwen").unwrap().context_length, 32768);
+        assert!(registry.get("unknown").is_none());
+    }
+
+    #[test]
+    fn test_bundled_registry_matches_builtin() {
+        let bundled = ModelRegistry::from_toml_str(include_str!("../models.toml")).unwrap();
+        let builtin = ModelRegistry::builtin();
+        assert_eq!(bundled.models().len(), builtin.models().len());
+        for spec in builtin.models() {
+            let entry = bundled.get(&spec.name).unwrap();
+            assert_eq!(entry.file, spec.file);
+            assert_eq!(entry.context_length, spec.context_length);
+            assert_eq!(entry.template, spec.template);
+        }
+        assert_eq!(bundled.aliases(), builtin.aliases());
+    }
+
+    #[test]
+    fn test_aliases_resolve_to_models() {
+        let registry = ModelRegistry::builtin();
+        assert_eq!(registry.resolve("gpt-3.5-turbo"), "qwen");
+        assert_eq!(registry.resolve("smollm2"), "smollm2");
+        assert_eq!(registry.resolve("unknown"), "unknown");
+    }
+
+    #[test]
+    fn test_invalid_aliases_rejected() {
+        let models = r#"
+            [[models]]
+            name = "a"
+            repo = "r"
+            file = "a.gguf"
+        "#;
+        assert!(ModelRegistry::from_toml_str(&format!("{}\n[aliases]\nb = \"missing\"", models)).is_err());
+        assert!(ModelRegistry::from_toml_str(&format!("{}\n[aliases]\na = \"a\"", models)).is_err());
+
+        let registry = ModelRegistry::from_toml_str(&format!("{}\n[aliases]\nb = \"a\"", models)).unwrap();
+        assert_eq!(registry.resolve("b"), "a");
+    }
+
+    #[test]
+    fn test_parse_registry() {
+        let registry = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "phi"
+            repo = "bartowski/phi-GGUF"
+            file = "phi-Q4_K_M.gguf"
+            quantization = "Q4_K_M"
+            chat_template = "templates/phi.json"
+
+            [models.defaults]
+            temperature = 0.2
+            max_tokens = 512
+            repeat_penalty = 1.1
+            frequency_penalty = 0.5
+
+            [[models]]
+            name = "tiny"
+            repo = "someone/tiny-GGUF"
+            file = "tiny.gguf"
+            prefix_cache = 0
+        "#).unwrap();
+
+        let phi = registry.get("phi").unwrap();
+        assert_eq!(phi.context_length, 4096);
+        assert_eq!(phi.chat_template.as_deref(), Some("templates/phi.json"));
+        assert_eq!(phi.defaults.temperature, Some(0.2));
+        assert_eq!(phi.defaults.max_tokens, Some(512));
+        assert_eq!(phi.defaults.repetition_penalty, Some(1.1));
+        assert_eq!(phi.defaults.frequency_penalty, Some(0.5));
+        assert!(phi.defaults.presence_penalty.is_none());
+        assert!(phi.defaults.top_p.is_none());
+        assert_eq!(phi.prefix_cache, 16);
+
+        let tiny = registry.get("tiny").unwrap();
+        assert_eq!(tiny.quantization, "");
+        assert!(tiny.chat_template.is_none());
+        assert_eq!(tiny.prefix_cache, 0);
+        assert!(!tiny.vision);
+    }
+
+    #[test]
+    fn test_parse_vision_model() {
+        let registry = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "llava"
+            repo = "llava-hf/llava-v1.6-mistral-7b-hf"
+            file = ""
+            vision = true
+        "#).unwrap();
+
+        assert!(registry.get("llava").unwrap().vision);
+    }
+
+    #[test]
+    fn test_model_placement_overrides_server_default() {
+        let mut registry = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "big"
+            repo = "someone/big-GGUF"
+            file = "big.gguf"
+            gpu_layers = 20
+            gpu_index = 1
+
+            [[models]]
+            name = "small"
+            repo = "someone/small-GGUF"
+            file = "small.gguf"
+            device = "cpu"
+        "#).unwrap();
+        registry.set_default_placement(Placement {
+            device: Device::Cuda,
+            gpu_layers: None,
+            gpu_index: 0,
+        });
+
+        let big = registry.placement(registry.get("big").unwrap());
+        assert_eq!(big, Placement { device: Device::Cuda, gpu_layers: Some(20), gpu_index: 1 });
+        assert_eq!(big.describe(), "cuda:1, 20 GPU layers");
+
+        let small = registry.placement(registry.get("small").unwrap());
+        assert_eq!(small.device, Device::Cpu);
+        assert_eq!(small.describe(), "cpu");
+
+        assert_eq!(Placement::default().describe(), "auto");
+    }
+
+    #[test]
+    fn test_adapter_entries() {
+        let registry = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "qwen-sql"
+            repo = "bartowski/Qwen2.5-3B-Instruct-GGUF"
+            file = "Qwen2.5-3B-Instruct-Q4_K_M.gguf"
+
+            [models.adapter]
+            model_id = "someone/qwen-sql-lora"
+            ordering = "adapters/qwen-sql.json"
+        "#).unwrap();
+        let adapter = registry.get("qwen-sql").unwrap().adapter.clone().unwrap();
+        assert_eq!(adapter.kind, AdapterKind::Lora);
+        assert_eq!(adapter.ordering, "adapters/qwen-sql.json");
+
+        let vision = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "llava"
+            repo = "llava-hf/llava-1.5-7b-hf"
+            vision = true
+
+            [models.adapter]
+            model_id = "someone/llava-lora"
+            ordering = "ordering.json"
+        "#);
+        assert!(vision.is_err());
+    }
+
+    #[test]
+    fn test_remote_entries_need_no_gguf() {
+        let registry = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "gpt-4o"
+
+            [models.remote]
+            base_url = "https://api.openai.com/v1"
+            api_key_env = "OPENAI_API_KEY"
+        "#).unwrap();
+        let remote = registry.get("gpt-4o").unwrap().remote.clone().unwrap();
+        assert_eq!(remote.base_url, "https://api.openai.com/v1");
+        assert!(remote.model.is_none());
+
+        let bad_url = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "x"
+
+            [models.remote]
+            base_url = "10.0.0.5:8000"
+        "#);
+        assert!(bad_url.is_err());
+    }
+
+    #[test]
+    fn test_register_adapter_copies_base_model() {
+        let mut registry = ModelRegistry::builtin();
+        let adapter = Adapter {
+            kind: AdapterKind::Xlora,
+            model_id: "lamm-mit/x-lora".to_string(),
+            ordering: "ordering.json".to_string(),
+            tgt_non_granular_index: None,
+        };
+
+        registry.register_adapter("qwen", "qwen-xlora", adapter.clone()).unwrap();
+        let spec = registry.get("qwen-xlora").unwrap();
+        assert_eq!(spec.file, "Qwen2.5-3B-Instruct-Q4_K_M.gguf");
+        assert_eq!(spec.adapter.as_ref(), Some(&adapter));
+        assert!(registry.get("qwen").unwrap().adapter.is_none());
+
+        assert!(registry.register_adapter("qwen", "qwen-xlora", adapter.clone()).is_err());
+        assert!(registry.register_adapter("qwen-xlora", "stacked", adapter.clone()).is_err());
+        assert!(registry.register_adapter("missing", "x", adapter).is_err());
+    }
+
+    #[test]
+    fn test_cpu_fallback_overrides_every_model() {
+        let mut registry = ModelRegistry::builtin();
+        let spec = ModelSpec {
+            device: Some(Device::Cuda),
+            gpu_layers: Some(20),
+            ..ModelSpec::new("gpu-only", "someone/x-GGUF", "x.gguf")
+        };
+        assert_eq!(registry.placement(&spec).device, Device::Cuda);
+
+        registry.fall_back_to_cpu();
+        assert!(registry.cpu_fallback());
+        assert_eq!(registry.placement(&spec).describe(), "cpu");
+    }
+
+    #[test]
+    fn test_unknown_device_rejected() {
+        let result = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "x"
+            repo = "someone/x-GGUF"
+            file = "x.gguf"
+            device = "vulkan"
+        "#);
+        assert!(result.is_err());
+        assert_eq!(Device::parse("GPU"), Some(Device::Cuda));
+        assert_eq!(Device::parse("vulkan"), None);
+    }
+
+    #[test]
+    fn test_missing_required_field() {
+        let result = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "broken"
+            file = "broken.gguf"
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_local_path_needs_no_repo() {
+        let registry = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "offline"
+            path = "/opt/models/phi-Q4_K_M.gguf"
+        "#).unwrap();
+        let spec = registry.get("offline").unwrap();
+        assert_eq!(spec.file, "phi-Q4_K_M.gguf");
+        assert_eq!(spec.gguf_path("models"), PathBuf::from("/opt/models/phi-Q4_K_M.gguf"));
+
+        let downloaded = ModelSpec::new("x", "someone/x-GGUF", "x.gguf");
+        assert_eq!(downloaded.gguf_path("models"), Path::new("models").join("x.gguf"));
+    }
+
+    #[test]
+    fn test_split_gguf_expands_shards() {
+        assert_eq!(split_gguf_files("Llama-70B-Q4_K_M-00001-of-00003.gguf"), vec![
+            "Llama-70B-Q4_K_M-00001-of-00003.gguf",
+            "Llama-70B-Q4_K_M-00002-of-00003.gguf",
+            "Llama-70B-Q4_K_M-00003-of-00003.gguf",
+        ]);
+        // only the first shard names the model, single files are left alone
+        assert_eq!(split_gguf_files("x-00002-of-00003.gguf"), vec!["x-00002-of-00003.gguf"]);
+        assert_eq!(split_gguf_files("x-00001-of-00001.gguf"), vec!["x-00001-of-00001.gguf"]);
+        assert_eq!(split_gguf_files("x.gguf"), vec!["x.gguf"]);
+
+        let spec = ModelSpec::local("big", "/opt/models/big-00001-of-00002.gguf");
+        assert_eq!(spec.gguf_paths("models"), vec![
+            PathBuf::from("/opt/models/big-00001-of-00002.gguf"),
+            PathBuf::from("/opt/models/big-00002-of-00002.gguf"),
+        ]);
+    }
+
+    #[test]
+    fn test_duplicate_names_rejected() {
+        let result = ModelRegistry::from_toml_str(r#"
+            [[models]]
+            name = "a"
+            repo = "r"
+            file = "a.gguf"
+
+            [[models]]
+            name = "a"
+            repo = "r"
+            file = "b.gguf"
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_register_model() {
+        let mut registry = ModelRegistry::builtin();
+        let mut spec = registry.get("qwen").unwrap().clone();
+        assert!(registry.register(spec.clone()).is_err());
+
+        spec.name = "gpt-3.5-turbo".to_string();
+        assert!(registry.register(spec.clone()).is_err());
+
+        spec.name = "qwen-copy".to_string();
+        registry.register(spec).unwrap();
+        assert_eq!(registry.models().len(), 4);
+        assert!(registry.get("qwen-copy").is_some());
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        let mut registry = ModelRegistry::builtin();
+        assert!(!registry.reserve("qwen"));
+        assert!(!registry.reserve("gpt-3.5-turbo"));
+        assert!(registry.reserve("pulled"));
+        assert!(!registry.reserve("pulled"));
+
+        // only the pull holding the reservation can register the name
+        let mut spec = registry.get("qwen").unwrap().clone();
+        spec.name = "pulled".to_string();
+        assert!(registry.register(spec.clone()).is_err());
+        registry.register_reserved(spec).unwrap();
+        assert!(registry.get("pulled").is_some());
+        assert!(!registry.reserve("pulled"));
+
+        // a failed pull gives the name back
+        assert!(registry.reserve("failed"));
+        registry.release("failed");
+        assert!(registry.reserve("failed"));
+    }
+
+    #[test]
+    fn test_load_missing_file_uses_builtin() {
+        let registry = ModelRegistry::load("does/not/exist/models.toml").unwrap();
+        assert_eq!(registry.models().len(), 3);
+    }
+
+    #[test]
+    fn test_request_overrides_model_defaults() {
+        let defaults = GenerationConfig {
+            temperature: Some(0.2),
+            max_tokens: Some(512),
+            ..Default::default()
+        };
+        let request = GenerationConfig {
+            temperature: Some(0.9),
+            ..Default::default()
+        };
+
+        let merged = request.or(&defaults);
+        assert_eq!(merged.temperature, Some(0.9));
+        assert_eq!(merged.max_tokens, Some(512));
+        assert!(merged.seed.is_none());
+    }
+
+    #[test]
+    fn test_max_tokens_capped_by_server_limit() {
+        let request = GenerationConfig {
+            max_tokens: Some(100_000),
+            ..Default::default()
+        };
+        assert_eq!(request.capped(None, 4096).max_tokens, Some(4096));
+        assert_eq!(request.capped(None, 0).max_tokens, Some(100_000));
+
+        // unset falls back to the model default, then to the limit itself
+        let unset = GenerationConfig::default();
+        assert_eq!(unset.capped(Some(512), 4096).max_tokens, Some(512));
+        assert_eq!(unset.capped(None, 4096).max_tokens, Some(4096));
+        assert_eq!(unset.capped(None, 0).max_tokens, None);
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::Result;
+use async_stream::stream;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+use crate::engine::{ChunkStream, InferenceEngine, StreamChunk};
+use crate::registry::{ModelSpec, RemoteBackend};
+use crate::types::{ChatMessage, GenerationConfig, TokenLogprobs, TopLogprob, Usage};
+
+
+/// 把对话转发给远程 OpenAI 兼容服务（vLLM、llama.cpp server、OpenAI 等）的后端，
+/// session、文件和 SSE 等处理与本地模型相同
+pub struct RemoteEngine {
+    client: reqwest::Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    defaults: GenerationConfig,
+}
+
+impl RemoteEngine {
+    pub fn new(remote: &RemoteBackend, spec: &ModelSpec) -> Result<Self> {
+        let api_key = match &remote.api_key_env {
+            Some(var) => Some(std::env::var(var)
+                .map_err(|_| anyhow::anyhow!("Environment variable {} with the API key of {} is not set", var, spec.name))?),
+            None => None,
+        };
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url: format!("{}/chat/completions", remote.base_url.trim_end_matches('/')),
+            model: remote.model.clone().unwrap_or_else(|| spec.name.clone()),
+            api_key,
+            defaults: spec.defaults.clone(),
+        })
+    }
+}
+
+
+// OpenAI chat completion request; top_k and repetition_penalty are not OpenAI parameters
+// but vLLM and llama.cpp accept them, so they are only sent when set
+fn request_body(model: &str, messages: &[ChatMessage], config: &GenerationConfig) -> Value {
+    let messages: Vec<Value> = messages.iter()
+        .map(|message| json!({ "role": message.role, "content": message.content }))
+        .collect();
+
+    let mut body = json!({
+        "model": model,
+        "messages": messages,
+        "stream": true,
+        "stream_options": { "include_usage": true },
+    });
+
+    let optional = [
+        ("temperature", config.temperature.map(Value::from)),
+        ("top_p", config.top_p.map(Value::from)),
+        ("top_k", config.top_k.map(Value::from)),
+        ("max_tokens", config.max_tokens.map(Value::from)),
+        ("seed", config.seed.map(Value::from)),
+        ("repetition_penalty", config.repetition_penalty.map(Value::from)),
+        ("presence_penalty", config.presence_penalty.map(Value::from)),
+        ("frequency_penalty", config.frequency_penalty.map(Value::from)),
+        ("logprobs", config.logprobs.map(Value::from)),
+        ("top_logprobs", config.top_logprobs.map(Value::from)),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            body[key] = value;
+        }
+    }
+    // top_logprobs is only accepted together with logprobs
+    if config.top_logprobs.is_some_and(|n| n > 0) {
+        body["logprobs"] = Value::from(true);
+    }
+    body
+}
+
+
+#[derive(Deserialize)]
+struct CompletionChunk {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    #[serde(default)]
+    usage: Option<RemoteUsage>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    #[serde(default)]
+    delta: Delta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+    #[serde(default)]
+    logprobs: Option<ChoiceLogprobs>,
+}
+
+#[derive(Default, Deserialize)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChoiceLogprobs {
+    #[serde(default)]
+    content: Vec<RemoteLogprob>,
+}
+
+#[derive(Deserialize)]
+struct RemoteLogprob {
+    token: String,
+    logprob: f32,
+    #[serde(default)]
+    top_logprobs: Vec<RemoteTopLogprob>,
+}
+
+#[derive(Deserialize)]
+struct RemoteTopLogprob {
+    token: String,
+    logprob: f32,
+}
+
+#[derive(Deserialize)]
+struct RemoteUsage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+
+/// 一行 SSE 数据对应的输出。`data: [DONE]` 和非 data 行（注释、空行）返回 None
+fn parse_sse_line(line: &str) -> Option<CompletionChunk> {
+    let data = line.strip_prefix("data:")?.trim();
+    if data == "[DONE]" {
+        return None;
+    }
+    match serde_json::from_str(data) {
+        Ok(chunk) => Some(chunk),
+        Err(e) => {
+            tracing::warn!(error = %e, "Ignoring malformed chunk from remote model");
+            None
+        }
+    }
+}
+
+// StreamChunk items of one completion chunk, in the order the local engines send them
+fn to_chunks(chunk: CompletionChunk, finish_reason: &mut Option<String>, usage: &mut Option<Usage>) -> Vec<StreamChunk> {
+    let mut chunks = Vec::new();
+    if let Some(choice) = chunk.choices.into_iter().next() {
+        if choice.finish_reason.is_some() {
+            *finish_reason = choice.finish_reason;
+        }
+        for logprob in choice.logprobs.map(|logprobs| logprobs.content).unwrap_or_default() {
+            chunks.push(StreamChunk::Logprobs(TokenLogprobs {
+                token: logprob.token,
+                logprob: logprob.logprob,
+                top_logprobs: logprob.top_logprobs.into_iter()
+                    .map(|top| TopLogprob { token: top.token, logprob: top.logprob })
+                    .collect(),
+            }));
+        }
+        if let Some(text) = choice.delta.content.filter(|text| !text.is_empty()) {
+            chunks.push(StreamChunk::Token(text));
+        }
+    }
+    if let Some(remote) = chunk.usage {
+        *usage = Some(Usage {
+            prompt_tokens: remote.prompt_tokens,
+            completion_tokens: remote.completion_tokens,
+            total_tokens: remote.total_tokens,
+            finish_reason: None,
+        });
+    }
+    chunks
+}
+
+
+#[async_trait]
+impl InferenceEngine for RemoteEngine {
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> Result<ChunkStream> {
+        let config = config.or(&self.defaults);
+        let mut request = self.client.post(&self.url).json(&request_body(&self.model, messages, &config));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Remote model returned {}: {}", status, body.trim());
+        }
+
+        let mut bytes = response.bytes_stream();
+        let model = self.model.clone();
+        let output_stream = stream! {
+            let mut buffer = String::new();
+            let mut finish_reason = None;
+            let mut usage = None;
+
+            loop {
+                // dropping the response on cancel closes the connection, which stops the remote generation
+                let next = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    next = bytes.next() => next,
+                };
+                let data = match next {
+                    Some(Ok(data)) => data,
+                    Some(Err(e)) => {
+                        tracing::warn!(model = %model, error = %e, "Remote model stream failed");
+                        break;
+                    }
+                    None => break,
+                };
+
+                buffer.push_str(&String::from_utf8_lossy(&data));
+                while let Some(end) = buffer.find('\n') {
+                    let line: String = buffer.drain(..=end).collect();
+                    if let Some(chunk) = parse_sse_line(line.trim_end()) {
+                        for item in to_chunks(chunk, &mut finish_reason, &mut usage) {
+                            yield item;
+                        }
+                    }
+                }
+            }
+
+            // servers that ignore stream_options send no usage, still report why generation stopped
+            let mut usage = usage.unwrap_or_default();
+            usage.finish_reason = finish_reason;
+            yield StreamChunk::Usage(usage);
+        };
+
+        Ok(Box::pin(output_stream))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MessageRole;
+
+    #[test]
+    fn test_request_body_sends_only_set_parameters() {
+        let messages = vec![ChatMessage::new(MessageRole::User, "Hi".to_string())];
+        let config = GenerationConfig {
+            temperature: Some(0.2),
+            top_logprobs: Some(3),
+            ..Default::default()
+        };
+
+        let body = request_body("llama-3.1-70b", &messages, &config);
+        assert_eq!(body["model"], "llama-3.1-70b");
+        assert_eq!(body["messages"][0], json!({"role": "user", "content": "Hi"}));
+        assert_eq!(body["temperature"], 0.2);
+        assert_eq!(body["logprobs"], true);
+        assert!(body.get("top_k").is_none());
+        assert!(body.get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn test_parse_sse_lines() {
+        assert!(parse_sse_line("data: [DONE]").is_none());
+        assert!(parse_sse_line(": keep-alive").is_none());
+        assert!(parse_sse_line("").is_none());
+
+        let chunk = parse_sse_line(r#"data: {"choices":[{"delta":{"content":"Hel"},"finish_reason":null}]}"#).unwrap();
+        let (mut finish_reason, mut usage) = (None, None);
+        let chunks = to_chunks(chunk, &mut finish_reason, &mut usage);
+        assert!(matches!(chunks.as_slice(), [StreamChunk::Token(text)] if text == "Hel"));
+
+        let last = parse_sse_line(
+            r#"data: {"choices":[{"delta":{},"finish_reason":"length"}],"usage":{"prompt_tokens":5,"completion_tokens":2,"total_tokens":7}}"#,
+        ).unwrap();
+        assert!(to_chunks(last, &mut finish_reason, &mut usage).is_empty());
+        assert_eq!(finish_reason.as_deref(), Some("length"));
+        assert_eq!(usage.unwrap().total_tokens, 7);
+    }
+
+    #[test]
+    fn test_logprobs_come_before_their_token() {
+        let chunk = parse_sse_line(r#"data: {"choices":[{"delta":{"content":"Hi"},"logprobs":{"content":[
+            {"token":"Hi","logprob":-0.1,"top_logprobs":[{"token":"Hello","logprob":-2.5}]}]}}]}"#.replace('\n', "").as_str()).unwrap();
+        let chunks = to_chunks(chunk, &mut None, &mut None);
+
+        match chunks.as_slice() {
+            [StreamChunk::Logprobs(logprobs), StreamChunk::Token(text)] => {
+                assert_eq!(logprobs.top_logprobs[0].token, "Hello");
+                assert_eq!(text, "Hi");
+            }
+            _ => panic!("unexpected chunks"),
+        }
+    }
+}
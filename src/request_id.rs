@@ -0,0 +1,76 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+};
+use tower_http::request_id::RequestId;
+use tracing::Span;
+
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+
+// 客户端传入的 id 会出现在日志和响应头中，只接受较短的、由字母数字和 -_.: 组成的值
+fn is_valid_request_id(value: &HeaderValue) -> bool {
+    let bytes = value.as_bytes();
+    !bytes.is_empty()
+        && bytes.len() <= MAX_REQUEST_ID_LEN
+        && bytes.iter().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// 去掉不合法的 x-request-id，之后的 SetRequestIdLayer 会为这样的请求生成新的 id
+pub async fn drop_invalid_request_id(mut request: Request) -> Request {
+    if request.headers().get(&REQUEST_ID_HEADER).is_some_and(|value| !is_valid_request_id(value)) {
+        request.headers_mut().remove(&REQUEST_ID_HEADER);
+    }
+    request
+}
+
+/// 每个请求的 tracing span，带上 request_id，便于把日志和客户端看到的 x-request-id 对应起来
+pub fn request_span(request: &Request<Body>) -> Span {
+    let request_id = request.extensions().get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or("-");
+    tracing::info_span!("request", method = %request.method(), uri = %request.uri(), request_id)
+}
+
+/// 请求 id 的字符串形式，用作生成的 request id（SSE 事件、日志和 /generate/cancel/{request_id}）
+pub fn request_id_string(request_id: &RequestId) -> String {
+    request_id.header_value()
+        .to_str()
+        .map(str::to_string)
+        .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id_validation() {
+        assert!(is_valid_request_id(&HeaderValue::from_static("3f2b9c1e-8d4a-4f6e-9b1a-2c7d5e8f0a13")));
+        assert!(is_valid_request_id(&HeaderValue::from_static("web:checkout.42_retry")));
+        assert!(!is_valid_request_id(&HeaderValue::from_static("")));
+        assert!(!is_valid_request_id(&HeaderValue::from_static("two words")));
+        assert!(!is_valid_request_id(&HeaderValue::from_str(&"a".repeat(MAX_REQUEST_ID_LEN + 1)).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_id_is_dropped() {
+        let request = Request::builder()
+            .header(REQUEST_ID_HEADER, "id\twith\ttabs")
+            .body(Body::empty())
+            .unwrap();
+        assert!(drop_invalid_request_id(request).await.headers().get(REQUEST_ID_HEADER).is_none());
+
+        let request = Request::builder()
+            .header(REQUEST_ID_HEADER, "client-123")
+            .body(Body::empty())
+            .unwrap();
+        let request = drop_invalid_request_id(request).await;
+        assert_eq!(request.headers().get(REQUEST_ID_HEADER).unwrap(), "client-123");
+    }
+}
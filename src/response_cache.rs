@@ -0,0 +1,149 @@
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use crate::types::{ChatMessage, GenerationConfig, Usage};
+
+
+/// 只缓存明确要求 temperature 为 0 的请求，这时同样的对话总是得到同样的回答
+pub fn cacheable(generation_config: &GenerationConfig) -> bool {
+    generation_config.temperature == Some(0.0)
+}
+
+
+/// 缓存的 key：租户、模型、发给模型的完整对话（系统消息、示例、历史和文件内容）和采样参数的 blake3 哈希。
+/// 消息只取角色和内容，消息 id 不影响结果。不同租户之间不共用缓存
+pub fn cache_key(
+    tenant: Option<&str>,
+    model: &str,
+    messages: &[ChatMessage],
+    generation_config: &GenerationConfig,
+) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(tenant.unwrap_or("").as_bytes());
+    hasher.update(b"\0");
+    hasher.update(model.as_bytes());
+    hasher.update(b"\0");
+    for message in messages {
+        hasher.update(format!("{:?}\0{}\0", message.role, message.content).as_bytes());
+    }
+    hasher.update(format!("{:?}", generation_config).as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+
+#[derive(Clone)]
+struct CachedResponse {
+    text: String,
+    usage: Usage,
+    stored_at: Instant,
+}
+
+
+/// 确定性请求的回答缓存（LRU）。capacity 为 0 时不缓存，ttl 为 0 时条目不过期
+pub struct ResponseCache {
+    entries: Option<Mutex<LruCache<String, CachedResponse>>>,
+    ttl: Duration,
+}
+
+pub type SharedResponseCache = Arc<ResponseCache>;
+
+pub fn new_response_cache(capacity: usize, ttl_secs: u64) -> SharedResponseCache {
+    Arc::new(ResponseCache {
+        entries: NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity))),
+        ttl: Duration::from_secs(ttl_secs),
+    })
+}
+
+impl ResponseCache {
+    pub fn enabled(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    /// 缓存的回答和当时的 token 用量，过期的条目在这里删除
+    pub async fn get(&self, key: &str) -> Option<(String, Usage)> {
+        self.get_at(key, Instant::now()).await
+    }
+
+    async fn get_at(&self, key: &str, now: Instant) -> Option<(String, Usage)> {
+        let mut entries = self.entries.as_ref()?.lock().await;
+        let entry = entries.get(key)?;
+        if !self.ttl.is_zero() && now.duration_since(entry.stored_at) >= self.ttl {
+            entries.pop(key);
+            return None;
+        }
+        Some((entry.text.clone(), entry.usage.clone()))
+    }
+
+    pub async fn put(&self, key: String, text: String, usage: Usage) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        entries.lock().await.put(key, CachedResponse { text, usage, stored_at: Instant::now() });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MessageRole;
+
+    fn greedy() -> GenerationConfig {
+        GenerationConfig { temperature: Some(0.0), ..Default::default() }
+    }
+
+    fn usage() -> Usage {
+        Usage { prompt_tokens: 4, completion_tokens: 1, total_tokens: 5, finish_reason: Some("stop".to_string()) }
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_prompt_model_params_and_tenant() {
+        let messages = vec![ChatMessage::new(MessageRole::User, "positive or negative: great".to_string())];
+        let key = cache_key(None, "qwen", &messages, &greedy());
+
+        // message ids are generated per request and must not matter
+        let same = vec![ChatMessage::new(MessageRole::User, "positive or negative: great".to_string())];
+        assert_eq!(key, cache_key(None, "qwen", &same, &greedy()));
+
+        let other = vec![ChatMessage::new(MessageRole::System, "positive or negative: great".to_string())];
+        assert_ne!(key, cache_key(None, "qwen", &other, &greedy()));
+        assert_ne!(key, cache_key(None, "smollm2", &messages, &greedy()));
+        assert_ne!(key, cache_key(Some("acme"), "qwen", &messages, &greedy()));
+        let longer = GenerationConfig { max_tokens: Some(8), ..greedy() };
+        assert_ne!(key, cache_key(None, "qwen", &messages, &longer));
+    }
+
+    #[test]
+    fn test_only_greedy_requests_are_cacheable() {
+        assert!(cacheable(&greedy()));
+        assert!(!cacheable(&GenerationConfig::default()));
+        assert!(!cacheable(&GenerationConfig { temperature: Some(0.7), ..Default::default() }));
+    }
+
+    #[tokio::test]
+    async fn test_entries_expire_and_are_bounded() {
+        let cache = new_response_cache(2, 60);
+        cache.put("a".to_string(), "positive".to_string(), usage()).await;
+        assert_eq!(cache.get("a").await.map(|(text, _)| text), Some("positive".to_string()));
+
+        let later = Instant::now() + Duration::from_secs(61);
+        assert!(cache.get_at("a", later).await.is_none());
+        assert!(cache.get("a").await.is_none());
+
+        for key in ["b", "c", "d"] {
+            cache.put(key.to_string(), key.to_string(), usage()).await;
+        }
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("d").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_zero_capacity_disables_cache() {
+        let cache = new_response_cache(0, 0);
+        cache.put("a".to_string(), "positive".to_string(), usage()).await;
+        assert!(!cache.enabled());
+        assert!(cache.get("a").await.is_none());
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+
+/// 模型加载的阶段：下载 GGUF 文件，或把权重加载到 GPU / 内存
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadPhase {
+    Downloading,
+    Loading,
+}
+
+
+/// 模型的加载状态。使用正在加载的模型的请求要等加载结束，所以加载期间 handler 对这个模型的请求
+/// 直接返回 503 和加载进度，而不是让请求卡住几分钟；其他模型的请求照常处理
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum ServiceState {
+    Ready,
+    Loading { model: String, phase: LoadPhase, elapsed_secs: u64 },
+    // 最近一次加载失败。不阻止请求：下一个使用该模型的请求会重新加载
+    Error { model: String, error: String },
+}
+
+
+#[derive(Default)]
+struct Current {
+    // 正在加载的模型，不同的模型可以同时加载
+    loading: HashMap<String, (LoadPhase, Instant)>,
+    // 最近一次加载失败的模型和原因，该模型加载成功后清除
+    error: Option<(String, String)>,
+}
+
+/// 每个模型 Loading -> 加载完成 / Error，由 get_or_load_engine 在加载模型时更新
+pub struct ServiceStatus {
+    current: RwLock<Current>,
+}
+
+pub type SharedServiceStatus = Arc<ServiceStatus>;
+
+pub fn new_service_status() -> SharedServiceStatus {
+    Arc::new(ServiceStatus {
+        current: RwLock::new(Current::default()),
+    })
+}
+
+fn loading_state(model: &str, phase: LoadPhase, started: Instant) -> ServiceState {
+    ServiceState::Loading {
+        model: model.to_string(),
+        phase,
+        elapsed_secs: started.elapsed().as_secs(),
+    }
+}
+
+impl ServiceStatus {
+    /// 整个服务的状态（/health）：有模型在加载时为最早开始的那次加载，否则为最近一次失败，否则 Ready
+    pub fn get(&self) -> ServiceState {
+        let current = self.current.read().unwrap();
+        if let Some((model, (phase, started))) = current.loading.iter().min_by_key(|(_, (_, started))| *started) {
+            return loading_state(model, *phase, *started);
+        }
+        match &current.error {
+            Some((model, error)) => ServiceState::Error { model: model.clone(), error: error.clone() },
+            None => ServiceState::Ready,
+        }
+    }
+
+    /// 这个模型正在加载时返回它的加载进度
+    pub fn loading(&self, model: &str) -> Option<ServiceState> {
+        let current = self.current.read().unwrap();
+        current.loading.get(model).map(|(phase, started)| loading_state(model, *phase, *started))
+    }
+
+    pub fn is_loading(&self, model: &str) -> bool {
+        self.current.read().unwrap().loading.contains_key(model)
+    }
+
+    /// 模型进入 Loading 状态，返回的 guard 结束加载。guard 没有 finish 就被 drop（请求超时、
+    /// 客户端断开）时记为 Error，不会一直停在 Loading
+    pub fn start_loading(self: &Arc<Self>, model: &str, phase: LoadPhase) -> LoadGuard {
+        self.current.write().unwrap().loading.insert(model.to_string(), (phase, Instant::now()));
+        LoadGuard {
+            status: self.clone(),
+            model: model.to_string(),
+            finished: false,
+        }
+    }
+
+    fn finish(&self, model: &str, result: Result<(), String>) {
+        let mut current = self.current.write().unwrap();
+        current.loading.remove(model);
+        match result {
+            Ok(()) if current.error.as_ref().is_some_and(|(failed, _)| failed == model) => current.error = None,
+            Ok(()) => {}
+            Err(error) => current.error = Some((model.to_string(), error)),
+        }
+    }
+}
+
+
+pub struct LoadGuard {
+    status: SharedServiceStatus,
+    model: String,
+    finished: bool,
+}
+
+impl LoadGuard {
+    /// 下载完成后进入加载阶段，耗时从开始下载时算起
+    pub fn set_phase(&self, phase: LoadPhase) {
+        if let Some((current, _)) = self.status.current.write().unwrap().loading.get_mut(&self.model) {
+            *current = phase;
+        }
+    }
+
+    pub fn finish(mut self, result: Result<(), String>) {
+        self.finished = true;
+        self.status.finish(&self.model, result);
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.status.finish(&self.model, Err("Loading was interrupted".to_string()));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_transitions() {
+        let status = new_service_status();
+        assert_eq!(status.get(), ServiceState::Ready);
+
+        let guard = status.start_loading("qwen", LoadPhase::Downloading);
+        guard.set_phase(LoadPhase::Loading);
+        assert!(status.is_loading("qwen"));
+        assert!(matches!(status.get(), ServiceState::Loading { phase: LoadPhase::Loading, .. }));
+
+        guard.finish(Err("out of memory".to_string()));
+        assert!(!status.is_loading("qwen"));
+        assert_eq!(status.get(), ServiceState::Error { model: "qwen".to_string(), error: "out of memory".to_string() });
+
+        status.start_loading("qwen", LoadPhase::Loading).finish(Ok(()));
+        assert_eq!(status.get(), ServiceState::Ready);
+    }
+
+    #[test]
+    fn test_models_load_independently() {
+        let status = new_service_status();
+        let qwen = status.start_loading("qwen", LoadPhase::Loading);
+        let smollm2 = status.start_loading("smollm2", LoadPhase::Downloading);
+        assert!(matches!(status.loading("smollm2"), Some(ServiceState::Loading { phase: LoadPhase::Downloading, .. })));
+        assert_eq!(status.loading("llama8b"), None);
+
+        // the first load to finish does not end the other one
+        qwen.finish(Ok(()));
+        assert!(!status.is_loading("qwen"));
+        assert!(status.is_loading("smollm2"));
+        assert!(matches!(status.get(), ServiceState::Loading { ref model, .. } if model == "smollm2"));
+
+        smollm2.finish(Err("out of memory".to_string()));
+        assert!(matches!(status.get(), ServiceState::Error { ref model, .. } if model == "smollm2"));
+        // a successful load of another model keeps the error
+        status.start_loading("qwen", LoadPhase::Loading).finish(Ok(()));
+        assert!(matches!(status.get(), ServiceState::Error { .. }));
+        status.start_loading("smollm2", LoadPhase::Loading).finish(Ok(()));
+        assert_eq!(status.get(), ServiceState::Ready);
+    }
+
+    #[test]
+    fn test_dropped_load_is_an_error() {
+        let status = new_service_status();
+        drop(status.start_loading("qwen", LoadPhase::Downloading));
+        assert!(matches!(status.get(), ServiceState::Error { .. }));
+    }
+
+    #[test]
+    fn test_state_json() {
+        let json = serde_json::to_value(ServiceState::Loading {
+            model: "qwen".to_string(),
+            phase: LoadPhase::Downloading,
+            elapsed_secs: 3,
+        }).unwrap();
+        assert_eq!(json, serde_json::json!({"state": "loading", "model": "qwen", "phase": "downloading", "elapsed_secs": 3}));
+    }
+}
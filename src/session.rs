@@ -1,30 +1,28 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Deref;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::time::Duration;
+use crate::file_parser::{FileCache, VectorIndex};
+use crate::file_store::unix_now;
+use crate::metrics::SharedMetrics;
+use crate::types::{ChatMessage, MessageRole};
 
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ChatMessage {
-    pub role: MessageRole,
-    pub content: String,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-pub enum MessageRole {
-    User,
-    Assistant,
-    System,
-}
-
-
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
 pub struct SessionConfig {
 
     pub max_turns: usize,
 
     pub system_prompt: Option<String>,
+
+    // 为 true 时超出 max_turns 的消息不直接丢弃，而是由模型压缩成摘要，作为系统消息放在对话前面
+    pub summarize_history: bool,
 }
 
 impl Default for SessionConfig {
@@ -32,16 +30,34 @@ impl Default for SessionConfig {
         Self {
             max_turns: 10,
             system_prompt: None,
+            summarize_history: false,
         }
     }
 }
 
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Session {
     pub id: String,
     pub messages: Vec<ChatMessage>,
     pub config: SessionConfig,
+    // unix 时间戳（秒）：创建时间和最近一次使用时间，空闲超过 session_ttl_secs 的 session 会被清理
+    pub created_at: u64,
+    pub last_active: u64,
+    // 会话标题，显示在前端的会话列表中
+    #[serde(default)]
+    pub title: Option<String>,
+    // summarize_history 模式下：已删除消息的摘要，以及已删除但还没有合并进摘要的消息
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub evicted: Vec<ChatMessage>,
+    // 创建 session 的用户（API key 对应的用户名），未开启认证时为 None；其他用户访问时视为不存在
+    #[serde(default)]
+    pub owner: Option<String>,
+    // session 开始时注入的用户长期记忆，之后修改记忆不影响已有的 session
+    #[serde(default)]
+    pub memory: Option<String>,
 }
 
 impl Session {
@@ -50,39 +66,210 @@ impl Session {
 
 
         if let Some(system_prompt) = &config.system_prompt {
-            messages.push(ChatMessage {
-                role: MessageRole::System,
-                content: system_prompt.clone(),
-            });
+            messages.push(ChatMessage::new(MessageRole::System, system_prompt.clone()));
         }
 
+        let now = unix_now();
         Self { id,
             messages,
-            config
+            config,
+            created_at: now,
+            last_active: now,
+            title: None,
+            summary: None,
+            evicted: Vec::new(),
+            owner: None,
+            memory: None,
         }
     }
 
 
+    pub fn is_owned_by(&self, owner: Option<&str>) -> bool {
+        self.owner.as_deref() == owner
+    }
+
+
     pub fn add_user_message(&mut self, content: String) {
-        self.messages.push(ChatMessage {
-            role: MessageRole::User,
-            content,
-        });
+        self.messages.push(ChatMessage::new(MessageRole::User, content));
         self.trim_history();
     }
 
 
     pub fn add_assistant_message(&mut self, content: String) {
-        self.messages.push(ChatMessage {
-            role: MessageRole::Assistant,
-            content,
-        });
+        self.messages.push(ChatMessage::new(MessageRole::Assistant, content));
+        self.trim_history();
+    }
+
+
+    /// 替换 session 配置，同步更新系统消息并按新的 max_turns 裁剪历史
+    pub fn set_config(&mut self, config: SessionConfig) {
+        self.messages.retain(|m| m.role != MessageRole::System);
+
+        if let Some(system_prompt) = &config.system_prompt {
+            self.messages.insert(0, ChatMessage::new(MessageRole::System, system_prompt.clone()));
+        }
+
+        self.config = config;
         self.trim_history();
     }
 
 
-    pub fn get_messages(&self) -> &[ChatMessage] {
-        &self.messages
+    /// 替换系统消息，其余配置保持不变
+    pub fn set_system_prompt(&mut self, system_prompt: String) {
+        let mut config = self.config.clone();
+        config.system_prompt = Some(system_prompt);
+        self.set_config(config);
+    }
+
+
+    /// 修改一条 user message 的内容，并删除它之后的全部消息，之后重新生成回答
+    pub fn edit_message(&mut self, message_id: &str, content: String) -> Result<(), SessionMessageError> {
+        let idx = self.messages.iter()
+            .position(|m| m.id == message_id)
+            .ok_or(SessionMessageError::MessageNotFound)?;
+        if self.messages[idx].role != MessageRole::User {
+            return Err(SessionMessageError::NotUserMessage);
+        }
+
+        self.messages[idx].content = content;
+        self.messages.truncate(idx + 1);
+        Ok(())
+    }
+
+
+    /// 复制为新的 session：历史截止到 at_message_id（包含该消息），未指定时复制全部历史
+    pub fn fork(&self, new_id: String, at_message_id: Option<&str>) -> Result<Session, SessionMessageError> {
+        let end = match at_message_id {
+            Some(message_id) => self.messages.iter()
+                .position(|m| m.id == message_id)
+                .ok_or(SessionMessageError::MessageNotFound)? + 1,
+            None => self.messages.len(),
+        };
+
+        let now = unix_now();
+        Ok(Session {
+            id: new_id,
+            messages: self.messages[..end].to_vec(),
+            config: self.config.clone(),
+            created_at: now,
+            last_active: now,
+            title: self.title.clone(),
+            summary: self.summary.clone(),
+            evicted: self.evicted.clone(),
+            owner: self.owner.clone(),
+            memory: self.memory.clone(),
+        })
+    }
+
+
+    /// 发送给模型的对话：用户记忆和摘要作为系统消息插在原有系统消息之后
+    pub fn conversation(&self) -> Vec<ChatMessage> {
+        let mut messages = self.messages.clone();
+        let mut idx = messages.iter()
+            .position(|m| m.role != MessageRole::System)
+            .unwrap_or(messages.len());
+
+        if let Some(memory) = &self.memory {
+            messages.insert(idx, ChatMessage::new(MessageRole::System, memory.clone()));
+            idx += 1;
+        }
+
+        if let Some(summary) = &self.summary {
+            messages.insert(idx, ChatMessage::new(
+                MessageRole::System,
+                format!("Summary of the earlier conversation:\n{}", summary),
+            ));
+        }
+
+        messages
+    }
+
+
+    /// 有待合并的已删除消息时，返回更新摘要用的对话，以及本次合并的消息数
+    pub fn summary_prompt(&self) -> Option<(Vec<ChatMessage>, usize)> {
+        if self.evicted.is_empty() {
+            return None;
+        }
+
+        let mut transcript = String::new();
+        for message in &self.evicted {
+            let speaker = match message.role {
+                MessageRole::User => "User",
+                MessageRole::Assistant => "Assistant",
+                MessageRole::System => "System",
+            };
+            transcript.push_str(&format!("{}: {}\n\n", speaker, truncate_chars(&message.content, SUMMARY_SOURCE_CHARS)));
+        }
+
+        let previous = match &self.summary {
+            Some(summary) => format!("Summary so far:\n{}\n\n", summary),
+            None => String::new(),
+        };
+
+        Some((vec![
+            ChatMessage::new(
+                MessageRole::System,
+                "You condense conversations into short summaries that keep names, facts, decisions and open questions. Reply with the summary only.".to_string(),
+            ),
+            ChatMessage::new(
+                MessageRole::User,
+                format!(
+                    "{}Earlier messages:\n{}Write an updated summary of the whole conversation in under {} words.",
+                    previous, transcript, SUMMARY_MAX_WORDS,
+                ),
+            ),
+        ], self.evicted.len()))
+    }
+
+
+    /// 保存新的摘要，并删除已经合并进摘要的 summarized 条消息（生成摘要期间可能又有新的消息被删除）
+    pub fn apply_summary(&mut self, summary: String, summarized: usize) {
+        self.summary = Some(summary);
+        self.evicted.drain(..summarized.min(self.evicted.len()));
+    }
+
+
+    /// 第一轮对话完成后还没有标题时，返回生成标题用的对话（第一个问题和回答）
+    pub fn title_prompt(&self) -> Option<Vec<ChatMessage>> {
+        if self.title.is_some() {
+            return None;
+        }
+
+        // 文件内容作为单独的 user message 放在问题前面，取回答前的最后一条 user message
+        let answer_idx = self.messages.iter().position(|m| m.role == MessageRole::Assistant)?;
+        let question = self.messages[..answer_idx].iter().rev().find(|m| m.role == MessageRole::User)?;
+        let answer = &self.messages[answer_idx];
+
+        Some(vec![
+            ChatMessage::new(
+                MessageRole::System,
+                "You write short titles for conversations. Reply with the title only.".to_string(),
+            ),
+            ChatMessage::new(
+                MessageRole::User,
+                format!(
+                    "Summarize this conversation in 5 words or fewer.\n\nUser: {}\n\nAssistant: {}",
+                    truncate_chars(&question.content, TITLE_SOURCE_CHARS),
+                    truncate_chars(&answer.content, TITLE_SOURCE_CHARS),
+                ),
+            ),
+        ])
+    }
+
+
+    /// 用户和模型的消息数（不含系统消息）
+    pub fn message_count(&self) -> usize {
+        self.messages.iter().filter(|m| m.role != MessageRole::System).count()
+    }
+
+
+    /// 用前端保存的历史替换对话。系统消息由 session 配置决定，前端发来的系统消息被忽略
+    pub fn replace_history(&mut self, messages: Vec<ChatMessage>) {
+        self.messages.retain(|m| m.role == MessageRole::System);
+        self.messages.extend(messages.into_iter().filter(|m| m.role != MessageRole::System));
+        self.summary = None;
+        self.evicted.clear();
+        self.trim_history();
     }
 
 
@@ -92,6 +279,8 @@ impl Session {
             .cloned();
 
         self.messages.clear();
+        self.summary = None;
+        self.evicted.clear();
 
         if let Some(msg) = system_msg {
             self.messages.push(msg);
@@ -99,34 +288,177 @@ impl Session {
     }
 
 
+    /// 置顶或取消置顶一条消息，取消置顶后按 max_turns 重新裁剪
+    pub fn set_pinned(&mut self, message_id: &str, pinned: bool) -> Result<(), SessionMessageError> {
+        let message = self.messages.iter_mut()
+            .find(|m| m.id == message_id)
+            .ok_or(SessionMessageError::MessageNotFound)?;
+
+        message.pinned = pinned;
+        self.trim_history();
+        Ok(())
+    }
+
+
+    // 按轮次裁剪：一轮是一条 user 消息和之后的回复，整轮删除，不会留下没有问题的回答。
+    // 有置顶消息的轮次整轮保留且不计入 max_turns，还没有回复的最后一轮也不计入
     fn trim_history(&mut self) {
+        // 每条消息所在的轮次（系统消息不属于任何轮次），以及每轮是否置顶、是否已回复
+        let mut turn_of = Vec::with_capacity(self.messages.len());
+        let mut turns: Vec<(bool, bool)> = Vec::new();
+        for m in &self.messages {
+            if m.role == MessageRole::System {
+                turn_of.push(None);
+                continue;
+            }
+            if m.role == MessageRole::User || turns.is_empty() {
+                turns.push((false, false));
+            }
+            let last = turns.len() - 1;
+            turns[last].0 |= m.pinned;
+            turns[last].1 |= m.role == MessageRole::Assistant;
+            turn_of.push(Some(last));
+        }
 
-        let non_system_messages: Vec<_> = self.messages.iter()
-            .filter(|m| m.role != MessageRole::System)
+        let last = turns.len().saturating_sub(1);
+        let counted: Vec<usize> = (0..turns.len())
+            .filter(|&i| !turns[i].0 && (i < last || turns[i].1))
             .collect();
+        if counted.len() <= self.config.max_turns {
+            return;
+        }
+
+        // 从最早的轮次开始删除
+        let removed: HashSet<usize> = counted[..counted.len() - self.config.max_turns].iter().copied().collect();
+        let mut kept = Vec::with_capacity(self.messages.len());
+        for (m, turn) in self.messages.drain(..).zip(turn_of) {
+            if turn.is_some_and(|turn| removed.contains(&turn)) {
+                if self.config.summarize_history {
+                    self.evicted.push(m);
+                }
+            } else {
+                kept.push(m);
+            }
+        }
+        self.messages = kept;
+    }
+}
 
 
-        let current_turns = non_system_messages.len() / 2;
+#[derive(Debug, PartialEq)]
+pub enum SessionMessageError {
+    SessionNotFound,
+    MessageNotFound,
+    NotUserMessage,
+}
 
-        if current_turns > self.config.max_turns {
-            let messages_to_remove = (current_turns - self.config.max_turns) * 2;
 
+// 生成摘要时每条消息最多取的字符数，以及摘要的长度
+const SUMMARY_SOURCE_CHARS: usize = 2000;
+const SUMMARY_MAX_WORDS: usize = 200;
 
-            let first_non_system_idx = self.messages.iter()
-                .position(|m| m.role != MessageRole::System)
-                .unwrap_or(0);
+// 生成标题时问题和回答各取的字符数
+const TITLE_SOURCE_CHARS: usize = 500;
+const MAX_TITLE_CHARS: usize = 60;
 
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => &text[..idx],
+        None => text,
+    }
+}
 
-            self.messages.drain(first_non_system_idx..first_non_system_idx + messages_to_remove);
-        }
+
+/// 整理模型生成的标题：取第一行，去掉引号、"Title:" 前缀和结尾的标点，限制长度
+pub fn clean_title(text: &str) -> Option<String> {
+    let line = text.lines().map(str::trim).find(|line| !line.is_empty())?;
+    let line = line
+        .strip_prefix("Title:")
+        .or_else(|| line.strip_prefix("title:"))
+        .unwrap_or(line)
+        .trim();
+    let line = line
+        .trim_matches(|c: char| matches!(c, '"' | '\'' | '*' | '`' | '“' | '”'))
+        .trim_end_matches(['.', '。', '!', '！'])
+        .trim();
+
+    if line.is_empty() {
+        return None;
     }
+    Some(truncate_chars(line, MAX_TITLE_CHARS).trim_end().to_string())
+}
+
+
+/// session 的共享存储（开启 redis feature 后可用 Redis），让负载均衡后面的多个实例看到同一份对话。
+/// 每个实例仍在内存中保留 session，读取前从存储刷新，修改后写回（后写入的覆盖先写入的）
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn load(&self, session_id: &str) -> Result<Option<Session>>;
+
+    async fn save(&self, session: &Session) -> Result<()>;
+
+    /// 返回 session 是否存在
+    async fn delete(&self, session_id: &str) -> Result<bool>;
+
+    /// 按最近使用时间倒序，返回 owner 的 session 总数和 offset 开始的最多 limit 个 session
+    async fn list(&self, owner: Option<&str>, offset: usize, limit: usize) -> Result<(usize, Vec<Session>)>;
+
+    /// 删除空闲超过 ttl_secs 的 session，返回被删除的 session id
+    async fn expire(&self, ttl_secs: u64, now: u64) -> Result<Vec<String>>;
 }
 
 
-pub type SessionManager = Arc<RwLock<HashMap<String, Session>>>;
+/// 内存中的 session 表，按 session id 分片加锁，不同 session 的请求不会争用同一把锁。
+/// 持有 entry 期间不能再访问同一个 map（可能在同一分片上死锁），也不能 await
+pub struct SessionTable {
+    sessions: DashMap<String, Session>,
+    store: Option<Arc<dyn SessionStore>>,
+    // 使用共享存储时，从存储刷新到写回期间持有的 session 锁，
+    // 否则刷新会用存储中的旧 session 覆盖本实例上已修改、尚未写回的 session
+    locks: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
+}
+
+impl Deref for SessionTable {
+    type Target = DashMap<String, Session>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sessions
+    }
+}
+
+pub type SessionManager = Arc<SessionTable>;
 
 pub fn new_session_manager() -> SessionManager {
-    Arc::new(RwLock::new(HashMap::new()))
+    Arc::new(SessionTable {
+        sessions: DashMap::new(),
+        store: None,
+        locks: DashMap::new(),
+    })
+}
+
+/// 使用共享存储的 session 表
+pub fn new_shared_session_manager(store: Arc<dyn SessionStore>) -> SessionManager {
+    Arc::new(SessionTable {
+        sessions: DashMap::new(),
+        store: Some(store),
+        locks: DashMap::new(),
+    })
+}
+
+
+/// SessionHelper::refresh 返回的锁，释放时没有其他请求等待的锁从表中删除
+struct SessionLock<'a> {
+    table: &'a SessionTable,
+    session_id: String,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for SessionLock<'_> {
+    fn drop(&mut self) {
+        if self.guard.take().is_some() {
+            self.table.locks.remove_if(&self.session_id, |_, lock| Arc::strong_count(lock) == 1);
+        }
+    }
 }
 
 
@@ -134,69 +466,370 @@ pub struct SessionHelper;
 
 impl SessionHelper {
 
+    // 使用共享存储时，先取得 session 的锁，再从存储读取最新的 session（可能被其他实例修改或删除）。
+    // 调用方持有返回的锁直到 persist 完成
+    async fn refresh<'a>(manager: &'a SessionManager, session_id: &str) -> SessionLock<'a> {
+        let lock = Self::lock(manager, session_id).await;
+        let Some(store) = &manager.store else {
+            return lock;
+        };
+
+        match store.load(session_id).await {
+            Ok(Some(session)) => {
+                manager.insert(session_id.to_string(), session);
+            }
+            Ok(None) => {
+                manager.remove(session_id);
+            }
+            Err(e) => tracing::error!(session_id = %session_id, error = %e, "Failed to load session from store"),
+        }
+        lock
+    }
+
+    // 不刷新，只取得 session 的锁（直接覆盖或删除 session 时）
+    async fn lock<'a>(manager: &'a SessionManager, session_id: &str) -> SessionLock<'a> {
+        let mut lock = SessionLock { table: manager, session_id: session_id.to_string(), guard: None };
+        if manager.store.is_some() {
+            let mutex = manager.locks.entry(session_id.to_string()).or_default().clone();
+            lock.guard = Some(mutex.lock_owned().await);
+        }
+        lock
+    }
+
+    // 修改后写回共享存储
+    async fn persist(manager: &SessionManager, session: Option<Session>) {
+        let (Some(store), Some(session)) = (&manager.store, session) else {
+            return;
+        };
+
+        if let Err(e) = store.save(&session).await {
+            tracing::error!(session_id = %session.id, error = %e, "Failed to save session to store");
+        }
+    }
+
+    // 只有使用共享存储时才需要复制一份写回
+    fn snapshot(manager: &SessionManager, session: &Session) -> Option<Session> {
+        manager.store.as_ref().map(|_| session.clone())
+    }
+
     pub async fn get_or_create(
         manager: &SessionManager,
         session_id: &str,
         config: SessionConfig,
     ) -> Session {
-        let mut sessions = manager.write().await;
+        let _lock = Self::refresh(manager, session_id).await;
+
+        let session = {
+            let mut session = manager.entry(session_id.to_string())
+                .or_insert_with(|| Session::new(session_id.to_string(), config));
+            session.last_active = unix_now();
+            session.clone()
+        };
+
+        Self::persist(manager, Self::snapshot(manager, &session)).await;
+        session
+    }
+
+    /// 在 session 的锁内修改 session（不存在时用 config 创建），返回闭包的结果。
+    /// 修改 session 应使用这个方法，而不是 get_or_create + update，否则并发请求会互相覆盖
+    pub async fn with_session<R>(
+        manager: &SessionManager,
+        session_id: &str,
+        config: SessionConfig,
+        f: impl FnOnce(&mut Session) -> R,
+    ) -> R {
+        let _lock = Self::refresh(manager, session_id).await;
 
-        sessions.entry(session_id.to_string())
-            .or_insert_with(|| Session::new(session_id.to_string(), config))
-            .clone()
+        let (result, snapshot) = {
+            let mut session = manager.entry(session_id.to_string())
+                .or_insert_with(|| Session::new(session_id.to_string(), config));
+            session.last_active = unix_now();
+
+            let result = f(&mut session);
+            (result, Self::snapshot(manager, &session))
+        };
+
+        Self::persist(manager, snapshot).await;
+        result
+    }
+
+    /// 获取 session 已保存的配置，session 不存在时返回默认配置
+    pub async fn get_config(manager: &SessionManager, session_id: &str) -> SessionConfig {
+        let _lock = Self::refresh(manager, session_id).await;
+
+        manager.get(session_id)
+            .map(|s| s.config.clone())
+            .unwrap_or_default()
+    }
+
+    /// 设置 session 配置（session 不存在时创建）
+    pub async fn set_config(
+        manager: &SessionManager,
+        session_id: &str,
+        config: SessionConfig,
+    ) -> Session {
+        Self::with_session(manager, session_id, SessionConfig::default(), |session| {
+            session.set_config(config);
+            session.clone()
+        }).await
     }
 
     /// 获取 session（如果存在）
     pub async fn get(manager: &SessionManager, session_id: &str) -> Option<Session> {
-        let sessions = manager.read().await;
-        sessions.get(session_id).cloned()
+        let _lock = Self::refresh(manager, session_id).await;
+
+        manager.get(session_id).map(|s| s.clone())
+    }
+
+    /// session 存在且属于 owner
+    pub async fn owns(manager: &SessionManager, session_id: &str, owner: Option<&str>) -> bool {
+        let _lock = Self::refresh(manager, session_id).await;
+
+        manager.get(session_id).is_some_and(|s| s.is_owned_by(owner))
+    }
+
+    /// 在修改或创建 session 之前调用：session 不存在时为 owner 创建（默认配置），
+    /// 返回 session 是否属于 owner 以及 session 是否由这次调用创建
+    pub async fn claim_or_create(manager: &SessionManager, session_id: &str, owner: Option<&str>) -> (bool, bool) {
+        let _lock = Self::refresh(manager, session_id).await;
+
+        let (owned, created, snapshot) = {
+            let mut created = false;
+            let session = manager.entry(session_id.to_string())
+                .or_insert_with(|| {
+                    created = true;
+                    let mut session = Session::new(session_id.to_string(), SessionConfig::default());
+                    session.owner = owner.map(str::to_string);
+                    session
+                });
+            let snapshot = if created { Self::snapshot(manager, &session) } else { None };
+            (session.is_owned_by(owner), created, snapshot)
+        };
+
+        Self::persist(manager, snapshot).await;
+        (owned, created)
     }
 
     /// 同步 session 消息（从前端恢复历史）
-    pub async fn _sync_messages(
+    pub async fn sync_messages(
         manager: &SessionManager,
         session_id: &str,
         messages: Vec<ChatMessage>,
         config: SessionConfig,
     ) -> Session {
-        let mut sessions = manager.write().await;
-        
         // 创建或更新 session
-        let session = sessions.entry(session_id.to_string())
-            .or_insert_with(|| Session::new(session_id.to_string(), config.clone()));
-        
-        // 替换消息历史
-        session.messages = messages;
-        
-        // 应用消息数量限制
-        session.config = config;
-        session.trim_history();
-        
-        session.clone()
+        Self::with_session(manager, session_id, config.clone(), |session| {
+            session.set_config(config);
+
+            // 替换消息历史，并应用消息数量限制
+            session.replace_history(messages);
+
+            session.clone()
+        }).await
+    }
+
+    pub async fn remove(manager: &SessionManager, session_id: &str) -> bool {
+        let _lock = Self::lock(manager, session_id).await;
+        let mut existed = manager.remove(session_id).is_some();
+
+        if let Some(store) = &manager.store {
+            match store.delete(session_id).await {
+                Ok(deleted) => existed |= deleted,
+                Err(e) => tracing::error!(session_id = %session_id, error = %e, "Failed to delete session from store"),
+            }
+        }
+        if !existed {
+            return false;
+        }
+        tracing::debug!(sessions = manager.len(), "Session removed");
+
+        true
+    }
+
+    // 在 session 的锁内修改已存在的 session，返回修改后的 session
+    async fn modify_existing(
+        manager: &SessionManager,
+        session_id: &str,
+        f: impl FnOnce(&mut Session) -> Result<(), SessionMessageError>,
+    ) -> Result<Session, SessionMessageError> {
+        let _lock = Self::refresh(manager, session_id).await;
+
+        let session = {
+            let mut session = manager.get_mut(session_id).ok_or(SessionMessageError::SessionNotFound)?;
+            f(&mut session)?;
+            session.last_active = unix_now();
+            session.clone()
+        };
+
+        Self::persist(manager, Self::snapshot(manager, &session)).await;
+        Ok(session)
+    }
+
+    /// 在 session 的锁内修改消息并截断之后的历史，返回修改后的 session
+    pub async fn edit_message(
+        manager: &SessionManager,
+        session_id: &str,
+        message_id: &str,
+        content: String,
+    ) -> Result<Session, SessionMessageError> {
+        Self::modify_existing(manager, session_id, |session| session.edit_message(message_id, content)).await
     }
 
-    pub async fn update(manager: &SessionManager, session: Session) {
-        let mut sessions = manager.write().await;
-        sessions.insert(session.id.clone(), session);
+    /// 置顶或取消置顶 session 中的一条消息
+    pub async fn set_pinned(
+        manager: &SessionManager,
+        session_id: &str,
+        message_id: &str,
+        pinned: bool,
+    ) -> Result<Session, SessionMessageError> {
+        Self::modify_existing(manager, session_id, |session| session.set_pinned(message_id, pinned)).await
     }
 
+    /// 保存生成的摘要，session 已删除时忽略
+    pub async fn apply_summary(manager: &SessionManager, session_id: &str, summary: String, summarized: usize) {
+        let _lock = Self::refresh(manager, session_id).await;
 
-    pub async fn remove(manager: &SessionManager, session_id: &str) -> bool {
-        let mut sessions = manager.write().await;
-        match sessions.get(session_id) {
-            Some(_) => {
-                sessions.remove(session_id);
-                {
-                    println!("Number of alive session {}", sessions.len());
+        let snapshot = match manager.get_mut(session_id) {
+            Some(mut session) => {
+                session.apply_summary(summary, summarized);
+                Self::snapshot(manager, &session)
+            }
+            None => None,
+        };
+        Self::persist(manager, snapshot).await;
+    }
+
+    /// 把 session 复制到新的 session id，返回新 session
+    pub async fn fork(
+        manager: &SessionManager,
+        session_id: &str,
+        new_id: String,
+        at_message_id: Option<&str>,
+    ) -> Result<Session, SessionMessageError> {
+        let _lock = Self::refresh(manager, session_id).await;
+
+        // 插入前先释放原 session 的读锁
+        let forked = manager.get(session_id)
+            .ok_or(SessionMessageError::SessionNotFound)?
+            .fork(new_id, at_message_id)?;
+
+        manager.insert(forked.id.clone(), forked.clone());
+        Self::persist(manager, Self::snapshot(manager, &forked)).await;
+        Ok(forked)
+    }
+
+    /// 设置 session 标题，不更新最近使用时间；session 已删除或已有标题时不修改
+    pub async fn set_title(manager: &SessionManager, session_id: &str, title: String) -> bool {
+        let _lock = Self::refresh(manager, session_id).await;
+
+        let snapshot = match manager.get_mut(session_id) {
+            Some(mut session) if session.title.is_none() => {
+                session.title = Some(title);
+                Self::snapshot(manager, &session)
+            }
+            _ => return false,
+        };
+        Self::persist(manager, snapshot).await;
+        true
+    }
+
+    /// 按最近使用时间倒序列出 owner 的 session，返回总数和 offset 开始的最多 limit 个 session
+    pub async fn list(
+        manager: &SessionManager,
+        owner: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> (usize, Vec<Session>) {
+        if let Some(store) = &manager.store {
+            match store.list(owner, offset, limit).await {
+                Ok(page) => return page,
+                Err(e) => tracing::warn!(error = %e, "Failed to list sessions from store, using local sessions"),
+            }
+        }
+
+        let mut ordered: Vec<(String, u64)> = manager.iter()
+            .filter(|entry| entry.is_owned_by(owner))
+            .map(|entry| (entry.id.clone(), entry.last_active))
+            .collect();
+        ordered.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let total = ordered.len();
+        // 排序后被删除的 session 直接跳过
+        let page = ordered.into_iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(session_id, _)| manager.get(&session_id).map(|s| s.clone()))
+            .collect();
+        (total, page)
+    }
+
+    /// 删除空闲超过 ttl_secs 的 session，返回被删除的 session id
+    pub async fn expire(manager: &SessionManager, ttl_secs: u64, now: u64) -> Vec<String> {
+        let mut expired = Vec::new();
+        manager.retain(|session_id, session| {
+            let keep = session.last_active.saturating_add(ttl_secs) > now;
+            if !keep {
+                expired.push(session_id.clone());
+            }
+            keep
+        });
+
+        if let Some(store) = &manager.store {
+            match store.expire(ttl_secs, now).await {
+                Ok(ids) => {
+                    for session_id in ids {
+                        if !expired.contains(&session_id) {
+                            expired.push(session_id);
+                        }
+                    }
                 }
-            },
-            None => {
-                return false
+                Err(e) => tracing::warn!(error = %e, "Failed to expire sessions in store"),
             }
         }
+        expired
+    }
+}
 
-        true
+
+/// 删除 session 的检索索引；文件仍然保存，可以通过 file_ids 重新附加，需要时重新建立索引
+pub async fn drop_session_index(index: &VectorIndex, file_cache: &FileCache, session_id: &str) {
+    index.write().await.remove(session_id);
+    for file in file_cache.write().await.values_mut() {
+        if file.session_id == session_id {
+            file.indexed = false;
+        }
+    }
+}
+
+
+/// 后台定期清理空闲的 session，防止公开部署时 session 无限累积。ttl_secs 为 0 时不清理
+pub fn spawn_session_sweeper(
+    manager: SessionManager,
+    index: VectorIndex,
+    file_cache: FileCache,
+    metrics: SharedMetrics,
+    ttl_secs: u64,
+    interval_secs: u64,
+) {
+    if ttl_secs == 0 {
+        return;
     }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let expired = SessionHelper::expire(&manager, ttl_secs, unix_now()).await;
+            if expired.is_empty() {
+                continue;
+            }
+            for session_id in &expired {
+                drop_session_index(&index, &file_cache, session_id).await;
+            }
+            metrics.record_sessions_evicted(expired.len());
+            tracing::info!(sessions = expired.len(), ttl_secs, "Evicted idle sessions");
+        }
+    });
 }
 
 
@@ -216,6 +849,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 5,
             system_prompt: Some("You are a helpful assistant.".to_string()),
+            summarize_history: false,
         };
         assert_eq!(config.max_turns, 5);
         assert_eq!(config.system_prompt, Some("You are a helpful assistant.".to_string()));
@@ -226,6 +860,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 10,
             system_prompt: None,
+            summarize_history: false,
         };
         let session = Session::new("test-id".to_string(), config);
 
@@ -238,6 +873,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 10,
             system_prompt: Some("System prompt".to_string()),
+            summarize_history: false,
         };
         let session = Session::new("test-id".to_string(), config);
 
@@ -293,6 +929,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 10,
             system_prompt: Some("System".to_string()),
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
@@ -310,6 +947,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 3,
             system_prompt: None,
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
@@ -326,6 +964,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 2,
             system_prompt: None,
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
@@ -342,6 +981,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 2,
             system_prompt: None,
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
@@ -364,6 +1004,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 2,
             system_prompt: Some("System".to_string()),
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
@@ -386,6 +1027,7 @@ mod tests {
         let config = SessionConfig {
             max_turns: 1,
             system_prompt: None,
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
@@ -403,56 +1045,248 @@ mod tests {
 
 
     #[test]
-    fn test_clear_without_system_prompt() {
-        let config = SessionConfig::default();
+    fn test_trim_history_keeps_pinned_messages() {
+        let config = SessionConfig {
+            max_turns: 2,
+            system_prompt: None,
+            summarize_history: false,
+        };
         let mut session = Session::new("test".to_string(), config);
 
-        session.add_user_message("Q1".to_string());
-        session.add_assistant_message("A1".to_string());
-        session.clear();
-
-        assert!(session.messages.is_empty());
+        session.add_user_message("Always answer in French".to_string());
+        let pinned_id = session.messages[0].id.clone();
+        session.set_pinned(&pinned_id, true).unwrap();
+        session.add_assistant_message("D'accord".to_string());
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("A2".to_string());
+        session.add_user_message("Q3".to_string());
+        session.add_assistant_message("A3".to_string());
+        session.add_user_message("Q4".to_string());
+        session.add_assistant_message("A4".to_string());
+
+        // the oldest unpinned turn goes first; the pinned turn stays whole and does not count
+        assert_eq!(session.messages.len(), 6);
+        assert_eq!(session.messages[0].content, "Always answer in French");
+        assert!(session.messages[0].pinned);
+        assert_eq!(session.messages[1].content, "D'accord");
+        assert_eq!(session.messages[2].content, "Q3");
+        assert_eq!(session.messages[5].content, "A4");
     }
 
     #[test]
-    fn test_clear_with_system_prompt() {
+    fn test_trim_history_removes_whole_turns() {
         let config = SessionConfig {
-            max_turns: 10,
-            system_prompt: Some("System prompt".to_string()),
+            max_turns: 1,
+            system_prompt: None,
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
+        // a turn with a pinned answer is kept with its question
         session.add_user_message("Q1".to_string());
         session.add_assistant_message("A1".to_string());
-        session.clear();
+        let answer_id = session.messages[1].id.clone();
+        session.set_pinned(&answer_id, true).unwrap();
+        // a turn with several replies (a tool call and the answer) is removed as a whole
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("call".to_string());
+        session.add_assistant_message("A2".to_string());
+        session.add_user_message("Q3".to_string());
+        // the unanswered question does not count yet
+        assert_eq!(session.messages.len(), 6);
 
-        // 只保留系统消息
-        assert_eq!(session.messages.len(), 1);
-        assert_eq!(session.messages[0].role, MessageRole::System);
-        assert_eq!(session.messages[0].content, "System prompt");
+        session.add_assistant_message("A3".to_string());
+        let contents: Vec<&str> = session.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["Q1", "A1", "Q3", "A3"]);
     }
 
-
     #[test]
-    fn test_get_messages() {
-        let config = SessionConfig::default();
+    fn test_trim_history_never_drops_pinned() {
+        let config = SessionConfig {
+            max_turns: 0,
+            system_prompt: None,
+            summarize_history: false,
+        };
         let mut session = Session::new("test".to_string(), config);
+        session.messages.push(ChatMessage { pinned: true, ..ChatMessage::new(MessageRole::User, "Keep".to_string()) });
 
-        session.add_user_message("Hello".to_string());
-        session.add_assistant_message("Hi".to_string());
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
 
-        let messages = session.get_messages();
-        assert_eq!(messages.len(), 2);
-        assert_eq!(messages[0].content, "Hello");
-        assert_eq!(messages[1].content, "Hi");
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(session.messages[0].content, "Keep");
     }
 
-
     #[test]
-    fn test_new_session_manager() {
+    fn test_unpin_trims_again() {
+        let config = SessionConfig {
+            max_turns: 1,
+            system_prompt: None,
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+        session.messages.push(ChatMessage { pinned: true, ..ChatMessage::new(MessageRole::User, "Q1".to_string()) });
+        session.messages.push(ChatMessage { pinned: true, ..ChatMessage::new(MessageRole::Assistant, "A1".to_string()) });
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("A2".to_string());
+        assert_eq!(session.messages.len(), 4);
+
+        let first_id = session.messages[0].id.clone();
+        let second_id = session.messages[1].id.clone();
+        session.set_pinned(&first_id, false).unwrap();
+        session.set_pinned(&second_id, false).unwrap();
+        assert_eq!(session.messages.len(), 2);
+        assert_eq!(session.messages[0].content, "Q2");
+
+        assert_eq!(session.set_pinned("missing", true), Err(SessionMessageError::MessageNotFound));
+    }
+
+    fn summarizing_session() -> Session {
+        let config = SessionConfig {
+            max_turns: 1,
+            system_prompt: Some("System".to_string()),
+            summarize_history: true,
+        };
+        let mut session = Session::new("test".to_string(), config);
+        session.add_user_message("My name is Ana".to_string());
+        session.add_assistant_message("Hello Ana".to_string());
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("A2".to_string());
+        session
+    }
+
+    #[test]
+    fn test_trim_history_keeps_evicted_for_summary() {
+        let session = summarizing_session();
+
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(session.evicted.len(), 2);
+        assert_eq!(session.evicted[0].content, "My name is Ana");
+
+        let (prompt, summarized) = session.summary_prompt().unwrap();
+        assert_eq!(summarized, 2);
+        assert!(prompt[1].content.contains("User: My name is Ana"));
+        assert!(prompt[1].content.contains("Assistant: Hello Ana"));
+        assert!(!prompt[1].content.contains("Summary so far"));
+    }
+
+    #[test]
+    fn test_trim_history_without_summary_mode_discards() {
+        let config = SessionConfig {
+            max_turns: 1,
+            system_prompt: None,
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("A2".to_string());
+
+        assert!(session.evicted.is_empty());
+        assert!(session.summary_prompt().is_none());
+    }
+
+    #[test]
+    fn test_apply_summary_and_conversation() {
+        let mut session = summarizing_session();
+        let (_, summarized) = session.summary_prompt().unwrap();
+
+        // another turn is trimmed while the summary is being generated
+        session.add_user_message("Q3".to_string());
+        session.add_assistant_message("A3".to_string());
+        assert_eq!(session.evicted.len(), 4);
+
+        session.apply_summary("The user is Ana.".to_string(), summarized);
+        assert_eq!(session.evicted.len(), 2);
+        assert_eq!(session.evicted[0].content, "Q2");
+
+        let (prompt, _) = session.summary_prompt().unwrap();
+        assert!(prompt[1].content.contains("Summary so far:\nThe user is Ana."));
+
+        let conversation = session.conversation();
+        assert_eq!(conversation.len(), session.messages.len() + 1);
+        assert_eq!(conversation[0].content, "System");
+        assert_eq!(conversation[1].role, MessageRole::System);
+        assert!(conversation[1].content.contains("The user is Ana."));
+        assert_eq!(conversation[2].content, "Q3");
+    }
+
+    #[test]
+    fn test_conversation_puts_memory_before_summary() {
+        let mut session = summarizing_session();
+        session.memory = Some("- name: Ana".to_string());
+        session.apply_summary("Earlier talk.".to_string(), 0);
+
+        let conversation = session.conversation();
+        assert_eq!(conversation.len(), session.messages.len() + 2);
+        assert_eq!(conversation[0].content, "System");
+        assert_eq!(conversation[1].content, "- name: Ana");
+        assert!(conversation[2].content.contains("Earlier talk."));
+    }
+
+    #[test]
+    fn test_clear_drops_summary() {
+        let mut session = summarizing_session();
+        session.apply_summary("Summary".to_string(), 2);
+        session.clear();
+
+        assert!(session.summary.is_none());
+        assert!(session.evicted.is_empty());
+        assert_eq!(session.conversation().len(), 1);
+    }
+
+    #[test]
+    fn test_clear_without_system_prompt() {
+        let config = SessionConfig::default();
+        let mut session = Session::new("test".to_string(), config);
+
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+        session.clear();
+
+        assert!(session.messages.is_empty());
+    }
+
+    #[test]
+    fn test_clear_with_system_prompt() {
+        let config = SessionConfig {
+            max_turns: 10,
+            system_prompt: Some("System prompt".to_string()),
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+        session.clear();
+
+        // 只保留系统消息
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(session.messages[0].role, MessageRole::System);
+        assert_eq!(session.messages[0].content, "System prompt");
+    }
+
+
+    #[test]
+    fn test_conversation_without_memory_or_summary() {
+        let config = SessionConfig::default();
+        let mut session = Session::new("test".to_string(), config);
+
+        session.add_user_message("Hello".to_string());
+        session.add_assistant_message("Hi".to_string());
+
+        let messages = session.conversation();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "Hello");
+        assert_eq!(messages[1].content, "Hi");
+    }
+
+
+    #[test]
+    fn test_new_session_manager() {
         let manager = new_session_manager();
-        let guard = manager.try_write();
-        assert!(guard.is_ok());
+        assert!(manager.is_empty());
     }
 
     #[tokio::test]
@@ -471,9 +1305,9 @@ mod tests {
         let manager = new_session_manager();
         let config = SessionConfig::default();
 
-        let mut session = SessionHelper::get_or_create(&manager, "session-1", config.clone()).await;
-        session.add_user_message("Hello".to_string());
-        SessionHelper::update(&manager, session).await;
+        SessionHelper::with_session(&manager, "session-1", config.clone(), |session| {
+            session.add_user_message("Hello".to_string());
+        }).await;
 
         let session = SessionHelper::get_or_create(&manager, "session-1", config).await;
 
@@ -483,16 +1317,177 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_helper_update() {
+    async fn test_helper_with_session_concurrent_appends() {
         let manager = new_session_manager();
-        let config = SessionConfig::default();
+        let config = SessionConfig {
+            max_turns: 100,
+            system_prompt: None,
+            summarize_history: false,
+        };
+
+        let tasks: Vec<_> = (0..20).map(|i| {
+            let manager = manager.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                SessionHelper::with_session(&manager, "session-1", config, |session| {
+                    session.add_user_message(format!("Q{}", i));
+                }).await;
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let session = SessionHelper::get(&manager, "session-1").await.unwrap();
+        assert_eq!(session.messages.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_helper_with_session_returns_result() {
+        let manager = new_session_manager();
+
+        let count = SessionHelper::with_session(&manager, "session-1", SessionConfig::default(), |session| {
+            session.add_user_message("Hello".to_string());
+            session.messages.len()
+        }).await;
+
+        assert_eq!(count, 1);
+        assert_eq!(SessionHelper::get(&manager, "session-1").await.unwrap().messages.len(), 1);
+    }
+
+    // in-memory stand-in for a shared store such as Redis
+    #[derive(Default)]
+    struct MemoryStore {
+        sessions: std::sync::Mutex<std::collections::HashMap<String, Session>>,
+    }
+
+    #[async_trait]
+    impl SessionStore for MemoryStore {
+        async fn load(&self, session_id: &str) -> Result<Option<Session>> {
+            Ok(self.sessions.lock().unwrap().get(session_id).cloned())
+        }
+
+        async fn save(&self, session: &Session) -> Result<()> {
+            // a network round trip, lets other requests run between the modify and the write
+            tokio::task::yield_now().await;
+            self.sessions.lock().unwrap().insert(session.id.clone(), session.clone());
+            Ok(())
+        }
+
+        async fn delete(&self, session_id: &str) -> Result<bool> {
+            Ok(self.sessions.lock().unwrap().remove(session_id).is_some())
+        }
+
+        async fn list(&self, owner: Option<&str>, offset: usize, limit: usize) -> Result<(usize, Vec<Session>)> {
+            let sessions = self.sessions.lock().unwrap();
+            let owned: Vec<&Session> = sessions.values().filter(|s| s.is_owned_by(owner)).collect();
+            Ok((owned.len(), owned.into_iter().skip(offset).take(limit).cloned().collect()))
+        }
+
+        async fn expire(&self, _ttl_secs: u64, _now: u64) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+    }
 
-        let mut session = Session::new("session-1".to_string(), config);
-        session.add_user_message("Test".to_string());
+    #[tokio::test]
+    async fn test_shared_store_is_seen_by_other_instances() {
+        let store = Arc::new(MemoryStore::default());
+        let first = new_shared_session_manager(store.clone());
+        let second = new_shared_session_manager(store.clone());
+
+        SessionHelper::with_session(&first, "session-1", SessionConfig::default(), |session| {
+            session.add_user_message("Hello".to_string());
+        }).await;
+
+        // the second instance picks up the message and appends to it
+        SessionHelper::with_session(&second, "session-1", SessionConfig::default(), |session| {
+            assert_eq!(session.messages.len(), 1);
+            session.add_assistant_message("Hi".to_string());
+        }).await;
+
+        let session = SessionHelper::get(&first, "session-1").await.unwrap();
+        assert_eq!(session.messages.len(), 2);
+
+        assert!(SessionHelper::remove(&second, "session-1").await);
+        assert!(SessionHelper::get(&first, "session-1").await.is_none());
+        assert!(store.sessions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shared_store_concurrent_appends_are_not_lost() {
+        let store = Arc::new(MemoryStore::default());
+        let manager = new_shared_session_manager(store.clone());
+        let config = SessionConfig { max_turns: 100, ..Default::default() };
+
+        let tasks: Vec<_> = (0..20).map(|i| {
+            let manager = manager.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                SessionHelper::with_session(&manager, "session-1", config, |session| {
+                    session.add_user_message(format!("Q{}", i));
+                }).await;
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(store.sessions.lock().unwrap()["session-1"].messages.len(), 20);
+        assert_eq!(SessionHelper::get(&manager, "session-1").await.unwrap().messages.len(), 20);
+        assert!(manager.locks.is_empty());
+    }
+
+    #[test]
+    fn test_replace_history_keeps_configured_system_prompt() {
+        let config = SessionConfig {
+            max_turns: 1,
+            system_prompt: Some("System".to_string()),
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+        session.add_user_message("Old".to_string());
+
+        session.replace_history(vec![
+            ChatMessage::new(MessageRole::System, "Injected".to_string()),
+            ChatMessage::new(MessageRole::User, "Q1".to_string()),
+            ChatMessage::new(MessageRole::Assistant, "A1".to_string()),
+            ChatMessage::new(MessageRole::User, "Q2".to_string()),
+            ChatMessage::new(MessageRole::Assistant, "A2".to_string()),
+        ]);
+
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(session.messages[0].content, "System");
+        assert_eq!(session.messages[1].content, "Q2");
+        assert_eq!(session.messages[2].content, "A2");
+    }
+
+    #[tokio::test]
+    async fn test_helper_sync_messages_creates_session() {
+        let manager = new_session_manager();
+
+        let session = SessionHelper::sync_messages(&manager, "session-1", vec![
+            ChatMessage::new(MessageRole::User, "Q1".to_string()),
+            ChatMessage::new(MessageRole::Assistant, "A1".to_string()),
+        ], SessionConfig::default()).await;
+        assert_eq!(session.messages.len(), 2);
+
+        let session = SessionHelper::sync_messages(&manager, "session-1", vec![
+            ChatMessage::new(MessageRole::User, "Q2".to_string()),
+        ], SessionConfig::default()).await;
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(SessionHelper::get(&manager, "session-1").await.unwrap().messages[0].content, "Q2");
+    }
+
+    #[tokio::test]
+    async fn test_helper_with_session() {
+        let manager = new_session_manager();
+        let config = SessionConfig::default();
 
-        SessionHelper::update(&manager, session).await;
+        SessionHelper::with_session(&manager, "session-1", config, |session| {
+            session.add_user_message("Test".to_string());
+        }).await;
 
-        let sessions = manager.read().await;
+        let sessions = &manager;
         assert!(sessions.contains_key("session-1"));
         assert_eq!(sessions.get("session-1").unwrap().messages.len(), 1);
     }
@@ -502,12 +1497,11 @@ mod tests {
         let manager = new_session_manager();
         let config = SessionConfig::default();
 
-        let session = SessionHelper::get_or_create(&manager, "session-1", config).await;
-        SessionHelper::update(&manager, session).await;
+        SessionHelper::get_or_create(&manager, "session-1", config).await;
 
         SessionHelper::remove(&manager, "session-1").await;
 
-        let sessions = manager.read().await;
+        let sessions = &manager;
         assert!(!sessions.contains_key("session-1"));
     }
 
@@ -517,7 +1511,7 @@ mod tests {
 
         SessionHelper::remove(&manager, "nonexistent").await;
 
-        let sessions = manager.read().await;
+        let sessions = &manager;
         assert!(sessions.is_empty());
     }
 
@@ -526,22 +1520,136 @@ mod tests {
         let manager = new_session_manager();
         let config = SessionConfig::default();
 
-        let mut session1 = SessionHelper::get_or_create(&manager, "session-1", config.clone()).await;
-        let mut session2 = SessionHelper::get_or_create(&manager, "session-2", config.clone()).await;
-
-        session1.add_user_message("Hello from 1".to_string());
-        session2.add_user_message("Hello from 2".to_string());
-
-        SessionHelper::update(&manager, session1).await;
-        SessionHelper::update(&manager, session2).await;
+        for (session_id, content) in [("session-1", "Hello from 1"), ("session-2", "Hello from 2")] {
+            SessionHelper::with_session(&manager, session_id, config.clone(), |session| {
+                session.add_user_message(content.to_string());
+            }).await;
+        }
 
-        let sessions = manager.read().await;
+        let sessions = &manager;
         assert_eq!(sessions.len(), 2);
         assert_eq!(sessions.get("session-1").unwrap().messages[0].content, "Hello from 1");
         assert_eq!(sessions.get("session-2").unwrap().messages[0].content, "Hello from 2");
     }
 
 
+    #[test]
+    fn test_set_config_adds_system_prompt() {
+        let mut session = Session::new("test".to_string(), SessionConfig::default());
+        session.add_user_message("Q1".to_string());
+
+        session.set_config(SessionConfig {
+            max_turns: 10,
+            system_prompt: Some("Be brief".to_string()),
+            summarize_history: false,
+        });
+
+        assert_eq!(session.messages.len(), 2);
+        assert_eq!(session.messages[0].role, MessageRole::System);
+        assert_eq!(session.messages[0].content, "Be brief");
+        assert_eq!(session.messages[1].content, "Q1");
+    }
+
+    #[test]
+    fn test_set_config_replaces_system_prompt() {
+        let config = SessionConfig {
+            max_turns: 10,
+            system_prompt: Some("Old".to_string()),
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+
+        session.set_config(SessionConfig::default());
+        assert!(session.messages.is_empty());
+
+        session.set_config(SessionConfig {
+            max_turns: 10,
+            system_prompt: Some("New".to_string()),
+            summarize_history: false,
+        });
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(session.messages[0].content, "New");
+    }
+
+    #[test]
+    fn test_set_system_prompt_keeps_history() {
+        let config = SessionConfig {
+            max_turns: 3,
+            system_prompt: Some("Old".to_string()),
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+
+        session.set_system_prompt("You are a pirate".to_string());
+
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(session.messages[0].role, MessageRole::System);
+        assert_eq!(session.messages[0].content, "You are a pirate");
+        assert_eq!(session.messages[1].content, "Q1");
+        assert_eq!(session.config.max_turns, 3);
+        assert_eq!(session.config.system_prompt, Some("You are a pirate".to_string()));
+    }
+
+    #[test]
+    fn test_set_config_trims_to_new_max_turns() {
+        let mut session = Session::new("test".to_string(), SessionConfig::default());
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("A2".to_string());
+
+        session.set_config(SessionConfig {
+            max_turns: 1,
+            system_prompt: None,
+            summarize_history: false,
+        });
+
+        assert_eq!(session.messages.len(), 2);
+        assert_eq!(session.messages[0].content, "Q2");
+    }
+
+    #[test]
+    fn test_session_config_deserialize_partial() {
+        let config: SessionConfig = serde_json::from_str(r#"{"system_prompt": "Hi"}"#).unwrap();
+        assert_eq!(config.max_turns, 10);
+        assert_eq!(config.system_prompt, Some("Hi".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_helper_get_config() {
+        let manager = new_session_manager();
+
+        let config = SessionHelper::get_config(&manager, "missing").await;
+        assert_eq!(config.max_turns, 10);
+
+        SessionHelper::set_config(&manager, "session-1", SessionConfig {
+            max_turns: 3,
+            system_prompt: Some("System".to_string()),
+            summarize_history: false,
+        }).await;
+
+        let config = SessionHelper::get_config(&manager, "session-1").await;
+        assert_eq!(config.max_turns, 3);
+        assert_eq!(config.system_prompt, Some("System".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_helper_set_config_creates_session() {
+        let manager = new_session_manager();
+
+        let session = SessionHelper::set_config(&manager, "session-1", SessionConfig {
+            max_turns: 5,
+            system_prompt: Some("System".to_string()),
+            summarize_history: false,
+        }).await;
+
+        assert_eq!(session.messages.len(), 1);
+        assert!(SessionHelper::get(&manager, "session-1").await.is_some());
+    }
+
+
     #[test]
     fn test_message_role_equality() {
         assert_eq!(MessageRole::User, MessageRole::User);
@@ -575,11 +1683,264 @@ mod tests {
     }
 
 
+    #[tokio::test]
+    async fn test_helper_with_session_refreshes_last_active() {
+        let manager = new_session_manager();
+
+        SessionHelper::with_session(&manager, "session-1", SessionConfig::default(), |session| {
+            session.last_active = 0;
+        }).await;
+        SessionHelper::with_session(&manager, "session-1", SessionConfig::default(), |_| ()).await;
+
+        let session = SessionHelper::get(&manager, "session-1").await.unwrap();
+        assert!(session.last_active > 0);
+        assert!(session.last_active >= session.created_at);
+    }
+
+    #[tokio::test]
+    async fn test_helper_list_pages_most_recent_first() {
+        let manager = new_session_manager();
+        {
+            let sessions = &manager;
+            for (id, last_active) in [("a", 100), ("b", 300), ("c", 200)] {
+                let mut session = Session::new(id.to_string(), SessionConfig::default());
+                session.last_active = last_active;
+                sessions.insert(id.to_string(), session);
+            }
+        }
+
+        let (total, page) = SessionHelper::list(&manager, None, 0, 2).await;
+        assert_eq!(total, 3);
+        assert_eq!(page.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), vec!["b", "c"]);
+
+        let (_, page) = SessionHelper::list(&manager, None, 2, 2).await;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, "a");
+
+        let (_, page) = SessionHelper::list(&manager, None, 5, 2).await;
+        assert!(page.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_helper_claim_scopes_sessions_to_owner() {
+        let manager = new_session_manager();
+
+        assert_eq!(SessionHelper::claim_or_create(&manager, "alice-1", Some("alice")).await, (true, true));
+        assert_eq!(SessionHelper::claim_or_create(&manager, "alice-1", Some("alice")).await, (true, false));
+        assert_eq!(SessionHelper::claim_or_create(&manager, "alice-1", Some("bob")).await, (false, false));
+        assert_eq!(SessionHelper::claim_or_create(&manager, "alice-1", None).await, (false, false));
+        assert_eq!(SessionHelper::claim_or_create(&manager, "bob-1", Some("bob")).await, (true, true));
+        assert_eq!(SessionHelper::claim_or_create(&manager, "bob-1", Some("bob")).await, (true, false));
+        assert_eq!(SessionHelper::claim_or_create(&manager, "alice-2", Some("alice")).await, (true, true));
+
+        assert!(SessionHelper::owns(&manager, "alice-1", Some("alice")).await);
+        assert!(!SessionHelper::owns(&manager, "alice-1", Some("bob")).await);
+        assert!(!SessionHelper::owns(&manager, "missing", Some("alice")).await);
+
+        let (total, page) = SessionHelper::list(&manager, Some("bob"), 0, 10).await;
+        assert_eq!(total, 1);
+        assert_eq!(page[0].id, "bob-1");
+        let (total, _) = SessionHelper::list(&manager, None, 0, 10).await;
+        assert_eq!(total, 0);
+    }
+
+    #[tokio::test]
+    async fn test_fork_keeps_owner() {
+        let manager = new_session_manager();
+        SessionHelper::claim_or_create(&manager, "session-1", Some("alice")).await;
+
+        let forked = SessionHelper::fork(&manager, "session-1", "session-2".to_string(), None).await.unwrap();
+        assert_eq!(forked.owner.as_deref(), Some("alice"));
+        assert!(SessionHelper::owns(&manager, "session-2", Some("alice")).await);
+    }
+
+    #[test]
+    fn test_message_count_excludes_system() {
+        let config = SessionConfig {
+            max_turns: 10,
+            system_prompt: Some("System".to_string()),
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+
+        assert_eq!(session.message_count(), 2);
+    }
+
+    #[test]
+    fn test_new_messages_have_unique_ids() {
+        let mut session = Session::new("test".to_string(), SessionConfig::default());
+        session.add_user_message("Q1".to_string());
+        session.add_user_message("Q2".to_string());
+
+        assert!(!session.messages[0].id.is_empty());
+        assert_ne!(session.messages[0].id, session.messages[1].id);
+    }
+
+    #[test]
+    fn test_message_without_id_gets_one() {
+        let message: ChatMessage = serde_json::from_str(r#"{"role": "user", "content": "Hi"}"#).unwrap();
+        assert!(!message.id.is_empty());
+    }
+
+    #[test]
+    fn test_developer_role_is_system() {
+        let message: ChatMessage = serde_json::from_str(r#"{"role": "developer", "content": "Be terse."}"#).unwrap();
+        assert_eq!(message.role, MessageRole::System);
+        assert_eq!(serde_json::to_value(&message.role).unwrap(), "system");
+    }
+
+    #[test]
+    fn test_edit_message_truncates_later_messages() {
+        let mut session = Session::new("test".to_string(), SessionConfig::default());
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("A2".to_string());
+        let first_id = session.messages[0].id.clone();
+
+        session.edit_message(&first_id, "Q1 edited".to_string()).unwrap();
+
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(session.messages[0].id, first_id);
+        assert_eq!(session.messages[0].content, "Q1 edited");
+    }
+
+    #[test]
+    fn test_edit_message_errors() {
+        let mut session = Session::new("test".to_string(), SessionConfig::default());
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+        let answer_id = session.messages[1].id.clone();
+
+        assert_eq!(session.edit_message("missing", "x".to_string()), Err(SessionMessageError::MessageNotFound));
+        assert_eq!(session.edit_message(&answer_id, "x".to_string()), Err(SessionMessageError::NotUserMessage));
+        assert_eq!(session.messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_helper_edit_message_missing_session() {
+        let manager = new_session_manager();
+        let result = SessionHelper::edit_message(&manager, "missing", "m", "x".to_string()).await;
+        assert!(matches!(result, Err(SessionMessageError::SessionNotFound)));
+    }
+
+    #[test]
+    fn test_fork_at_message() {
+        let config = SessionConfig {
+            max_turns: 10,
+            system_prompt: Some("System".to_string()),
+            summarize_history: false,
+        };
+        let mut session = Session::new("test".to_string(), config);
+        session.add_user_message("Q1".to_string());
+        session.add_assistant_message("A1".to_string());
+        session.add_user_message("Q2".to_string());
+        session.add_assistant_message("A2".to_string());
+        let answer_id = session.messages[2].id.clone();
+
+        let forked = session.fork("fork".to_string(), Some(&answer_id)).unwrap();
+        assert_eq!(forked.id, "fork");
+        assert_eq!(forked.messages.len(), 3);
+        assert_eq!(forked.messages[2].content, "A1");
+        assert_eq!(forked.config.system_prompt, Some("System".to_string()));
+
+        let full = session.fork("full".to_string(), None).unwrap();
+        assert_eq!(full.messages.len(), 5);
+
+        assert!(matches!(session.fork("x".to_string(), Some("missing")), Err(SessionMessageError::MessageNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_helper_fork_leaves_original_untouched() {
+        let manager = new_session_manager();
+        SessionHelper::with_session(&manager, "session-1", SessionConfig::default(), |session| {
+            session.add_user_message("Q1".to_string());
+        }).await;
+
+        SessionHelper::fork(&manager, "session-1", "session-2".to_string(), None).await.unwrap();
+        SessionHelper::with_session(&manager, "session-2", SessionConfig::default(), |session| {
+            session.add_assistant_message("A1".to_string());
+        }).await;
+
+        assert_eq!(SessionHelper::get(&manager, "session-1").await.unwrap().messages.len(), 1);
+        assert_eq!(SessionHelper::get(&manager, "session-2").await.unwrap().messages.len(), 2);
+        assert!(matches!(
+            SessionHelper::fork(&manager, "missing", "x".to_string(), None).await,
+            Err(SessionMessageError::SessionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_title_prompt_after_first_exchange() {
+        let mut session = Session::new("test".to_string(), SessionConfig::default());
+        assert!(session.title_prompt().is_none());
+
+        session.add_user_message("[file context]".to_string());
+        session.add_user_message("How do I sort a Vec?".to_string());
+        assert!(session.title_prompt().is_none());
+
+        session.add_assistant_message("Use sort().".to_string());
+        let prompt = session.title_prompt().unwrap();
+        assert_eq!(prompt.len(), 2);
+        assert!(prompt[1].content.contains("User: How do I sort a Vec?"));
+        assert!(prompt[1].content.contains("Assistant: Use sort()."));
+        assert!(!prompt[1].content.contains("[file context]"));
+
+        session.title = Some("Sorting vectors".to_string());
+        assert!(session.title_prompt().is_none());
+    }
+
+    #[test]
+    fn test_clean_title() {
+        assert_eq!(clean_title("\"Sorting a Rust Vec.\"\n"), Some("Sorting a Rust Vec".to_string()));
+        assert_eq!(clean_title("\n Title: Trip planning\nMore text"), Some("Trip planning".to_string()));
+        assert_eq!(clean_title("  \n\"\""), None);
+        assert_eq!(clean_title(&"a".repeat(100)).unwrap().len(), MAX_TITLE_CHARS);
+    }
+
+    #[tokio::test]
+    async fn test_helper_set_title_keeps_existing() {
+        let manager = new_session_manager();
+        assert!(!SessionHelper::set_title(&manager, "missing", "Title".to_string()).await);
+
+        SessionHelper::get_or_create(&manager, "session-1", SessionConfig::default()).await;
+        assert!(SessionHelper::set_title(&manager, "session-1", "First".to_string()).await);
+        assert!(!SessionHelper::set_title(&manager, "session-1", "Second".to_string()).await);
+
+        let session = SessionHelper::get(&manager, "session-1").await.unwrap();
+        assert_eq!(session.title, Some("First".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_helper_expire_removes_idle_sessions() {
+        let manager = new_session_manager();
+
+        let mut idle = Session::new("idle".to_string(), SessionConfig::default());
+        idle.last_active = 1000;
+        let mut active = Session::new("active".to_string(), SessionConfig::default());
+        active.last_active = 5000;
+        {
+            let sessions = &manager;
+            sessions.insert(idle.id.clone(), idle);
+            sessions.insert(active.id.clone(), active);
+        }
+
+        let expired = SessionHelper::expire(&manager, 3600, 5000).await;
+        assert_eq!(expired, vec!["idle".to_string()]);
+
+        let sessions = &manager;
+        assert!(sessions.contains_key("active"));
+        assert!(!sessions.contains_key("idle"));
+    }
+
     #[test]
     fn test_max_turns_zero() {
         let config = SessionConfig {
             max_turns: 0,
             system_prompt: None,
+            summarize_history: false,
         };
         let mut session = Session::new("test".to_string(), config);
 
@@ -0,0 +1,126 @@
+use std::time::Duration;
+use axum::response::sse::KeepAlive;
+use tokio::time::Instant;
+use crate::config::ServerConfig;
+
+
+/// SSE 响应的 keep-alive 注释，防止代理在模型思考或排队时断开空闲的连接
+pub fn keep_alive(config: &ServerConfig) -> KeepAlive {
+    KeepAlive::new()
+        .interval(Duration::from_secs(config.sse_keep_alive_secs.max(1)))
+        .text("keep-alive")
+}
+
+/// 生成任务和 SSE 响应之间的 channel 容量。客户端读得慢时，生成最多领先这么多个事件
+pub fn buffer_size(config: &ServerConfig) -> usize {
+    config.sse_buffer_size.max(1)
+}
+
+
+/// 把连续的 token 合并成一个 `token` 事件：攒够 max_tokens 个，或第一个 token 已等待 max_delay 时发出
+/// （max_delay 为 0 时只按个数）。慢速链路上减少事件数和每个事件的开销，代价是延迟。
+/// max_tokens 为 1 时每个 token 立即发出
+pub struct TokenCoalescer {
+    max_tokens: usize,
+    max_delay: Option<Duration>,
+    text: String,
+    tokens: usize,
+    since: Option<Instant>,
+}
+
+impl TokenCoalescer {
+    pub fn new(max_tokens: usize, max_delay: Duration) -> Self {
+        Self {
+            max_tokens: max_tokens.max(1),
+            max_delay: (!max_delay.is_zero()).then_some(max_delay),
+            text: String::new(),
+            tokens: 0,
+            since: None,
+        }
+    }
+
+    /// 按配置合并。请求 logprobs 时每个 token 带自己的 log probability，不能合并
+    pub fn from_config(config: &ServerConfig, logprobs: bool) -> Self {
+        if logprobs {
+            return Self::new(1, Duration::ZERO);
+        }
+        Self::new(config.sse_coalesce_tokens, Duration::from_millis(config.sse_coalesce_ms))
+    }
+
+    /// 加入一段文本，达到发送条件时返回要发送的内容
+    pub fn push(&mut self, text: &str) -> Option<String> {
+        self.text.push_str(text);
+        self.tokens += 1;
+        let since = *self.since.get_or_insert_with(Instant::now);
+
+        if self.tokens >= self.max_tokens || self.max_delay.is_some_and(|delay| since.elapsed() >= delay) {
+            return self.flush();
+        }
+        None
+    }
+
+    /// 取出攒下的全部内容
+    pub fn flush(&mut self) -> Option<String> {
+        self.tokens = 0;
+        self.since = None;
+        (!self.text.is_empty()).then(|| std::mem::take(&mut self.text))
+    }
+
+    /// 攒下的内容最晚应在何时发出，没有内容或不按时间发出时为 None
+    pub fn deadline(&self) -> Option<Instant> {
+        Some(self.since? + self.max_delay?)
+    }
+}
+
+/// 在 deadline 完成，deadline 为 None 时永远不完成。用在 select 中，按时发出攒下的 token
+pub async fn flush_timer(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_sends_every_token() {
+        let mut coalescer = TokenCoalescer::from_config(&ServerConfig::default(), false);
+        assert_eq!(coalescer.push("Hel").as_deref(), Some("Hel"));
+        assert_eq!(coalescer.push("lo").as_deref(), Some("lo"));
+        assert_eq!(coalescer.flush(), None);
+        assert_eq!(coalescer.deadline(), None);
+    }
+
+    #[test]
+    fn test_coalesce_by_count() {
+        let mut coalescer = TokenCoalescer::new(3, Duration::ZERO);
+        assert_eq!(coalescer.push("a"), None);
+        assert_eq!(coalescer.push("b"), None);
+        assert!(coalescer.deadline().is_none());
+        assert_eq!(coalescer.push("c").as_deref(), Some("abc"));
+
+        assert_eq!(coalescer.push("d"), None);
+        assert_eq!(coalescer.flush().as_deref(), Some("d"));
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_by_time() {
+        let mut coalescer = TokenCoalescer::new(100, Duration::from_millis(20));
+        assert_eq!(coalescer.push("a"), None);
+        assert!(coalescer.deadline().is_some());
+
+        // 没有新 token 时由 flush_timer 按时发出
+        flush_timer(coalescer.deadline()).await;
+        assert_eq!(coalescer.push("b").as_deref(), Some("ab"));
+    }
+
+    #[test]
+    fn test_logprobs_disable_coalescing() {
+        let config = ServerConfig { sse_coalesce_tokens: 8, sse_coalesce_ms: 100, ..Default::default() };
+        assert_eq!(TokenCoalescer::from_config(&config, true).push("a").as_deref(), Some("a"));
+        assert_eq!(TokenCoalescer::from_config(&config, false).push("a"), None);
+    }
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use std::sync::Arc;
+use crate::config::ServerConfig;
+use crate::file_store::{DiskFileStore, SharedFileStore};
+use crate::session::SessionStore;
+
+
+/// 按 `state_store` 配置打开的存储。session_store 为 None 时 session 只保存在内存中
+pub struct Stores {
+    pub session_store: Option<Arc<dyn SessionStore>>,
+    pub file_store: SharedFileStore,
+}
+
+pub async fn open_stores(config: &ServerConfig) -> Result<Stores> {
+    match config.state_store.as_str() {
+        "local" => Ok(Stores {
+            session_store: None,
+            file_store: Arc::new(DiskFileStore::new(&config.file_dir)),
+        }),
+        "redis" => open_redis(config).await,
+        other => anyhow::bail!("Unknown state_store \"{}\", expected \"local\" or \"redis\"", other),
+    }
+}
+
+#[cfg(feature = "redis")]
+async fn open_redis(config: &ServerConfig) -> Result<Stores> {
+    let store = Arc::new(crate::redis_store::RedisStore::connect(&config.redis_url, &config.redis_prefix).await?);
+
+    Ok(Stores {
+        session_store: Some(store.clone()),
+        file_store: store,
+    })
+}
+
+#[cfg(not(feature = "redis"))]
+async fn open_redis(_config: &ServerConfig) -> Result<Stores> {
+    anyhow::bail!("state_store = \"redis\" requires building the server with --features redis")
+}
@@ -0,0 +1,153 @@
+use crate::file_parser::chunk_text_with_overlap;
+use crate::types::{ChatMessage, MessageRole};
+
+
+// POST /files/{file_id}/summarize 默认和允许的摘要长度（词数）
+pub const DEFAULT_SUMMARY_WORDS: usize = 200;
+pub const MAX_SUMMARY_WORDS: usize = 2000;
+
+// 每段的摘要只需要几句话
+pub const SECTION_SUMMARY_MAX_TOKENS: usize = 256;
+// 每段最多的 token 数（按 4 字符 / token 估算），上下文更长的模型也不把整本书塞进一次生成
+const MAX_SECTION_TOKENS: usize = 3000;
+// 提示词本身和对话模板占用的 token
+const PROMPT_OVERHEAD_TOKENS: usize = 200;
+
+const SYSTEM_PROMPT: &str = "You summarize documents accurately. Keep names, numbers, dates and conclusions. \
+    Do not add information that is not in the text. Reply with the summary only.";
+
+
+/// 一段原文的最大字符数：上下文长度减去回复和提示词占用的部分
+pub fn section_chars(context_length: usize, reply_tokens: usize) -> usize {
+    let tokens = context_length
+        .saturating_sub(reply_tokens)
+        .saturating_sub(PROMPT_OVERHEAD_TOKENS)
+        .clamp(256, MAX_SECTION_TOKENS);
+    tokens * 4
+}
+
+
+/// 把文档切成不重叠的段落，每段不超过 max_chars
+pub fn split_sections(content: &str, max_chars: usize) -> Vec<String> {
+    chunk_text_with_overlap(content, max_chars, 0)
+}
+
+
+/// map 阶段：一段原文的摘要
+pub fn section_prompt(filename: &str, section: &str, index: usize, total: usize) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::new(MessageRole::System, SYSTEM_PROMPT.to_string()),
+        ChatMessage::new(
+            MessageRole::User,
+            format!(
+                "This is part {} of {} of the document \"{}\".\n\n{}\n\nSummarize this part in a few sentences.",
+                index + 1, total, filename, section,
+            ),
+        ),
+    ]
+}
+
+
+/// 只有一段时直接对全文做摘要
+pub fn document_prompt(filename: &str, content: &str, max_words: usize) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::new(MessageRole::System, SYSTEM_PROMPT.to_string()),
+        ChatMessage::new(
+            MessageRole::User,
+            format!("Document \"{}\":\n\n{}\n\nSummarize the document in under {} words.", filename, content, max_words),
+        ),
+    ]
+}
+
+
+/// reduce 阶段：把各段的摘要合并成整篇文档的摘要
+pub fn combine_prompt(filename: &str, summaries: &[String], max_words: usize) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::new(MessageRole::System, SYSTEM_PROMPT.to_string()),
+        ChatMessage::new(
+            MessageRole::User,
+            format!(
+                "Summaries of consecutive parts of the document \"{}\":\n\n{}\n\nCombine them into one summary of the whole document in under {} words.",
+                filename, number_summaries(summaries), max_words,
+            ),
+        ),
+    ]
+}
+
+
+fn number_summaries(summaries: &[String]) -> String {
+    summaries.iter()
+        .enumerate()
+        .map(|(i, summary)| format!("{}. {}", i + 1, summary.trim()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+
+/// 各段摘要合起来仍超过 max_chars 时，按顺序分组（每组不超过 max_chars），
+/// 每组先合并成一个中间摘要再做最后的合并。返回的每组作为一段"原文"
+pub fn group_summaries(summaries: &[String], max_chars: usize) -> Vec<String> {
+    let mut groups = Vec::new();
+    let mut current = String::new();
+    for summary in summaries {
+        let summary = summary.trim();
+        if !current.is_empty() && current.chars().count() + summary.chars().count() + 2 > max_chars {
+            groups.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(summary);
+    }
+    if !current.is_empty() {
+        groups.push(current);
+    }
+    groups
+}
+
+
+/// 各段摘要能否放进一次合并的生成
+pub fn fits_in_one_pass(summaries: &[String], max_chars: usize) -> bool {
+    number_summaries(summaries).chars().count() <= max_chars
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_chars_stays_within_bounds() {
+        assert_eq!(section_chars(4096, 512), (4096 - 512 - 200) * 4);
+        assert_eq!(section_chars(128_000, 512), MAX_SECTION_TOKENS * 4);
+        assert_eq!(section_chars(512, 512), 256 * 4);
+    }
+
+    #[test]
+    fn test_split_sections_covers_text_without_overlap() {
+        let text = "First sentence here. Second sentence here.\n\nThird one in a new paragraph.";
+        let sections = split_sections(text, 30);
+        assert!(sections.len() > 1);
+        let joined = sections.join(" ");
+        assert_eq!(joined.matches("Second sentence").count(), 1);
+        assert!(joined.contains("Third one"));
+    }
+
+    #[test]
+    fn test_group_summaries() {
+        let summaries = vec!["a".repeat(40), "b".repeat(40), "c".repeat(40)];
+        let groups = group_summaries(&summaries, 90);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0], format!("{}\n\n{}", "a".repeat(40), "b".repeat(40)));
+
+        assert!(!fits_in_one_pass(&summaries, 90));
+        assert!(fits_in_one_pass(&summaries, 200));
+    }
+
+    #[test]
+    fn test_combine_prompt_numbers_sections() {
+        let messages = combine_prompt("report.pdf", &["Intro.".to_string(), "Results.".to_string()], 100);
+        assert!(messages[1].content.contains("1. Intro.\n\n2. Results."));
+        assert!(messages[1].content.contains("under 100 words"));
+    }
+}
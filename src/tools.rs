@@ -0,0 +1,725 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use crate::config::ServerConfig;
+use crate::types::{ChatMessage, MessageRole};
+
+
+// 模型调用工具时输出的标记，工具结果以 <tool_result> 消息返回给模型
+const TOOL_CALL_START: &str = "<tool_call>";
+const TOOL_CALL_END: &str = "</tool_call>";
+
+// 工具输出最多返回给模型的字符数
+const MAX_TOOL_OUTPUT_CHARS: usize = 4000;
+
+
+/// 可以由模型调用的工具。请求通过 `tools` 字段按名称启用
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// 参数的 JSON Schema
+    fn parameters(&self) -> Value;
+
+    async fn call(&self, arguments: &Value) -> Result<String>;
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    /// 按模型调用工具时的格式输出，写回 session 的 assistant 消息中
+    pub fn render(&self) -> String {
+        format!("{}{}{}", TOOL_CALL_START, json!({"name": self.name, "arguments": self.arguments}), TOOL_CALL_END)
+    }
+}
+
+
+/// 每次工具调用的超时
+pub fn tool_timeout(config: &ServerConfig) -> Duration {
+    Duration::from_secs(config.tool_timeout_secs.max(1))
+}
+
+
+/// 按名称选出请求启用的工具。代码执行需要在配置中开启 code_execution
+pub fn select_tools(names: &[String], config: &ServerConfig) -> Result<Vec<Arc<dyn Tool>>, String> {
+    let timeout = tool_timeout(config);
+    let mut tools: Vec<Arc<dyn Tool>> = Vec::with_capacity(names.len());
+
+    for name in names {
+        if tools.iter().any(|tool| tool.name() == name) {
+            continue;
+        }
+        let tool: Arc<dyn Tool> = match name.as_str() {
+            "calculator" => Arc::new(Calculator),
+            "python" | "javascript" if !config.code_execution => {
+                return Err(format!("Tool {} requires code_execution to be enabled on the server", name));
+            }
+            "python" => Arc::new(CodeRunner::python(&config.python_command, &config.code_sandbox_command, timeout)),
+            "javascript" => Arc::new(CodeRunner::javascript(&config.node_command, &config.code_sandbox_command, timeout)),
+            _ => return Err(format!("Unknown tool {}", name)),
+        };
+        tools.push(tool);
+    }
+    Ok(tools)
+}
+
+
+/// 告诉模型有哪些工具以及调用方式的系统消息
+pub fn tool_system_prompt(tools: &[Arc<dyn Tool>]) -> String {
+    let mut prompt = format!(
+        "You can use tools. To call a tool, reply with a single line\n\
+         {}{{\"name\": \"<tool name>\", \"arguments\": {{...}}}}{}\n\
+         and stop. The result is sent back to you in a <tool_result> message; then continue the answer. \
+         Only call a tool when it helps, and never invent tool results.\n\nAvailable tools:\n",
+        TOOL_CALL_START, TOOL_CALL_END,
+    );
+    for tool in tools {
+        prompt.push_str(&format!("- {}: {} Arguments: {}\n", tool.name(), tool.description(), tool.parameters()));
+    }
+    prompt.trim_end().to_string()
+}
+
+
+/// 在原有系统消息之后插入工具说明
+pub fn with_tool_prompt(messages: &[ChatMessage], tools: &[Arc<dyn Tool>]) -> Vec<ChatMessage> {
+    let mut messages = messages.to_vec();
+    if tools.is_empty() {
+        return messages;
+    }
+
+    let idx = messages.iter()
+        .position(|m| m.role != MessageRole::System)
+        .unwrap_or(messages.len());
+    messages.insert(idx, ChatMessage::new(MessageRole::System, tool_system_prompt(tools)));
+    messages
+}
+
+
+/// 从模型的回复中找出工具调用，返回调用之前的文本和调用本身。JSON 无效时视为普通文本
+pub fn parse_tool_call(text: &str) -> Option<(String, ToolCall)> {
+    let start = text.find(TOOL_CALL_START)?;
+    let body = &text[start + TOOL_CALL_START.len()..];
+    let body = body.find(TOOL_CALL_END).map(|end| &body[..end]).unwrap_or(body);
+
+    let value: Value = serde_json::from_str(body.trim()).ok()?;
+    let name = value.get("name")?.as_str()?.to_string();
+    let arguments = value.get("arguments").cloned().unwrap_or_else(|| json!({}));
+
+    Some((text[..start].trim_end().to_string(), ToolCall { name, arguments }))
+}
+
+
+/// 执行工具调用，超时或失败时返回错误信息（同样发给模型）
+pub async fn run_tool(tools: &[Arc<dyn Tool>], call: &ToolCall, timeout: Duration) -> (String, bool) {
+    let Some(tool) = tools.iter().find(|tool| tool.name() == call.name) else {
+        return (format!("Unknown tool {}", call.name), false);
+    };
+
+    match tokio::time::timeout(timeout, tool.call(&call.arguments)).await {
+        Ok(Ok(output)) => (truncate_output(output), true),
+        Ok(Err(e)) => (format!("Error: {}", e), false),
+        Err(_) => (format!("Error: timed out after {}s", timeout.as_secs()), false),
+    }
+}
+
+
+/// 工具结果作为 user 消息加入对话
+pub fn tool_result_message(call: &ToolCall, output: &str) -> ChatMessage {
+    ChatMessage::new(
+        MessageRole::User,
+        format!("<tool_result name=\"{}\">\n{}\n</tool_result>", call.name, output),
+    )
+}
+
+
+fn truncate_output(output: String) -> String {
+    match output.char_indices().nth(MAX_TOOL_OUTPUT_CHARS) {
+        Some((idx, _)) => format!("{}\n[output truncated]", &output[..idx]),
+        None => output,
+    }
+}
+
+
+/// 流式输出时过滤掉工具调用的标记：可能是 <tool_call> 开头的文本先保留，
+/// 确认不是工具调用后再输出；进入工具调用后本轮的其余 token 都不再输出
+#[derive(Default)]
+pub struct ToolCallFilter {
+    pending: String,
+    in_call: bool,
+}
+
+impl ToolCallFilter {
+    /// 返回可以发送给客户端的文本
+    pub fn push(&mut self, token: &str) -> String {
+        if self.in_call {
+            return String::new();
+        }
+        self.pending.push_str(token);
+
+        if let Some(start) = self.pending.find(TOOL_CALL_START) {
+            self.in_call = true;
+            let visible = self.pending[..start].to_string();
+            self.pending.clear();
+            return visible;
+        }
+
+        // 末尾可能是标记的前半部分，先保留
+        let keep = (1..TOOL_CALL_START.len())
+            .rev()
+            .find(|len| self.pending.ends_with(&TOOL_CALL_START[..*len]))
+            .unwrap_or(0);
+        let emit = self.pending.len() - keep;
+        self.pending.drain(..emit).collect()
+    }
+
+    /// 本轮结束，返回保留的文本
+    pub fn finish(&mut self) -> String {
+        self.in_call = false;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+
+/// 计算数学表达式：+ - * / % ^、括号、pi / e，以及 sqrt、abs、exp、ln、log、log2、
+/// sin、cos、tan、asin、acos、atan、floor、ceil、round、min、max、pow
+pub struct Calculator;
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluates a math expression exactly, e.g. \"(3 + 4.5) * 2 ^ 10 / sqrt(7)\". Use it for any arithmetic."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({"type": "object", "properties": {"expression": {"type": "string"}}, "required": ["expression"]})
+    }
+
+    async fn call(&self, arguments: &Value) -> Result<String> {
+        let expression = arguments.get("expression")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("Missing string argument \"expression\""))?;
+        Ok(format_number(evaluate(expression)?))
+    }
+}
+
+
+pub fn evaluate(expression: &str) -> Result<f64> {
+    let mut parser = ExprParser { chars: expression.chars().collect(), pos: 0 };
+    let value = parser.expression()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        bail!("Unexpected '{}' at position {}", parser.chars[parser.pos], parser.pos + 1);
+    }
+    if !value.is_finite() {
+        bail!("Result is not a finite number");
+    }
+    Ok(value)
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+
+// 递归下降：expression = term (('+' | '-') term)*，term = unary (('*' | '/' | '%') unary)*，
+// unary = '-' unary | power，power = primary ('^' unary)?
+struct ExprParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expression(&mut self) -> Result<f64> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    bail!("Division by zero");
+                }
+                value /= divisor;
+            } else if self.eat('%') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    bail!("Division by zero");
+                }
+                value %= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64> {
+        if self.eat('-') {
+            return Ok(-self.unary()?);
+        }
+        if self.eat('+') {
+            return self.unary();
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<f64> {
+        let base = self.primary()?;
+        if self.eat('^') {
+            // right associative (2 ^ 3 ^ 2 = 2 ^ 9), and -2 ^ 2 = -(2 ^ 2)
+            return Ok(base.powf(self.unary()?));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<f64> {
+        self.skip_whitespace();
+        let Some(&c) = self.chars.get(self.pos) else {
+            bail!("Unexpected end of expression");
+        };
+
+        if self.eat('(') {
+            let value = self.expression()?;
+            if !self.eat(')') {
+                bail!("Missing ')'");
+            }
+            return Ok(value);
+        }
+
+        if c.is_ascii_digit() || c == '.' {
+            let start = self.pos;
+            while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                self.pos += 1;
+            }
+            // exponent, e.g. 1.5e-3
+            if self.chars.get(self.pos).is_some_and(|c| *c == 'e' || *c == 'E')
+                && self.chars.get(self.pos + 1).is_some_and(|c| c.is_ascii_digit() || *c == '-' || *c == '+')
+            {
+                self.pos += 2;
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+            }
+            let number: String = self.chars[start..self.pos].iter().collect();
+            return number.parse().map_err(|_| anyhow!("Invalid number {}", number));
+        }
+
+        if c.is_ascii_alphabetic() {
+            let start = self.pos;
+            while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric()) {
+                self.pos += 1;
+            }
+            let name: String = self.chars[start..self.pos].iter().collect::<String>().to_lowercase();
+            return self.identifier(&name);
+        }
+
+        bail!("Unexpected '{}' at position {}", c, self.pos + 1)
+    }
+
+    fn identifier(&mut self, name: &str) -> Result<f64> {
+        match name {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+
+        if !self.eat('(') {
+            bail!("Unknown name {}", name);
+        }
+        let mut args = vec![self.expression()?];
+        while self.eat(',') {
+            args.push(self.expression()?);
+        }
+        if !self.eat(')') {
+            bail!("Missing ')' after arguments of {}", name);
+        }
+
+        let unary = |f: fn(f64) -> f64| -> Result<f64> {
+            match args.as_slice() {
+                [x] => Ok(f(*x)),
+                _ => bail!("{} takes 1 argument", name),
+            }
+        };
+        let binary = |f: fn(f64, f64) -> f64| -> Result<f64> {
+            match args.as_slice() {
+                [x, y] => Ok(f(*x, *y)),
+                _ => bail!("{} takes 2 arguments", name),
+            }
+        };
+
+        match name {
+            "sqrt" => unary(f64::sqrt),
+            "abs" => unary(f64::abs),
+            "exp" => unary(f64::exp),
+            "ln" => unary(f64::ln),
+            "log" | "log10" => unary(f64::log10),
+            "log2" => unary(f64::log2),
+            "sin" => unary(f64::sin),
+            "cos" => unary(f64::cos),
+            "tan" => unary(f64::tan),
+            "asin" => unary(f64::asin),
+            "acos" => unary(f64::acos),
+            "atan" => unary(f64::atan),
+            "floor" => unary(f64::floor),
+            "ceil" => unary(f64::ceil),
+            "round" => unary(f64::round),
+            "min" => binary(f64::min),
+            "max" => binary(f64::max),
+            "pow" => binary(f64::powf),
+            _ => bail!("Unknown function {}", name),
+        }
+    }
+}
+
+
+/// 在子进程中执行 Python / JavaScript 代码：独立的临时目录、清空的环境变量、无标准输入，
+/// Unix 上用 ulimit 限制 CPU 时间和写文件大小（Python 还限制内存）。解释器在 sandbox 命令
+/// （config 的 code_sandbox_command）下运行，子进程有自己的进程组，调用结束或超时后整个进程组被结束
+pub struct CodeRunner {
+    name: &'static str,
+    description: &'static str,
+    command: String,
+    args: Vec<String>,
+    sandbox: Vec<String>,
+    file_name: &'static str,
+    limits: &'static str,
+    timeout: Duration,
+}
+
+impl CodeRunner {
+    pub fn python(command: &str, sandbox: &[String], timeout: Duration) -> Self {
+        Self {
+            name: "python",
+            description: "Runs a Python 3 script and returns what it prints. No network or package installs; \
+                          use print() for results.",
+            command: command.to_string(),
+            // -I: isolated mode, ignores PYTHON* variables and the user site directory
+            args: vec!["-I".to_string()],
+            sandbox: sandbox.to_vec(),
+            file_name: "main.py",
+            limits: "ulimit -v 1048576; ",
+            timeout,
+        }
+    }
+
+    pub fn javascript(command: &str, sandbox: &[String], timeout: Duration) -> Self {
+        Self {
+            name: "javascript",
+            description: "Runs a JavaScript (Node.js) script and returns what it prints. Use console.log() for results.",
+            command: command.to_string(),
+            args: Vec::new(),
+            sandbox: sandbox.to_vec(),
+            file_name: "main.js",
+            // V8 reserves a large address space up front, so no memory limit here
+            limits: "",
+            timeout,
+        }
+    }
+
+    fn command(&self, dir: &Path) -> Command {
+        let cpu_secs = self.timeout.as_secs().max(1);
+        // sandbox 命令在前，{dir} 替换为代码所在的目录
+        let dir_str = dir.to_string_lossy();
+        let program: Vec<String> = self.sandbox.iter()
+            .map(|arg| arg.replace("{dir}", &dir_str))
+            .chain(std::iter::once(self.command.clone()))
+            .collect();
+
+        #[cfg(unix)]
+        let mut command = {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("ulimit -t {}; ulimit -f 10240; {}exec \"$@\"", cpu_secs, self.limits))
+                .arg("sh")
+                .args(&program)
+                // 自己的进程组，结束时连同代码启动的子进程一起结束
+                .process_group(0);
+            command
+        };
+        #[cfg(not(unix))]
+        let mut command = {
+            let _ = cpu_secs;
+            let mut command = Command::new(&program[0]);
+            command.args(&program[1..]);
+            command
+        };
+
+        command
+            .args(&self.args)
+            .arg(self.file_name)
+            .current_dir(dir)
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .env("HOME", dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        command
+    }
+}
+
+#[async_trait]
+impl Tool for CodeRunner {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn parameters(&self) -> Value {
+        json!({"type": "object", "properties": {"code": {"type": "string"}}, "required": ["code"]})
+    }
+
+    async fn call(&self, arguments: &Value) -> Result<String> {
+        let code = arguments.get("code")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("Missing string argument \"code\""))?;
+
+        let dir = ToolDir::create().await?;
+        tokio::fs::write(dir.0.join(self.file_name), code).await?;
+
+        // 超时由 run_tool 处理：future 被丢弃时结束进程组并删除目录
+        let child = self.command(&dir.0).spawn()?;
+        let _group = ProcessGroup(child.id());
+        let output = child.wait_with_output().await?;
+
+        let mut result = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            result.push_str(&format!("\n[stderr]\n{}", stderr.trim_end()));
+        }
+        if !output.status.success() {
+            result.push_str(&format!("\n[exit status: {}]", output.status));
+        }
+        if result.trim().is_empty() {
+            result = "(no output)".to_string();
+        }
+        Ok(result.trim().to_string())
+    }
+}
+
+
+// 代码执行的临时目录，drop 时删除（包括调用超时被取消时）
+struct ToolDir(PathBuf);
+
+impl ToolDir {
+    async fn create() -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("llm-tool-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for ToolDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            tracing::warn!(path = %self.0.display(), error = %e, "Failed to remove tool directory");
+        }
+    }
+}
+
+
+// 代码执行子进程的进程组（组 id 即子进程的 pid），drop 时结束组内所有进程，
+// 代码在后台启动的进程不会在调用结束后继续运行
+struct ProcessGroup(Option<u32>);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            let _ = std::process::Command::new("kill")
+                .args(["-KILL", "--", &format!("-{}", pid)])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_precedence_and_functions() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(evaluate("-2 ^ 2").unwrap(), -4.0);
+        assert_eq!(evaluate("2 ^ -1").unwrap(), 0.5);
+        assert_eq!(evaluate("10 % 4 + sqrt(16) - max(1, 3)").unwrap(), 3.0);
+        assert_eq!(evaluate("1.5e3 / 3").unwrap(), 500.0);
+        assert!((evaluate("sin(pi / 2)").unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("2 +").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("foo(1)").is_err());
+        assert!(evaluate("max(1)").is_err());
+        assert!(evaluate("1 2").is_err());
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(42.0), "42");
+        assert_eq!(format_number(-3.5), "-3.5");
+    }
+
+    #[test]
+    fn test_parse_tool_call() {
+        let text = "Let me compute.\n<tool_call>{\"name\": \"calculator\", \"arguments\": {\"expression\": \"2+2\"}}</tool_call>";
+        let (before, call) = parse_tool_call(text).unwrap();
+        assert_eq!(before, "Let me compute.");
+        assert_eq!(call.name, "calculator");
+        assert_eq!(call.arguments["expression"], "2+2");
+
+        // closing tag missing because generation stopped
+        let (_, call) = parse_tool_call("<tool_call>{\"name\": \"python\"}").unwrap();
+        assert_eq!(call.arguments, json!({}));
+
+        assert!(parse_tool_call("no tools here").is_none());
+        assert!(parse_tool_call("<tool_call>not json</tool_call>").is_none());
+    }
+
+    #[test]
+    fn test_rendered_call_parses_back() {
+        let call = ToolCall {
+            name: "calculator".to_string(),
+            arguments: json!({"expression": "1/3"}),
+        };
+        assert_eq!(parse_tool_call(&call.render()), Some((String::new(), call)));
+    }
+
+    #[test]
+    fn test_filter_hides_tool_call_markup() {
+        let mut filter = ToolCallFilter::default();
+        let mut visible = String::new();
+        for token in ["The answer", " is <", "tool", "_call>{\"name\"", ": \"calculator\"}", "</tool_call>"] {
+            visible.push_str(&filter.push(token));
+        }
+        visible.push_str(&filter.finish());
+        assert_eq!(visible, "The answer is ");
+
+        let mut filter = ToolCallFilter::default();
+        assert_eq!(filter.push("a <b"), "a <b");
+        assert_eq!(filter.push(" <tool"), " ");
+        assert_eq!(filter.finish(), "<tool");
+    }
+
+    #[test]
+    fn test_select_tools() {
+        let mut config = ServerConfig::default();
+        let tools = select_tools(&["calculator".to_string(), "calculator".to_string()], &config).unwrap();
+        assert_eq!(tools.len(), 1);
+
+        assert!(select_tools(&["python".to_string()], &config).is_err());
+        assert!(select_tools(&["shell".to_string()], &config).is_err());
+
+        config.code_execution = true;
+        assert_eq!(select_tools(&["python".to_string()], &config).unwrap()[0].name(), "python");
+    }
+
+    #[tokio::test]
+    async fn test_tool_dir_is_removed_on_drop() {
+        let dir = ToolDir::create().await.unwrap();
+        tokio::fs::write(dir.0.join("main.py"), "print(1)").await.unwrap();
+        let path = dir.0.clone();
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_background_processes_are_killed() {
+        let runner = CodeRunner {
+            name: "shell",
+            description: "",
+            command: "sh".to_string(),
+            args: Vec::new(),
+            sandbox: Vec::new(),
+            file_name: "main.sh",
+            limits: "",
+            timeout: Duration::from_secs(5),
+        };
+        // the script exits at once and leaves a child behind
+        let output = runner.call(&json!({"code": "sleep 30 >/dev/null 2>&1 &\necho $!"})).await.unwrap();
+        let pid: u32 = output.trim().parse().unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // gone, or a zombie waiting for init to reap it
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        assert!(stat.is_empty() || stat.contains(") Z "), "{}", stat);
+    }
+
+    #[tokio::test]
+    async fn test_run_tool_reports_errors() {
+        let tools = select_tools(&["calculator".to_string()], &ServerConfig::default()).unwrap();
+        let call = |arguments: Value| ToolCall { name: "calculator".to_string(), arguments };
+
+        let (output, ok) = run_tool(&tools, &call(json!({"expression": "6 * 7"})), Duration::from_secs(1)).await;
+        assert_eq!((output.as_str(), ok), ("42", true));
+
+        let (output, ok) = run_tool(&tools, &call(json!({})), Duration::from_secs(1)).await;
+        assert!(!ok && output.starts_with("Error:"));
+
+        let unknown = ToolCall { name: "shell".to_string(), arguments: json!({}) };
+        assert!(!run_tool(&tools, &unknown, Duration::from_secs(1)).await.1);
+    }
+}
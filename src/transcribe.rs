@@ -0,0 +1,251 @@
+use anyhow::Result;
+use crate::config::ServerConfig;
+
+// whisper 需要 16kHz 单声道 PCM
+#[cfg(feature = "transcribe")]
+const WHISPER_SAMPLE_RATE: u32 = 16000;
+
+pub fn is_audio_extension(extension: &str) -> bool {
+    matches!(extension.to_lowercase().as_str(), "wav" | "mp3" | "m4a")
+}
+
+
+/// 语音转文字，使用 whisper.cpp（GGML 格式的 Whisper 模型），模型在第一次使用时下载并加载
+#[cfg_attr(not(feature = "transcribe"), allow(dead_code))]
+pub struct Transcriber {
+    model_dir: String,
+    repo: String,
+    file: String,
+    language: String,
+    #[cfg(feature = "transcribe")]
+    context: tokio::sync::OnceCell<std::sync::Arc<whisper_rs::WhisperContext>>,
+}
+
+impl Transcriber {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            model_dir: config.model_dir.clone(),
+            repo: config.whisper_repo.clone(),
+            file: config.whisper_model.clone(),
+            language: config.whisper_language.clone(),
+            #[cfg(feature = "transcribe")]
+            context: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        cfg!(feature = "transcribe")
+    }
+
+    #[cfg(not(feature = "transcribe"))]
+    pub async fn transcribe(&self, _audio: Vec<u8>, _extension: &str) -> Result<String> {
+        anyhow::bail!("Server was built without the transcribe feature")
+    }
+
+    #[cfg(feature = "transcribe")]
+    pub async fn transcribe(&self, audio: Vec<u8>, extension: &str) -> Result<String> {
+        let context = self.context
+            .get_or_try_init(|| self.load_context())
+            .await?
+            .clone();
+
+        let extension = extension.to_lowercase();
+        let language = self.language.clone();
+
+        // decoding and inference are CPU bound, keep them off the async workers
+        tokio::task::spawn_blocking(move || {
+            let samples = decode_audio(audio, &extension)?;
+            run_whisper(&context, &samples, &language)
+        })
+        .await?
+    }
+
+    #[cfg(feature = "transcribe")]
+    async fn load_context(&self) -> Result<std::sync::Arc<whisper_rs::WhisperContext>> {
+        use whisper_rs::{WhisperContext, WhisperContextParameters};
+
+        let path = format!("{}/{}", self.model_dir, self.file);
+        crate::mistral_runner::download_model(&self.repo, &self.file, &path).await?;
+
+        tracing::info!(path = %path, "Loading whisper model");
+        let context = tokio::task::spawn_blocking(move || {
+            WhisperContext::new_with_params(&path, WhisperContextParameters::default())
+        })
+        .await??;
+
+        Ok(std::sync::Arc::new(context))
+    }
+}
+
+
+#[cfg(feature = "transcribe")]
+fn run_whisper(context: &whisper_rs::WhisperContext, samples: &[f32], language: &str) -> Result<String> {
+    use whisper_rs::{FullParams, SamplingStrategy};
+
+    let mut state = context.create_state()?;
+
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    params.set_language(Some(language));
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_timestamps(false);
+
+    state.full(params, samples)?;
+
+    let mut text = String::new();
+    for i in 0..state.full_n_segments()? {
+        text.push_str(&state.full_get_segment_text(i)?);
+    }
+
+    Ok(text.trim().to_string())
+}
+
+
+// 解码 wav / mp3 / m4a，转换为 16kHz 单声道
+#[cfg(feature = "transcribe")]
+fn decode_audio(audio: Vec<u8>, extension: &str) -> Result<Vec<f32>> {
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::errors::Error as SymphoniaError;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    let source = MediaSourceStream::new(Box::new(std::io::Cursor::new(audio)), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+
+    let probed = symphonia::default::get_probe()
+        .format(&hint, source, &FormatOptions::default(), &MetadataOptions::default())?;
+    let mut format = probed.format;
+
+    let track = format.default_track()
+        .ok_or_else(|| anyhow::anyhow!("No audio track found"))?;
+    let track_id = track.id;
+    let sample_rate = track.codec_params.sample_rate
+        .ok_or_else(|| anyhow::anyhow!("Unknown sample rate"))?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+
+        samples.extend(downmix(buffer.samples(), spec.channels.count()));
+    }
+
+    Ok(resample(&samples, sample_rate, WHISPER_SAMPLE_RATE))
+}
+
+
+// 交错的多声道采样取平均
+#[cfg(feature = "transcribe")]
+fn downmix(interleaved: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return interleaved.to_vec();
+    }
+    interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+
+// 线性插值重采样，对语音识别足够
+#[cfg(feature = "transcribe")]
+fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let ratio = from_rate as f64 / to_rate as f64;
+    let out_len = (samples.len() as f64 / ratio).floor() as usize;
+
+    (0..out_len)
+        .map(|i| {
+            let pos = i as f64 * ratio;
+            let idx = pos.floor() as usize;
+            let frac = (pos - idx as f64) as f32;
+            let current = samples[idx];
+            let next = samples.get(idx + 1).copied().unwrap_or(current);
+            current + (next - current) * frac
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_extension_detection() {
+        assert!(is_audio_extension("wav"));
+        assert!(is_audio_extension("MP3"));
+        assert!(is_audio_extension("m4a"));
+        assert!(!is_audio_extension("mp4"));
+        assert!(!is_audio_extension("txt"));
+    }
+
+    #[cfg(feature = "transcribe")]
+    #[test]
+    fn test_downmix_stereo() {
+        assert_eq!(downmix(&[1.0, 0.0, 0.5, 0.5], 2), vec![0.5, 0.5]);
+        assert_eq!(downmix(&[0.1, 0.2], 1), vec![0.1, 0.2]);
+    }
+
+    #[cfg(feature = "transcribe")]
+    #[test]
+    fn test_resample_halves_length() {
+        let samples: Vec<f32> = (0..32000).map(|i| i as f32).collect();
+        let resampled = resample(&samples, 32000, 16000);
+        assert_eq!(resampled.len(), 16000);
+        assert_eq!(resampled[1], 2.0);
+    }
+
+    #[cfg(feature = "transcribe")]
+    #[test]
+    fn test_resample_interpolates_upsampling() {
+        let resampled = resample(&[0.0, 1.0], 8000, 16000);
+        assert_eq!(resampled, vec![0.0, 0.5, 1.0, 1.0]);
+    }
+
+    #[cfg(feature = "transcribe")]
+    #[test]
+    fn test_decode_wav() {
+        // 0.1s of silence, 16-bit mono PCM at 16kHz
+        let sample_count: u32 = 1600;
+        let data_len = sample_count * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&32000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.extend(std::iter::repeat(0u8).take(data_len as usize));
+
+        let samples = decode_audio(wav, "wav").unwrap();
+        assert_eq!(samples.len(), sample_count as usize);
+        assert!(samples.iter().all(|s| *s == 0.0));
+    }
+}
@@ -1,47 +1,805 @@
 use serde::{Serialize, Deserialize};
-use crate::session::ChatMessage;
+use utoipa::{IntoParams, ToSchema};
+use std::collections::HashMap;
+use crate::file_parser::FileStatus;
+use crate::memory::Facts;
+use crate::few_shot::Example;
+use crate::registry::Adapter;
+use crate::service_state::ServiceState;
+use crate::session::SessionConfig;
+use crate::audit::AuditRecord;
+use crate::usage::{KeyUsage, TokenQuota};
+use crate::benchmark::BenchmarkRun;
+use crate::evals::EvalSuite;
 
-#[derive(Deserialize)]
+
+/// 对话中的一条消息，session、handler 和推理后端共用
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatMessage {
+    // 消息 id，用于编辑消息；前端同步的消息没有 id 时自动生成
+    #[serde(default = "new_message_id")]
+    pub id: String,
+    pub role: MessageRole,
+    pub content: String,
+    // 置顶的消息（重要的指令、文件内容等）裁剪历史时不会被删除
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+fn new_message_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+impl ChatMessage {
+    pub fn new(role: MessageRole, content: String) -> Self {
+        Self {
+            id: new_message_id(),
+            role,
+            content,
+            pinned: false,
+        }
+    }
+}
+
+/// 消息的角色，JSON 中为小写字符串。OpenAI 新的 "developer" 角色按 system 处理
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRole {
+    User,
+    Assistant,
+    #[serde(alias = "developer")]
+    System,
+}
+
+
+#[derive(Deserialize, ToSchema)]
 pub struct InferenceRequest {
-    #[serde(rename = "model_name")]  //expected input format: model name:   , prompt: 
+    #[serde(rename = "model_name", default)]  //expected input format: model name:   , prompt: 
     pub model: String,
     pub prompt: String,
     #[serde(default)]
     pub session_id: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    /// 可复现的生成：同样的输入得到同样的输出。mistralrs 没有按请求的随机数种子，所以带 seed 的请求
+    /// 按贪心解码，不同的 seed 结果相同；同时设置 temperature > 0、top_k > 1 或 top_p < 1 时返回 400，
+    /// 模型注册表中的默认采样参数不使用
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default, alias = "repeat_penalty")]
+    pub repetition_penalty: Option<f32>,
+    // OpenAI 风格的惩罚：presence 对出现过的 token 扣固定值，frequency 按出现次数扣分，范围 -2.0 到 2.0
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    // 流式输出时随每个 token 返回 log probability，以及概率最高的 top_logprobs 个候选 token
+    #[serde(default)]
+    pub logprobs: Option<bool>,
+    #[serde(default)]
+    pub top_logprobs: Option<usize>,
+    // 设置后替换该 session 的系统消息，并保存到 session 配置中
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    // 已上传文件的 id，加入本 session 的检索范围（可以是其他 session 上传的文件）
+    #[serde(default)]
+    pub file_ids: Vec<String>,
+    // 已上传图片的 id，随本轮 prompt 一起发给视觉模型
+    #[serde(default)]
+    pub image_ids: Vec<String>,
+    // 允许模型调用的内置工具：calculator、python、javascript
+    #[serde(default)]
+    pub tools: Vec<String>,
+    // few-shot 示例组的名称（PUT /examples/{name} 注册），示例放在系统消息之后，上下文不够时先丢弃示例
+    #[serde(default)]
+    pub examples: Option<String>,
+    // 设置后立即返回 202，生成结束后把签名的结果 POST 到这个地址（需要配置 webhook_secret）
+    #[serde(default)]
+    pub callback_url: Option<String>,
+}
+
+impl InferenceRequest {
+    pub fn generation_config(&self) -> GenerationConfig {
+        GenerationConfig {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+            repetition_penalty: self.repetition_penalty,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            logprobs: self.logprobs,
+            top_logprobs: self.top_logprobs,
+        }
+    }
 }
 
-#[derive(Serialize)]
+
+// 单次请求的采样参数，未设置的字段使用模型注册表中的默认值，再回退到后端默认值
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct GenerationConfig {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub max_tokens: Option<usize>,
+    /// 可复现的生成：同样的输入得到同样的输出。mistralrs 没有按请求的随机数种子，所以带 seed 的请求
+    /// 按贪心解码，不同的 seed 结果相同；同时设置 temperature > 0、top_k > 1 或 top_p < 1 时返回 400，
+    /// 模型注册表中的默认采样参数不使用
+    pub seed: Option<u64>,
+    #[serde(alias = "repeat_penalty")]
+    pub repetition_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<usize>,
+}
+
+impl GenerationConfig {
+    /// 用 defaults 填充未设置的字段
+    pub fn or(&self, defaults: &GenerationConfig) -> GenerationConfig {
+        GenerationConfig {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            seed: self.seed.or(defaults.seed),
+            repetition_penalty: self.repetition_penalty.or(defaults.repetition_penalty),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+            logprobs: self.logprobs.or(defaults.logprobs),
+            top_logprobs: self.top_logprobs.or(defaults.top_logprobs),
+        }
+    }
+
+    /// 限制生成长度：max_tokens（请求的或模型默认的）不超过 limit，都未设置时使用 limit。limit 为 0 时不限制
+    pub fn capped(&self, default_max_tokens: Option<usize>, limit: usize) -> GenerationConfig {
+        let max_tokens = self.max_tokens.or(default_max_tokens);
+        let max_tokens = match limit {
+            0 => max_tokens,
+            limit => Some(max_tokens.map_or(limit, |n| n.min(limit))),
+        };
+        GenerationConfig {
+            max_tokens,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct InferenceResponse {
     pub text: String,
     #[serde(skip_serializing_if="Option::is_none")]
     pub session_id: Option<String>,
+    #[serde(flatten)]
+    pub usage: Usage,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolInvocation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    // 回答来自 response cache，没有调用模型；usage 为生成这个回答时的用量
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+}
+
+
+/// `POST /generate/batch` 的请求体：每个 prompt 单独生成（不使用 session），
+/// 共用模型、系统消息、few-shot 示例和采样参数
+#[derive(Deserialize, ToSchema)]
+pub struct BatchRequest {
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    pub prompts: Vec<String>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub examples: Option<String>,
+    // 为 true 时按完成的顺序逐行返回 BatchItem（application/x-ndjson）
+    #[serde(default)]
+    pub stream: bool,
+    // 与 /generate 相同：立即返回 202，全部完成后把 BatchItem 数组 POST 到这个地址。不能和 stream 同时使用
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    #[serde(flatten)]
+    pub generation: GenerationConfig,
+}
+
+
+// 带 callback_url 的请求已进入队列，结果稍后发送到 callback_url
+#[derive(Serialize, ToSchema)]
+pub struct AcceptedResponse {
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+}
+
+
+// 批量生成中的一项。生成失败时 text 为空，error 为原因
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct BatchItem {
+    // 在请求的 prompts 中的位置
+    pub index: usize,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub usage: Usage,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+}
+
+
+// 生成过程中模型调用的一次工具
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ToolInvocation {
+    pub name: String,
+    pub arguments: serde_json::Value,
+    pub output: String,
+    pub success: bool,
+}
+
+
+// token 用量，由模型的 tokenizer 统计
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    // 生成结束的原因："stop"（模型结束）、"length"（达到 max_tokens）、"canceled"、
+    // "content_filter"（回答被内容审核拦截）或 "error"（推理失败）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+}
+
+impl Usage {
+    /// 累加多轮生成（工具调用）的用量，结束原因取最后一轮的
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        if other.finish_reason.is_some() {
+            self.finish_reason = other.finish_reason.clone();
+        }
+    }
+}
+
+
+/// 一次生成的耗时：排队、首个 token（流式输出时）、总时间（毫秒），以及生成速度
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Timings {
+    pub queue_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttft_ms: Option<u64>,
+    pub total_ms: u64,
+    pub tokens_per_second: f64,
+}
+
+
+/// 生成的一个 token 的 log probability，请求 `logprobs: true` 时随 token 事件返回
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct TokenLogprobs {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ModelInfo {
+    pub name: String,
+    pub repo: String,
+    pub file: String,
+    pub quantization: String,
+    pub context_length: usize,
+    pub downloaded: bool,
+    pub loaded: bool,
+    pub vision: bool,
+    // 加载到的设备，例如 "cpu"、"cuda:0"，远程模型为 "remote"
+    pub device: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ListModelsResponse {
+    pub models: Vec<ModelInfo>,
+}
+
+
+// DELETE /models/{name}/files 的结果：deleted 为是否删除了文件，freed_bytes 为释放的磁盘空间
+#[derive(Serialize, ToSchema)]
+pub struct ModelFilesResponse {
+    pub model: String,
+    pub deleted: bool,
+    pub freed_bytes: u64,
+}
+
+
+// POST /models/{name}/adapters：name 为注册后使用的 model_name，其余字段见 models.toml 的 [models.adapter]
+#[derive(Deserialize, ToSchema)]
+pub struct AdapterRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub adapter: Adapter,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AdapterResponse {
+    pub model: String,
+    pub base: String,
+}
+
+
+// POST /tokenize：model_name 为空时使用默认模型
+#[derive(Deserialize, ToSchema)]
+pub struct TokenizeRequest {
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    pub text: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenizeResponse {
+    pub model: String,
+    pub tokens: Vec<u32>,
+    pub count: usize,
+}
+
+// POST /detokenize
+#[derive(Deserialize, ToSchema)]
+pub struct DetokenizeRequest {
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    pub tokens: Vec<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DetokenizeResponse {
+    pub model: String,
+    pub text: String,
+}
+
+
+// POST /models/{name}/load 和 /unload 的结果：模型现在是否已加载
+#[derive(Serialize, ToSchema)]
+pub struct ModelLoadResponse {
+    pub model: String,
+    pub loaded: bool,
+}
+
+
+/// `GET /v1/models` 中的一项，与 OpenAI API 的格式相同
+#[derive(Serialize, ToSchema)]
+pub struct OpenAIModel {
+    pub id: String,
+    pub object: &'static str,
+    // 本地模型没有创建时间，固定为 0
+    pub created: u64,
+    pub owned_by: String,
+}
+
+impl OpenAIModel {
+    pub fn new(id: String, owned_by: String) -> Self {
+        Self {
+            id,
+            object: "model",
+            created: 0,
+            owned_by,
+        }
+    }
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct OpenAIModelList {
+    pub object: &'static str,
+    pub data: Vec<OpenAIModel>,
+}
+
+
+#[derive(Deserialize, ToSchema)]
+pub struct PullModelRequest {
+    #[serde(default)]
+    pub repo: String,
+    #[serde(default)]
+    pub file: String,
+    // 服务器上已有的 GGUF 文件，设置时不下载，repo / file 不使用
+    #[serde(default)]
+    pub path: Option<String>,
+    // 注册后使用的 model_name
+    pub alias: String,
+}
+
+
+/// `POST /models/pull` 的 SSE 事件，`event:` 字段为 [`PullEvent::name`]：
+///
+/// - `downloading` `{"downloaded": 0, "total": 0}`：下载进度（字节），total 未知时为 0
+/// - `success` `{"model": "..."}`：模型已注册，可直接用于推理
+/// - `error` `{"error": "..."}`：下载失败、本地文件不存在或文件不是 GGUF 格式
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum PullEvent {
+    Downloading { downloaded: u64, total: u64 },
+    Success { model: String },
+    Error { error: String },
+}
+
+impl PullEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PullEvent::Downloading { .. } => "downloading",
+            PullEvent::Success { .. } => "success",
+            PullEvent::Error { .. } => "error",
+        }
+    }
+}
+
+
+#[derive(Deserialize, IntoParams)]
+pub struct UploadQuery {
+    #[serde(default)]
+    pub session_id: Option<String>,
+    // 客户端生成的 ID（字母、数字、- 和 _，最多 64 个字符），上传期间可通过 GET /uploads/{upload_id} 查询进度
+    #[serde(default)]
+    pub upload_id: Option<String>,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadStatus {
+    Uploading,
+    Completed,
+    Failed,
+}
+
+
+/// 一次上传请求的进度
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct UploadProgress {
+    pub upload_id: String,
+    pub status: UploadStatus,
+    // 已收到的文件字节数
+    pub received_bytes: u64,
+    // 请求的 Content-Length（包括 multipart 的分隔和表单字段），未知时为 null
+    pub total_bytes: Option<u64>,
+    // 已完整收到的文件数
+    pub files: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct UploadResponse {
     pub file_id: String,
     pub filename: String,
     pub file_size: usize,
+    pub session_id: String,
+    // 文档为 processing，解析完成后变为 ready，见 GET /files/{file_id}/status
+    pub status: FileStatus,
+}
+
+
+// 已上传文件的元数据
+#[derive(Serialize, ToSchema)]
+pub struct FileInfo {
+    pub file_id: String,
+    pub filename: String,
+    pub extension: String,
+    pub file_size: usize,
+    // 解析后的文本字符数
+    pub char_count: usize,
+    pub uploaded_at: u64,
+    pub session_id: String,
+    pub indexed: bool,
+    pub status: FileStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+
+#[derive(Deserialize, IntoParams)]
+pub struct ListFilesQuery {
+    #[serde(default)]
+    pub session_id: Option<String>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ListFilesResponse {
+    pub files: Vec<FileInfo>,
+}
+
+
+#[derive(Deserialize, IntoParams)]
+pub struct FileStatusQuery {
+    // 为 true 时以 SSE 推送状态，解析结束后关闭
+    #[serde(default)]
+    pub stream: bool,
+}
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentFormat {
+    #[default]
+    Text,
+    Markdown,
+    Json,
+}
+
+
+#[derive(Deserialize, IntoParams)]
+pub struct FileContentQuery {
+    // text（默认）、markdown 或 json
+    #[serde(default)]
+    pub format: ContentFormat,
+    // 按检索使用的块返回：text 中每块带有 prompt 中的块标题，markdown 中每块为一节，json 中为 chunks 数组
+    #[serde(default)]
+    pub chunked: bool,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct FileChunk {
+    pub index: usize,
+    pub text: String,
+}
+
+
+/// `format=json` 时的响应，chunked 时只有 chunks，否则只有 content
+#[derive(Serialize, ToSchema)]
+pub struct FileContentResponse {
+    pub file_id: String,
+    pub filename: String,
+    pub char_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<FileChunk>>,
+}
+
+
+/// `GET /files/{file_id}/status` 的响应，`?stream=true` 时也是 SSE `status` 事件的数据
+#[derive(Clone, Serialize, ToSchema)]
+pub struct FileStatusResponse {
+    pub file_id: String,
+    pub status: FileStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct TranscribeResponse {
+    pub text: String,
+    pub filename: String,
+    // 请求带 session_id 时，转写结果保存为文件并加入该 session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
 }
 
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct DeleteResponse {
     pub file_id: String,
     pub result: bool,
 }
 
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct RemoveSessionResponse {
     pub session_id: String,
     pub cleared: bool,
 }
 
 
+// 会话列表的分页参数，limit 默认 20，最大 100
+#[derive(Deserialize, IntoParams)]
+pub struct ListSessionsQuery {
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+
+// 会话列表中的一项，时间为 unix 时间戳（秒）
+#[derive(Serialize, ToSchema)]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub title: Option<String>,
+    pub message_count: usize,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+
+// 按最近使用时间倒序排列
+#[derive(Serialize, ToSchema)]
+pub struct ListSessionsResponse {
+    pub sessions: Vec<SessionSummary>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+
+// 编辑消息的请求：新的消息内容，以及重新生成使用的模型和采样参数
+#[derive(Deserialize, ToSchema)]
+pub struct EditMessageRequest {
+    pub content: String,
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    #[serde(flatten)]
+    pub generation: GenerationConfig,
+}
+
+
+#[derive(Deserialize, ToSchema)]
+pub struct PinMessageRequest {
+    pub pinned: bool,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct PinMessageResponse {
+    pub session_id: String,
+    pub message_id: String,
+    pub pinned: bool,
+    pub message_count: usize,
+}
+
+
+#[derive(Deserialize, IntoParams)]
+pub struct ForkSessionQuery {
+    // 复制到这条消息为止（包含），未指定时复制全部历史
+    #[serde(default)]
+    pub at_message_id: Option<String>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ForkSessionResponse {
+    pub session_id: String,
+    pub forked_from: String,
+    pub message_count: usize,
+}
+
+
+// 修改长期记忆：value 为 null 时删除该条目，未列出的条目保持不变
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateMemoryRequest {
+    pub facts: HashMap<String, Option<String>>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct MemoryResponse {
+    #[schema(value_type = HashMap<String, String>)]
+    pub facts: Facts,
+}
+
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateExampleSetRequest {
+    pub examples: Vec<Example>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ExampleSetResponse {
+    pub name: String,
+    pub examples: Vec<Example>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ExampleSetInfo {
+    pub name: String,
+    pub examples: usize,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ListExampleSetsResponse {
+    pub sets: Vec<ExampleSetInfo>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct RemoveExampleSetResponse {
+    pub name: String,
+    pub removed: bool,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct EvalSuiteResponse {
+    pub name: String,
+    pub suite: EvalSuite,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct EvalSuiteInfo {
+    pub name: String,
+    pub cases: usize,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ListEvalSuitesResponse {
+    pub suites: Vec<EvalSuiteInfo>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct RemoveEvalSuiteResponse {
+    pub name: String,
+    pub removed: bool,
+}
+
+
+// `POST /evals/{name}/run` 的请求体，model_name 为空时使用集合中的 model，再回退到默认模型
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct EvalRunRequest {
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+}
+
+
+// 一个用例的结果。生成失败时 passed 为 false，error 为原因
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct EvalCaseResult {
+    // 在集合的 cases 中的位置
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub passed: bool,
+    pub output: String,
+    // 失败时期望和回答的逐行对比："- " 为期望中的行，"+ " 为回答中的行
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct EvalReport {
+    pub suite: String,
+    pub model: String,
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+    // 按 cases 的顺序
+    pub results: Vec<EvalCaseResult>,
+    pub usage: Usage,
+    pub duration_ms: u64,
+}
+
+
 // 获取 session 的响应
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct GetSessionResponse {
     pub session_id: String,
     pub messages: Vec<ChatMessage>,
@@ -50,7 +808,7 @@ pub struct GetSessionResponse {
 
 
 // 同步 session 的请求
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct SyncSessionRequest {
     pub session_id: String,
     pub messages: Vec<ChatMessage>,
@@ -58,9 +816,507 @@ pub struct SyncSessionRequest {
 
 
 // 同步 session 的响应
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct SyncSessionResponse {
     pub session_id: String,
     pub synced: bool,
     pub message_count: usize,
 }
+
+
+// 更新 session 配置的响应
+#[derive(Serialize, ToSchema)]
+pub struct SessionConfigResponse {
+    pub session_id: String,
+    pub config: SessionConfig,
+}
+
+
+// 取消生成的响应
+#[derive(Serialize, ToSchema)]
+pub struct CancelResponse {
+    pub request_id: String,
+    pub cancelled: bool,
+}
+
+
+// ---- GET /health 的各项检查 ----
+
+// 已加载的模型和它们的估算占用（MB），budget 为 model_memory_budget_mb（0 表示不限制）。
+// 加载模型期间模型缓存被锁住，loaded 和 memory_used_mb 为 null
+#[derive(Serialize, ToSchema)]
+pub struct ModelsHealth {
+    pub default_model: String,
+    pub default_model_loaded: bool,
+    pub loaded: Option<Vec<String>>,
+    pub memory_used_mb: Option<u64>,
+    pub memory_budget_mb: u64,
+}
+
+// enabled 为模型是否在 GPU 上运行；显存由 nvidia-smi 查询，查询不到时为 null
+#[derive(Serialize, ToSchema)]
+pub struct GpuHealth {
+    pub enabled: bool,
+    pub index: usize,
+    pub free_vram_mb: Option<u64>,
+    pub total_vram_mb: Option<u64>,
+}
+
+// model_dir 和 file_dir 所在磁盘的可用空间（MB）
+#[derive(Serialize, ToSchema)]
+pub struct DiskHealth {
+    pub model_dir_free_mb: Option<u64>,
+    pub file_dir_free_mb: Option<u64>,
+}
+
+// pending 为正在生成和排队的请求数，达到 capacity 后返回 429
+#[derive(Serialize, ToSchema)]
+pub struct QueueHealth {
+    pub pending: usize,
+    pub capacity: usize,
+    pub max_concurrent: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CacheHealth {
+    pub sessions: usize,
+    pub files: usize,
+    pub active_generations: usize,
+    // 解析结果缓存的条数和文本总字节数
+    pub parsed_files: usize,
+    pub parse_cache_bytes: usize,
+}
+
+/// `GET /health/ready` 的响应，默认模型加载完成前和加载其他模型期间为 503
+#[derive(Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub default_model: String,
+    pub service: ServiceState,
+}
+
+
+/// `/generate/stream` 的 SSE 事件。每个事件的 `event:` 字段为 [`StreamEvent::name`]，
+/// `data:` 字段为 JSON。事件顺序：
+///
+/// - `request` `{"request_id": "..."}`：最先发送，可用于 POST /generate/cancel/{request_id}
+/// - `token` `{"content": "..."}`：每个生成的 token，请求 `logprobs: true` 时带
+///   `"logprobs": {"token": "...", "logprob": -0.1, "top_logprobs": [{"token": "...", "logprob": -0.1}]}`
+/// - `tool_call` `{"name": "...", "arguments": {}}`：模型调用工具（请求带 tools 时）
+/// - `tool_result` `{"name": "...", "output": "...", "success": true}`：工具的结果，之后模型继续生成
+/// - `usage` `{"prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0, "finish_reason": "stop"}`：生成结束后，
+///   达到 max_tokens 时 finish_reason 为 "length"
+/// - `error` `{"error": "...", "request_id": "..."}`：生成失败时；超过 generation_timeout_secs 时带
+///   `"finish_reason": "timeout"`，回答被内容审核拦截时带 `"finish_reason": "content_filter"`。
+///   request_id 与响应头 x-request-id 相同，便于在服务端日志中查找
+/// - `session` `{"session_id": "..."}`：本次对话所属的 session
+/// - `done` `{"timings": {"queue_ms": 0, "ttft_ms": 0, "total_ms": 0, "tokens_per_second": 0.0}}`：
+///   最后一个事件，未开始生成（排队时取消）时为 `{}`
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum StreamEvent {
+    Request { request_id: String },
+    Token {
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        logprobs: Option<TokenLogprobs>,
+    },
+    ToolCall { name: String, arguments: serde_json::Value },
+    ToolResult { name: String, output: String, success: bool },
+    Usage(Usage),
+    Error {
+        error: String,
+        request_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        finish_reason: Option<String>,
+    },
+    Session { session_id: String },
+    Done {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timings: Option<Timings>,
+    },
+}
+
+impl StreamEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::Request { .. } => "request",
+            StreamEvent::Token { .. } => "token",
+            StreamEvent::ToolCall { .. } => "tool_call",
+            StreamEvent::ToolResult { .. } => "tool_result",
+            StreamEvent::Usage(_) => "usage",
+            StreamEvent::Error { .. } => "error",
+            StreamEvent::Session { .. } => "session",
+            StreamEvent::Done { .. } => "done",
+        }
+    }
+}
+
+
+#[derive(Deserialize, ToSchema)]
+pub struct AgentRunRequest {
+    pub task: String,
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    #[serde(default)]
+    pub tools: Vec<String>,
+    // 最多的生成次数，默认 5
+    #[serde(default)]
+    pub max_iterations: Option<usize>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(flatten)]
+    pub generation: GenerationConfig,
+}
+
+
+/// `POST /files/{file_id}/summarize` 的请求体，和 session 无关
+#[derive(Deserialize, ToSchema)]
+pub struct SummarizeRequest {
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    // 摘要的长度上限（词数），默认 200
+    #[serde(default)]
+    pub max_words: Option<usize>,
+    // 为 true 时返回 SSE，见 SummarizeEvent
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(flatten)]
+    pub generation: GenerationConfig,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct SummarizeResponse {
+    pub file_id: String,
+    pub summary: String,
+    // 文档被切成的段数，1 表示整篇文档一次完成摘要
+    pub sections: usize,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+
+/// `POST /files/{file_id}/extract` 的请求体。schema 为 JSON Schema 的子集：
+/// `{"type": "object", "properties": {"total": {"type": "number", "description": "..."}}, "required": ["total"]}`，
+/// 字段类型为 string、number、integer、boolean 或它们的 array，可以有 enum
+#[derive(Deserialize, ToSchema)]
+pub struct ExtractRequest {
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    pub schema: serde_json::Value,
+    #[serde(flatten)]
+    pub generation: GenerationConfig,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ExtractResponse {
+    pub file_id: String,
+    // 按 schema 抽取的对象，没有找到或校验失败的字段为 null
+    pub data: serde_json::Value,
+    // 没有找到的 required 字段
+    pub missing: Vec<String>,
+    // 模型的回答两次都不符合字段类型的字段
+    pub errors: Vec<ExtractFieldError>,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct ExtractFieldError {
+    pub field: String,
+    pub error: String,
+}
+
+
+/// `POST /files/{file_id}/summarize` 在 `"stream": true` 时的 SSE 事件，`event:` 字段为
+/// [`SummarizeEvent::name`]。事件顺序：
+///
+/// - `request` `{"request_id": "..."}`：最先发送，可用于 POST /generate/cancel/{request_id}
+/// - `section` `{"index": 0, "total": 3, "summary": "..."}`：文档有多段时，每段的摘要
+/// - `token` `{"content": "..."}`：最终摘要的内容
+/// - `summary` `{"summary": "...", "sections": 3, "prompt_tokens": 0, ...}`：完整的最终摘要和全部生成的 usage
+/// - `error` `{"error": "...", "request_id": "..."}`：生成失败时，代替 summary
+/// - `done` `{}`：最后一个事件
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum SummarizeEvent {
+    Request { request_id: String },
+    Section { index: usize, total: usize, summary: String },
+    Token { content: String },
+    Summary {
+        summary: String,
+        sections: usize,
+        #[serde(flatten)]
+        usage: Usage,
+    },
+    Error { error: String, request_id: String },
+    Done {},
+}
+
+impl SummarizeEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SummarizeEvent::Request { .. } => "request",
+            SummarizeEvent::Section { .. } => "section",
+            SummarizeEvent::Token { .. } => "token",
+            SummarizeEvent::Summary { .. } => "summary",
+            SummarizeEvent::Error { .. } => "error",
+            SummarizeEvent::Done {} => "done",
+        }
+    }
+}
+
+
+/// `/agent/run` 的 SSE 事件，`event:` 字段为 [`AgentEvent::name`]。事件顺序：
+///
+/// - `request` `{"request_id": "..."}`：最先发送，可用于 POST /generate/cancel/{request_id}
+/// - 每次迭代：`thought` `{"iteration": 1, "content": "..."}`，然后
+///   `tool_call` `{"iteration": 1, "name": "...", "arguments": {}}` 和
+///   `tool_result` `{"iteration": 1, "name": "...", "output": "...", "success": true}`
+/// - `final` `{"content": "...", "iterations": 2, "completed": true, "prompt_tokens": 0, ...}`：
+///   模型给出答案，或用完 max_iterations（completed 为 false）
+/// - `error` `{"error": "...", "request_id": "..."}`：生成失败时，代替 final
+/// - `done` `{}`：最后一个事件
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum AgentEvent {
+    Request { request_id: String },
+    Thought { iteration: usize, content: String },
+    ToolCall { iteration: usize, name: String, arguments: serde_json::Value },
+    ToolResult { iteration: usize, name: String, output: String, success: bool },
+    Final {
+        content: String,
+        iterations: usize,
+        completed: bool,
+        #[serde(flatten)]
+        usage: Usage,
+    },
+    Error { error: String, request_id: String },
+    Done {},
+}
+
+impl AgentEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentEvent::Request { .. } => "request",
+            AgentEvent::Thought { .. } => "thought",
+            AgentEvent::ToolCall { .. } => "tool_call",
+            AgentEvent::ToolResult { .. } => "tool_result",
+            AgentEvent::Final { .. } => "final",
+            AgentEvent::Error { .. } => "error",
+            AgentEvent::Done {} => "done",
+        }
+    }
+}
+
+
+// ---- Ollama 兼容接口（/api/generate、/api/chat、/api/tags），字段与 Ollama API 相同 ----
+
+// Ollama 的采样参数，num_predict 为 -1 时不限制长度
+#[derive(Clone, Debug, Default, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct OllamaOptions {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub num_predict: Option<i64>,
+    /// 同 GenerationConfig::seed：贪心解码，不能和随机采样的参数一起设置
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+impl OllamaOptions {
+    pub fn generation_config(&self) -> GenerationConfig {
+        GenerationConfig {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_tokens: self.num_predict.filter(|n| *n > 0).map(|n| n as usize),
+            seed: self.seed,
+            repetition_penalty: self.repeat_penalty,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            ..Default::default()
+        }
+    }
+}
+
+fn default_stream() -> bool {
+    true
+}
+
+
+#[derive(Deserialize, ToSchema)]
+pub struct OllamaGenerateRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(default)]
+    pub system: Option<String>,
+    // base64 编码的图片
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: OllamaOptions,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct OllamaMessage {
+    pub role: MessageRole,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+
+#[derive(Deserialize, ToSchema)]
+pub struct OllamaChatRequest {
+    pub model: String,
+    pub messages: Vec<OllamaMessage>,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: OllamaOptions,
+}
+
+
+// 最后一行（done = true）附带的统计，时间单位为纳秒
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct OllamaStats {
+    pub done_reason: String,
+    pub total_duration: u64,
+    pub prompt_eval_count: usize,
+    pub eval_count: usize,
+}
+
+
+/// `/api/generate` 和 `/api/chat` 的响应。流式时每行一个（application/x-ndjson），
+/// generate 使用 response 字段，chat 使用 message 字段
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct OllamaResponse {
+    pub model: String,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<OllamaMessage>,
+    pub done: bool,
+    #[serde(flatten)]
+    pub stats: Option<OllamaStats>,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct OllamaModelDetails {
+    pub format: String,
+    pub family: String,
+    pub parameter_size: String,
+    pub quantization_level: String,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct OllamaModel {
+    pub name: String,
+    pub model: String,
+    pub modified_at: String,
+    // 已下载的 GGUF 文件大小（字节），未下载时为 0
+    pub size: u64,
+    pub digest: String,
+    pub details: OllamaModelDetails,
+}
+
+
+#[derive(Serialize, ToSchema)]
+pub struct OllamaTagsResponse {
+    pub models: Vec<OllamaModel>,
+}
+
+
+// 审计日志的查询条件，都可以省略。since / until 为 unix 时间戳（秒），limit 默认 100，最大 1000
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub since: Option<u64>,
+    #[serde(default)]
+    pub until: Option<u64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+
+// 最新的记录在前
+#[derive(Serialize, ToSchema)]
+pub struct AuditLogResponse {
+    pub records: Vec<AuditRecord>,
+}
+
+
+// 管理员可以查询其他用户的用量
+#[derive(Deserialize, IntoParams)]
+pub struct UsageQuery {
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+
+// 配额为 0 表示不限制
+#[derive(Serialize, ToSchema)]
+pub struct UsageResponse {
+    pub owner: String,
+    pub usage: KeyUsage,
+    pub quota: TokenQuota,
+    // owner 所属的租户及其全部用户的用量之和，未配置 api_keys 时为 null
+    pub tenant: Option<String>,
+    pub tenant_usage: Option<KeyUsage>,
+}
+
+
+/// `POST /admin/benchmark` 的请求体，都可以省略，model_name 为空时使用默认模型
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct BenchmarkRequest {
+    #[serde(rename = "model_name", default)]
+    pub model: String,
+    // 记录在结果中，例如 "a10g, driver 550"
+    #[serde(default)]
+    pub label: Option<String>,
+    // 每个测试 prompt 运行的次数，默认 1，最大 10
+    #[serde(default)]
+    pub runs: Option<usize>,
+    // 每次生成的 token 数，默认 128
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+}
+
+
+// 历史结果的查询条件，limit 默认 20，最大 1000
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct BenchmarkQuery {
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+
+// 最新的结果在前
+#[derive(Serialize, ToSchema)]
+pub struct BenchmarkHistoryResponse {
+    pub runs: Vec<BenchmarkRun>,
+}
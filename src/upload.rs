@@ -0,0 +1,248 @@
+use dashmap::DashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use crate::config::ServerConfig;
+use crate::types::{UploadProgress, UploadStatus};
+
+
+// 结束的上传的进度保留的时间，之后查询返回 404
+const FINISHED_TTL: Duration = Duration::from_secs(10 * 60);
+const MAX_UPLOAD_ID_LEN: usize = 64;
+
+
+/// 上传中的文件写入的临时目录
+pub fn spool_dir(config: &ServerConfig) -> PathBuf {
+    if config.upload_spool_dir.is_empty() {
+        std::env::temp_dir().join("llm-uploads")
+    } else {
+        PathBuf::from(&config.upload_spool_dir)
+    }
+}
+
+
+/// 写入磁盘的上传文件，收到的内容不在内存中累积。drop 时删除临时文件
+pub struct SpoolFile {
+    path: PathBuf,
+    file: Option<tokio::fs::File>,
+    size: usize,
+}
+
+impl SpoolFile {
+    pub async fn create(dir: &Path) -> io::Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}.part", uuid::Uuid::new_v4()));
+        let file = tokio::fs::File::create(&path).await?;
+        Ok(Self { path, file: Some(file), size: 0 })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Err(io::Error::other("spool file is already finished"));
+        };
+        file.write_all(chunk).await?;
+        self.size += chunk.len();
+        Ok(())
+    }
+
+    /// 文件收完后调用，之后只能读取
+    pub async fn finish(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub async fn read(&self) -> io::Result<Vec<u8>> {
+        tokio::fs::read(&self.path).await
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!(path = %self.path.display(), error = %e, "Failed to remove spooled upload");
+        }
+    }
+}
+
+
+pub fn valid_upload_id(upload_id: &str) -> bool {
+    !upload_id.is_empty()
+        && upload_id.len() <= MAX_UPLOAD_ID_LEN
+        && upload_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+
+struct TrackedUpload {
+    tenant: Option<String>,
+    progress: UploadProgress,
+    updated_at: Instant,
+}
+
+
+/// 按客户端提供的 upload_id 记录上传进度，只在处理上传的实例上可见
+#[derive(Default)]
+pub struct UploadTracker {
+    uploads: DashMap<String, TrackedUpload>,
+}
+
+pub type SharedUploadTracker = Arc<UploadTracker>;
+
+pub fn new_upload_tracker() -> SharedUploadTracker {
+    Arc::new(UploadTracker::default())
+}
+
+impl UploadTracker {
+    /// 开始记录一次上传。同一个 ID 的上传还在进行中时返回 None
+    pub fn start(
+        self: &Arc<Self>,
+        upload_id: &str,
+        tenant: Option<&str>,
+        total_bytes: Option<u64>,
+    ) -> Option<UploadGuard> {
+        self.prune(Instant::now());
+        if self.uploads.get(upload_id).is_some_and(|upload| upload.progress.status == UploadStatus::Uploading) {
+            return None;
+        }
+        self.uploads.insert(upload_id.to_string(), TrackedUpload {
+            tenant: tenant.map(str::to_string),
+            progress: UploadProgress {
+                upload_id: upload_id.to_string(),
+                status: UploadStatus::Uploading,
+                received_bytes: 0,
+                total_bytes,
+                files: 0,
+                error: None,
+            },
+            updated_at: Instant::now(),
+        });
+        Some(UploadGuard { tracker: self.clone(), upload_id: upload_id.to_string() })
+    }
+
+    /// 只返回同一租户的上传
+    pub fn get(&self, tenant: Option<&str>, upload_id: &str) -> Option<UploadProgress> {
+        self.uploads.get(upload_id)
+            .filter(|upload| upload.tenant.as_deref() == tenant)
+            .map(|upload| upload.progress.clone())
+    }
+
+    fn update(&self, upload_id: &str, f: impl FnOnce(&mut UploadProgress)) {
+        if let Some(mut upload) = self.uploads.get_mut(upload_id) {
+            f(&mut upload.progress);
+            upload.updated_at = Instant::now();
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        self.uploads.retain(|_, upload| {
+            upload.progress.status == UploadStatus::Uploading || now.duration_since(upload.updated_at) < FINISHED_TTL
+        });
+    }
+}
+
+
+/// 一次上传的进度。没有调用 complete 或 fail 就被 drop（例如客户端断开）时记为失败
+pub struct UploadGuard {
+    tracker: SharedUploadTracker,
+    upload_id: String,
+}
+
+impl UploadGuard {
+    pub fn received(&self, bytes: usize) {
+        self.tracker.update(&self.upload_id, |progress| progress.received_bytes += bytes as u64);
+    }
+
+    pub fn file_received(&self) {
+        self.tracker.update(&self.upload_id, |progress| progress.files += 1);
+    }
+
+    pub fn complete(self) {
+        self.tracker.update(&self.upload_id, |progress| progress.status = UploadStatus::Completed);
+    }
+
+    pub fn fail(self, error: String) {
+        self.tracker.update(&self.upload_id, |progress| {
+            progress.status = UploadStatus::Failed;
+            progress.error = Some(error);
+        });
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.tracker.update(&self.upload_id, |progress| {
+            if progress.status == UploadStatus::Uploading {
+                progress.status = UploadStatus::Failed;
+                progress.error = Some("Upload interrupted".to_string());
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_progress_lifecycle() {
+        let tracker = new_upload_tracker();
+        let guard = tracker.start("up-1", Some("acme"), Some(1000)).unwrap();
+        assert!(tracker.start("up-1", Some("acme"), None).is_none());
+
+        guard.received(400);
+        guard.received(200);
+        guard.file_received();
+        let progress = tracker.get(Some("acme"), "up-1").unwrap();
+        assert_eq!(progress.status, UploadStatus::Uploading);
+        assert_eq!(progress.received_bytes, 600);
+        assert_eq!(progress.total_bytes, Some(1000));
+        assert_eq!(progress.files, 1);
+        assert!(tracker.get(Some("other"), "up-1").is_none());
+
+        guard.complete();
+        assert_eq!(tracker.get(Some("acme"), "up-1").unwrap().status, UploadStatus::Completed);
+
+        // a finished id can be reused
+        let again = tracker.start("up-1", Some("acme"), None).unwrap();
+        drop(again);
+        let progress = tracker.get(Some("acme"), "up-1").unwrap();
+        assert_eq!(progress.status, UploadStatus::Failed);
+        assert_eq!(progress.error.as_deref(), Some("Upload interrupted"));
+
+        tracker.prune(Instant::now() + FINISHED_TTL);
+        assert!(tracker.get(Some("acme"), "up-1").is_none());
+    }
+
+    #[test]
+    fn test_upload_id_format() {
+        assert!(valid_upload_id("3f2c9a1e-upload_7"));
+        assert!(!valid_upload_id(""));
+        assert!(!valid_upload_id("../etc"));
+        assert!(!valid_upload_id(&"a".repeat(65)));
+    }
+
+    #[tokio::test]
+    async fn test_spool_file_is_removed_on_drop() {
+        let dir = std::env::temp_dir().join(format!("spool-test-{}", uuid::Uuid::new_v4()));
+        let mut spool = SpoolFile::create(&dir).await.unwrap();
+        spool.write(b"hello ").await.unwrap();
+        spool.write(b"world").await.unwrap();
+        spool.finish().await.unwrap();
+        assert_eq!(spool.size(), 11);
+        assert_eq!(spool.read().await.unwrap(), b"hello world");
+        assert!(spool.write(b"!").await.is_err());
+
+        let path = spool.path.clone();
+        drop(spool);
+        assert!(!path.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
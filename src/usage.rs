@@ -0,0 +1,263 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use utoipa::ToSchema;
+use crate::json_file::JsonFile;
+
+
+// 未配置 api_keys 时所有请求计入这个名称
+pub const ANONYMOUS: &str = "anonymous";
+
+
+/// 一个 API key（按 api_keys 中的用户名）累计的用量。day / month 为 UTC 日期，
+/// 进入新的一天或一个月时对应的计数从 0 开始
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct KeyUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    // YYYY-MM-DD
+    pub day: String,
+    pub day_tokens: u64,
+    // YYYY-MM
+    pub month: String,
+    pub month_tokens: u64,
+}
+
+impl KeyUsage {
+    // 切换到 now 所在的日期和月份
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let day = now.format("%Y-%m-%d").to_string();
+        if self.day != day {
+            self.day = day;
+            self.day_tokens = 0;
+        }
+        let month = now.format("%Y-%m").to_string();
+        if self.month != month {
+            self.month = month;
+            self.month_tokens = 0;
+        }
+    }
+
+    fn add(&mut self, prompt_tokens: u64, completion_tokens: u64, now: DateTime<Utc>) {
+        self.roll(now);
+        let tokens = prompt_tokens + completion_tokens;
+        self.requests += 1;
+        self.prompt_tokens += prompt_tokens;
+        self.completion_tokens += completion_tokens;
+        self.total_tokens += tokens;
+        self.day_tokens += tokens;
+        self.month_tokens += tokens;
+    }
+}
+
+
+/// 每个 key 每天 / 每月最多使用的 token 数（prompt 和生成合计），0 表示不限制
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct TokenQuota {
+    pub daily: u64,
+    pub monthly: u64,
+}
+
+
+/// 超出配额：period 为 "day" 或 "month"，reset_at 为计数清零的 unix 时间戳
+#[derive(Debug, PartialEq)]
+pub struct QuotaExceeded {
+    pub period: &'static str,
+    pub limit: u64,
+    pub used: u64,
+    pub reset_at: i64,
+}
+
+
+/// 按用户名保存的用量计数。设置 path 时每次更新后写入该 JSON 文件，重启后继续累计
+pub struct UsageTracker {
+    usage: DashMap<String, KeyUsage>,
+    file: Option<JsonFile>,
+}
+
+pub type SharedUsageTracker = Arc<UsageTracker>;
+
+/// 读取保存的用量，path 为空时只保存在内存中，文件不存在时从 0 开始
+pub async fn load_usage(path: &str) -> Result<SharedUsageTracker> {
+    let usage = DashMap::new();
+    let path = (!path.is_empty()).then(|| PathBuf::from(path));
+
+    if let Some(path) = &path {
+        match fs::read(path).await {
+            Ok(data) => {
+                let saved: BTreeMap<String, KeyUsage> = serde_json::from_slice(&data)?;
+                tracing::info!(keys = saved.len(), path = %path.display(), "Loaded usage counters");
+                for (owner, key_usage) in saved {
+                    usage.insert(owner, key_usage);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(Arc::new(UsageTracker {
+        usage,
+        file: path.map(JsonFile::new),
+    }))
+}
+
+impl UsageTracker {
+    /// owner 到 now 为止的用量
+    pub fn get(&self, owner: &str, now: DateTime<Utc>) -> KeyUsage {
+        let mut key_usage = self.usage.get(owner).map(|entry| entry.clone()).unwrap_or_default();
+        key_usage.roll(now);
+        key_usage
+    }
+
+    /// member 为 true 的所有用户（例如同一租户的用户）的用量之和
+    pub fn total(&self, member: impl Fn(&str) -> bool, now: DateTime<Utc>) -> KeyUsage {
+        let mut total = KeyUsage::default();
+        total.roll(now);
+        for entry in self.usage.iter().filter(|entry| member(entry.key())) {
+            let mut key_usage = entry.value().clone();
+            key_usage.roll(now);
+            total.requests += key_usage.requests;
+            total.prompt_tokens += key_usage.prompt_tokens;
+            total.completion_tokens += key_usage.completion_tokens;
+            total.total_tokens += key_usage.total_tokens;
+            total.day_tokens += key_usage.day_tokens;
+            total.month_tokens += key_usage.month_tokens;
+        }
+        total
+    }
+
+    /// 把一次生成的 token 计入 owner（None 时为 ANONYMOUS）
+    pub async fn add(&self, owner: Option<&str>, prompt_tokens: usize, completion_tokens: usize) {
+        let owner = owner.unwrap_or(ANONYMOUS);
+        self.usage
+            .entry(owner.to_string())
+            .or_default()
+            .add(prompt_tokens as u64, completion_tokens as u64, Utc::now());
+        self.save().await;
+    }
+
+    /// 请求开始前检查 owner 是否已用完当天或当月的配额。
+    /// 正在进行的生成不计入，所以最后一个请求可能让用量略超过配额
+    pub fn check(&self, owner: Option<&str>, quota: TokenQuota, now: DateTime<Utc>) -> Result<(), QuotaExceeded> {
+        let key_usage = self.get(owner.unwrap_or(ANONYMOUS), now);
+        if quota.daily > 0 && key_usage.day_tokens >= quota.daily {
+            return Err(QuotaExceeded {
+                period: "day",
+                limit: quota.daily,
+                used: key_usage.day_tokens,
+                reset_at: next_day(now),
+            });
+        }
+        if quota.monthly > 0 && key_usage.month_tokens >= quota.monthly {
+            return Err(QuotaExceeded {
+                period: "month",
+                limit: quota.monthly,
+                used: key_usage.month_tokens,
+                reset_at: next_month(now),
+            });
+        }
+        Ok(())
+    }
+
+    async fn save(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let snapshot = || -> BTreeMap<String, KeyUsage> {
+            self.usage.iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect()
+        };
+        if let Err(e) = file.save(snapshot).await {
+            tracing::error!(path = %file.path().display(), error = %e, "Failed to save usage counters");
+        }
+    }
+}
+
+
+// 下一个 UTC 零点
+fn next_day(now: DateTime<Utc>) -> i64 {
+    let tomorrow = now.date_naive() + Duration::days(1);
+    tomorrow.and_hms_opt(0, 0, 0).map_or(0, |t| t.and_utc().timestamp())
+}
+
+// 下个月 1 日的 UTC 零点
+fn next_month(now: DateTime<Utc>) -> i64 {
+    let (year, month) = if now.month() == 12 { (now.year() + 1, 1) } else { (now.year(), now.month() + 1) };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map_or(0, |t| t.and_utc().timestamp())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_counters_reset_per_day_and_month() {
+        let mut usage = KeyUsage::default();
+        usage.add(10, 5, at(2025, 1, 31, 12));
+        usage.add(1, 1, at(2025, 1, 31, 23));
+        assert_eq!((usage.day_tokens, usage.month_tokens), (17, 17));
+
+        usage.add(3, 0, at(2025, 2, 1, 0));
+        assert_eq!(usage.day, "2025-02-01");
+        assert_eq!((usage.day_tokens, usage.month_tokens, usage.total_tokens), (3, 3, 20));
+        assert_eq!(usage.requests, 3);
+    }
+
+    #[tokio::test]
+    async fn test_quota_check_and_persistence() {
+        let dir = std::env::temp_dir().join(format!("usage-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("usage.json");
+        let tracker = load_usage(path.to_str().unwrap()).await.unwrap();
+        tracker.add(Some("alice"), 60, 40).await;
+
+        let now = Utc::now();
+        let quota = TokenQuota { daily: 100, monthly: 0 };
+        let exceeded = tracker.check(Some("alice"), quota, now).unwrap_err();
+        assert_eq!((exceeded.period, exceeded.used), ("day", 100));
+        assert_eq!(exceeded.reset_at, next_day(now));
+        assert!(tracker.check(Some("bob"), quota, now).is_ok());
+        assert!(tracker.check(Some("alice"), TokenQuota::default(), now).is_ok());
+
+        let reloaded = load_usage(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(reloaded.get("alice", now).total_tokens, 100);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_total_of_members() {
+        let tracker = load_usage("").await.unwrap();
+        tracker.add(Some("alice"), 10, 5).await;
+        tracker.add(Some("bob"), 1, 1).await;
+        tracker.add(Some("carol"), 100, 0).await;
+
+        let total = tracker.total(|user| user == "alice" || user == "bob", Utc::now());
+        assert_eq!((total.requests, total.total_tokens, total.day_tokens), (2, 17, 17));
+    }
+
+    #[test]
+    fn test_reset_times() {
+        assert_eq!(next_day(at(2025, 12, 31, 15)), at(2026, 1, 1, 0).timestamp());
+        assert_eq!(next_month(at(2025, 12, 31, 15)), at(2026, 1, 1, 0).timestamp());
+        assert_eq!(next_month(at(2025, 3, 10, 0)), at(2025, 4, 1, 0).timestamp());
+    }
+}
@@ -0,0 +1,164 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::{CONTENT_LENGTH, CONTENT_TYPE}, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use crate::AppState;
+use crate::error::{BodyTooLargeError, PromptTooLongError, ValidationError};
+use crate::types::GenerationConfig;
+
+
+pub fn invalid(field: &str, error: impl Into<String>) -> Response {
+    (StatusCode::BAD_REQUEST, Json(ValidationError {
+        error: error.into(),
+        field: field.to_string(),
+    })).into_response()
+}
+
+pub fn prompt_too_long(field: &str, length: usize, limit: usize, unit: &str) -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(PromptTooLongError {
+        error: format!("{} is too long: {} {}, at most {} allowed", field, length, unit, limit),
+        field: field.to_string(),
+        length,
+        limit,
+        unit: unit.to_string(),
+    })).into_response()
+}
+
+
+/// prompt 不能为空（只有空白也算空），字符数不超过 max_chars（0 表示不限制）
+pub fn validate_prompt(field: &str, text: &str, max_chars: usize) -> Result<(), Response> {
+    if text.trim().is_empty() {
+        return Err(invalid(field, format!("{} must not be empty", field)));
+    }
+    let chars = text.chars().count();
+    if max_chars > 0 && chars > max_chars {
+        return Err(prompt_too_long(field, chars, max_chars, "chars"));
+    }
+    Ok(())
+}
+
+
+// 采样参数的取值范围，超出范围时返回出错的字段和原因。NaN 不在任何范围内
+pub fn check_sampling(config: &GenerationConfig) -> Result<(), (&'static str, &'static str)> {
+    if config.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+        return Err(("temperature", "temperature must be between 0 and 2"));
+    }
+    if config.top_p.is_some_and(|p| p.is_nan() || p <= 0.0 || p > 1.0) {
+        return Err(("top_p", "top_p must be greater than 0 and at most 1"));
+    }
+    if config.max_tokens == Some(0) {
+        return Err(("max_tokens", "max_tokens must be at least 1"));
+    }
+    if config.repetition_penalty.is_some_and(|p| p.is_nan() || p <= 0.0) {
+        return Err(("repetition_penalty", "repetition_penalty must be greater than 0"));
+    }
+    if config.presence_penalty.is_some_and(|p| !(-2.0..=2.0).contains(&p)) {
+        return Err(("presence_penalty", "presence_penalty must be between -2 and 2"));
+    }
+    if config.frequency_penalty.is_some_and(|p| !(-2.0..=2.0).contains(&p)) {
+        return Err(("frequency_penalty", "frequency_penalty must be between -2 and 2"));
+    }
+    // 带 seed 的请求按贪心解码（见 sampling_params），不能同时要求随机采样
+    let sampling = config.temperature.is_some_and(|t| t > 0.0)
+        || config.top_k.is_some_and(|k| k > 1)
+        || config.top_p.is_some_and(|p| p < 1.0);
+    if config.seed.is_some() && sampling {
+        return Err(("seed", "seed makes decoding greedy and cannot be combined with temperature, top_k or top_p sampling; \
+            remove the seed, or set temperature to 0"));
+    }
+    Ok(())
+}
+
+pub fn validate_sampling(config: &GenerationConfig) -> Result<(), Response> {
+    check_sampling(config).map_err(|(field, error)| invalid(field, error))
+}
+
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim_start().to_ascii_lowercase().starts_with("application/json"))
+}
+
+fn body_too_large(limit: usize) -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(BodyTooLargeError {
+        error: format!("JSON body is larger than {} bytes", limit),
+        limit,
+    })).into_response()
+}
+
+/// JSON 请求体不超过 max_json_body_size（0 表示不限制），上传文件的 multipart 请求仍由 max_upload_size 限制
+pub async fn limit_json_body(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let limit = state.config.max_json_body_size;
+    if limit == 0 || !is_json(request.headers()) {
+        return next.run(request).await;
+    }
+
+    let declared = request.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return body_too_large(limit);
+    }
+
+    // chunked bodies have no Content-Length, so read at most `limit` bytes before passing the body on.
+    // a client that disconnects mid-body also ends up here, nobody reads that response
+    let (parts, body) = request.into_parts();
+    match axum::body::to_bytes(body, limit).await {
+        Ok(bytes) => next.run(Request::from_parts(parts, Body::from(bytes))).await,
+        Err(_) => body_too_large(limit),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_prompt() {
+        assert!(validate_prompt("prompt", "Hello", 10).is_ok());
+        assert!(validate_prompt("prompt", &"a".repeat(50), 0).is_ok());
+        assert_eq!(validate_prompt("prompt", " \n\t", 10).unwrap_err().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(validate_prompt("prompt", "Hello world", 10).unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // the limit counts characters, not bytes
+        assert!(validate_prompt("prompt", "你好世界", 4).is_ok());
+    }
+
+    #[test]
+    fn test_sampling_ranges() {
+        assert!(check_sampling(&GenerationConfig::default()).is_ok());
+        assert!(check_sampling(&GenerationConfig { temperature: Some(0.0), top_p: Some(1.0), ..Default::default() }).is_ok());
+        assert!(check_sampling(&GenerationConfig { seed: Some(7), temperature: Some(0.0), ..Default::default() }).is_ok());
+
+        let cases = [
+            (GenerationConfig { temperature: Some(-0.5), ..Default::default() }, "temperature"),
+            (GenerationConfig { temperature: Some(f64::NAN), ..Default::default() }, "temperature"),
+            (GenerationConfig { top_p: Some(0.0), ..Default::default() }, "top_p"),
+            (GenerationConfig { max_tokens: Some(0), ..Default::default() }, "max_tokens"),
+            (GenerationConfig { repetition_penalty: Some(0.0), ..Default::default() }, "repetition_penalty"),
+            (GenerationConfig { frequency_penalty: Some(3.0), ..Default::default() }, "frequency_penalty"),
+            (GenerationConfig { seed: Some(7), temperature: Some(0.8), ..Default::default() }, "seed"),
+            (GenerationConfig { seed: Some(7), top_k: Some(40), ..Default::default() }, "seed"),
+            (GenerationConfig { seed: Some(7), top_p: Some(0.9), ..Default::default() }, "seed"),
+        ];
+        for (config, field) in cases {
+            assert_eq!(check_sampling(&config).unwrap_err().0, field);
+        }
+    }
+
+    #[test]
+    fn test_json_content_type() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_json(&headers));
+        headers.insert(CONTENT_TYPE, "application/json; charset=utf-8".parse().unwrap());
+        assert!(is_json(&headers));
+        headers.insert(CONTENT_TYPE, "multipart/form-data; boundary=x".parse().unwrap());
+        assert!(!is_json(&headers));
+    }
+}
@@ -0,0 +1,63 @@
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use rust_embed::RustEmbed;
+use crate::AppState;
+
+
+// web/ 下的静态文件在编译时打包进二进制文件，运行时不需要额外的文件
+#[derive(RustEmbed)]
+#[folder = "web/"]
+struct Assets;
+
+
+fn asset(path: &str) -> Response {
+    match Assets::get(path) {
+        Some(file) => (
+            [
+                (header::CONTENT_TYPE, file.metadata.mimetype().to_string()),
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+            ],
+            file.data,
+        ).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn index() -> Response {
+    asset("index.html")
+}
+
+async fn static_file(Path(path): Path<String>) -> Response {
+    asset(&path)
+}
+
+
+/// 内置的聊天页面：`/` 为页面，`/ui/...` 为它的脚本和样式。页面本身不需要认证，
+/// 调用 API 时使用页面中填写的 API key
+pub fn web_routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(index))
+        .route("/ui/{*path}", get(static_file))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assets_are_embedded() {
+        let response = asset("index.html");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+
+        let script = asset("app.js");
+        assert!(script.headers()[header::CONTENT_TYPE].to_str().unwrap().contains("javascript"));
+        assert_eq!(asset("missing.js").status(), StatusCode::NOT_FOUND);
+    }
+}
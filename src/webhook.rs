@@ -0,0 +1,357 @@
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::Serialize;
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
+use crate::file_store::unix_now;
+
+
+// 发送失败（连接失败、超时或 5xx）时最多尝试的次数，以及第一次重试前的等待，之后每次加倍
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+
+
+/// 回调的 body。接收方用 webhook_secret 对 `"{X-Webhook-Timestamp}.{body}"` 计算 HMAC-SHA256，
+/// 和 `X-Webhook-Signature`（`sha256=<hex>`）比较
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookPayload {
+    // "generation.completed"、"generation.failed" 或 "batch.completed"
+    pub event: String,
+    pub request_id: String,
+    pub timestamp: u64,
+    // 完成时为同步请求会得到的响应（InferenceResponse 或 BatchItem 数组）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl WebhookPayload {
+    pub fn completed(event: &str, request_id: &str, result: &impl Serialize) -> Self {
+        Self {
+            event: event.to_string(),
+            request_id: request_id.to_string(),
+            timestamp: unix_now(),
+            result: serde_json::to_value(result).ok(),
+            error: None,
+        }
+    }
+
+    pub fn failed(event: &str, request_id: &str, error: String) -> Self {
+        Self {
+            event: event.to_string(),
+            request_id: request_id.to_string(),
+            timestamp: unix_now(),
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+
+fn hmac_hex(secret: &str, message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// X-Webhook-Signature 的值，签名的内容包括时间戳，防止旧的回调被重放
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(body);
+    format!("sha256={}", hmac_hex(secret, &message))
+}
+
+
+/// 把生成的结果 POST 到请求的 callback_url。未设置 webhook_secret 时不接受 callback_url。
+/// 不跟随重定向；没有列在 webhook_allowed_hosts 中的 host 只能是公网地址（防止 SSRF 访问内网和云的元数据服务）
+pub struct WebhookSender {
+    client: reqwest::Client,
+    timeout: Duration,
+    secret: String,
+    // 为空时允许任意公网 host
+    allowed_hosts: Vec<String>,
+}
+
+pub type SharedWebhookSender = Arc<WebhookSender>;
+
+fn client_builder(timeout: Duration) -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none())
+}
+
+pub fn new_webhook_sender(secret: &str, allowed_hosts: &[String], timeout_secs: u64) -> SharedWebhookSender {
+    let timeout = Duration::from_secs(timeout_secs.max(1));
+    Arc::new(WebhookSender {
+        client: client_builder(timeout).build().unwrap_or_default(),
+        timeout,
+        secret: secret.to_string(),
+        allowed_hosts: allowed_hosts.to_vec(),
+    })
+}
+
+impl WebhookSender {
+    pub fn enabled(&self) -> bool {
+        !self.secret.is_empty()
+    }
+
+    /// 校验请求中的 callback_url：必须是 http 或 https，配置了 webhook_allowed_hosts 时 host 必须在其中
+    pub fn check_url(&self, url: &str) -> Result<Url, String> {
+        if !self.enabled() {
+            return Err("Webhooks are not enabled on this server".to_string());
+        }
+        check_url(url, &self.allowed_hosts)
+    }
+
+    // 发送用的 client：host 在允许列表中时直接使用；否则解析域名，所有地址都必须是公网地址，
+    // 并固定连接到检查过的地址（防止 DNS rebinding）
+    async fn client_for(&self, url: &Url) -> Result<reqwest::Client, String> {
+        let domain = url.host_str().unwrap_or("");
+        // IP 地址在 check_url 中已检查
+        if host_ip(domain).is_some() || host_allowed(domain, &self.allowed_hosts) {
+            return Ok(self.client.clone());
+        }
+
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, port)).await
+            .map_err(|e| format!("Failed to resolve {}: {}", domain, e))?
+            .collect();
+        if let Some(addr) = addrs.iter().find(|addr| !is_global(addr.ip())) {
+            return Err(format!("{} resolves to non-public address {}", domain, addr.ip()));
+        }
+        let Some(addr) = addrs.first() else {
+            return Err(format!("{} has no addresses", domain));
+        };
+        client_builder(self.timeout)
+            .resolve(domain, *addr)
+            .build()
+            .map_err(|e| e.to_string())
+    }
+
+    /// 发送回调，失败时按 RETRY_DELAY 加倍重试，最后仍失败时只记录日志
+    pub async fn deliver(&self, url: &Url, payload: &WebhookPayload) {
+        let client = match self.client_for(url).await {
+            Ok(client) => client,
+            Err(error) => {
+                tracing::warn!(request_id = %payload.request_id, url = %url, error = %error, "Webhook target rejected");
+                return;
+            }
+        };
+        let body = match serde_json::to_vec(payload) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!(request_id = %payload.request_id, error = %e, "Failed to serialize webhook payload");
+                return;
+            }
+        };
+        let signature = sign(&self.secret, payload.timestamp, &body);
+
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = client.post(url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(TIMESTAMP_HEADER, payload.timestamp.to_string())
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await;
+
+            let error = match result {
+                Ok(response) if response.status().is_success() => {
+                    tracing::info!(request_id = %payload.request_id, url = %url, event = %payload.event, "Webhook delivered");
+                    return;
+                }
+                // 重定向不跟随，4xx 说明接收方拒绝了这个回调，重试也不会成功
+                Ok(response) if response.status().is_redirection() || response.status().is_client_error() => {
+                    tracing::warn!(request_id = %payload.request_id, url = %url, status = %response.status(), "Webhook rejected");
+                    return;
+                }
+                Ok(response) => format!("status {}", response.status()),
+                Err(e) => e.to_string(),
+            };
+
+            tracing::warn!(request_id = %payload.request_id, url = %url, attempt, error = %error, "Webhook delivery failed");
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        tracing::error!(request_id = %payload.request_id, url = %url, "Giving up on webhook");
+    }
+}
+
+// URL 中的 IPv6 地址带有方括号
+fn host_ip(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+fn host_allowed(host: &str, allowed_hosts: &[String]) -> bool {
+    allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host))
+}
+
+fn check_url(url: &str, allowed_hosts: &[String]) -> Result<Url, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid callback_url: {}", e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err("callback_url must be an http or https URL".to_string());
+    }
+    let host = parsed.host_str().unwrap_or("");
+    if host_allowed(host, allowed_hosts) {
+        return Ok(parsed);
+    }
+    if !allowed_hosts.is_empty() {
+        return Err(format!("callback_url host \"{}\" is not allowed", host));
+    }
+
+    if host_ip(host).is_some_and(|ip| !is_global(ip)) || host.eq_ignore_ascii_case("localhost") {
+        return Err(format!("callback_url host \"{}\" is not a public address, add it to webhook_allowed_hosts", host));
+    }
+    Ok(parsed)
+}
+
+
+/// 公网地址：排除 loopback、私有网络（RFC 1918、ULA）、link-local（包括 169.254.169.254）、
+/// CGNAT、文档和保留地址段、组播
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_global_v4(ip),
+            None => is_global_v6(ip),
+        },
+    }
+}
+
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        || a >= 240
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b)))
+}
+
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 unique local, fe80::/10 link-local, 2001:db8::/32 documentation
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_matches_rfc_4231() {
+        assert_eq!(
+            hmac_hex("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+    }
+
+    #[test]
+    fn test_signature_covers_timestamp_and_body() {
+        let signature = sign("secret", 1700000000, br#"{"event":"x"}"#);
+        assert_eq!(signature, "sha256=ca458d767c4041a70394d25abfdb6d6bc3777d2044b7dcf28fcc31911996fde4");
+        assert_ne!(signature, sign("secret", 1700000001, br#"{"event":"x"}"#));
+    }
+
+    #[test]
+    fn test_callback_url_checks() {
+        let allowed = vec!["hooks.example.com".to_string()];
+        assert!(check_url("https://hooks.example.com/llm", &allowed).is_ok());
+        assert!(check_url("https://evil.example.com/llm", &allowed).is_err());
+        assert!(check_url("https://hooks.example.com/llm", &[]).is_ok());
+        assert!(check_url("ftp://hooks.example.com/llm", &[]).is_err());
+        assert!(check_url("not a url", &[]).is_err());
+
+        let disabled = new_webhook_sender("", &[], 10);
+        assert!(disabled.check_url("https://hooks.example.com/llm").is_err());
+    }
+
+    #[test]
+    fn test_private_callback_hosts_need_allow_list() {
+        for url in [
+            "http://127.0.0.1:9000/done",
+            "http://localhost/done",
+            "http://10.1.2.3/done",
+            "http://192.168.0.10/done",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/done",
+            "http://[::1]/done",
+            "http://[fd00::1]/done",
+            "http://[::ffff:10.0.0.1]/done",
+        ] {
+            assert!(check_url(url, &[]).is_err(), "{}", url);
+        }
+        assert!(check_url("http://8.8.8.8/done", &[]).is_ok());
+        assert!(check_url("http://[2606:4700::1111]/done", &[]).is_ok());
+
+        let allowed = vec!["127.0.0.1".to_string()];
+        assert!(check_url("http://127.0.0.1:9000/done", &allowed).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unlisted_domains_must_resolve_to_public_addresses() {
+        let sender = new_webhook_sender("secret", &[], 10);
+        let url = Url::parse("http://localhost:9000/done").unwrap();
+        assert!(sender.client_for(&url).await.is_err());
+
+        let listed = new_webhook_sender("secret", &["localhost".to_string()], 10);
+        assert!(listed.client_for(&url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_not_followed() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        // the redirect target counts the connections it gets
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let target_hits = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = target.accept().await {
+                target_hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let _ = socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await;
+            }
+        });
+
+        let hook = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook_addr = hook.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = hook.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 302 Found\r\nlocation: http://{}/stolen\r\ncontent-length: 0\r\n\r\n", target_addr);
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let sender = new_webhook_sender("secret", &["127.0.0.1".to_string()], 5);
+        let url = sender.check_url(&format!("http://{}/hook", hook_addr)).unwrap();
+        sender.deliver(&url, &WebhookPayload::failed("generation.failed", "req-1", "boom".to_string())).await;
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+}
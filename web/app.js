@@ -0,0 +1,327 @@
+// Minimal chat client for the server it is served from: /generate/stream, /upload and /sessions.
+"use strict";
+
+const $ = (id) => document.getElementById(id);
+
+const state = {
+  sessionId: null,
+  requestId: null,
+  streaming: false,
+};
+
+
+function headers(extra = {}) {
+  const key = localStorage.getItem("apiKey");
+  return key ? { ...extra, Authorization: `Bearer ${key}` } : extra;
+}
+
+// fetch + JSON, non-2xx responses throw the server's `error` message
+async function api(method, path, body) {
+  const init = { method, headers: headers() };
+  if (body instanceof FormData) {
+    init.body = body;
+  } else if (body !== undefined) {
+    init.headers = headers({ "Content-Type": "application/json" });
+    init.body = JSON.stringify(body);
+  }
+  const response = await fetch(path, init);
+  if (!response.ok) throw await responseError(response);
+  return response.json();
+}
+
+async function responseError(response) {
+  let message = `${response.status} ${response.statusText}`;
+  try {
+    const body = await response.json();
+    if (body.error) message = body.error;
+  } catch {
+    // not JSON, keep the status line
+  }
+  if (response.status === 401) message += " (set the API key in the sidebar)";
+  return new Error(message);
+}
+
+
+// ---- messages ----
+
+function addMessage(role, content = "") {
+  const element = document.createElement("div");
+  element.className = `message ${role}`;
+  const text = document.createElement("div");
+  text.textContent = content;
+  element.appendChild(text);
+  $("messages").appendChild(element);
+  scrollToBottom();
+  return { element, text };
+}
+
+function addTool(message, label, value) {
+  const tool = document.createElement("div");
+  tool.className = "tool";
+  tool.textContent = `${label}: ${typeof value === "string" ? value : JSON.stringify(value)}`;
+  message.element.insertBefore(tool, message.text);
+}
+
+function scrollToBottom() {
+  const messages = $("messages");
+  messages.scrollTop = messages.scrollHeight;
+}
+
+function setStreaming(streaming) {
+  state.streaming = streaming;
+  $("send").disabled = streaming;
+  $("stop").hidden = !streaming;
+}
+
+
+// ---- models and sessions ----
+
+async function loadModels() {
+  const select = $("model");
+  try {
+    const { models } = await api("GET", "/models");
+    const saved = localStorage.getItem("model");
+    select.innerHTML = "";
+    for (const model of models) {
+      const option = document.createElement("option");
+      option.value = model.name;
+      option.textContent = model.loaded ? `${model.name} (loaded)` : model.name;
+      select.appendChild(option);
+    }
+    if (saved && models.some((model) => model.name === saved)) select.value = saved;
+  } catch (error) {
+    addMessage("error", `Could not list models: ${error.message}`);
+  }
+}
+
+async function loadSessions() {
+  const list = $("sessions");
+  let sessions;
+  try {
+    ({ sessions } = await api("GET", "/sessions?limit=100"));
+  } catch {
+    return;
+  }
+
+  list.innerHTML = "";
+  for (const session of sessions) {
+    const item = document.createElement("li");
+    item.classList.toggle("active", session.session_id === state.sessionId);
+    const title = document.createElement("span");
+    title.textContent = session.title || "Untitled chat";
+    const remove = document.createElement("button");
+    remove.type = "button";
+    remove.title = "Delete chat";
+    remove.textContent = "✕";
+    remove.onclick = async (event) => {
+      event.stopPropagation();
+      await api("DELETE", `/sessions/${session.session_id}`).catch(() => {});
+      if (session.session_id === state.sessionId) newChat();
+      loadSessions();
+    };
+    item.append(title, remove);
+    item.onclick = () => openSession(session.session_id);
+    list.appendChild(item);
+  }
+}
+
+async function openSession(sessionId) {
+  if (state.streaming) return;
+  const session = await api("GET", `/sessions/${sessionId}`);
+  newChat();
+  state.sessionId = sessionId;
+  for (const message of session.messages) {
+    addMessage(message.role, message.content);
+  }
+  loadSessions();
+}
+
+function newChat() {
+  state.sessionId = null;
+  $("messages").innerHTML = "";
+  $("files").innerHTML = "";
+  for (const item of $("sessions").children) item.classList.remove("active");
+}
+
+
+// ---- uploads ----
+
+async function upload(files) {
+  const form = new FormData();
+  for (const file of files) form.append("file", file, file.name);
+  const uploadId = crypto.randomUUID();
+  const params = new URLSearchParams({ upload_id: uploadId });
+  if (state.sessionId) params.set("session_id", state.sessionId);
+
+  const progress = document.createElement("span");
+  progress.className = "file";
+  progress.textContent = "Uploading…";
+  $("files").appendChild(progress);
+  const timer = setInterval(() => showProgress(uploadId, progress), 500);
+
+  try {
+    const uploaded = await api("POST", `/upload?${params}`, form);
+    for (const file of uploaded) {
+      state.sessionId = file.session_id;
+      const chip = document.createElement("span");
+      chip.className = "file";
+      $("files").appendChild(chip);
+      watchFile(file, chip);
+    }
+  } catch (error) {
+    addMessage("error", `Upload failed: ${error.message}`);
+  } finally {
+    clearInterval(timer);
+    progress.remove();
+  }
+}
+
+async function showProgress(uploadId, chip) {
+  try {
+    const progress = await api("GET", `/uploads/${uploadId}`);
+    if (progress.total_bytes) {
+      const percent = Math.min(100, Math.round((100 * progress.received_bytes) / progress.total_bytes));
+      chip.textContent = `Uploading… ${percent}%`;
+    }
+  } catch {
+    // not started yet or served by another instance
+  }
+}
+
+// documents are parsed in the background, poll until they are ready
+async function watchFile(file, chip) {
+  let status = file.status;
+  while (status === "processing") {
+    chip.textContent = `${file.filename} (processing)`;
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    try {
+      status = (await api("GET", `/files/${file.file_id}/status`)).status;
+    } catch {
+      status = "failed";
+    }
+  }
+  chip.textContent = status === "failed" ? `${file.filename} (failed)` : file.filename;
+  chip.classList.toggle("failed", status === "failed");
+}
+
+
+// ---- streaming ----
+
+// calls onEvent(name, data) for every SSE event of the response
+async function readEvents(response, onEvent) {
+  const reader = response.body.getReader();
+  const decoder = new TextDecoder();
+  let buffer = "";
+  let name = "message";
+  let data = "";
+
+  for (;;) {
+    const { done, value } = await reader.read();
+    if (done) break;
+    buffer += decoder.decode(value, { stream: true });
+
+    let end;
+    while ((end = buffer.indexOf("\n")) >= 0) {
+      const line = buffer.slice(0, end).replace(/\r$/, "");
+      buffer = buffer.slice(end + 1);
+
+      if (line === "") {
+        if (data) onEvent(name, JSON.parse(data));
+        name = "message";
+        data = "";
+      } else if (line.startsWith("event:")) {
+        name = line.slice(6).trim();
+      } else if (line.startsWith("data:")) {
+        data += line.slice(5).trimStart();
+      }
+    }
+  }
+}
+
+async function send() {
+  const prompt = $("prompt").value;
+  if (!prompt.trim() || state.streaming) return;
+
+  $("prompt").value = "";
+  addMessage("user", prompt);
+  const answer = addMessage("assistant");
+  setStreaming(true);
+
+  const body = { prompt, model_name: $("model").value };
+  if (state.sessionId) body.session_id = state.sessionId;
+
+  try {
+    const response = await fetch("/generate/stream", {
+      method: "POST",
+      headers: headers({ "Content-Type": "application/json" }),
+      body: JSON.stringify(body),
+    });
+    if (!response.ok) throw await responseError(response);
+
+    await readEvents(response, (name, data) => {
+      switch (name) {
+        case "request":
+          state.requestId = data.request_id;
+          break;
+        case "token":
+          answer.text.textContent += data.content;
+          scrollToBottom();
+          break;
+        case "tool_call":
+          addTool(answer, `Calling ${data.name}`, data.arguments);
+          break;
+        case "tool_result":
+          addTool(answer, `${data.name} returned`, data.output);
+          break;
+        case "error":
+          addMessage("error", data.error);
+          break;
+        case "session":
+          state.sessionId = data.session_id;
+          break;
+      }
+    });
+  } catch (error) {
+    addMessage("error", error.message);
+  } finally {
+    state.requestId = null;
+    setStreaming(false);
+    loadSessions();
+  }
+}
+
+async function stop() {
+  if (state.requestId) {
+    await api("POST", `/generate/cancel/${state.requestId}`).catch(() => {});
+  }
+}
+
+
+// ---- setup ----
+
+$("api-key").value = localStorage.getItem("apiKey") || "";
+$("api-key").onchange = (event) => {
+  localStorage.setItem("apiKey", event.target.value.trim());
+  loadModels();
+  loadSessions();
+};
+$("model").onchange = (event) => localStorage.setItem("model", event.target.value);
+$("new-chat").onclick = newChat;
+$("stop").onclick = stop;
+$("file-input").onchange = (event) => {
+  upload(event.target.files);
+  event.target.value = "";
+};
+$("composer").onsubmit = (event) => {
+  event.preventDefault();
+  send();
+};
+$("prompt").onkeydown = (event) => {
+  if (event.key === "Enter" && !event.shiftKey) {
+    event.preventDefault();
+    send();
+  }
+};
+
+loadModels();
+loadSessions();